@@ -0,0 +1,254 @@
+//! Pre-shared-key authentication handshake.
+//!
+//! Right after a connection is accepted the receiver sends one byte saying
+//! whether authentication is required. If it is, a random 32-byte nonce
+//! follows and the sender must answer with `BLAKE3-keyed(key, nonce)`, where
+//! the key is derived from the PSK. The receiver replies with an ACK byte and
+//! drops the connection on failure, before any file header is read.
+//!
+//! With [`Features::MUTUAL_AUTH`] agreed the sender also sends a nonce of its
+//! own after its answer, and the receiver's `Ack::Ok` is followed by
+//! `BLAKE3-keyed(key, sender nonce | receiver nonce)`, so the sender knows it
+//! reached a receiver holding the key. A sender with a key refuses receivers
+//! that don't require authentication or can't prove they hold the key.
+
+use anyhow::{Context, Result};
+use std::{io::Read, path::Path};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{Ack, handshake::Features};
+
+const KEY_CONTEXT: &str = "fast-sync 2025 pre-shared key v1";
+const HTTP_TOKEN_CONTEXT: &str = "fast-sync 2025 http upload token v1";
+const NONCE_LEN: usize = 32;
+
+/// Derive the 32-byte handshake key from a PSK value
+pub fn derive_key(psk: &[u8]) -> [u8; 32] {
+    blake3::derive_key(KEY_CONTEXT, psk)
+}
+
+/// Resolve `--psk` / `--psk-file` into a handshake key
+pub fn load_key(psk: Option<&str>, psk_file: Option<&Path>) -> Result<Option<[u8; 32]>> {
+    load(psk, psk_file, "--psk", KEY_CONTEXT)
+}
+
+/// Resolve `--http-token` / `--http-token-file` into the key `--http`
+/// uploads are checked against, unrelated to the PSK's
+pub fn load_http_token(token: Option<&str>, token_file: Option<&Path>) -> Result<Option<[u8; 32]>> {
+    load(token, token_file, "--http-token", HTTP_TOKEN_CONTEXT)
+}
+
+/// Key derived from an `--http-token` value
+pub fn derive_http_key(token: &[u8]) -> [u8; 32] {
+    blake3::derive_key(HTTP_TOKEN_CONTEXT, token)
+}
+
+fn load(
+    value: Option<&str>,
+    file: Option<&Path>,
+    flag: &str,
+    context: &str,
+) -> Result<Option<[u8; 32]>> {
+    match (value, file) {
+        (Some(_), Some(_)) => anyhow::bail!("{flag} and {flag}-file are mutually exclusive"),
+        (Some(value), None) => Ok(Some(blake3::derive_key(context, value.as_bytes()))),
+        (None, Some(path)) => {
+            let raw = std::fs::read(path).with_context(|| format!("Read {}", path.display()))?;
+            let value = raw.strip_suffix(b"\n").unwrap_or(&raw);
+            anyhow::ensure!(!value.is_empty(), "{} is empty", path.display());
+            Ok(Some(blake3::derive_key(context, value)))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+fn nonce() -> Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0u8; NONCE_LEN];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut nonce))
+        .context("Read nonce from /dev/urandom")?;
+    Ok(nonce)
+}
+
+/// The receiver's proof, over both nonces so it can't be replayed as a
+/// sender's answer
+fn receiver_proof(key: &[u8; 32], theirs: &[u8], ours: &[u8]) -> blake3::Hash {
+    blake3::Hasher::new_keyed(key)
+        .update(theirs)
+        .update(ours)
+        .finalize()
+}
+
+/// Receiver side: challenge the peer if a key is configured, and prove we
+/// hold it too when `features` include [`Features::MUTUAL_AUTH`]
+pub async fn accept<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    key: Option<&[u8; 32]>,
+    features: Features,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let Some(key) = key else {
+        writer.write_all(&[0]).await?;
+        return Ok(());
+    };
+    let nonce = nonce()?;
+    let mut msg = vec![1];
+    msg.extend_from_slice(&nonce);
+    writer.write_all(&msg).await?;
+
+    let mut response = [0u8; 32];
+    reader.read_exact(&mut response).await?;
+    let mut theirs = [0u8; NONCE_LEN];
+    let mutual = features.contains(Features::MUTUAL_AUTH);
+    if mutual {
+        reader.read_exact(&mut theirs).await?;
+    }
+    // blake3::Hash equality is constant-time
+    if blake3::keyed_hash(key, &nonce) != blake3::Hash::from(response) {
+        let _ = writer.write_u8(Ack::Failed as u8).await;
+        anyhow::bail!("Authentication failed");
+    }
+    let mut msg = vec![Ack::Ok as u8];
+    if mutual {
+        msg.extend_from_slice(receiver_proof(key, &theirs, &nonce).as_bytes());
+    }
+    writer.write_all(&msg).await?;
+    Ok(())
+}
+
+/// Sender side: answer the receiver's challenge, if any. With a key, the
+/// receiver must require authentication and prove it holds the key as well,
+/// which needs [`Features::MUTUAL_AUTH`] in the agreed `features`
+pub async fn connect<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    key: Option<&[u8; 32]>,
+    features: Features,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    anyhow::ensure!(
+        key.is_none() || features.contains(Features::MUTUAL_AUTH),
+        "Destination can't prove it holds the PSK; refusing to send to it"
+    );
+    if reader.read_u8().await? == 0 {
+        anyhow::ensure!(
+            key.is_none(),
+            "Destination doesn't require authentication; refusing to send to it with a PSK"
+        );
+        return Ok(());
+    }
+    let key = key.context("Destination requires authentication (--psk / --psk-file)")?;
+    let mut nonce = [0u8; NONCE_LEN];
+    reader.read_exact(&mut nonce).await?;
+    let ours = self::nonce()?;
+    let mut msg = blake3::keyed_hash(key, &nonce).as_bytes().to_vec();
+    msg.extend_from_slice(&ours);
+    writer.write_all(&msg).await?;
+    anyhow::ensure!(
+        Ack::try_from(reader.read_u8().await?)? == Ack::Ok,
+        "Destination rejected our key"
+    );
+    let mut proof = [0u8; 32];
+    reader.read_exact(&mut proof).await?;
+    anyhow::ensure!(
+        receiver_proof(key, &ours, &nonce) == blake3::Hash::from(proof),
+        "Destination doesn't hold our PSK"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MUTUAL: Features = Features::MUTUAL_AUTH;
+
+    /// Run both sides over an in-memory pipe
+    async fn run(
+        sender_key: Option<[u8; 32]>,
+        receiver_key: Option<[u8; 32]>,
+        features: Features,
+    ) -> (Result<()>, Result<()>) {
+        let (a, b) = tokio::io::duplex(1024);
+        // Each side owns its end, so a side that gives up closes the pipe
+        tokio::join!(
+            async move {
+                let (mut reader, mut writer) = tokio::io::split(a);
+                connect(&mut reader, &mut writer, sender_key.as_ref(), features).await
+            },
+            async move {
+                let (mut reader, mut writer) = tokio::io::split(b);
+                accept(&mut reader, &mut writer, receiver_key.as_ref(), features).await
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn matching_keys_authenticate_both_ways() {
+        let key = derive_key(b"secret");
+        let (sender, receiver) = run(Some(key), Some(key), MUTUAL).await;
+        sender.unwrap();
+        receiver.unwrap();
+        let (sender, receiver) = run(None, None, MUTUAL).await;
+        sender.unwrap();
+        receiver.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrong_key_fails_on_both_sides() {
+        let (sender, receiver) =
+            run(Some(derive_key(b"one")), Some(derive_key(b"two")), MUTUAL).await;
+        assert!(sender.is_err());
+        assert!(receiver.is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_key_fails() {
+        let (sender, receiver) = run(None, Some(derive_key(b"secret")), MUTUAL).await;
+        assert!(sender.is_err());
+        assert!(receiver.is_err());
+    }
+
+    #[tokio::test]
+    async fn keyed_senders_refuse_downgrades() {
+        let key = derive_key(b"secret");
+        // A receiver without a key, e.g. a rogue one found by discovery
+        let (sender, _) = run(Some(key), None, MUTUAL).await;
+        assert!(sender.is_err());
+        // One that leaves mutual authentication out of the handshake
+        let (sender, _) = run(Some(key), Some(key), Features::LIST).await;
+        assert!(sender.is_err());
+    }
+
+    #[tokio::test]
+    async fn receivers_must_prove_the_key() {
+        let key = derive_key(b"secret");
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut ar, mut aw) = tokio::io::split(a);
+        let (mut br, mut bw) = tokio::io::split(b);
+        // Accepts whatever the sender answers, without knowing the key
+        let rogue = async {
+            bw.write_all(&[1; 1 + NONCE_LEN]).await.unwrap();
+            let mut answer = [0u8; 32 + NONCE_LEN];
+            br.read_exact(&mut answer).await.unwrap();
+            bw.write_u8(Ack::Ok as u8).await.unwrap();
+            bw.write_all(&[0; 32]).await.unwrap();
+        };
+        let (res, ()) = tokio::join!(connect(&mut ar, &mut aw, Some(&key), MUTUAL), rogue);
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_receivers_take_keyless_senders() {
+        let (sender, receiver) = run(None, None, Features::LIST).await;
+        sender.unwrap();
+        receiver.unwrap();
+    }
+}
@@ -0,0 +1,521 @@
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand};
+use fast_sync::{
+    Ack, FrameReader, FrameWriter, ManifestEntry, auth, control,
+    filter::Filter,
+    handshake::{self, Features},
+    manifest,
+    shutdown::Signals,
+    transfer_log,
+};
+use std::{collections::BTreeMap, path::PathBuf, process::ExitCode};
+use tokio::net::TcpStream;
+
+/// Maintenance tools for fast-sync deployments
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare a local tree with a receiver's by checksum without transferring
+    /// anything; exits with status 1 if they differ
+    Verify(VerifyArgs),
+    /// Write systemd unit files for the watcher and the receiver
+    InstallService(InstallArgs),
+    /// Send a command to a watcher's `--control` socket: status, queue,
+    /// pause, resume or resync PATH
+    Ctl(CtlArgs),
+    /// Mirror a directory with another host running `fast-sync peer`: runs a
+    /// receiver and a watcher on it, neither echoing the other's writes
+    Peer(PeerArgs),
+    /// Search watcher and receiver `--transfer-log` files for what went
+    /// where and when; exits with status 1 if nothing matches
+    History(HistoryArgs),
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// Local directory to compare
+    #[arg(long)]
+    dir: PathBuf,
+
+    /// Receiver to compare against, as HOST:PORT
+    #[arg(long)]
+    remote: String,
+
+    /// Where `--dir` lives under the receiver's destination directory, like
+    /// the watcher's DIR:PREFIX
+    #[arg(long, default_value = "")]
+    prefix: String,
+
+    /// Pre-shared key to authenticate with the receiver
+    #[arg(long)]
+    psk: Option<String>,
+
+    /// File containing the pre-shared key
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+
+    /// Sender id to list as, for receivers running with --per-sender-dir
+    /// [default: this host's name]
+    #[arg(long)]
+    sender_id: Option<String>,
+
+    /// Only compare files matching this glob (repeatable, gitignore-style)
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Never compare paths matching this glob (repeatable, gitignore-style)
+    #[arg(long)]
+    exclude: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct InstallArgs {
+    /// Directory to write the units to
+    #[arg(long, default_value = "/etc/systemd/system")]
+    unit_dir: PathBuf,
+
+    /// Directory holding the `watcher` and `client` binaries [default: this
+    /// binary's directory]
+    #[arg(long)]
+    bin_dir: Option<PathBuf>,
+
+    /// Directory with the `watcher.toml` and `receiver.toml` config files the
+    /// services are started with
+    #[arg(long, default_value = "/etc/fast-sync")]
+    config_dir: PathBuf,
+
+    /// Systemd watchdog timeout in seconds; 0 disables it
+    #[arg(long, default_value_t = 30)]
+    watchdog_sec: u64,
+
+    /// Print the units instead of writing them
+    #[arg(long)]
+    print: bool,
+}
+
+#[derive(Args, Debug)]
+struct CtlArgs {
+    /// The watcher's control socket
+    #[arg(long, default_value = "/run/fast-sync.sock")]
+    socket: PathBuf,
+
+    /// Command and its argument
+    #[arg(required = true, num_args = 1..)]
+    command: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct PeerArgs {
+    /// Directory to keep in sync
+    #[arg(long)]
+    dir: PathBuf,
+
+    /// The other peer's receiver, as HOST:PORT
+    #[arg(long)]
+    remote: String,
+
+    /// Where this peer's receiver listens, as IP:PORT
+    #[arg(long, default_value = "0.0.0.0:5001")]
+    bind: String,
+
+    /// When both sides changed a file: the receiver's --on-conflict policy
+    /// (overwrite, skip, rename or newer-wins)
+    #[arg(long, default_value = "newer-wins")]
+    on_conflict: String,
+
+    /// File containing the pre-shared key, used in both directions
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+
+    /// Config file with further options for the watcher
+    #[arg(long)]
+    watcher_config: Option<PathBuf>,
+
+    /// Config file with further options for the receiver
+    #[arg(long)]
+    receiver_config: Option<PathBuf>,
+
+    /// Directory holding the `watcher` and `client` binaries [default: this
+    /// binary's directory]
+    #[arg(long)]
+    bin_dir: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct HistoryArgs {
+    /// Transfer log to search (repeatable), a watcher's or a receiver's
+    #[arg(long = "log", required = true)]
+    logs: Vec<PathBuf>,
+
+    /// Only names, or rename and link targets, matching this glob
+    /// (gitignore-style)
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Only this destination (HOST:PORT) or sender id
+    #[arg(long)]
+    peer: Option<String>,
+
+    /// Only this action: sent, received, appended, deleted, renamed,
+    /// symlink or hard-link
+    #[arg(long)]
+    action: Option<String>,
+
+    /// Only changes at or after this time, in UTC, as a prefix of RFC 3339
+    /// such as 2026-10-14 or 2026-10-14T16:00
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Only the last this many matches
+    #[arg(long)]
+    last: Option<usize>,
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    match Cli::parse().command {
+        Command::Verify(args) => verify(args).await,
+        Command::InstallService(args) => install_service(args).map(|()| ExitCode::SUCCESS),
+        Command::Ctl(args) => ctl(args).await,
+        Command::Peer(args) => peer(args).await,
+        Command::History(args) => history(args),
+    }
+}
+
+/// Run the receiver and the watcher of one side of a mirrored pair until
+/// either exits or a signal stops both
+async fn peer(args: PeerArgs) -> Result<ExitCode> {
+    let bin_dir = match args.bin_dir {
+        Some(dir) => dir,
+        None => std::env::current_exe()?
+            .parent()
+            .context("Binary has no directory")?
+            .to_path_buf(),
+    };
+    std::fs::create_dir_all(&args.dir).with_context(|| format!("Create {}", args.dir.display()))?;
+    let mut receiver = tokio::process::Command::new(bin_dir.join("client"));
+    // Keeps mtimes, so `newer-wins` compares the sides' edit times
+    receiver
+        .args([
+            "--bind",
+            &args.bind,
+            "--mark-received",
+            "--preserve",
+            "mtime",
+            "--on-conflict",
+            &args.on_conflict,
+        ])
+        .arg("--dest-dir")
+        .arg(&args.dir);
+    let mut watcher = tokio::process::Command::new(bin_dir.join("watcher"));
+    watcher
+        .args([
+            "--dests",
+            &args.remote,
+            "--ignore-received",
+            "--propagate-deletes",
+            "--exclude",
+            ".fast-sync-tmp/",
+        ])
+        .arg("--watch-dir")
+        .arg(&args.dir);
+    for (cmd, config) in [
+        (&mut receiver, &args.receiver_config),
+        (&mut watcher, &args.watcher_config),
+    ] {
+        if let Some(psk_file) = &args.psk_file {
+            cmd.arg("--psk-file").arg(psk_file);
+        }
+        if let Some(config) = config {
+            cmd.arg("--config").arg(config);
+        }
+        cmd.kill_on_drop(true);
+    }
+    let mut signals = Signals::new()?;
+    let mut receiver = receiver.spawn().context("Start the receiver")?;
+    let mut watcher = watcher.spawn().context("Start the watcher")?;
+    let (name, status) = tokio::select! {
+        status = receiver.wait() => ("receiver", Some(status?)),
+        status = watcher.wait() => ("watcher", Some(status?)),
+        sig = signals.recv() => (sig, None),
+    };
+    match status {
+        Some(status) => eprintln!("[!] The {name} exited ({status}); stopping"),
+        None => println!("[*] {name}: stopping the watcher and the receiver"),
+    }
+    // The watcher first, so it can still hand its queue to the other side
+    for child in [&mut watcher, &mut receiver] {
+        if let Some(pid) = child.id() {
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        }
+        let _ = child.wait().await;
+    }
+    Ok(if status.is_none() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Print the matching lines of the logs, oldest first
+fn history(args: HistoryArgs) -> Result<ExitCode> {
+    let filter = args
+        .name
+        .map(|glob| Filter::new(&[glob], &[]))
+        .transpose()?;
+    let mut matches = Vec::new();
+    for path in &args.logs {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("Read {}", path.display()))?;
+        for line in text.lines() {
+            // time, peer, action, name, size, blake3, target and, from a
+            // watcher, the destination's answer
+            let mut fields = transfer_log::fields(line);
+            if fields.len() < 7 {
+                continue;
+            }
+            fields.resize(8, String::new());
+            let [time, peer, action, name, size, checksum, target, result] =
+                <[String; 8]>::try_from(fields).unwrap();
+            let wanted = args.since.as_ref().is_none_or(|since| time >= *since)
+                && args.peer.as_ref().is_none_or(|p| *p == peer)
+                && args.action.as_ref().is_none_or(|a| *a == action)
+                && filter.as_ref().is_none_or(|f| {
+                    f.allows_file(name.as_ref())
+                        || (!target.is_empty() && f.allows_file(target.as_ref()))
+                });
+            if wanted {
+                matches.push((time, peer, action, name, size, checksum, target, result));
+            }
+        }
+    }
+    // A stable sort keeps each log's own order within a millisecond
+    matches.sort_by(|a, b| a.0.cmp(&b.0));
+    let skip = args
+        .last
+        .map_or(0, |last| matches.len().saturating_sub(last));
+    for (time, peer, action, name, size, checksum, target, result) in &matches[skip..] {
+        // Escaped again, so each change stays on one line
+        let mut line = format!("{time}  {peer}  {action:<9} {}", transfer_log::escape(name));
+        if !target.is_empty() {
+            line.push_str(&format!(" -> {}", transfer_log::escape(target)));
+        }
+        if !size.is_empty() {
+            line.push_str(&format!(" ({size} bytes)"));
+        }
+        if !checksum.is_empty() {
+            line.push_str(&format!(" blake3 {checksum}"));
+        }
+        if !result.is_empty() {
+            line.push_str(&format!(": {result}"));
+        }
+        println!("{line}");
+    }
+    Ok(if matches.is_empty() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+async fn ctl(mut args: CtlArgs) -> Result<ExitCode> {
+    // The watcher resolves paths against its own working directory
+    if args.command[0] == "resync"
+        && let Some(path) = args.command.get_mut(1)
+    {
+        *path = std::path::absolute(&*path)?.display().to_string();
+    }
+    let answer = control::send(&args.socket, &args.command.join(" ")).await?;
+    print!("{answer}");
+    Ok(if answer.starts_with("error:") {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// Emit a `Type=notify` unit per binary, each reading its config file
+fn install_service(args: InstallArgs) -> Result<()> {
+    let bin_dir = match args.bin_dir {
+        Some(dir) => dir,
+        None => std::env::current_exe()?
+            .parent()
+            .context("Binary has no directory")?
+            .to_path_buf(),
+    };
+    let units = [
+        (
+            "fast-sync-watcher.service",
+            "fast-sync file watcher and sender",
+            "watcher",
+            "watcher.toml",
+            true,
+        ),
+        (
+            "fast-sync-receiver.service",
+            "fast-sync file receiver",
+            "client",
+            "receiver.toml",
+            false,
+        ),
+    ];
+    for (unit, description, bin, config, reloads) in units {
+        let watchdog = match args.watchdog_sec {
+            0 => String::new(),
+            secs => format!("WatchdogSec={secs}\n"),
+        };
+        let reload = if reloads {
+            "ExecReload=/bin/kill -HUP $MAINPID\n"
+        } else {
+            ""
+        };
+        let text = format!(
+            "[Unit]\n\
+             Description={description}\n\
+             Wants=network-online.target\n\
+             After=network-online.target\n\
+             \n\
+             [Service]\n\
+             Type=notify\n\
+             ExecStart={} --config {}\n\
+             {reload}\
+             Restart=on-failure\n\
+             {watchdog}\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n",
+            bin_dir.join(bin).display(),
+            args.config_dir.join(config).display(),
+        );
+        if args.print {
+            println!("# {unit}\n{text}");
+            continue;
+        }
+        let path = args.unit_dir.join(unit);
+        std::fs::write(&path, text).with_context(|| format!("Write {}", path.display()))?;
+        println!("[+] Wrote {}", path.display());
+    }
+    if !args.print {
+        println!(
+            "[*] Run `systemctl daemon-reload`, then `systemctl enable --now` the services you need"
+        );
+    }
+    Ok(())
+}
+
+async fn verify(args: VerifyArgs) -> Result<ExitCode> {
+    let key = auth::load_key(args.psk.as_deref(), args.psk_file.as_deref())?;
+    let filter = Filter::new(&args.include, &args.exclude)?;
+    let prefix = args.prefix.trim_matches('/');
+
+    let local: BTreeMap<_, _> = manifest::build(&args.dir, &filter)
+        .into_iter()
+        .map(|e| (e.name.clone(), e))
+        .collect();
+    let sender_id = args.sender_id.or_else(handshake::hostname);
+    let remote: BTreeMap<_, _> = list_remote(&args.remote, key.as_ref(), sender_id.as_deref())
+        .await
+        .with_context(|| format!("List {}", args.remote))?
+        .into_iter()
+        .filter_map(|mut e| {
+            let rel = match prefix {
+                "" => e.name.clone(),
+                _ => e.name.strip_prefix(prefix).ok()?.to_path_buf(),
+            };
+            filter.allows_file(&rel).then(|| {
+                e.name = rel.clone();
+                (rel, e)
+            })
+        })
+        .collect();
+
+    let (mut missing, mut mismatched) = (0, 0);
+    for (name, ours) in &local {
+        match remote.get(name) {
+            None => {
+                println!("missing   {}", name.display());
+                missing += 1;
+            }
+            Some(theirs) if theirs.size != ours.size || theirs.checksum != ours.checksum => {
+                println!(
+                    "mismatch  {} ({} bytes here, {} there)",
+                    name.display(),
+                    ours.size,
+                    theirs.size
+                );
+                mismatched += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    let mut extra = 0;
+    for name in remote.keys().filter(|name| !local.contains_key(*name)) {
+        println!("extra     {}", name.display());
+        extra += 1;
+    }
+    println!(
+        "[*] {} local, {} remote files: {missing} missing, {extra} extra, {mismatched} mismatched",
+        local.len(),
+        remote.len()
+    );
+    Ok(if missing + extra + mismatched == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Fetch the receiver's manifest of its whole destination directory
+async fn list_remote(
+    addr: &str,
+    key: Option<&[u8; 32]>,
+    sender_id: Option<&str>,
+) -> Result<Vec<ManifestEntry>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    let (mut reader, mut writer) = stream.split();
+    let features = Features::LIST
+        .union(Features::RAW_NAMES)
+        .union(Features::MUTUAL_AUTH);
+    let features = if sender_id.is_some() {
+        features.union(Features::SENDER_ID)
+    } else {
+        features
+    };
+    let params = handshake::connect(
+        &mut reader,
+        &mut writer,
+        features,
+        handshake::MAX_CHUNK_SIZE,
+    )
+    .await?;
+    anyhow::ensure!(
+        params.features.contains(Features::LIST),
+        "Receiver does not support listing"
+    );
+    auth::connect(&mut reader, &mut writer, key, params.features).await?;
+    if let Some(id) = sender_id.filter(|_| params.features.contains(Features::SENDER_ID)) {
+        handshake::send_id(&mut writer, id).await?;
+    }
+    let mut reader = FrameReader::new(reader);
+    let mut writer = FrameWriter::new(writer);
+
+    writer.write_list(0).await?;
+    let mut entries = Vec::new();
+    loop {
+        let batch = reader.read_manifest().await?;
+        if batch.is_empty() {
+            break;
+        }
+        entries.extend(batch);
+    }
+    let (_, ack) = reader.read_ack().await?;
+    anyhow::ensure!(ack == Ack::Ok, "Receiver answered the listing with {ack:?}");
+    Ok(entries)
+}
@@ -0,0 +1,5241 @@
+//! The `client`: receives what watchers send into its destination directory.
+//! The binary only calls [`main`]; a [`Receiver`](crate::receiver::Receiver)
+//! serves senders with the same code.
+
+use crate::{
+    APPEND_CHECK, Ack, Confirm, DedupReply, DeltaOp, FileMeta, FrameReader, FrameWriter, Integrity,
+    MAX_MANIFEST_ENTRIES, MIN_DELTA_BLOCK_SIZE, ManifestEntry, MessageKind, ProtocolHeader, auth,
+    block_hashes,
+    config::{self, Value},
+    daemon, debug, discovery,
+    echo::{self, Op},
+    error, fields,
+    filter::Filter,
+    handshake, http, info,
+    keepalive::Keepalive,
+    log, manifest,
+    metrics::{self, Counter, Gauge, Histogram},
+    multicast::{self, Datagram, PACKET_LEN},
+    parallel_hash,
+    receiver::{Event, TMP_DIR, check_name, resolve_dest},
+    reflink,
+    shutdown::Signals,
+    storage::{LocalDir, Storage, UploadCommand, preallocate},
+    throttle::{self, RateLimiter},
+    timeout::{self, Timed},
+    transfer_log::TransferLog,
+    warn, websocket, xattr,
+};
+use anyhow::{Context, Result};
+use blake3::Hasher;
+use clap::{Parser, ValueEnum, parser::ValueSource};
+use memmap2::Mmap;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    ops::ControlFlow,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{FileExt, OpenOptionsExt},
+        },
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::{Semaphore, SemaphorePermit, mpsc, watch},
+    task::{JoinHandle, JoinSet},
+};
+
+/// File receiver
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// TOML config file; command-line flags override its values.
+    /// `[[sender]]` tables limit which paths a sender may write
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Bind IP address; `::` listens on IPv6 and IPv4 alike
+    #[arg(long, default_value = "0.0.0.0")]
+    bind_ip: IpAddr,
+
+    /// Bind port
+    #[arg(long, default_value_t = 5001)]
+    bind_port: u16,
+
+    /// Listen on this IP:PORT (repeatable, e.g. one per interface), instead
+    /// of --bind-ip and --bind-port. IPv6 addresses go in brackets:
+    /// [fd00::1]:5001
+    #[arg(long)]
+    bind: Vec<SocketAddr>,
+
+    /// Dial out to a watcher running with --listen at this HOST:PORT
+    /// (repeatable) and receive over that connection, redialing whenever it
+    /// ends. Nothing listens unless --bind, --bind-ip or --bind-port is also
+    /// given.
+    #[arg(long)]
+    connect_to: Vec<String>,
+
+    /// Destination directory
+    #[arg(long, default_value = "/destino")]
+    pub(crate) dest_dir: PathBuf,
+
+    /// Put each sender's files in a directory of their own under
+    /// --dest-dir, named after the id it announces (or its IP address)
+    #[arg(long)]
+    per_sender_dir: bool,
+
+    /// Directory for the temporary files of transfers in progress, instead
+    /// of .fast-sync-tmp/ in the destination directory. It must be on the
+    /// same filesystem.
+    #[arg(long)]
+    tmp_dir: Option<PathBuf>,
+
+    /// Remove temporary files of transfers that haven't been written to for
+    /// this many seconds; younger ones are kept for their sender to resume
+    #[arg(long, default_value_t = 86400)]
+    pub(crate) stale_part_secs: u64,
+
+    /// Most bytes each sender's directory may hold (`K`/`M`/`G` suffixes
+    /// allowed); files beyond it are refused
+    #[arg(long, value_parser = crate::parse_size, requires = "per_sender_dir")]
+    quota: Option<u64>,
+
+    /// Refuse every change from senders that match no `[[sender]]` table
+    #[arg(long)]
+    deny_unlisted: bool,
+
+    /// Refuse files that would leave less than this much free space on the
+    /// destination filesystem (`K`/`M`/`G` suffixes allowed)
+    #[arg(long, value_parser = crate::parse_size, default_value = "0")]
+    min_free: u64,
+
+    /// Refuse files larger than this (`K`/`M`/`G` suffixes allowed)
+    #[arg(long, value_parser = crate::parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Most bytes per second all senders together may write (`K`/`M`/`G`
+    /// suffixes allowed). Senders over it are slowed down by holding back
+    /// their ACKs, not refused
+    #[arg(long, value_parser = throttle::parse_rate)]
+    max_rate: Option<u64>,
+
+    /// Most files per second all senders together may write
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_file_rate: Option<u64>,
+
+    /// Like --max-rate, for each connection
+    #[arg(long, value_parser = throttle::parse_rate)]
+    conn_max_rate: Option<u64>,
+
+    /// Like --max-file-rate, for each connection
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    conn_max_file_rate: Option<u64>,
+
+    /// Write at most this many files of 1 MiB or more at once, across all
+    /// connections; the others wait their turn while smaller files go on
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent_writes: Option<u32>,
+
+    /// Close connections that send no message for this many seconds
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+
+    /// Drop connections whose reads or writes make no progress for this many
+    /// seconds while a message is under way
+    #[arg(long)]
+    io_timeout: Option<u64>,
+
+    /// Drop connections that take longer than this many seconds to deliver
+    /// one file
+    #[arg(long)]
+    file_timeout: Option<u64>,
+
+    /// Send TCP keepalive probes after a connection has been quiet for this
+    /// many seconds
+    #[arg(long)]
+    keepalive: Option<u32>,
+
+    /// Seconds between keepalive probes
+    #[arg(long, default_value_t = 10)]
+    keepalive_interval: u32,
+
+    /// Unanswered keepalive probes before a connection is dropped
+    #[arg(long, default_value_t = 3)]
+    keepalive_count: u32,
+
+    /// Pre-shared key senders must authenticate with
+    #[arg(long)]
+    psk: Option<String>,
+
+    /// File containing the pre-shared key
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+
+    /// Bearer token --http uploads must carry. It crosses the network in
+    /// the clear, so it is not the PSK; without it, uploads are refused when
+    /// there is a PSK and taken from anyone otherwise
+    #[arg(long)]
+    http_token: Option<String>,
+
+    /// File containing the --http-token
+    #[arg(long)]
+    http_token_file: Option<PathBuf>,
+
+    /// Source attributes to apply to received files (comma-separated)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    preserve: Vec<Preserve>,
+
+    /// How received data is written to disk. There is no io_uring
+    /// backend; `thread` is the fallback that overlaps writes with reads
+    #[arg(long, value_enum, default_value_t = IoBackend::Sync)]
+    io_backend: IoBackend,
+
+    /// How file content is checked: with BLAKE3 checksums (full), or only by
+    /// TCP's checksums and the file's size (tcp-only), which saves the hashing
+    /// on trusted links when the watcher asks for it too
+    #[arg(long, value_enum, default_value_t = Integrity::Full)]
+    integrity: Integrity,
+
+    /// Threads to verify a large received file with, once it is in
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    hash_threads: u16,
+
+    /// Also listen on this multicast group or broadcast address (ADDR:PORT)
+    /// for the content a watcher with the same --multicast sends all its
+    /// receivers at once; only what doesn't arrive there comes over TCP.
+    /// Needs a PSK, which authenticates the datagrams
+    #[arg(long, value_name = "ADDR:PORT")]
+    multicast: Option<SocketAddr>,
+
+    /// Advertise this receiver on the local network over mDNS, as a
+    /// `_fastsync._tcp` service that watchers with --discover find
+    #[arg(long)]
+    advertise: bool,
+
+    /// Name to advertise under with --advertise, which --discover-allow on
+    /// watchers matches (default: the host name)
+    #[arg(long, value_name = "NAME")]
+    advertise_name: Option<String>,
+
+    /// Where whole files are held until they verified: in a temporary file,
+    /// or for files up to --stage-max in memory, written out in one go once
+    /// verified
+    #[arg(long, value_enum, default_value_t = Stage::Disk)]
+    stage: Stage,
+
+    /// Largest file --stage memory holds in memory (`K`/`M`/`G` suffixes
+    /// allowed)
+    #[arg(long, value_parser = crate::parse_size, default_value = "1M")]
+    stage_max: u64,
+
+    /// What must reach the disk before a file is acknowledged
+    #[arg(long, value_enum, default_value_t = Durability::None)]
+    durability: Durability,
+
+    /// Before a file is overwritten or deleted, move its old version into a
+    /// snapshot directory named after the current time under this directory,
+    /// on the same filesystem as --dest-dir
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+
+    /// With --backup-dir, remove all but this many most recent snapshots
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    backup_keep: Option<u64>,
+
+    /// Also put every received file into this directory (repeatable), at the
+    /// same path as under --dest-dir and before it appears there: hard-linked
+    /// on the same filesystem, otherwise reflinked or copied
+    #[arg(long, value_name = "DIR")]
+    also_into: Vec<PathBuf>,
+
+    /// What to do with an incoming file whose name already exists
+    #[arg(long, value_enum, default_value_t = OnConflict::Overwrite)]
+    on_conflict: OnConflict,
+
+    /// How to store names the destination filesystem may not take as sent
+    #[arg(long, value_enum, default_value_t = Names::Raw)]
+    names: Names,
+
+    /// Tag received files with a `user.fast-sync.received` attribute, so a
+    /// watcher on --dest-dir running with --ignore-received doesn't send
+    /// them back
+    #[arg(long)]
+    mark_received: bool,
+
+    /// Shell command to run after each file is verified and renamed into
+    /// place, with FASTSYNC_PATH, FASTSYNC_NAME, FASTSYNC_SIZE and
+    /// FASTSYNC_CHECKSUM set
+    #[arg(long)]
+    on_received: Option<String>,
+
+    /// Shell command that stores each verified file instead of renaming it
+    /// into --dest-dir, e.g. an upload to an object store (there is no
+    /// built-in S3 client): the file is on its stdin, with FASTSYNC_NAME,
+    /// FASTSYNC_SIZE and FASTSYNC_CHECKSUM set. Nothing stays here to work
+    /// on, so deltas, appends, scrubs, listings, symlinks and hard links
+    /// are turned off, and renames fail.
+    #[arg(long, value_name = "CMD")]
+    upload_command: Option<String>,
+
+    /// Shell command to run for each delete under --upload-command, with
+    /// FASTSYNC_NAME set; without it, deletes leave the stored files alone
+    #[arg(long, value_name = "CMD", requires = "upload_command")]
+    upload_delete: Option<String>,
+
+    /// Append a tab-separated line to this file for every change applied,
+    /// from every sender: time, sender, action, name, size, blake3 and
+    /// target
+    #[arg(long)]
+    transfer_log: Option<PathBuf>,
+
+    /// Index received content by checksum in this file, so a file a watcher
+    /// announces with --dedup-above is copied from one already here with
+    /// the same content instead of being sent
+    #[arg(long)]
+    dedup_index: Option<PathBuf>,
+
+    /// Remember what each sender's transfers put in place for this many
+    /// seconds, so a file resent after its ACK was lost is acknowledged
+    /// again instead of being applied and announced twice; 0 turns it off
+    #[arg(long, default_value_t = 600)]
+    resend_window: u64,
+
+    /// Forward what arrives to these destinations (the watcher's --dests
+    /// syntax) by running the `watcher` binary next to this one on
+    /// --dest-dir
+    #[arg(long)]
+    relay_to: Option<String>,
+
+    /// Watcher config file for the relay's other options, e.g. its
+    /// --state-dir or --psk-file
+    #[arg(long, requires = "relay_to")]
+    relay_config: Option<PathBuf>,
+
+    /// Detach from the terminal and run in the background
+    #[arg(long)]
+    daemon: bool,
+
+    /// With --daemon, write the daemon's process ID to this file
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+
+    /// Most verbose level to log
+    #[arg(long, value_enum, default_value_t = log::Level::Info)]
+    log_level: log::Level,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = log::Format::Text)]
+    log_format: log::Format,
+
+    /// Serve Prometheus metrics on this port at /metrics
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// Also take files PUT over plain HTTP to /files/<name> on this IP:PORT,
+    /// with the BLAKE3 of their content as hex in an X-Checksum header. They
+    /// go through the same checks and steps as files from a watcher. Watchers
+    /// with --websocket connect here too.
+    #[arg(long, value_name = "IP:PORT")]
+    http: Option<SocketAddr>,
+}
+
+/// Attribute that `--preserve` can copy from the sender
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Preserve {
+    /// Permission bits
+    Mode,
+    /// uid and gid (needs CAP_CHOWN)
+    Owner,
+    /// Modification time
+    Mtime,
+    /// `user.*` extended attributes and POSIX ACLs, if the sender sends them
+    Xattrs,
+}
+
+/// Where disk writes of received data happen
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum IoBackend {
+    /// In the connection task, between socket reads
+    Sync,
+    /// On a writer thread per transfer, overlapping socket reads; a plain
+    /// thread doing blocking writes, not io_uring
+    Thread,
+}
+
+/// Where a file's data waits for its checksum
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Stage {
+    Disk,
+    Memory,
+}
+
+/// Policy for files that already exist at the destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OnConflict {
+    /// Replace the existing file
+    Overwrite,
+    /// Keep the existing file and drop the incoming one
+    Skip,
+    /// Move the existing file aside to NAME.conflict-TIMESTAMP
+    Rename,
+    /// Replace the existing file only if the incoming one has a newer mtime
+    NewerWins,
+}
+
+/// Translation of received names into destination paths
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Names {
+    /// Store the bytes as sent
+    Raw,
+    /// Write bytes that are not UTF-8 as %XX
+    Escape,
+    /// Also write characters Windows filesystems reject (control characters
+    /// and \ : * ? " < > |) as %XX
+    Portable,
+}
+
+impl Names {
+    fn translate(self, name: PathBuf) -> PathBuf {
+        let bytes = name.as_os_str().as_bytes();
+        if self == Self::Raw || (self == Self::Escape && std::str::from_utf8(bytes).is_ok()) {
+            return name;
+        }
+        let mut out = String::with_capacity(bytes.len());
+        for chunk in bytes.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                if self == Self::Portable && (c.is_ascii_control() || "\\:*?\"<>|".contains(c)) {
+                    out.push_str(&format!("%{:02X}", c as u8));
+                } else {
+                    out.push(c);
+                }
+            }
+            for byte in chunk.invalid() {
+                out.push_str(&format!("%{byte:02X}"));
+            }
+        }
+        PathBuf::from(out)
+    }
+}
+
+/// How much of a received file is synced to disk before its ACK
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Durability {
+    /// Leave it to the page cache
+    None,
+    /// fdatasync the file's data before renaming it into place
+    Fdatasync,
+    /// fsync the file before renaming it and its directory after
+    Full,
+}
+
+/// Settings shared by every connection
+pub(crate) struct ReceiveOptions {
+    /// Canonical root every received path is checked against
+    dest_dir: PathBuf,
+    per_sender_dir: bool,
+    /// `--tmp-dir`, canonical
+    tmp_dir: Option<PathBuf>,
+    /// Where verified files go: --dest-dir, or `--upload-command`
+    pub(crate) storage: Arc<dyn Storage>,
+    quota: Option<Quota>,
+    policy: Option<Policy>,
+    min_free: u64,
+    max_file_size: Option<u64>,
+    /// `--max-rate` and `--max-file-rate`
+    limits: Limits,
+    /// `--conn-max-rate` and `--conn-max-file-rate`
+    conn_rates: (Option<u64>, Option<u64>),
+    /// `--max-concurrent-writes`
+    write_slots: Option<Semaphore>,
+    idle_timeout: Option<Duration>,
+    io_timeout: Option<Duration>,
+    file_timeout: Option<Duration>,
+    keepalive: Option<Keepalive>,
+    /// Handshake key derived from the PSK
+    key: Option<[u8; 32]>,
+    /// Key derived from `--http-token`
+    http_key: Option<[u8; 32]>,
+    preserve: Vec<Preserve>,
+    io_backend: IoBackend,
+    integrity: Integrity,
+    hash_threads: usize,
+    /// Largest whole file buffered in memory, under `--stage memory`
+    stage_memory: Option<u64>,
+    durability: Durability,
+    on_conflict: OnConflict,
+    names: Names,
+    mark_received: bool,
+    backups: Option<Backups>,
+    /// Canonical `--also-into` directories
+    also_into: Vec<PathBuf>,
+    /// Queue of the `--on-received` runner
+    hooks: Option<mpsc::UnboundedSender<Hook>>,
+    transfer_log: Option<TransferLog>,
+    dedup: Option<DedupIndex>,
+    versions: Versions,
+    applied: Option<Applied>,
+    stripes: Stripes,
+    multicast: Option<Arc<Multicast>>,
+    /// Told of every change applied, for a [`Receiver`](crate::receiver::Receiver)
+    pub(crate) on_change: Option<Box<dyn Fn(Event) + Send + Sync>>,
+}
+
+/// Wait before redialing a `--connect-to` watcher, doubling up to `DIAL_MAX`
+const DIAL_BASE: Duration = Duration::from_secs(1);
+const DIAL_MAX: Duration = Duration::from_secs(60);
+
+/// How often temporary areas are swept of files left behind
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Newest content version put in place per destination path, so an older
+/// one that arrives late, e.g. a retry, can't overwrite it. Forgotten on
+/// restart.
+#[derive(Default)]
+struct Versions(Mutex<HashMap<PathBuf, u64>>);
+
+/// A sender's id and one of its transfer ids
+type TransferKey = (String, [u8; 16]);
+
+/// Transfers put in place within `--resend-window`
+struct Applied {
+    window: Duration,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    /// When each was put in place, and the checksum of its content
+    by_key: HashMap<TransferKey, (Instant, blake3::Hash)>,
+    /// Oldest first, for expiry
+    order: VecDeque<(Instant, TransferKey)>,
+}
+
+impl Applied {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::default(),
+        }
+    }
+
+    /// Did this transfer already put this content in place?
+    fn contains(&self, sender: &str, id: &[u8; 16], checksum: &blake3::Hash) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        while let Some(&(at, _)) = seen
+            .order
+            .front()
+            .filter(|(at, _)| at.elapsed() > self.window)
+        {
+            let (_, key) = seen.order.pop_front().unwrap();
+            // Unless a later change under the same id took its place
+            if seen.by_key.get(&key).is_some_and(|&(t, _)| t == at) {
+                seen.by_key.remove(&key);
+            }
+        }
+        seen.by_key
+            .get(&(sender.to_string(), *id))
+            .is_some_and(|(_, have)| have == checksum)
+    }
+
+    fn insert(&self, key: TransferKey, checksum: blake3::Hash) {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.by_key.insert(key.clone(), (now, checksum));
+        seen.order.push_back((now, key));
+    }
+}
+
+/// Striped files being put together from [`MessageKind::Range`] messages,
+/// by sender and stripe id
+struct Stripes {
+    /// Untouched this long, a stripe's sender is not coming back for it
+    max_age: Duration,
+    open: Mutex<HashMap<TransferKey, Stripe>>,
+}
+
+struct Stripe {
+    path: PathBuf,
+    size: u64,
+    checksum: [u8; 32],
+    /// Verified ranges: their lengths by offset
+    ranges: BTreeMap<u64, u64>,
+    /// Every range is in; later copies of them are resends
+    complete: bool,
+    touched: Instant,
+}
+
+impl Stripes {
+    fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            open: Mutex::default(),
+        }
+    }
+
+    /// The temporary file at `path` for a range of the stripe `key`, created
+    /// with room for all `size` bytes by the first range; `None` once the
+    /// stripe is complete
+    fn open(
+        &self,
+        storage: &dyn Storage,
+        key: &TransferKey,
+        path: &Path,
+        size: u64,
+        checksum: &[u8; 32],
+    ) -> std::io::Result<Option<File>> {
+        let mut open = self.open.lock().unwrap();
+        open.retain(|_, stripe| {
+            let stale = stripe.touched.elapsed() >= self.max_age;
+            if stale && !stripe.complete {
+                let _ = std::fs::remove_file(&stripe.path);
+            }
+            !stale
+        });
+        // The same stripe with other content starts over
+        if let Some(stripe) = open
+            .get_mut(key)
+            .filter(|s| s.size == size && &s.checksum == checksum)
+        {
+            stripe.touched = Instant::now();
+            if stripe.complete {
+                return Ok(None);
+            }
+            return OpenOptions::new().write(true).open(&stripe.path).map(Some);
+        }
+        let file = storage.create(path, size, false)?;
+        let stripe = Stripe {
+            path: path.to_path_buf(),
+            size,
+            checksum: *checksum,
+            ranges: BTreeMap::new(),
+            complete: false,
+            touched: Instant::now(),
+        };
+        open.insert(key.clone(), stripe);
+        Ok(Some(file))
+    }
+
+    fn contains(&self, key: &TransferKey) -> bool {
+        self.open.lock().unwrap().contains_key(key)
+    }
+
+    /// Note a verified range; once it completes the stripe, the stripe is
+    /// held complete until the returned guard is dropped without being kept
+    fn add(&self, key: &TransferKey, offset: u64, len: u64) -> Option<Assembled<'_>> {
+        let mut open = self.open.lock().unwrap();
+        let stripe = open.get_mut(key)?;
+        stripe.ranges.insert(offset, len);
+        stripe.touched = Instant::now();
+        stripe.complete = stripe.ranges.values().sum::<u64>() >= stripe.size;
+        stripe.complete.then(|| Assembled {
+            stripes: self,
+            key: key.clone(),
+            kept: false,
+        })
+    }
+}
+
+/// A stripe whose ranges are all in. Unless [`kept`](Self::keep) once the
+/// file is in place, it is forgotten, so a resend starts over.
+struct Assembled<'a> {
+    stripes: &'a Stripes,
+    key: TransferKey,
+    kept: bool,
+}
+
+impl Assembled<'_> {
+    fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Assembled<'_> {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.stripes.open.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+/// Most multicast sends held unclaimed at once, so a flood of them can't
+/// fill the disk; the rest is left to TCP
+const MAX_INCOMING: usize = 64;
+
+/// Largest multicast send taken, even when --max-file-size allows more, so
+/// the bookkeeping of one stays small; larger files come over TCP
+const MAX_MULTICAST_SIZE: u64 = 4 << 30;
+
+/// Content that came in on the `--multicast` group, by multicast id, until
+/// the file message that follows it on a connection claims it
+struct Multicast {
+    /// Where its unnamed temporary files go: on the filesystem of every
+    /// temporary area
+    dir: PathBuf,
+    /// Datagram tag key
+    key: [u8; 32],
+    /// Unclaimed this long, a send's file message is not coming
+    max_age: Duration,
+    max_size: u64,
+    state: Mutex<Deliveries>,
+}
+
+#[derive(Default)]
+struct Deliveries {
+    incoming: HashMap<[u8; 16], Incoming>,
+    /// Claimed lately, so their stragglers don't start them over
+    claimed: HashMap<[u8; 16], Instant>,
+}
+
+/// What arrived of one multicast send
+struct Incoming {
+    /// Unnamed until claimed
+    data: File,
+    parity: File,
+    size: u64,
+    group: u8,
+    /// Which data packets and which parity packets are in
+    have: Vec<bool>,
+    parities: Vec<bool>,
+    touched: Instant,
+}
+
+impl Multicast {
+    fn new(
+        dir: PathBuf,
+        key: &[u8; 32],
+        max_age: Duration,
+        max_size: Option<u64>,
+    ) -> std::io::Result<Self> {
+        // Better now than on every datagram, where unnamed files are unsupported
+        unnamed(&dir)?;
+        let max_size = max_size.map_or(MAX_MULTICAST_SIZE, |max| max.min(MAX_MULTICAST_SIZE));
+        Ok(Self {
+            dir,
+            key: multicast::tag_key(key),
+            max_age,
+            max_size,
+            state: Mutex::default(),
+        })
+    }
+
+    /// Keep the packet of a datagram for its send
+    fn keep(&self, datagram: &Datagram) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Deliveries { incoming, claimed } = &mut *state;
+        if claimed.contains_key(&datagram.id) {
+            return Ok(());
+        }
+        if !incoming.contains_key(&datagram.id) {
+            incoming.retain(|_, sent| sent.touched.elapsed() < self.max_age);
+            claimed.retain(|_, at| at.elapsed() < self.max_age);
+            if incoming.len() >= MAX_INCOMING || datagram.size > self.max_size {
+                return Ok(());
+            }
+            incoming.insert(datagram.id, Incoming::new(&self.dir, datagram)?);
+        }
+        incoming.get_mut(&datagram.id).unwrap().add(datagram)
+    }
+
+    /// What arrived of the send `id` of a `size`-byte file, for the file
+    /// message that follows it; later datagrams of it are dropped
+    fn claim(&self, id: &[u8; 16], size: u64) -> Option<Incoming> {
+        let mut state = self.state.lock().unwrap();
+        state.claimed.retain(|_, at| at.elapsed() < self.max_age);
+        state.claimed.insert(*id, Instant::now());
+        state.incoming.remove(id).filter(|sent| sent.size == size)
+    }
+}
+
+impl Incoming {
+    fn new(dir: &Path, first: &Datagram) -> std::io::Result<Self> {
+        let data = unnamed(dir)?;
+        data.set_len(first.size)?;
+        let packets = multicast::packets(first.size) as usize;
+        let groups = if first.group > 0 {
+            packets.div_ceil(first.group as usize)
+        } else {
+            0
+        };
+        Ok(Self {
+            data,
+            parity: unnamed(dir)?,
+            size: first.size,
+            group: first.group,
+            have: vec![false; packets],
+            parities: vec![false; groups],
+            touched: Instant::now(),
+        })
+    }
+
+    fn add(&mut self, datagram: &Datagram) -> std::io::Result<()> {
+        if datagram.size != self.size || datagram.group != self.group {
+            return Ok(());
+        }
+        self.touched = Instant::now();
+        let offset = datagram.index as u64 * PACKET_LEN as u64;
+        match datagram.kind {
+            multicast::Kind::Data => {
+                self.data.write_all_at(datagram.data, offset)?;
+                self.have[datagram.index as usize] = true;
+            }
+            multicast::Kind::Parity => {
+                self.parity.write_all_at(datagram.data, offset)?;
+                self.parities[datagram.index as usize] = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuild every lost packet its parity packet allows, then list the
+    /// byte runs still missing
+    fn repair(&mut self) -> std::io::Result<Vec<(u64, u64)>> {
+        let at = |index: usize| (index * PACKET_LEN) as u64;
+        let len = |index: usize| multicast::data_len(self.size, index as u64);
+        let group = self.group as usize;
+        let mut parity = vec![0; PACKET_LEN];
+        let mut packet = vec![0; PACKET_LEN];
+        for g in (0..self.parities.len()).filter(|&g| self.parities[g]) {
+            let members = g * group..((g + 1) * group).min(self.have.len());
+            let mut lost = members.clone().filter(|&i| !self.have[i]);
+            let (Some(lost), None) = (lost.next(), lost.next()) else {
+                continue;
+            };
+            self.parity.read_exact_at(&mut parity, at(g))?;
+            for i in members.filter(|&i| i != lost) {
+                let packet = &mut packet[..len(i)];
+                self.data.read_exact_at(packet, at(i))?;
+                multicast::xor_into(&mut parity, packet);
+            }
+            self.data.write_all_at(&parity[..len(lost)], at(lost))?;
+            self.have[lost] = true;
+        }
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for i in (0..self.have.len()).filter(|&i| !self.have[i]) {
+            match runs.last_mut() {
+                Some((start, run)) if *start + *run == at(i) => *run += len(i) as u64,
+                _ => runs.push((at(i), len(i) as u64)),
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Give the data file the name `path`, replacing whatever has it
+    fn link(&self, path: &Path) -> std::io::Result<()> {
+        let _ = std::fs::remove_file(path);
+        let from = std::ffi::CString::new(format!("/proc/self/fd/{}", self.data.as_raw_fd()))?;
+        let to = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+        if unsafe {
+            libc::linkat(
+                libc::AT_FDCWD,
+                from.as_ptr(),
+                libc::AT_FDCWD,
+                to.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            )
+        } != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// A temporary file without a name in `dir`, gone once closed unless it
+/// is given one
+fn unnamed(dir: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_TMPFILE)
+        .open(dir)
+}
+
+/// Bind the `--multicast` socket, joined to the group unless `addr` is a
+/// broadcast address
+fn join_multicast(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = match addr.ip() {
+        IpAddr::V4(ip) => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port()))?;
+            if ip.is_multicast() {
+                socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+            }
+            socket
+        }
+        IpAddr::V6(ip) => {
+            let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, addr.port()))?;
+            socket.join_multicast_v6(&ip, 0)?;
+            socket
+        }
+    };
+    // Room for a burst while a datagram is written out; the kernel caps it
+    // at net.core.rmem_max
+    let size: libc::c_int = 8 << 20;
+    let len = std::mem::size_of_val(&size) as libc::socklen_t;
+    unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            (&size as *const libc::c_int).cast(),
+            len,
+        )
+    };
+    Ok(socket)
+}
+
+/// Answer mDNS queries for the receiver service with `announcement`, which
+/// also goes out once at startup; runs on a thread of its own
+fn advertise(socket: UdpSocket, announcement: Vec<u8>) {
+    if let Err(e) = socket.send_to(&announcement, discovery::MDNS) {
+        debug!("[!] Failed to announce over mDNS: {e}");
+    }
+    let mut buf = vec![0; 9000];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("[!] Failed to receive from mDNS: {e}");
+                std::thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        if discovery::asks_for_service(&buf[..n])
+            && let Err(e) = socket.send_to(&announcement, discovery::reply_to(from))
+        {
+            debug!("[!] Failed to answer an mDNS query from {from}: {e}");
+        }
+    }
+}
+
+/// Keep what arrives on the `--multicast` socket; runs on a thread of its own
+fn receive_multicast(socket: UdpSocket, multicast: Arc<Multicast>) {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = match socket.recv(&mut buf) {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("[!] Failed to receive from --multicast: {e}");
+                std::thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        if let Some(datagram) = multicast::decode(&buf[..n], Some(&multicast.key))
+            && let Err(e) = multicast.keep(&datagram)
+        {
+            debug!("[!] Failed to keep a multicast datagram: {e}");
+        }
+    }
+}
+
+impl ReceiveOptions {
+    /// Apply a change through `apply`, under `--mark-received` leaving an
+    /// echo marker for it first
+    async fn echoed(&self, root: &Path, op: Op<'_>, apply: impl Future<Output = Ack>) -> Ack {
+        // Deleting or moving what isn't there makes no event to mark
+        let exists = |name: &Path| root.join(name).symlink_metadata().is_ok();
+        let marks = self.mark_received
+            && match op {
+                Op::Delete(name) | Op::Rename(name, _) => exists(name),
+                Op::Symlink(_) => true,
+            };
+        if marks && let Err(e) = echo::record(root, &op) {
+            warn!("[!] Failed to leave an echo marker: {e}");
+        }
+        let ack = apply.await;
+        if marks && ack != Ack::Ok {
+            echo::consume(root, &op);
+        }
+        ack
+    }
+
+    /// Note an applied change in the `--transfer-log`, and tell
+    /// [`on_change`](Self::on_change)
+    fn record(&self, ack: Ack, sender: &str, name: &Path, change: Change) {
+        if let Some(on_change) = self.on_change.as_ref().filter(|_| ack == Ack::Ok) {
+            on_change(change.event(sender, name));
+        }
+        if let Some(log) = self.transfer_log.as_ref().filter(|_| ack == Ack::Ok) {
+            let [action, size, checksum, target] = change.fields();
+            log.write(&[
+                sender,
+                &action,
+                &name.to_string_lossy(),
+                &size,
+                &checksum,
+                &target,
+            ]);
+        }
+    }
+
+    /// `root`'s counterparts under the `--also-into` directories
+    fn also_roots(&self, root: &Path) -> Vec<PathBuf> {
+        let rel = root.strip_prefix(&self.dest_dir).unwrap_or(Path::new(""));
+        self.also_into.iter().map(|dir| dir.join(rel)).collect()
+    }
+
+    /// Link or copy a staged file into each `--also-into` directory's
+    /// temporary area, ready to be renamed to the returned destinations.
+    /// Whether each had to be copied comes along: a copy needs its own
+    /// attributes.
+    fn stage_copies(
+        &self,
+        root: &Path,
+        dest_path: &Path,
+        part: &Path,
+    ) -> Result<Vec<(PartFile, PathBuf, bool)>> {
+        let rel = dest_path.strip_prefix(root)?;
+        let full = dest_path.strip_prefix(&self.dest_dir)?;
+        let mut copies = Vec::new();
+        for (dir, also_root) in self.also_into.iter().zip(self.also_roots(root)) {
+            std::fs::create_dir_all(&also_root)?;
+            let dest = resolve_dest(&also_root, rel)?;
+            let tmp = dir.join(TMP_DIR);
+            let copy = PartFile::new(&tmp, part_path(&tmp, full)?);
+            let _ = std::fs::remove_file(&copy.path);
+            let copied = create_part(&copy.path, |path| match std::fs::hard_link(part, path) {
+                Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                    reflink::copy(part, path).map(|_| true)
+                }
+                linked => linked.map(|()| false),
+            })
+            .with_context(|| format!("Copy into {}", dir.display()))?;
+            copies.push((copy, dest, copied));
+        }
+        Ok(copies)
+    }
+
+    /// Apply a delete or rename that went through in `root` to the
+    /// `--also-into` directories too
+    fn mirror(&self, root: &Path, op: Op) {
+        for dir in self.also_roots(root).into_iter().filter(|dir| dir.is_dir()) {
+            let res = match op {
+                Op::Delete(name) => check_name(&dir, name).and_then(|path| {
+                    match std::fs::symlink_metadata(&path) {
+                        Ok(md) if md.is_dir() => std::fs::remove_dir_all(&path)?,
+                        Ok(_) => std::fs::remove_file(&path)?,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                    Ok(())
+                }),
+                Op::Rename(from, to) => check_name(&dir, from)
+                    .and_then(|old| Ok((old, resolve_dest(&dir, to)?)))
+                    .and_then(|(old, new)| Ok(std::fs::rename(old, new)?)),
+                Op::Symlink(_) => Ok(()),
+            };
+            if let Err(e) = res {
+                warn!("[!] Failed to mirror into {}: {e:#}", dir.display());
+            }
+        }
+    }
+
+    /// Where the temporary files of transfers into `root` go
+    fn tmp_area(&self, root: &Path) -> PathBuf {
+        match &self.tmp_dir {
+            // Per sender as well, so names from different senders can't clash
+            Some(dir) => dir.join(root.strip_prefix(&self.dest_dir).unwrap_or(Path::new(""))),
+            None => root.join(TMP_DIR),
+        }
+    }
+}
+
+impl Versions {
+    /// Is there a newer version of `path` than `version` already?
+    fn is_stale(&self, path: &Path, version: u64) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(path)
+            .is_some_and(|&have| have > version)
+    }
+
+    /// Take `version` of `path` as the newest unless a newer one got there
+    /// first; `false` if it did
+    fn claim(&self, path: &Path, version: u64) -> bool {
+        let mut versions = self.0.lock().unwrap();
+        let have = versions.entry(path.to_path_buf()).or_insert(version);
+        if *have > version {
+            return false;
+        }
+        *have = version;
+        true
+    }
+}
+
+/// Bytes and files per second received files may be written at
+struct Limits {
+    bytes: Option<RateLimiter>,
+    files: Option<RateLimiter>,
+}
+
+impl Limits {
+    fn new(bytes: Option<u64>, files: Option<u64>) -> Self {
+        Self {
+            bytes: bytes.map(RateLimiter::new),
+            files: files.map(RateLimiter::new),
+        }
+    }
+
+    /// Wait until a file of `size` bytes may be written
+    async fn admit(&self, size: u64) {
+        if let Some(files) = &self.files {
+            files.acquire(1).await;
+        }
+        if let Some(bytes) = &self.bytes {
+            bytes.acquire(size as usize).await;
+        }
+    }
+}
+
+/// `--quota`: what each sender directory may hold
+struct Quota {
+    limit: u64,
+    /// Bytes under each sender directory and when they were last counted
+    usage: Mutex<HashMap<PathBuf, (u64, Instant)>>,
+}
+
+/// How long counted usage is trusted; files received in between are added
+/// to it, deletions only show in the next count
+const QUOTA_RECOUNT: Duration = Duration::from_secs(60);
+
+/// Smallest file that needs one of the `--max-concurrent-writes` slots
+const LARGE_WRITE: u64 = 1024 * 1024;
+
+/// Rate limit and write slot waits shorter than this are neither logged nor taken off
+/// `--file-timeout`
+const THROTTLE_NOTICE: Duration = Duration::from_millis(10);
+
+impl Quota {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Would `growth` more bytes take `root` over the quota?
+    async fn exceeded(&self, root: &Path, growth: u64) -> bool {
+        let counted = self.usage.lock().unwrap().get(root).copied();
+        let used = match counted {
+            Some((used, counted)) if counted.elapsed() < QUOTA_RECOUNT => used,
+            _ => {
+                let dir = root.to_path_buf();
+                let used = tokio::task::spawn_blocking(move || disk_usage(&dir))
+                    .await
+                    .unwrap_or(0);
+                let mut usage = self.usage.lock().unwrap();
+                usage.insert(root.to_path_buf(), (used, Instant::now()));
+                used
+            }
+        };
+        used.saturating_add(growth) > self.limit
+    }
+
+    fn add(&self, root: &Path, growth: u64) {
+        if let Some((used, _)) = self.usage.lock().unwrap().get_mut(root) {
+            *used += growth;
+        }
+    }
+}
+
+/// `[[sender]]` tables: the paths each sender may write
+struct Policy {
+    rules: Vec<SenderRule>,
+    deny_unlisted: bool,
+}
+
+/// One `[[sender]]` table: senders with this `id` and/or connecting from
+/// this `addr` may only change what `allow` matches and `deny` doesn't
+struct SenderRule {
+    id: Option<String>,
+    addr: Option<IpAddr>,
+    filter: Filter,
+}
+
+/// What one connection may change
+enum Access {
+    Any,
+    Only(Filter),
+    Nothing,
+}
+
+impl Policy {
+    fn from_config(table: &config::Table, deny_unlisted: bool) -> Result<Option<Self>> {
+        let rules = match table.get("sender") {
+            None => Vec::new(),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::Table(t) => SenderRule::from_table(t),
+                    _ => anyhow::bail!("[[sender]] entries must be tables"),
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => anyhow::bail!("sender must be [[sender]] tables"),
+        };
+        Ok((!rules.is_empty() || deny_unlisted).then_some(Self {
+            rules,
+            deny_unlisted,
+        }))
+    }
+
+    /// What sender `id`, connected from `addr`, may change: the first
+    /// matching table decides
+    fn access(&self, id: &str, addr: IpAddr) -> Access {
+        let rule = self
+            .rules
+            .iter()
+            .find(|r| r.id.as_deref().is_none_or(|i| i == id) && r.addr.is_none_or(|a| a == addr));
+        match rule {
+            Some(rule) => Access::Only(rule.filter.clone()),
+            None if self.deny_unlisted => Access::Nothing,
+            None => Access::Any,
+        }
+    }
+}
+
+impl SenderRule {
+    fn from_table(table: &config::Table) -> Result<Self> {
+        let strings = |key: &str| -> Result<Vec<String>> {
+            match table.get(key) {
+                None => Ok(Vec::new()),
+                Some(Value::String(s)) => Ok(vec![s.clone()]),
+                Some(Value::Array(items)) => items
+                    .iter()
+                    .map(|v| {
+                        v.as_str()
+                            .map(str::to_string)
+                            .with_context(|| format!("[[sender]] {key} must be strings"))
+                    })
+                    .collect(),
+                Some(_) => anyhow::bail!("[[sender]] {key} must be a string or list of strings"),
+            }
+        };
+        for key in table.keys() {
+            anyhow::ensure!(
+                matches!(key.as_str(), "id" | "addr" | "allow" | "deny"),
+                "Unknown [[sender]] key {key:?}"
+            );
+        }
+        let id = match table.get("id") {
+            None => None,
+            Some(v) => Some(
+                v.as_str()
+                    .context("[[sender]] id must be a string")?
+                    .to_string(),
+            ),
+        };
+        let addr = match table.get("addr") {
+            None => None,
+            Some(v) => {
+                let addr = v.as_str().context("[[sender]] addr must be a string")?;
+                Some(
+                    addr.parse()
+                        .with_context(|| format!("Invalid [[sender]] addr {addr:?}"))?,
+                )
+            }
+        };
+        anyhow::ensure!(
+            id.is_some() || addr.is_some(),
+            "[[sender]] tables need an id or an addr"
+        );
+        Ok(Self {
+            id,
+            addr,
+            filter: Filter::new(&strings("allow")?, &strings("deny")?)?,
+        })
+    }
+}
+
+impl Access {
+    fn allows(&self, name: &Path) -> bool {
+        match self {
+            Access::Any => true,
+            Access::Only(filter) => filter.allows_file(name),
+            Access::Nothing => false,
+        }
+    }
+}
+
+/// Total size of the regular files below `dir`
+fn disk_usage(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => disk_usage(&e.path()),
+            Ok(t) if t.is_file() => e.metadata().map_or(0, |m| m.len()),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Free space on the filesystem holding `path`
+fn free_space(path: &Path) -> std::io::Result<u64> {
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut st) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(st.f_bavail as u64 * st.f_frsize as u64)
+}
+
+/// Create `dir` for the option `flag` and return its canonical path. It must
+/// be outside `dest_dir` but on the same filesystem, so that files can be
+/// renamed between the two.
+fn beside_dest(dir: &Path, dest_dir: &Path, flag: &str) -> Result<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::create_dir_all(dir).with_context(|| format!("Create {}", dir.display()))?;
+    let dir = std::fs::canonicalize(dir)?;
+    anyhow::ensure!(
+        !dir.starts_with(dest_dir),
+        "{flag} must be outside --dest-dir"
+    );
+    anyhow::ensure!(
+        std::fs::metadata(&dir)?.dev() == std::fs::metadata(dest_dir)?.dev(),
+        "{flag} must be on the same filesystem as --dest-dir"
+    );
+    Ok(dir)
+}
+
+/// `--backup-dir`: where old versions go before they are replaced or deleted
+struct Backups {
+    /// Canonical, on the same filesystem as `dest_dir`
+    dir: PathBuf,
+    dest_dir: PathBuf,
+    keep: Option<usize>,
+}
+
+/// A file for `--on-received` to announce
+struct Hook {
+    path: PathBuf,
+    name: PathBuf,
+    size: u64,
+    /// Unless it came without one, under `--integrity tcp-only`
+    checksum: Option<blake3::Hash>,
+}
+
+/// A change as the transfer log records it
+enum Change<'a> {
+    Received(&'a Hook),
+    Deleted,
+    Renamed(&'a Path),
+    Linked(&'a Path),
+    HardLinked(&'a Path),
+    /// Grown in place to this size
+    Appended(u64),
+}
+
+impl Change<'_> {
+    fn event(&self, sender: &str, name: &Path) -> Event {
+        let (sender, name) = (sender.to_string(), name.to_path_buf());
+        match self {
+            Change::Received(hook) => Event::Received {
+                sender,
+                name,
+                path: hook.path.clone(),
+                size: hook.size,
+                checksum: hook.checksum.map(|c| *c.as_bytes()),
+            },
+            Change::Deleted => Event::Deleted { sender, name },
+            Change::Renamed(to) => Event::Renamed {
+                sender,
+                from: name,
+                to: to.to_path_buf(),
+            },
+            Change::Linked(target) => Event::Linked {
+                sender,
+                name,
+                target: target.to_path_buf(),
+            },
+            Change::HardLinked(existing) => Event::HardLinked {
+                sender,
+                name,
+                existing: existing.to_path_buf(),
+            },
+            Change::Appended(size) => Event::Appended {
+                sender,
+                name,
+                size: *size,
+            },
+        }
+    }
+
+    /// The action, size, blake3 and target columns
+    fn fields(&self) -> [String; 4] {
+        let (action, size, checksum, target) = match self {
+            Change::Received(hook) => (
+                "received",
+                hook.size.to_string(),
+                hook.checksum
+                    .map(|c| c.to_hex().to_string())
+                    .unwrap_or_default(),
+                None,
+            ),
+            Change::Deleted => ("deleted", String::new(), String::new(), None),
+            Change::Renamed(to) => ("renamed", String::new(), String::new(), Some(to)),
+            Change::Linked(target) => ("symlink", String::new(), String::new(), Some(target)),
+            Change::HardLinked(existing) => {
+                ("hard-link", String::new(), String::new(), Some(existing))
+            }
+            Change::Appended(size) => ("appended", size.to_string(), String::new(), None),
+        };
+        [
+            action.to_string(),
+            size,
+            checksum,
+            target
+                .map(|t| t.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        ]
+    }
+}
+
+/// `--dedup-index`: where content that arrived earlier is, by checksum. Files
+/// change and move after the fact, so an entry is checked before it is used.
+struct DedupIndex {
+    file: Mutex<File>,
+    paths: Mutex<HashMap<[u8; 32], PathBuf>>,
+}
+
+impl DedupIndex {
+    /// Load the index, dropping the records later ones replaced.
+    /// Records are `hex blake3 | ' ' | path | NUL`.
+    fn open(path: &Path) -> Result<Self> {
+        let raw = match std::fs::read(path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Read {}", path.display())),
+        };
+        let mut paths = HashMap::new();
+        for record in raw.split(|&b| b == 0) {
+            let Some((hex, rest)) = record.split_at_checked(64) else {
+                continue;
+            };
+            let (Ok(hash), Some(name)) = (blake3::Hash::from_hex(hex), rest.strip_prefix(b" "))
+            else {
+                continue;
+            };
+            paths.insert(
+                *hash.as_bytes(),
+                PathBuf::from(std::ffi::OsStr::from_bytes(name)),
+            );
+        }
+        let mut compacted = Vec::new();
+        for (hash, name) in &paths {
+            compacted.extend_from_slice(blake3::Hash::from(*hash).to_hex().as_bytes());
+            compacted.push(b' ');
+            compacted.extend_from_slice(name.as_os_str().as_bytes());
+            compacted.push(0);
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, &compacted)
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .with_context(|| format!("Write {}", path.display()))?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Open {}", path.display()))?;
+        info!(
+            "[*] Dedup index {} knows {} files",
+            path.display(),
+            paths.len()
+        );
+        Ok(Self {
+            file: Mutex::new(file),
+            paths: Mutex::new(paths),
+        })
+    }
+
+    /// A file that still holds this content
+    fn find(&self, checksum: &[u8; 32], size: u64) -> Option<PathBuf> {
+        let path = self.paths.lock().unwrap().get(checksum).cloned()?;
+        if is_identical(&path, size, checksum, 1) {
+            return Some(path);
+        }
+        self.paths.lock().unwrap().remove(checksum);
+        None
+    }
+
+    /// Note that `path` now holds the content with this checksum
+    fn add(&self, checksum: &[u8; 32], path: &Path) {
+        let mut record = blake3::Hash::from(*checksum).to_hex().as_bytes().to_vec();
+        record.push(b' ');
+        record.extend_from_slice(path.as_os_str().as_bytes());
+        record.push(0);
+        if let Err(e) = self.file.lock().unwrap().write_all(&record) {
+            warn!("[!] Failed to write to --dedup-index: {e}");
+        }
+        self.paths
+            .lock()
+            .unwrap()
+            .insert(*checksum, path.to_path_buf());
+    }
+}
+
+/// Receiver metrics
+struct Metrics {
+    received: Arc<Counter>,
+    unchanged: Arc<Counter>,
+    failed: Arc<Counter>,
+    bytes: Arc<Counter>,
+    connections: Arc<Gauge>,
+    duration: Arc<Histogram>,
+    size: Arc<Histogram>,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let r = metrics::registry();
+    Metrics {
+        received: r.counter(
+            "fastsync_files_received_total",
+            "Files received and verified",
+            &[],
+        ),
+        unchanged: r.counter(
+            "fastsync_files_unchanged_total",
+            "Files skipped because the local copy matched",
+            &[],
+        ),
+        failed: r.counter(
+            "fastsync_files_failed_total",
+            "Transfers that failed verification",
+            &[],
+        ),
+        bytes: r.counter(
+            "fastsync_bytes_received_total",
+            "Bytes of received file content",
+            &[],
+        ),
+        connections: r.gauge("fastsync_connections", "Connected senders", &[]),
+        duration: r.histogram(
+            "fastsync_transfer_duration_seconds",
+            "Time from header to ACK per file",
+            &[],
+            metrics::DURATION_BUCKETS,
+        ),
+        size: r.histogram(
+            "fastsync_transfer_bytes",
+            "Size of received files",
+            &[],
+            metrics::SIZE_BUCKETS,
+        ),
+    }
+});
+
+/// The options from `args` that every connection shares, with the
+/// `--on-received` runner and the `--multicast` socket if they are set
+pub(crate) fn receive_options(
+    args: &Args,
+    table: &config::Table,
+) -> Result<(ReceiveOptions, Option<JoinHandle<()>>, Option<UdpSocket>)> {
+    let dest_dir = &args.dest_dir;
+    let key = auth::load_key(args.psk.as_deref(), args.psk_file.as_deref())?;
+    let http_key =
+        auth::load_http_token(args.http_token.as_deref(), args.http_token_file.as_deref())?;
+
+    std::fs::create_dir_all(dest_dir).ok();
+    let (hooks, hook_runner) = match args.on_received.clone() {
+        Some(cmd) => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (Some(tx), Some(tokio::spawn(run_hooks(cmd, rx))))
+        }
+        None => (None, None),
+    };
+    let dest_dir = std::fs::canonicalize(dest_dir)
+        .with_context(|| format!("Resolve {}", dest_dir.display()))?;
+    let backups = match &args.backup_dir {
+        Some(dir) => Some(Backups::new(
+            dir,
+            &dest_dir,
+            args.backup_keep.map(|n| n as usize),
+        )?),
+        None => None,
+    };
+    let tmp_dir = args
+        .tmp_dir
+        .as_ref()
+        .map(|dir| beside_dest(dir, &dest_dir, "--tmp-dir"))
+        .transpose()?;
+    let also_into = args
+        .also_into
+        .iter()
+        .map(|dir| {
+            std::fs::create_dir_all(dir).with_context(|| format!("Create {}", dir.display()))?;
+            let dir = std::fs::canonicalize(dir)?;
+            anyhow::ensure!(
+                !dir.starts_with(&dest_dir) && !dest_dir.starts_with(&dir),
+                "--also-into {} must be outside --dest-dir",
+                dir.display()
+            );
+            Ok(dir)
+        })
+        .collect::<Result<_>>()?;
+    let stale_part_age = Duration::from_secs(args.stale_part_secs);
+    let (multicast, multicast_socket) = match args.multicast {
+        Some(addr) => {
+            // Anyone on the network can send to the group
+            let key = key
+                .as_ref()
+                .context("--multicast needs --psk or --psk-file, to authenticate datagrams")?;
+            let dir = tmp_dir.clone().unwrap_or_else(|| dest_dir.clone());
+            let multicast = Multicast::new(dir, key, stale_part_age, args.max_file_size).context(
+                "--multicast needs unnamed temporary files (O_TMPFILE) beside --dest-dir",
+            )?;
+            let socket =
+                join_multicast(addr).with_context(|| format!("Join --multicast {addr}"))?;
+            info!("[*] Listening on multicast {addr}");
+            (Some(Arc::new(multicast)), Some(socket))
+        }
+        None => (None, None),
+    };
+    let opts = ReceiveOptions {
+        dest_dir,
+        per_sender_dir: args.per_sender_dir,
+        tmp_dir,
+        storage: match &args.upload_command {
+            Some(upload) => {
+                let mut storage = UploadCommand::new(upload);
+                if let Some(delete) = &args.upload_delete {
+                    storage = storage.with_delete(delete);
+                }
+                Arc::new(storage)
+            }
+            None => Arc::new(LocalDir),
+        },
+        quota: args.quota.map(Quota::new),
+        policy: Policy::from_config(table, args.deny_unlisted)?,
+        min_free: args.min_free,
+        max_file_size: args.max_file_size,
+        limits: Limits::new(args.max_rate, args.max_file_rate),
+        conn_rates: (args.conn_max_rate, args.conn_max_file_rate),
+        write_slots: args
+            .max_concurrent_writes
+            .map(|n| Semaphore::new(n as usize)),
+        idle_timeout: args.idle_timeout.map(Duration::from_secs),
+        io_timeout: args.io_timeout.map(Duration::from_secs),
+        file_timeout: args.file_timeout.map(Duration::from_secs),
+        keepalive: args.keepalive.map(|idle| Keepalive {
+            idle,
+            interval: args.keepalive_interval,
+            count: args.keepalive_count,
+        }),
+        key,
+        http_key,
+        preserve: args.preserve.clone(),
+        io_backend: args.io_backend,
+        integrity: args.integrity,
+        hash_threads: args.hash_threads.into(),
+        stage_memory: (args.stage == Stage::Memory).then_some(args.stage_max),
+        durability: args.durability,
+        on_conflict: args.on_conflict,
+        names: args.names,
+        mark_received: args.mark_received,
+        backups,
+        also_into,
+        hooks,
+        transfer_log: args
+            .transfer_log
+            .as_deref()
+            .map(TransferLog::open)
+            .transpose()?,
+        dedup: args
+            .dedup_index
+            .as_deref()
+            .map(DedupIndex::open)
+            .transpose()?,
+        versions: Versions::default(),
+        applied: (args.resend_window > 0)
+            .then(|| Applied::new(Duration::from_secs(args.resend_window))),
+        stripes: Stripes::new(stale_part_age),
+        multicast,
+        on_change: None,
+    };
+    Ok((opts, hook_runner, multicast_socket))
+}
+
+/// Run the `client` with the process's arguments
+pub fn main() -> Result<()> {
+    let (args, matches, table) = config::parse_args::<Args>()?;
+    if args.daemon {
+        daemon::daemonize(args.pid_file.as_deref())?;
+    }
+    let is_set = |id| matches.value_source(id) != Some(ValueSource::DefaultValue);
+    let listens = args.connect_to.is_empty() || is_set("bind_ip") || is_set("bind_port");
+    tokio::runtime::Runtime::new()?.block_on(run(args, table, listens))
+}
+
+async fn run(args: Args, table: config::Table, listens: bool) -> Result<()> {
+    log::init(args.log_level, args.log_format);
+    let mut signals = Signals::new()?;
+    let is_section = |v: &Value| matches!(v, Value::Table(_)) || config::is_table_array(v);
+    if let Some(section) = table
+        .iter()
+        .find(|(k, v)| *k != "sender" && is_section(v))
+        .map(|(k, _)| k)
+    {
+        anyhow::bail!("Unknown config section [{section}]");
+    }
+    let binds = match args.bind.is_empty() {
+        true if listens => vec![SocketAddr::new(args.bind_ip, args.bind_port)],
+        true => Vec::new(),
+        false => args.bind.clone(),
+    };
+    let (opts, hook_runner, multicast_socket) = receive_options(&args, &table)?;
+    let opts = Arc::new(opts);
+    let sweeper = spawn_helpers(
+        &opts,
+        multicast_socket,
+        Duration::from_secs(args.stale_part_secs),
+    );
+    // Every listener feeds the same accept loop
+    let (accept_tx, mut accepted) = mpsc::channel(64);
+    let mut listeners = JoinSet::new();
+    for &bind in &binds {
+        let listener = listen(bind).with_context(|| format!("Listen on {bind}"))?;
+        info!("[*] Listening on {bind}");
+        let tx = accept_tx.clone();
+        listeners.spawn(async move {
+            loop {
+                let accepted = listener.accept().await;
+                if tx.send(accepted).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(accept_tx);
+    let (http_tx, mut http_accepted) = mpsc::channel(64);
+    if let Some(addr) = args.http {
+        let listener = listen(addr).with_context(|| format!("Listen for HTTP on {addr}"))?;
+        info!("[*] Taking HTTP uploads on {addr}{HTTP_PREFIX}");
+        listeners.spawn(async move {
+            loop {
+                let accepted = listener.accept().await;
+                if http_tx.send(accepted).await.is_err() {
+                    break;
+                }
+            }
+        });
+    } else {
+        drop(http_tx);
+    }
+    if args.advertise {
+        let bind = *binds
+            .first()
+            .context("--advertise needs a port to listen on")?;
+        let host = handshake::hostname()
+            .and_then(|host| host.split('.').next().map(str::to_string))
+            .filter(|host| discovery::valid_name(host))
+            .unwrap_or_else(|| "fast-sync".into());
+        let name = args.advertise_name.clone().unwrap_or_else(|| host.clone());
+        anyhow::ensure!(
+            discovery::valid_name(&name),
+            "--advertise-name must be one DNS label: 1 to 63 bytes, no dots"
+        );
+        let addrs = match bind.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => vec![ip],
+            _ => discovery::local_addrs(),
+        };
+        let announcement = discovery::Advertisement {
+            name: &name,
+            host: &host,
+            port: bind.port(),
+            addrs: &addrs,
+        }
+        .encode();
+        let socket = discovery::responder_socket().context("Open the mDNS port for --advertise")?;
+        info!("[*] Advertising as {name} over mDNS");
+        std::thread::spawn(move || advertise(socket, announcement));
+    }
+    if let Some(port) = args.metrics_port {
+        let addr = SocketAddr::new(binds.first().map_or(args.bind_ip, |b| b.ip()), port);
+        LazyLock::force(&METRICS);
+        metrics::serve(addr).await?;
+        info!("[*] Metrics on {addr}/metrics");
+    }
+    let mut relay = match &args.relay_to {
+        Some(dests) => Some(spawn_relay(
+            dests,
+            args.relay_config.as_deref(),
+            &opts.dest_dir,
+            args.log_level,
+            args.log_format,
+        )?),
+        None => None,
+    };
+    daemon::ready();
+
+    // Set on SIGTERM / SIGINT: connections stop after the file in progress
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut conns = JoinSet::new();
+    for addr in &args.connect_to {
+        conns.spawn(dial(addr.clone(), opts.clone(), stop_rx.clone()));
+    }
+    loop {
+        let accepted = tokio::select! {
+            Some(accepted) = accepted.recv() => accepted,
+            Some(_) = conns.join_next(), if !conns.is_empty() => continue,
+            Some(accepted) = http_accepted.recv() => {
+                match accepted {
+                    Ok((conn, peer)) => {
+                        debug!("[*] HTTP connection from {peer}");
+                        let opts = opts.clone();
+                        let stop = stop_rx.clone();
+                        conns.spawn(log::in_span(fields!(peer = peer.to_string()), async move { ingest(conn, peer, &opts, stop).await }));
+                    }
+                    Err(e) => warn!("[!] HTTP accept error: {e}"),
+                }
+                continue;
+            }
+            status = async { relay.as_mut().unwrap().wait().await }, if relay.is_some() => {
+                error!(
+                    "[!] Relay watcher exited ({}); no longer forwarding",
+                    status.map_or_else(|e| e.to_string(), |s| s.to_string())
+                );
+                relay = None;
+                continue;
+            }
+            sig = signals.recv() => {
+                daemon::notify("STOPPING=1");
+                info!("[*] {sig}: finishing {} connections", conns.len());
+                break;
+            }
+        };
+        let (conn, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("[!] Accept error: {e}");
+                continue;
+            }
+        };
+        conns.spawn(serve_peer(conn, peer, opts.clone(), stop_rx.clone()));
+    }
+
+    listeners.shutdown().await;
+    sweeper.abort();
+    let _ = stop_tx.send(true);
+    let finished = tokio::select! {
+        _ = conns.join_all() => true,
+        // Dropping the tasks removes their `.part` files, except resumable ones
+        sig = signals.recv() => {
+            warn!("[!] {sig} again: aborting transfers in progress");
+            false
+        }
+    };
+    // Commands for files that made it in still run
+    drop(opts);
+    if let Some(runner) = hook_runner.filter(|_| finished) {
+        tokio::select! {
+            _ = runner => {}
+            sig = signals.recv() => warn!("[!] {sig} again: not waiting for --on-received commands"),
+        }
+    }
+    // The relay sends what is left in its queues before it exits
+    if let Some(mut relay) = relay.filter(|_| finished) {
+        if let Some(pid) = relay.id() {
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        }
+        tokio::select! {
+            _ = relay.wait() => {}
+            sig = signals.recv() => warn!("[!] {sig} again: not waiting for the relay watcher"),
+        }
+    }
+    if finished {
+        info!("[*] All connections finished, exiting");
+    }
+    Ok(())
+}
+
+/// Start receiving from the `--multicast` group on `socket`, if it is set, and
+/// the sweeps of temporary files older than `max_age`, one every
+/// `SWEEP_INTERVAL`. The first sweep runs right away, for what a crash left
+/// behind.
+pub(crate) fn spawn_helpers(
+    opts: &Arc<ReceiveOptions>,
+    socket: Option<UdpSocket>,
+    max_age: Duration,
+) -> JoinHandle<()> {
+    if let Some(socket) = socket {
+        let multicast = opts.multicast.clone().unwrap();
+        std::thread::spawn(move || receive_multicast(socket, multicast));
+    }
+    let opts = opts.clone();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let opts = opts.clone();
+            let removed = tokio::task::spawn_blocking(move || sweep_tmp_areas(&opts, max_age))
+                .await
+                .unwrap_or(0);
+            if removed > 0 {
+                info!("[-] Removed {removed} stale temporary files");
+            }
+        }
+    })
+}
+
+/// Start `--relay-to`: a watcher on `dest_dir` that leaves out transfers in
+/// progress
+fn spawn_relay(
+    dests: &str,
+    config: Option<&Path>,
+    dest_dir: &Path,
+    level: log::Level,
+    format: log::Format,
+) -> Result<tokio::process::Child> {
+    let exe = std::env::current_exe()?.with_file_name("watcher");
+    let mut cmd = tokio::process::Command::new(&exe);
+    cmd.arg("--watch-dir")
+        .arg(dest_dir)
+        .args(["--dests", dests])
+        .args(["--exclude", &format!("{TMP_DIR}/")])
+        .args(["--log-level", level.to_possible_value().unwrap().get_name()])
+        .args([
+            "--log-format",
+            format.to_possible_value().unwrap().get_name(),
+        ])
+        // Readiness and the watchdog are this process's to report
+        .env_remove("NOTIFY_SOCKET")
+        .env_remove("WATCHDOG_USEC")
+        .kill_on_drop(true);
+    if let Some(config) = config {
+        cmd.arg("--config").arg(config);
+    }
+    let child = cmd
+        .spawn()
+        .with_context(|| format!("Start {}", exe.display()))?;
+    info!("[*] Relaying to {dests}");
+    Ok(child)
+}
+
+/// `--connect-to`: keep a connection to the watcher at `addr` open,
+/// backing off while it can't be reached
+async fn dial(addr: String, opts: Arc<ReceiveOptions>, mut stop: watch::Receiver<bool>) {
+    let mut failures = 0;
+    while !*stop.borrow() {
+        let conn = tokio::select! {
+            conn = TcpStream::connect(&addr) => conn,
+            _ = stop.wait_for(|&stop| stop) => break,
+        };
+        match conn {
+            Ok(conn) => {
+                failures = 0;
+                let peer = conn
+                    .peer_addr()
+                    .map_or_else(|_| addr.clone(), |p| p.to_string());
+                info!("[*] Connected to watcher at {peer}");
+                METRICS.connections.inc();
+                let res = log::in_span(
+                    fields!(peer = peer.clone()),
+                    handle_conn(conn, &opts, stop.clone()),
+                )
+                .await;
+                METRICS.connections.dec();
+                if let Err(e) = res {
+                    error!("[!] Connection to {peer} failed: {e:#}");
+                }
+            }
+            Err(e) => {
+                failures += 1;
+                match failures {
+                    1 => warn!("[!] Could not reach watcher at {addr}: {e}; retrying"),
+                    _ => debug!("[!] Could not reach watcher at {addr}: {e}"),
+                }
+            }
+        }
+        // Also after a connection ends, so one that fails right away isn't
+        // redialed in a tight loop
+        let delay = DIAL_BASE.saturating_mul(1 << failures.min(6)).min(DIAL_MAX);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop.wait_for(|&stop| stop) => break,
+        }
+    }
+}
+
+/// Files PUT to `--http` go to this path followed by their name
+const HTTP_PREFIX: &str = "/files/";
+
+/// Header of an `--http` upload with the BLAKE3 of its content, in hex
+const CHECKSUM_HEADER: &str = "X-Checksum";
+
+/// Payload chunk size `--http` uploads are passed on in
+const HTTP_CHUNK: u32 = 1 << 20;
+
+/// Status line, extra headers and body of an answer to an `--http` upload
+type HttpAnswer = (
+    &'static str,
+    &'static [(&'static str, &'static str)],
+    String,
+);
+
+/// Take a file PUT over HTTP by `peer` and answer with how it went, or
+/// receive over the WebSocket it asks for
+async fn ingest(
+    mut conn: TcpStream,
+    peer: SocketAddr,
+    opts: &ReceiveOptions,
+    stop: watch::Receiver<bool>,
+) {
+    // The WebSocket's own traffic is what the protocol's timeouts watch
+    let mut timed = Timed::new(&mut conn, opts.io_timeout);
+    let answer = match http::read_request(&mut timed).await {
+        Ok((request, early)) if websocket::is_upgrade(&request) => {
+            info!("[*] Connected from {peer} over WebSocket");
+            METRICS.connections.inc();
+            if let Err(e) = tunnel(conn, &request, early, peer, opts, stop).await {
+                error!("[!] Connection from {peer} failed: {e:#}");
+            }
+            METRICS.connections.dec();
+            return;
+        }
+        Ok((request, body)) => {
+            info!("[*] HTTP upload from {peer}");
+            ingest_file(&mut timed, request, body, peer, opts, stop).await
+        }
+        Err(e) => Err(e),
+    };
+    let (status, headers, body) = match answer {
+        Ok(answer) => answer,
+        Err(e) => {
+            warn!("[!] HTTP upload from {peer} failed: {e:#}");
+            ("400 Bad Request", &[][..], format!("{e:#}\n"))
+        }
+    };
+    if let Err(e) = http::respond(&mut timed, status, headers, &body).await {
+        debug!("[!] Failed to answer the HTTP upload from {peer}: {e}");
+    }
+}
+
+/// Grant the WebSocket upgrade `request` asks for and receive over it, as
+/// over a connection of its own; `early` arrived with the request
+async fn tunnel(
+    mut conn: TcpStream,
+    request: &http::Request,
+    early: Vec<u8>,
+    peer: SocketAddr,
+    opts: &ReceiveOptions,
+    stop: watch::Receiver<bool>,
+) -> Result<()> {
+    conn.set_nodelay(true)?;
+    if let Some(keepalive) = &opts.keepalive {
+        keepalive.apply(&conn)?;
+    }
+    websocket::accept(&mut conn, request).await?;
+    let (near, far) = tokio::io::duplex(2 * HTTP_CHUNK as usize);
+    let (reader, writer) = tokio::io::split(near);
+    let (pumped, served) = tokio::join!(
+        websocket::pump(conn, early, far, websocket::Role::Server),
+        serve_conn(reader, writer, peer, opts, stop),
+    );
+    served?;
+    pumped
+}
+
+/// Check an `--http` upload and run it through [`serve_conn`], as the one
+/// file message of an in-memory connection
+async fn ingest_file<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    request: http::Request,
+    body: Vec<u8>,
+    peer: SocketAddr,
+    opts: &ReceiveOptions,
+    stop: watch::Receiver<bool>,
+) -> Result<HttpAnswer> {
+    if request.method != "PUT" {
+        return Ok((
+            "405 Method Not Allowed",
+            &[("Allow", "PUT")],
+            "Only PUT is supported\n".into(),
+        ));
+    }
+    let path = request.target.split('?').next().unwrap_or_default();
+    let Some(name) = path
+        .strip_prefix(HTTP_PREFIX)
+        .and_then(http::percent_decode)
+    else {
+        return Ok((
+            "404 Not Found",
+            &[],
+            format!("Files go to {HTTP_PREFIX}<name>\n"),
+        ));
+    };
+    match (&opts.http_key, &opts.key) {
+        (Some(key), _) => {
+            // blake3::Hash equality is constant-time
+            let token = request
+                .header("Authorization")
+                .and_then(|v| v.strip_prefix("Bearer "));
+            if token.is_none_or(|token| {
+                blake3::Hash::from(auth::derive_http_key(token.trim().as_bytes()))
+                    != blake3::Hash::from(*key)
+            }) {
+                return Ok((
+                    "401 Unauthorized",
+                    &[("WWW-Authenticate", "Bearer")],
+                    "Needs the --http-token as a bearer token\n".into(),
+                ));
+            }
+        }
+        (None, Some(_)) => {
+            return Ok((
+                "403 Forbidden",
+                &[],
+                "Uploads need an --http-token on the receiver\n".into(),
+            ));
+        }
+        (None, None) => {}
+    }
+    let Some(size) = request.content_length()? else {
+        return Ok((
+            "411 Length Required",
+            &[],
+            "Needs a Content-Length\n".into(),
+        ));
+    };
+    let Some(checksum) = request
+        .header(CHECKSUM_HEADER)
+        .and_then(|v| blake3::Hash::from_hex(v.trim()).ok())
+    else {
+        return Ok((
+            "400 Bad Request",
+            &[],
+            format!("Needs the BLAKE3 of the content as hex in {CHECKSUM_HEADER}\n"),
+        ));
+    };
+    if request
+        .header("Expect")
+        .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+    {
+        http::send_continue(conn).await?;
+    }
+    let name = PathBuf::from(std::ffi::OsStr::from_bytes(&name));
+    let (near, far) = tokio::io::duplex(2 * HTTP_CHUNK as usize);
+    let (far_reader, far_writer) = tokio::io::split(far);
+    let (received, sent) = tokio::join!(
+        serve_conn(far_reader, far_writer, peer, opts, stop),
+        pass_on(near, conn, body, name, size, checksum, opts.key.as_ref()),
+    );
+    let (ack, reason) = match sent {
+        Ok(acked) => acked,
+        // The receiving side's error says why, if it failed first
+        Err(e) => return Err(received.err().unwrap_or(e)),
+    };
+    let (status, text) = match ack {
+        Ack::Ok | Ack::MovedAside => ("201 Created", "Stored"),
+        Ack::Unchanged => ("200 OK", "Already there with this content"),
+        Ack::Kept | Ack::Superseded => ("409 Conflict", "Kept the copy already there"),
+        Ack::RejectedName | Ack::Forbidden => ("403 Forbidden", "Not allowed"),
+        Ack::NoSpace | Ack::OverQuota => ("507 Insufficient Storage", "No room"),
+        Ack::ChecksumMismatch => (
+            "422 Unprocessable Content",
+            "The content doesn't match its checksum",
+        ),
+        Ack::Failed => ("500 Internal Server Error", "Failed"),
+    };
+    Ok((
+        status,
+        &[],
+        format!("{}\n", reason.as_deref().unwrap_or(text)),
+    ))
+}
+
+/// Send `name` over `conn` as a watcher would: `size` bytes of content,
+/// `head` of them already read and the rest coming from `body`
+async fn pass_on<C: AsyncRead + AsyncWrite, B: AsyncRead + Unpin>(
+    conn: C,
+    body: &mut B,
+    mut head: Vec<u8>,
+    name: PathBuf,
+    size: u64,
+    checksum: blake3::Hash,
+    key: Option<&[u8; 32]>,
+) -> Result<(Ack, Option<String>)> {
+    let (mut reader, mut writer) = tokio::io::split(conn);
+    let features = handshake::Features::RAW_NAMES
+        .union(handshake::Features::ACK_REASONS)
+        .union(handshake::Features::MUTUAL_AUTH);
+    let params = handshake::connect(&mut reader, &mut writer, features, HTTP_CHUNK).await?;
+    auth::connect(&mut reader, &mut writer, key, params.features).await?;
+    let (mut reader, mut writer) = (FrameReader::new(reader), FrameWriter::new(writer));
+    reader.set_features(params.features);
+    writer.set_features(params.features);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let meta = FileMeta {
+        mode: 0o644,
+        uid: unsafe { libc::geteuid() },
+        gid: unsafe { libc::getegid() },
+        mtime_sec: now.as_secs() as i64,
+        mtime_nsec: now.subsec_nanos(),
+    };
+    let header = ProtocolHeader {
+        name,
+        size,
+        meta,
+        confirm: None,
+        xattrs: None,
+        version: None,
+        transfer_id: None,
+    };
+    writer.write_header(MessageKind::File, 1, &header).await?;
+    head.truncate(size.min(head.len() as u64) as usize);
+    let mut chunk = head;
+    let mut sent = 0;
+    while sent < size {
+        let want = (size - sent).min(params.chunk_size as u64) as usize;
+        let mut filled = chunk.len();
+        chunk.resize(want.max(filled), 0);
+        while filled < want {
+            let n = body.read(&mut chunk[filled..want]).await?;
+            anyhow::ensure!(
+                n > 0,
+                "Upload ended after {} of {size} bytes",
+                sent + filled as u64
+            );
+            filled += n;
+        }
+        writer.write_chunk(&chunk[..want]).await?;
+        sent += want as u64;
+        chunk.drain(..want);
+    }
+    writer.write_checksum(checksum.as_bytes()).await?;
+    let (_, ack, reason) = reader.read_ack_reason().await?;
+    Ok((ack, reason))
+}
+
+/// Bind a listening socket of `addr`'s family. An IPv6 socket also accepts
+/// IPv4 clients unless bound to a specific address.
+pub(crate) fn listen(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = if addr.is_ipv6() {
+        TcpSocket::new_v6()?
+    } else {
+        TcpSocket::new_v4()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_nodelay(true)?;
+    if addr.is_ipv6() {
+        let off: libc::c_int = 0;
+        let res = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                (&off as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error()).context("Clear IPV6_V6ONLY");
+        }
+    }
+    socket.bind(addr)?;
+    Ok(socket.listen(1024)?)
+}
+
+/// Run the `--on-received` command for each file in turn, so downstream
+/// steps see files in the order they arrived
+async fn run_hooks(cmd: String, mut rx: mpsc::UnboundedReceiver<Hook>) {
+    while let Some(hook) = rx.recv().await {
+        let status = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&cmd)
+            .env("FASTSYNC_PATH", &hook.path)
+            .env("FASTSYNC_NAME", &hook.name)
+            .env("FASTSYNC_SIZE", hook.size.to_string())
+            .env(
+                "FASTSYNC_CHECKSUM",
+                hook.checksum
+                    .map(|c| c.to_hex().to_string())
+                    .unwrap_or_default(),
+            )
+            .status()
+            .await;
+        match status {
+            Ok(status) if status.success() => {
+                debug!("[*] --on-received done for {}", hook.name.display(); name = &hook.name)
+            }
+            Ok(status) => {
+                warn!("[!] --on-received for {} failed ({status})", hook.name.display(); name = &hook.name)
+            }
+            Err(e) => {
+                warn!("[!] Failed to run --on-received for {}: {e}", hook.name.display(); name = &hook.name)
+            }
+        }
+    }
+}
+
+/// A temporary file that is removed unless it was renamed into place or
+/// belongs to a resumable transfer. Either way its directories in the
+/// temporary area go with it once they are empty.
+struct PartFile {
+    path: PathBuf,
+    /// Temporary area the file is in
+    tmp: PathBuf,
+    kept: bool,
+}
+
+impl PartFile {
+    fn new(tmp: &Path, path: PathBuf) -> Self {
+        Self {
+            path,
+            tmp: tmp.to_path_buf(),
+            kept: false,
+        }
+    }
+
+    /// Left behind if the transfer is interrupted, so it can be resumed
+    fn resumable(tmp: &Path, path: PathBuf) -> Self {
+        Self {
+            path,
+            tmp: tmp.to_path_buf(),
+            kept: true,
+        }
+    }
+
+    /// Rename into `dest` and stop tracking the file
+    fn persist(mut self, dest: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.path, dest)?;
+        self.kept = true;
+        prune_tmp_dirs(&self.tmp, &self.path);
+        Ok(())
+    }
+
+    /// Remove the file, once dropped, even if it is resumable
+    fn discard(&mut self) {
+        self.kept = false;
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = std::fs::remove_file(&self.path);
+            prune_tmp_dirs(&self.tmp, &self.path);
+        }
+    }
+}
+
+/// A received and verified file, waiting to be renamed into place
+struct Staged {
+    part: PartFile,
+    dest_path: PathBuf,
+    name: PathBuf,
+    size: u64,
+    meta: FileMeta,
+    xattrs: Option<Vec<xattr::Xattr>>,
+    checksum: Option<blake3::Hash>,
+    durability: Durability,
+    /// Rename the copy it replaces under `--on-conflict rename`
+    moves_aside: bool,
+    version: Option<u64>,
+    /// How much it adds to the sender directory's usage
+    growth: u64,
+    /// Sender and transfer id, for `--resend-window`
+    transfer: Option<TransferKey>,
+}
+
+impl Staged {
+    /// Rename into place, synced as configured so an ACKed file survives a
+    /// crash, after moving aside or backing up the copy it replaces. `None`
+    /// if a newer version got there in the meantime.
+    async fn install(self, root: &Path, opts: &ReceiveOptions) -> Result<Option<Hook>> {
+        let Staged {
+            mut part,
+            dest_path,
+            name,
+            size,
+            meta,
+            xattrs,
+            checksum,
+            durability,
+            moves_aside,
+            version,
+            growth,
+            transfer,
+        } = self;
+        if version.is_some_and(|v| !opts.versions.claim(&dest_path, v)) {
+            info!(
+                "[=] Dropped {}: a newer version was put in place while it arrived",
+                name.display();
+                name = &name
+            );
+            return Ok(None);
+        }
+        sync_file(&part.path, durability)
+            .with_context(|| format!("Sync {}", part.path.display()))?;
+        if moves_aside && dest_path.is_file() {
+            let aside = conflict_path(&dest_path);
+            std::fs::rename(&dest_path, &aside)
+                .with_context(|| format!("Move {} aside", dest_path.display()))?;
+            info!(
+                "[>] Moved previous {} aside to {}",
+                name.display(),
+                aside.display();
+                name = &name
+            );
+        }
+        if let Some(backups) = &opts.backups {
+            backups
+                .save(&dest_path, false)
+                .with_context(|| format!("Back up {}", name.display()))?;
+        }
+        // Before the rename, so a watcher on the directory only ever sees the
+        // finished file
+        if let Err(e) = apply_meta(&part.path, &meta, xattrs.as_deref(), &opts.preserve) {
+            warn!("[!] Failed to apply attributes to {}: {e}", name.display());
+        }
+        if opts.mark_received
+            && let Err(e) = File::open(&part.path).and_then(|f| xattr::mark_received(&f))
+        {
+            warn!("[!] Failed to mark {} as received: {e}", name.display());
+        }
+        // Every copy is ready before any of them appears
+        let copies = opts
+            .stage_copies(root, &dest_path, &part.path)
+            .with_context(|| format!("Stage copies of {}", name.display()))?;
+        for (copy, ..) in copies.iter().filter(|(_, _, copied)| *copied) {
+            if let Err(e) = apply_meta(&copy.path, &meta, xattrs.as_deref(), &opts.preserve) {
+                warn!(
+                    "[!] Failed to apply attributes to a copy of {}: {e}",
+                    name.display()
+                );
+            }
+            if opts.mark_received
+                && let Err(e) = File::open(&copy.path).and_then(|f| xattr::mark_received(&f))
+            {
+                warn!(
+                    "[!] Failed to mark a copy of {} as received: {e}",
+                    name.display()
+                );
+            }
+            sync_file(&copy.path, durability)
+                .with_context(|| format!("Sync {}", copy.path.display()))?;
+        }
+        let storage = opts.storage.clone();
+        let (path, stored_name, dest) = (part.path.clone(), name.clone(), dest_path.clone());
+        let stored = tokio::task::spawn_blocking(move || {
+            storage.commit(&path, &stored_name, &dest, checksum.as_ref())
+        })
+        .await??;
+        // Whatever the storage left behind goes with the part
+        part.discard();
+        drop(part);
+        let mut dirs = Vec::new();
+        if stored {
+            dirs.push(dest_path.parent().unwrap_or(root).to_path_buf());
+        }
+        for (copy, dest, _) in copies {
+            match copy.persist(&dest) {
+                Ok(()) => dirs.push(dest.parent().unwrap_or(&dest).to_path_buf()),
+                Err(e) => warn!(
+                    "[!] Failed to rename a copy of {} into {}: {e}",
+                    name.display(),
+                    dest.display()
+                ),
+            }
+        }
+        if durability == Durability::Full {
+            for dir in dirs {
+                File::open(&dir)?
+                    .sync_all()
+                    .with_context(|| format!("Sync {}", dir.display()))?;
+            }
+        }
+        if let Some(quota) = &opts.quota {
+            quota.add(root, growth);
+        }
+        if let (Some(applied), Some(key), Some(checksum)) = (&opts.applied, transfer, checksum) {
+            applied.insert(key, checksum);
+        }
+        Ok(Some(Hook {
+            path: dest_path,
+            name,
+            size,
+            checksum,
+        }))
+    }
+}
+
+/// Members of an open [`MessageKind::Transaction`], renamed into place
+/// together once the last one has been received and verified, or of an
+/// open [`MessageKind::Batch`] or [`MessageKind::Archive`], put in place as
+/// they come
+struct Transaction {
+    seq: u32,
+    /// Members still to come; an archive runs until its closing frame
+    remaining: u16,
+    /// A batch's members' outcomes so far, in order
+    batch: Option<Vec<Ack>>,
+    archive: bool,
+    staged: Vec<Staged>,
+    /// How the first member that went wrong was refused
+    failure: Option<Ack>,
+    /// No member had new content
+    unchanged: bool,
+}
+
+impl Transaction {
+    fn new(seq: u32, count: u16) -> Self {
+        Self {
+            seq,
+            remaining: count,
+            batch: None,
+            archive: false,
+            staged: Vec::new(),
+            failure: None,
+            unchanged: true,
+        }
+    }
+
+    fn batch(seq: u32, count: u16) -> Self {
+        Self {
+            batch: Some(Vec::with_capacity(count as usize)),
+            ..Self::new(seq, count)
+        }
+    }
+
+    fn archive(seq: u32) -> Self {
+        Self {
+            archive: true,
+            ..Self::batch(seq, 1)
+        }
+    }
+
+    /// Note a member's outcome in place of its ACK
+    fn record(&mut self, ack: Ack) {
+        if let Some(statuses) = &mut self.batch {
+            statuses.push(ack);
+            return;
+        }
+        match ack {
+            Ack::Unchanged | Ack::Kept | Ack::Superseded => {}
+            Ack::Ok | Ack::MovedAside => self.unchanged = false,
+            Ack::Failed
+            | Ack::RejectedName
+            | Ack::NoSpace
+            | Ack::OverQuota
+            | Ack::Forbidden
+            | Ack::ChecksumMismatch => {
+                self.failure.get_or_insert(ack);
+            }
+        }
+    }
+
+    /// Rename the members into place in the order they were sent, so a
+    /// marker file sent last only appears once the rest are there, and ACK
+    /// the transaction as a whole. If any member failed, none is installed.
+    async fn commit<W: AsyncWrite + Unpin>(
+        self,
+        writer: &mut FrameWriter<W>,
+        root: &Path,
+        sender: &str,
+        opts: &ReceiveOptions,
+    ) -> Result<()> {
+        if let Some(statuses) = &self.batch
+            && self.archive
+        {
+            let missing: Vec<u32> = (0..statuses.len() as u32)
+                .filter(|&i| statuses[i as usize].is_failure())
+                .collect();
+            info!(
+                "[+] Unpacked archive of {} files from {sender} ({} not stored)",
+                statuses.len(),
+                missing.len();
+                seq = self.seq
+            );
+            writer.write_stale(&missing).await?;
+            return writer.write_ack(self.seq, Ack::Ok).await;
+        }
+        if let Some(statuses) = &self.batch {
+            return writer.write_batch_ack(self.seq, statuses).await;
+        }
+        let count = self.staged.len();
+        let ack = match self.failure {
+            Some(ack) => {
+                warn!(
+                    "[!] Dropping transaction of {count} received files: a member was refused with {ack:?}";
+                    seq = self.seq
+                );
+                METRICS.failed.inc();
+                ack
+            }
+            None => {
+                let mut names = Vec::new();
+                let mut ack = if self.unchanged {
+                    Ack::Unchanged
+                } else {
+                    Ack::Ok
+                };
+                for staged in self.staged {
+                    match staged.install(root, opts).await {
+                        Ok(None) => {}
+                        Ok(Some(hook)) => {
+                            METRICS.received.inc();
+                            METRICS.bytes.add(hook.size);
+                            METRICS.size.observe(hook.size as f64);
+                            names.push(hook.name.display().to_string());
+                            opts.record(Ack::Ok, sender, &hook.name, Change::Received(&hook));
+                            if let Some(index) = &opts.dedup
+                                && let Some(checksum) = hook.checksum
+                            {
+                                index.add(checksum.as_bytes(), &hook.path);
+                            }
+                            if let Some(hooks) = &opts.hooks {
+                                let _ = hooks.send(hook);
+                            }
+                        }
+                        // The members after it are dropped
+                        Err(e) => {
+                            warn!(
+                                "[!] Transaction {} stopped after {} of {count} files: {e:#}",
+                                self.seq,
+                                names.len();
+                                seq = self.seq
+                            );
+                            METRICS.failed.inc();
+                            writer.explain(format!("{e:#}"));
+                            ack = Ack::Failed;
+                            break;
+                        }
+                    }
+                }
+                if !names.is_empty() {
+                    info!("[+] Committed {} together", names.join(", "); seq = self.seq);
+                }
+                ack
+            }
+        };
+        writer.write_ack(self.seq, ack).await
+    }
+}
+
+/// Receive files from one sender until it disconnects or `stop` is set
+/// Serve a sender that connected from `peer` until it leaves or `stop` is set
+pub(crate) async fn serve_peer(
+    conn: TcpStream,
+    peer: SocketAddr,
+    opts: Arc<ReceiveOptions>,
+    stop: watch::Receiver<bool>,
+) {
+    info!("[*] Connected from {}", peer);
+    log::in_span(fields!(peer = peer.to_string()), async move {
+        METRICS.connections.inc();
+        if let Err(e) = handle_conn(conn, &opts, stop).await {
+            error!("[!] Connection from {peer} failed: {e:#}");
+        }
+        METRICS.connections.dec();
+    })
+    .await
+}
+
+async fn handle_conn(
+    mut conn: TcpStream,
+    opts: &ReceiveOptions,
+    stop: watch::Receiver<bool>,
+) -> Result<()> {
+    conn.set_nodelay(true)?;
+    if let Some(keepalive) = &opts.keepalive {
+        keepalive.apply(&conn)?;
+    }
+    let peer = conn.peer_addr()?;
+    let (reader, writer) = conn.split();
+    serve_conn(reader, writer, peer, opts, stop).await
+}
+
+/// Receive from the sender at `peer` over `reader` and `writer`, from the
+/// handshake until it closes the connection
+async fn serve_conn<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: R,
+    writer: W,
+    peer: SocketAddr,
+    opts: &ReceiveOptions,
+    mut stop: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut conn = Conn::accept(reader, writer, peer, opts).await?;
+    loop {
+        if conn.txn.as_ref().is_some_and(|t| t.remaining == 0) {
+            let txn = conn.txn.take().unwrap();
+            txn.commit(&mut conn.writer, &conn.root, &conn.id, opts)
+                .await?;
+        }
+        conn.reader.get_mut().set_stall(opts.idle_timeout);
+        conn.reader.get_mut().set_deadline(None);
+        conn.writer.get_mut().set_deadline(None);
+        let total_start = Instant::now();
+        let next = tokio::select! {
+            biased;
+            _ = stop.wait_for(|&stop| stop) => {
+                info!("[*] Closing connection from {peer} for shutdown");
+                break;
+            }
+            next = conn.reader.read_kind() => match next {
+                Err(e) if timeout::is_timeout(&e) => {
+                    info!(
+                        "[*] Closing connection from {peer}: idle for {}s",
+                        opts.idle_timeout.unwrap_or_default().as_secs()
+                    );
+                    break;
+                }
+                next => next?,
+            },
+        };
+        let Some((kind, seq)) = next else {
+            info!("[*] Connection from {peer} closed");
+            break;
+        };
+        conn.reader.get_mut().set_stall(opts.io_timeout);
+        if kind == MessageKind::Archive
+            && let Some(open) = conn.txn.take_if(|t| t.archive)
+        {
+            anyhow::ensure!(open.seq == seq, "Archive #{} closed as #{seq}", open.seq);
+            open.commit(&mut conn.writer, &conn.root, &conn.id, opts)
+                .await?;
+            continue;
+        }
+        if conn.txn.is_some() && kind != MessageKind::File {
+            anyhow::bail!("{kind:?} message inside a transaction");
+        }
+        match kind {
+            MessageKind::Delete => conn.delete(seq).await?,
+            MessageKind::Rename => conn.rename(seq).await?,
+            MessageKind::HardLink => conn.hard_link(seq).await?,
+            MessageKind::Symlink => conn.symlink(seq).await?,
+            MessageKind::Manifest => conn.manifest(seq).await?,
+            MessageKind::List => conn.list(seq).await?,
+            MessageKind::Heartbeat => conn.writer.write_ack(seq, Ack::Ok).await?,
+            MessageKind::Transaction => conn.transaction(seq).await?,
+            MessageKind::Batch => conn.batch(seq).await?,
+            MessageKind::Archive => conn.archive(seq),
+            MessageKind::File
+            | MessageKind::Delta
+            | MessageKind::Resume
+            | MessageKind::Dedup
+            | MessageKind::Append
+            | MessageKind::Range
+            | MessageKind::Multicast => {
+                if conn.file(kind, seq, total_start).await? == Served::GaveUp {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A sender's connection once it is through the handshake, with what
+/// serving its messages needs
+struct Conn<'a, R, W> {
+    reader: FrameReader<Timed<R>>,
+    writer: FrameWriter<Timed<W>>,
+    peer: SocketAddr,
+    opts: &'a ReceiveOptions,
+    params: handshake::Params,
+    /// The id the sender announced, or its IP address
+    id: String,
+    access: Access,
+    /// How changes its `[[sender]]` policy doesn't allow are refused
+    forbidden: Ack,
+    /// How files that don't match their checksum are refused
+    damaged: Ack,
+    /// Where its files go: the destination directory, or its own in there
+    root: PathBuf,
+    /// Temporary area of `root`
+    tmp: PathBuf,
+    limits: Limits,
+    /// The transaction, batch or archive in progress
+    txn: Option<Transaction>,
+}
+
+/// How a file message left the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Served {
+    /// Ready for the next message
+    Next,
+    /// The sender took too long with the payload, whose rest is still on
+    /// its way, so the connection can't go on
+    GaveUp,
+}
+
+/// A file message whose header was read and that passed the receiver's
+/// checks
+struct Offered {
+    kind: MessageKind,
+    seq: u32,
+    name: PathBuf,
+    size: u64,
+    meta: FileMeta,
+    confirm: Confirm,
+    xattrs: Option<Vec<xattr::Xattr>>,
+    version: Option<u64>,
+    transfer_id: Option<[u8; 16]>,
+    /// Checksum sent ahead of the payload by dedup, range and multicast
+    /// messages
+    announced: Option<[u8; 32]>,
+    range: Option<crate::RangeHeader>,
+    /// The multicast send that carried the content
+    multicast: Option<[u8; 16]>,
+    block_size: Option<u32>,
+    /// Payload bytes in this message: a range carries part of the file
+    wire: u64,
+    /// The stripe a range belongs to
+    stripe: Option<TransferKey>,
+    dest_path: PathBuf,
+    /// Rename the copy it replaces under `--on-conflict rename`
+    moves_aside: bool,
+    /// How much it adds to the sender directory's usage
+    growth: u64,
+    total_start: Instant,
+    header_end: Instant,
+}
+
+/// A file's content once it is in, waiting for its checksum to be checked
+struct Arrived<'a> {
+    part: PartFile,
+    tmp_path: PathBuf,
+    /// The stripe a range completed
+    assembled: Option<Assembled<'a>>,
+    received: Received,
+    /// What the content must hash to
+    checksum: [u8; 32],
+    /// Content already here stood in for the payload
+    local: bool,
+    data: Duration,
+}
+
+impl<'a, R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Conn<'a, R, W> {
+    /// Agree on the protocol with the sender at `peer`, authenticate it and
+    /// find out who it is
+    async fn accept(
+        reader: R,
+        writer: W,
+        peer: SocketAddr,
+        opts: &'a ReceiveOptions,
+    ) -> Result<Self> {
+        let (mut reader, mut writer) = (
+            Timed::new(reader, opts.io_timeout),
+            Timed::new(writer, opts.io_timeout),
+        );
+        let mut supported = opts.storage.features();
+        if opts.applied.is_none() {
+            supported = supported.without(handshake::Features::TRANSFER_IDS);
+        }
+        if opts.integrity == Integrity::Full {
+            supported = supported.without(handshake::Features::TCP_ONLY);
+        }
+        if opts.multicast.is_none() {
+            supported = supported.without(handshake::Features::MULTICAST);
+        }
+        let params = handshake::accept_only(&mut reader, &mut writer, supported).await?;
+        debug!(
+            "[*] {peer} speaks protocol v{} with features {}",
+            params.version, params.features
+        );
+        auth::accept(&mut reader, &mut writer, opts.key.as_ref(), params.features).await?;
+        let sender = if params.features.contains(handshake::Features::SENDER_ID) {
+            Some(handshake::read_id(&mut reader).await?)
+        } else {
+            None
+        };
+        let id = sender.unwrap_or_else(|| peer.ip().to_canonical().to_string());
+        let access = opts.policy.as_ref().map_or(Access::Any, |policy| {
+            policy.access(&id, peer.ip().to_canonical())
+        });
+        // Older senders don't know the policy NACK, and don't resend a rejected name
+        let forbidden = if params.features.contains(handshake::Features::POLICY) {
+            Ack::Forbidden
+        } else {
+            Ack::RejectedName
+        };
+        let damaged = if params.features.contains(handshake::Features::ACK_REASONS) {
+            Ack::ChecksumMismatch
+        } else {
+            Ack::Failed
+        };
+        let root = if opts.per_sender_dir {
+            let dir = opts.dest_dir.join(&id);
+            std::fs::create_dir_all(&dir).with_context(|| format!("Create {}", dir.display()))?;
+            info!(
+                "[*] {peer} is sender {id}; receiving into {}",
+                dir.display()
+            );
+            std::fs::canonicalize(&dir)?
+        } else {
+            opts.dest_dir.clone()
+        };
+        let mut reader = FrameReader::new(reader);
+        reader.set_chunk_size(params.chunk_size);
+        reader.set_features(params.features);
+        let mut writer = FrameWriter::new(writer);
+        writer.set_features(params.features);
+        Ok(Self {
+            reader,
+            writer,
+            peer,
+            opts,
+            params,
+            id,
+            access,
+            forbidden,
+            damaged,
+            tmp: opts.tmp_area(&root),
+            root,
+            limits: Limits::new(opts.conn_rates.0, opts.conn_rates.1),
+            txn: None,
+        })
+    }
+
+    /// How to refuse a change to `names` if the sender's `[[sender]]` policy
+    /// doesn't allow one of them
+    fn refused(&mut self, names: &[&Path]) -> Option<Ack> {
+        let name = names.iter().find(|name| !self.access.allows(name))?;
+        warn!(
+            "[!] Refused {} from sender {}: not allowed by its [[sender]] policy",
+            name.display(),
+            self.id
+        );
+        self.writer.explain(format_args!(
+            "{} is not allowed by this sender's [[sender]] policy",
+            name.display()
+        ));
+        Some(self.forbidden)
+    }
+
+    /// [`refuse`] the file message `file` was read from
+    async fn refuse(&mut self, file: &Offered, ack: Ack) -> Result<()> {
+        refuse(
+            &mut self.reader,
+            &mut self.writer,
+            &mut self.txn,
+            file.kind,
+            file.seq,
+            file.wire,
+            ack,
+        )
+        .await
+    }
+
+    /// NACK a file the sender took too long with; its payload is still on
+    /// its way, so the connection can't go on and the NACK is a courtesy
+    async fn give_up(&mut self, file: &Offered, e: anyhow::Error) -> Served {
+        warn!(
+            "[!] Gave up on {} from {}: {e:#}",
+            file.name.display(),
+            self.peer;
+            name = &file.name,
+            seq = file.seq
+        );
+        self.writer.get_mut().set_deadline(None);
+        self.writer.explain(format_args!("gave up: {e:#}"));
+        let _ = self.writer.write_ack(file.seq, Ack::Failed).await;
+        METRICS.failed.inc();
+        Served::GaveUp
+    }
+
+    async fn delete(&mut self, seq: u32) -> Result<()> {
+        let opts = self.opts;
+        let name = opts.names.translate(self.reader.read_name().await?);
+        let ack = match self.refused(&[&name]) {
+            Some(ack) => ack,
+            None => {
+                let delete = delete_path(
+                    opts.storage.clone(),
+                    &self.root,
+                    &name,
+                    opts.backups.as_ref(),
+                );
+                opts.echoed(&self.root, Op::Delete(&name), delete).await
+            }
+        };
+        if ack == Ack::Ok {
+            opts.mirror(&self.root, Op::Delete(&name));
+        }
+        opts.record(ack, &self.id, &name, Change::Deleted);
+        self.writer.write_ack(seq, ack).await
+    }
+
+    async fn rename(&mut self, seq: u32) -> Result<()> {
+        let opts = self.opts;
+        let from = opts.names.translate(self.reader.read_name().await?);
+        let to = opts.names.translate(self.reader.read_name().await?);
+        let ack = match self.refused(&[&from, &to]) {
+            Some(ack) => ack,
+            None => {
+                let rename = async {
+                    rename_path(
+                        opts.storage.as_ref(),
+                        &self.root,
+                        &from,
+                        &to,
+                        opts.backups.as_ref(),
+                    )
+                };
+                opts.echoed(&self.root, Op::Rename(&from, &to), rename)
+                    .await
+            }
+        };
+        if ack == Ack::Ok {
+            opts.mirror(&self.root, Op::Rename(&from, &to));
+        }
+        opts.record(ack, &self.id, &from, Change::Renamed(&to));
+        self.writer.write_ack(seq, ack).await
+    }
+
+    async fn hard_link(&mut self, seq: u32) -> Result<()> {
+        let opts = self.opts;
+        let name = opts.names.translate(self.reader.read_name().await?);
+        let existing = opts.names.translate(self.reader.read_name().await?);
+        // Linking reads `existing` too
+        let ack = self
+            .refused(&[&name, &existing])
+            .unwrap_or_else(|| hard_link(&self.root, &self.tmp, &name, &existing));
+        opts.record(ack, &self.id, &name, Change::HardLinked(&existing));
+        self.writer.write_ack(seq, ack).await
+    }
+
+    async fn symlink(&mut self, seq: u32) -> Result<()> {
+        let opts = self.opts;
+        let name = opts.names.translate(self.reader.read_name().await?);
+        let target = opts.names.translate(self.reader.read_name().await?);
+        let ack = match self.refused(&[&name]) {
+            Some(ack) => ack,
+            None => {
+                let link = async { create_symlink(&self.root, &self.tmp, &name, &target) };
+                opts.echoed(&self.root, Op::Symlink(&name), link).await
+            }
+        };
+        opts.record(ack, &self.id, &name, Change::Linked(&target));
+        self.writer.write_ack(seq, ack).await
+    }
+
+    /// Answer a scrub with the manifest entries our copies don't match
+    async fn manifest(&mut self, seq: u32) -> Result<()> {
+        let mut entries = self.reader.read_manifest().await?;
+        for entry in &mut entries {
+            entry.name = self.opts.names.translate(std::mem::take(&mut entry.name));
+        }
+        let mut stale = stale_entries(&self.root, &entries);
+        // Nothing the sender may not write is worth sending; the indices
+        // stay those of the manifest as sent
+        stale.retain(|&i| self.access.allows(&entries[i as usize].name));
+        debug!(
+            "[=] Manifest from {}: {} of {} files differ",
+            self.peer,
+            stale.len(),
+            entries.len();
+            seq = seq
+        );
+        self.writer.write_stale(&stale).await?;
+        self.writer.write_ack(seq, Ack::Ok).await
+    }
+
+    async fn list(&mut self, seq: u32) -> Result<()> {
+        // Our own temporary files are not part of the tree
+        let mut entries =
+            manifest::build(&self.root, &Filter::new(&[], &[format!("/{TMP_DIR}/")])?);
+        // Older senders fail on names that are not UTF-8
+        if !self
+            .params
+            .features
+            .contains(handshake::Features::RAW_NAMES)
+        {
+            entries.retain(|e| e.name.to_str().is_some());
+        }
+        entries.retain(|e| self.access.allows(&e.name));
+        debug!("[=] Listing {} files for {}", entries.len(), self.peer; seq = seq);
+        for batch in entries.chunks(MAX_MANIFEST_ENTRIES) {
+            self.writer.write_entries(batch).await?;
+        }
+        self.writer.write_entries(&[]).await?;
+        self.writer.write_ack(seq, Ack::Ok).await
+    }
+
+    /// Open a transaction, whose members are only put in place once the
+    /// last one arrived
+    async fn transaction(&mut self, seq: u32) -> Result<()> {
+        let count = self.reader.read_u16().await?;
+        debug!("[*] Transaction of {count} files from {}", self.peer; seq = seq);
+        self.txn = Some(Transaction::new(seq, count));
+        Ok(())
+    }
+
+    /// Open a batch, whose members are answered with one ACK
+    async fn batch(&mut self, seq: u32) -> Result<()> {
+        let count = self.reader.read_u16().await?;
+        debug!("[*] Batch of {count} files from {}", self.peer; seq = seq);
+        self.txn = Some(Transaction::batch(seq, count));
+        Ok(())
+    }
+
+    /// Open an archive, whose members come until its closing frame
+    fn archive(&mut self, seq: u32) {
+        info!("[*] Receiving an archive from {}", self.peer; seq = seq);
+        self.txn = Some(Transaction::archive(seq));
+    }
+
+    /// Receive a file message of any kind and put the file in place
+    async fn file(&mut self, kind: MessageKind, seq: u32, total_start: Instant) -> Result<Served> {
+        if let Some(txn) = &mut self.txn
+            && !txn.archive
+        {
+            txn.remaining -= 1;
+        }
+        self.reader.get_mut().set_deadline(self.opts.file_timeout);
+        self.writer.get_mut().set_deadline(self.opts.file_timeout);
+        let Some(mut file) = self.admit(kind, seq, total_start).await? else {
+            return Ok(Served::Next);
+        };
+        // Held until this file is written and in place
+        let _slot = self.hold_back(&file).await?;
+        if file.kind == MessageKind::Append {
+            if self.append(&file).await? {
+                return Ok(Served::Next);
+            }
+            file.kind = MessageKind::File;
+        }
+        let mut tmp_path = part_path(&self.tmp, &file.name)?;
+        let mut assembled = None;
+        // The whole payload is in `tmp_path` already
+        let mut complete = false;
+        if file.range.is_some() {
+            match self.range(&file, &mut tmp_path).await? {
+                ControlFlow::Continue(stripe) => assembled = Some(stripe),
+                ControlFlow::Break(served) => return Ok(served),
+            }
+            complete = true;
+        }
+        let mut part = match file.kind {
+            MessageKind::Resume => PartFile::resumable(&self.tmp, tmp_path.clone()),
+            _ => PartFile::new(&self.tmp, tmp_path.clone()),
+        };
+        if let Some(send) = file.multicast {
+            if let ControlFlow::Break(served) = self.multicast(&file, send, &tmp_path).await? {
+                return Ok(served);
+            }
+            complete = true;
+        }
+        let local = self.dedup(&file, &tmp_path, complete).await?;
+        let have = local.is_some();
+        let out = match self.open(&file, &tmp_path, &mut part, have).await? {
+            ControlFlow::Continue(out) => out,
+            ControlFlow::Break(served) => return Ok(served),
+        };
+        let data_start = Instant::now();
+        let received = match local {
+            Some(local) => local,
+            None => match self.receive(&file, &tmp_path, out).await {
+                Err(e) if timeout::is_timeout(&e) => return Ok(self.give_up(&file, e).await),
+                // A bad chunk drops the connection; the sender retries
+                received => received.with_context(|| format!("Receive {}", file.name.display()))?,
+            },
+        };
+        let checksum = match file.announced.filter(|_| have) {
+            Some(checksum) => checksum,
+            None => self.reader.read_checksum().await?,
+        };
+        let arrived = Arrived {
+            part,
+            tmp_path,
+            assembled,
+            received,
+            checksum,
+            local: have,
+            data: data_start.elapsed(),
+        };
+        self.finish(file, arrived).await?;
+        Ok(Served::Next)
+    }
+
+    /// Read a file message's header and check the file may be received,
+    /// refusing it (`None`) if not
+    async fn admit(
+        &mut self,
+        kind: MessageKind,
+        seq: u32,
+        total_start: Instant,
+    ) -> Result<Option<Offered>> {
+        let (opts, peer) = (self.opts, self.peer);
+        let header = self.reader.read_header().await?;
+        let (announced, range, multicast) = match kind {
+            MessageKind::Dedup => (Some(self.reader.read_checksum().await?), None, None),
+            MessageKind::Range => {
+                let range = self.reader.read_range().await?;
+                anyhow::ensure!(
+                    range.len > 0
+                        && range
+                            .offset
+                            .checked_add(range.len)
+                            .is_some_and(|end| end <= header.size),
+                    "Range of {} bytes at {} in a file of {}",
+                    range.len,
+                    range.offset,
+                    header.size
+                );
+                (Some(range.checksum), Some(range), None)
+            }
+            MessageKind::Multicast => {
+                let (send, checksum) = self.reader.read_multicast().await?;
+                (Some(checksum), None, Some(send))
+            }
+            _ => (None, None, None),
+        };
+        let block_size = match kind {
+            MessageKind::Delta => Some(self.reader.read_u32().await?),
+            _ => None,
+        };
+        let header_end = Instant::now();
+        let ProtocolHeader {
+            name,
+            size,
+            meta,
+            confirm,
+            xattrs,
+            version,
+            transfer_id,
+        } = header;
+        let mut file = Offered {
+            kind,
+            seq,
+            name: opts.names.translate(name),
+            size,
+            meta,
+            confirm: confirm.unwrap_or_default(),
+            xattrs,
+            version,
+            transfer_id,
+            announced,
+            range,
+            multicast,
+            block_size,
+            wire: range.map_or(size, |range| range.len),
+            stripe: range.map(|range| (self.id.clone(), range.stripe)),
+            dest_path: PathBuf::new(),
+            moves_aside: false,
+            growth: 0,
+            total_start,
+            header_end,
+        };
+        let name = &file.name;
+
+        if let Some(ack) = self.refused(&[name]) {
+            self.refuse(&file, ack).await?;
+            return Ok(None);
+        }
+        if let Some(max) = opts.max_file_size.filter(|&max| size > max) {
+            warn!(
+                "[!] Refused {} ({} bytes) from {peer}: over the --max-file-size of {max} bytes",
+                name.display(),
+                size;
+                name = name,
+                seq = seq,
+                size = size
+            );
+            self.writer.explain(format_args!(
+                "{size} bytes is over the receiver's --max-file-size of {max} bytes"
+            ));
+            self.refuse(&file, Ack::NoSpace).await?;
+            METRICS.failed.inc();
+            return Ok(None);
+        }
+        // Tiny blocks would cost a hash per few bytes of our copy
+        if let Some(block_size) = block_size.filter(|&block_size| block_size < MIN_DELTA_BLOCK_SIZE)
+        {
+            warn!(
+                "[!] Refused {} from {peer}: delta blocks of {block_size} bytes",
+                name.display();
+                name = name,
+                seq = seq
+            );
+            self.writer.explain(format_args!(
+                "delta blocks must be at least {MIN_DELTA_BLOCK_SIZE} bytes"
+            ));
+            self.refuse(&file, Ack::Failed).await?;
+            METRICS.failed.inc();
+            return Ok(None);
+        }
+
+        file.dest_path = match resolve_dest(&self.root, name) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!("[!] Rejected name {:?} from {peer}: {e}", name; seq = seq);
+                self.writer.explain(format_args!("invalid name: {e}"));
+                discard(&mut self.reader, &mut self.writer, kind, file.wire).await?;
+                answer(&mut self.writer, &mut self.txn, seq, Ack::RejectedName).await?;
+                return Ok(None);
+            }
+        };
+        let (name, dest_path) = (&file.name, &file.dest_path);
+        if version.is_some_and(|v| opts.versions.is_stale(dest_path, v)) {
+            info!(
+                "[=] Ignored {}: a newer version is already in place",
+                name.display();
+                name = name,
+                seq = seq
+            );
+            self.refuse(&file, Ack::Superseded).await?;
+            return Ok(None);
+        }
+        // A copy the conflict policy protects
+        let existing = std::fs::symlink_metadata(dest_path)
+            .ok()
+            .filter(|m| m.is_file());
+        let keep = existing
+            .as_ref()
+            .is_some_and(|existing| match opts.on_conflict {
+                OnConflict::Overwrite | OnConflict::Rename => false,
+                OnConflict::Skip => true,
+                OnConflict::NewerWins => existing
+                    .modified()
+                    .is_ok_and(|mtime| mtime >= file.meta.mtime()),
+            });
+        if keep {
+            info!("[=] Kept existing {} (--on-conflict)", name.display(); name = name, seq = seq);
+            let ack = if self
+                .params
+                .features
+                .contains(handshake::Features::CONFLICTS)
+            {
+                Ack::Kept
+            } else {
+                Ack::Unchanged
+            };
+            self.refuse(&file, ack).await?;
+            return Ok(None);
+        }
+        file.moves_aside = opts.on_conflict == OnConflict::Rename && existing.is_some();
+        // The temporary copy needs `size` bytes until it replaces the old one
+        let replaced = existing
+            .as_ref()
+            .filter(|_| !file.moves_aside)
+            .map_or(0, |m| m.len());
+        file.growth = size.saturating_sub(replaced);
+        // A striped file was counted and its space reserved with its first range
+        let joining = file
+            .stripe
+            .as_ref()
+            .is_some_and(|key| opts.stripes.contains(key));
+        let name = &file.name;
+        if let Some(quota) = &opts.quota
+            && !joining
+            && quota.exceeded(&self.root, file.growth).await
+        {
+            warn!(
+                "[!] Refused {} ({} bytes) from {peer}: over the --quota of {} bytes",
+                name.display(),
+                size,
+                quota.limit;
+                name = name,
+                seq = seq,
+                size = size
+            );
+            self.writer.explain(format_args!(
+                "this sender's --quota of {} bytes is used up",
+                quota.limit
+            ));
+            let ack = if self.params.features.contains(handshake::Features::QUOTAS) {
+                Ack::OverQuota
+            } else {
+                Ack::NoSpace
+            };
+            self.refuse(&file, ack).await?;
+            METRICS.failed.inc();
+            return Ok(None);
+        }
+        if opts.min_free > 0
+            && !joining
+            && free_space(&self.root).is_ok_and(|free| free.saturating_sub(size) < opts.min_free)
+        {
+            warn!(
+                "[!] Refused {} ({} bytes): it would leave less than --min-free",
+                name.display(),
+                size;
+                name = name,
+                seq = seq,
+                size = size
+            );
+            self.writer
+                .explain("it would leave less than the receiver's --min-free");
+            self.refuse(&file, Ack::NoSpace).await?;
+            METRICS.failed.inc();
+            return Ok(None);
+        }
+        Ok(Some(file))
+    }
+
+    /// Wait for the rate limits, and for a write slot if the file is large.
+    /// The payload and the ACK wait, and a sender with a full window stops
+    /// sending.
+    async fn hold_back(&mut self, file: &Offered) -> Result<Option<SemaphorePermit<'a>>> {
+        let opts = self.opts;
+        let wait_start = Instant::now();
+        self.limits.admit(file.wire).await;
+        opts.limits.admit(file.wire).await;
+        let slot = match &opts.write_slots {
+            Some(slots) if file.size >= LARGE_WRITE => Some(slots.acquire().await?),
+            _ => None,
+        };
+        let held = wait_start.elapsed();
+        if held >= THROTTLE_NOTICE {
+            debug!(
+                "[latency] Held {} from {} back {held:.2?} for the rate limits or a write slot",
+                file.name.display(),
+                self.peer;
+                name = &file.name,
+                seq = file.seq
+            );
+            self.reader.get_mut().set_deadline(opts.file_timeout);
+            self.writer.get_mut().set_deadline(opts.file_timeout);
+        }
+        Ok(slot)
+    }
+
+    /// Put an append onto the end of the destination's copy, unless the
+    /// sender starts over (`false`), which makes it a whole-file message. A
+    /// copy the conflict policy or --backup-dir must keep is never changed
+    /// in place, nor one whose --also-into copies would miss the new bytes.
+    async fn append(&mut self, file: &Offered) -> Result<bool> {
+        let opts = self.opts;
+        let Offered {
+            seq,
+            ref name,
+            size,
+            ref dest_path,
+            ..
+        } = *file;
+        let in_place = !file.moves_aside && opts.backups.is_none() && opts.also_into.is_empty();
+        let start = match receive_append(
+            &mut self.reader,
+            &mut self.writer,
+            dest_path,
+            size,
+            in_place,
+        )
+        .await?
+        {
+            None => return Ok(false),
+            Some(Err(ack)) => {
+                warn!(
+                    "[!] Failed to append to {}",
+                    name.display();
+                    name = name,
+                    seq = seq
+                );
+                METRICS.failed.inc();
+                answer(&mut self.writer, &mut self.txn, seq, ack).await?;
+                return Ok(true);
+            }
+            Some(Ok(start)) => start,
+        };
+        if let Some(version) = file.version {
+            opts.versions.claim(dest_path, version);
+        }
+        if let Err(e) = apply_meta(
+            dest_path,
+            &file.meta,
+            file.xattrs.as_deref(),
+            &opts.preserve,
+        ) {
+            warn!("[!] Failed to apply attributes to {}: {e}", name.display());
+        }
+        if opts.mark_received
+            && let Err(e) = File::open(dest_path).and_then(|f| xattr::mark_received(&f))
+        {
+            warn!("[!] Failed to mark {} as received: {e}", name.display());
+        }
+        if let Err(e) = sync_file(dest_path, self.durability(file)) {
+            warn!("[!] Failed to sync {}: {e}", name.display());
+        }
+        if let Some(quota) = &opts.quota {
+            quota.add(&self.root, file.growth);
+        }
+        let ack = if start == size {
+            Ack::Unchanged
+        } else {
+            Ack::Ok
+        };
+        answer(&mut self.writer, &mut self.txn, seq, ack).await?;
+        opts.record(ack, &self.id, name, Change::Appended(size));
+        let total = file.total_start.elapsed();
+        METRICS.received.inc();
+        METRICS.bytes.add(size - start);
+        info!(
+            "[+] Appended {} bytes to {} at {start} | Total: {:.2?}", size - start, name.display(), total;
+            name = name, seq = seq, size = size, total_ms = total
+        );
+        Ok(true)
+    }
+
+    /// Write a range straight into its stripe's temporary file, which is
+    /// `tmp_path` from then on. The range that completes the stripe carries
+    /// on as if the whole file had come at once.
+    async fn range(
+        &mut self,
+        file: &Offered,
+        tmp_path: &mut PathBuf,
+    ) -> Result<ControlFlow<Served, Assembled<'a>>> {
+        let opts = self.opts;
+        let (Some(range), Some(key)) = (&file.range, &file.stripe) else {
+            unreachable!("only range messages have a range")
+        };
+        let (name, seq, size) = (&file.name, file.seq, file.size);
+        let id: String = range.stripe.iter().map(|b| format!("{b:02x}")).collect();
+        tmp_path.set_extension(format!("{id}.part"));
+        let out =
+            match opts
+                .stripes
+                .open(opts.storage.as_ref(), key, tmp_path, size, &range.checksum)
+            {
+                Ok(Some(out)) => out,
+                Ok(None) => {
+                    debug!(
+                        "[=] {} is already complete; ignoring a resent range of it",
+                        name.display();
+                        name = name,
+                        seq = seq
+                    );
+                    discard(&mut self.reader, &mut self.writer, file.kind, file.wire).await?;
+                    self.writer.write_ack(seq, Ack::Ok).await?;
+                    return Ok(ControlFlow::Break(Served::Next));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+                    return self.no_space(file, e).await;
+                }
+                Err(e) => return Err(e).with_context(|| format!("Open {}", tmp_path.display())),
+            };
+        let mut hasher = Hasher::new();
+        let received = match (&out).seek(SeekFrom::Start(range.offset)) {
+            Ok(_) => {
+                let mut out = PartWriter::new(out, opts.io_backend);
+                let read = out
+                    .write_payload(&mut self.reader, range.len, |data| {
+                        hasher.update(data);
+                    })
+                    .await;
+                match read {
+                    Ok(()) => out.finish().await,
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e.into()),
+        };
+        match received {
+            Err(e) if timeout::is_timeout(&e) => {
+                return Ok(ControlFlow::Break(self.give_up(file, e).await));
+            }
+            received => received.with_context(|| format!("Receive {}", name.display()))?,
+        }
+        METRICS.bytes.add(range.len);
+        if hasher.finalize().as_bytes() != &self.reader.read_checksum().await? {
+            warn!(
+                "[!] Invalid checksum for {} at {}",
+                name.display(),
+                range.offset;
+                name = name,
+                seq = seq
+            );
+            self.writer
+                .explain("the received range doesn't match its checksum");
+            self.writer.write_ack(seq, self.damaged).await?;
+            METRICS.failed.inc();
+            return Ok(ControlFlow::Break(Served::Next));
+        }
+        match opts.stripes.add(key, range.offset, range.len) {
+            Some(assembled) => Ok(ControlFlow::Continue(assembled)),
+            None => {
+                debug!(
+                    "[>] Range {}..{} of {} from {}",
+                    range.offset,
+                    range.offset + range.len,
+                    name.display(),
+                    self.peer;
+                    name = name,
+                    seq = seq
+                );
+                self.writer.write_ack(seq, Ack::Ok).await?;
+                Ok(ControlFlow::Break(Served::Next))
+            }
+        }
+    }
+
+    /// Take what the multicast group delivered of the file `send` as its
+    /// temporary file at `tmp_path`, and have the sender fill in the rest
+    async fn multicast(
+        &mut self,
+        file: &Offered,
+        send: [u8; 16],
+        tmp_path: &Path,
+    ) -> Result<ControlFlow<Served>> {
+        let (name, seq, size) = (&file.name, file.seq, file.size);
+        let incoming = self
+            .opts
+            .multicast
+            .as_ref()
+            .and_then(|group| group.claim(&send, size));
+        let prepared = match incoming {
+            Some(mut incoming) => {
+                let tmp_path = tmp_path.to_path_buf();
+                tokio::task::spawn_blocking(move || {
+                    let missing = incoming.repair()?;
+                    create_part(&tmp_path, |path| incoming.link(path))?;
+                    Ok((incoming.data, missing))
+                })
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+            }
+            None => self.opts.storage.create(tmp_path, size, false).map(|out| {
+                (
+                    out,
+                    if size > 0 {
+                        vec![(0, size)]
+                    } else {
+                        Vec::new()
+                    },
+                )
+            }),
+        };
+        let (out, missing) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+                return self.no_space(file, e).await;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Open {}", tmp_path.display())),
+        };
+        self.writer.write_missing(&missing).await?;
+        let mut received = Ok(());
+        for &(offset, len) in &missing {
+            let mut at = offset;
+            received = read_payload(&mut self.reader, len, |data| {
+                out.write_all_at(data, at)?;
+                at += data.len() as u64;
+                Ok(())
+            })
+            .await;
+            if received.is_err() {
+                break;
+            }
+        }
+        match received {
+            Err(e) if timeout::is_timeout(&e) => {
+                return Ok(ControlFlow::Break(self.give_up(file, e).await));
+            }
+            received => received.with_context(|| format!("Receive {}", name.display()))?,
+        }
+        let repaired: u64 = missing.iter().map(|(_, len)| len).sum();
+        METRICS.bytes.add(repaired);
+        debug!(
+            "[>] {} came by multicast but for {repaired} bytes",
+            name.display();
+            name = name,
+            seq = seq
+        );
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Refuse a file there is no room for, before any of it is written
+    async fn no_space<T>(
+        &mut self,
+        file: &Offered,
+        e: std::io::Error,
+    ) -> Result<ControlFlow<Served, T>> {
+        warn!(
+            "[!] No space for {} ({} bytes)",
+            file.name.display(),
+            file.size;
+            name = &file.name,
+            seq = file.seq,
+            size = file.size
+        );
+        self.writer.explain(&e);
+        self.refuse(file, Ack::NoSpace).await?;
+        METRICS.failed.inc();
+        Ok(ControlFlow::Break(Served::Next))
+    }
+
+    /// Content already here that stands in for the payload of a message
+    /// that announced its checksum: the temporary file a range or the
+    /// multicast group `complete`d, the destination's copy, or a `--dedup`
+    /// match copied to `tmp_path`
+    async fn dedup(
+        &self,
+        file: &Offered,
+        tmp_path: &Path,
+        complete: bool,
+    ) -> Result<Option<Received>> {
+        let opts = self.opts;
+        let (name, seq) = (&file.name, file.seq);
+        Ok(match file.announced {
+            Some(_) if complete => {
+                let got = hash_file(tmp_path, opts.hash_threads).await?;
+                Some(Received::Part(Some(got)))
+            }
+            Some(checksum)
+                if is_identical(&file.dest_path, file.size, &checksum, opts.hash_threads) =>
+            {
+                Some(Received::Existing(checksum.into()))
+            }
+            Some(checksum) => opts
+                .dedup
+                .as_ref()
+                .and_then(|index| index.find(&checksum, file.size))
+                .and_then(|source| match reflink::copy(&source, tmp_path) {
+                    Ok(method) => {
+                        info!(
+                            "[=] Copied {} from {} ({method}) instead of receiving it",
+                            name.display(),
+                            source.display();
+                            name = name,
+                            seq = seq
+                        );
+                        Some(Received::Part(Some(checksum.into())))
+                    }
+                    Err(e) => {
+                        warn!(
+                            "[!] Failed to copy {} for {}: {e}",
+                            source.display(),
+                            name.display();
+                            name = name,
+                            seq = seq
+                        );
+                        let _ = std::fs::remove_file(tmp_path);
+                        None
+                    }
+                }),
+            None => None,
+        })
+    }
+
+    /// Open the temporary file the payload goes to, unless it stages in
+    /// memory or content already here stands in for it (`have`), and tell a
+    /// dedup message's sender whether to send the payload
+    async fn open(
+        &mut self,
+        file: &Offered,
+        tmp_path: &Path,
+        part: &mut PartFile,
+        have: bool,
+    ) -> Result<ControlFlow<Served, Option<File>>> {
+        let opts = self.opts;
+        // Transactions keep their members until the last one arrived, so
+        // they always stage on disk
+        let in_memory = matches!(file.kind, MessageKind::File | MessageKind::Dedup)
+            && self.txn.as_ref().is_none_or(|t| t.batch.is_some())
+            && opts.stage_memory.is_some_and(|max| file.size <= max);
+        let opened = if in_memory || have {
+            Ok(None)
+        } else {
+            opts.storage
+                .create(tmp_path, file.size, file.kind == MessageKind::Resume)
+                .map(Some)
+        };
+        let out = match opened {
+            Ok(out) => out,
+            // Say so before any data is sent where the protocol allows, and
+            // before any is written in any case
+            Err(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+                if std::fs::metadata(tmp_path).is_ok_and(|m| m.len() == 0) {
+                    part.discard();
+                }
+                return self.no_space(file, e).await;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Open {}", tmp_path.display())),
+        };
+        if file.kind == MessageKind::Dedup {
+            self.writer
+                .write_dedup_reply(if have {
+                    DedupReply::Have
+                } else {
+                    DedupReply::Send
+                })
+                .await?;
+        }
+        Ok(ControlFlow::Continue(out))
+    }
+
+    /// Receive the payload into `out`, the temporary file at `tmp_path`, or
+    /// into memory without one
+    async fn receive(
+        &mut self,
+        file: &Offered,
+        tmp_path: &Path,
+        out: Option<File>,
+    ) -> Result<Received> {
+        let opts = self.opts;
+        let (reader, writer) = (&mut self.reader, &mut self.writer);
+        let size = file.size;
+        // Plain file messages under --integrity tcp-only come without a checksum
+        let hashed = file.kind != MessageKind::File
+            || !self.params.features.contains(handshake::Features::TCP_ONLY);
+        match (file.kind, out) {
+            (MessageKind::File | MessageKind::Dedup, None) => {
+                receive_memory(reader, size, hashed).await
+            }
+            // Verified all at once with --hash-threads rather than as it arrives
+            (MessageKind::File | MessageKind::Dedup, Some(out))
+                if hashed && opts.hash_threads > 1 && size >= parallel_hash::PARALLEL_MIN =>
+            {
+                receive_full(reader, PartWriter::new(out, opts.io_backend), size, false).await?;
+                hash_file(tmp_path, opts.hash_threads)
+                    .await
+                    .map(|got| Received::Part(Some(got)))
+            }
+            (MessageKind::File | MessageKind::Dedup, Some(out)) => {
+                receive_full(reader, PartWriter::new(out, opts.io_backend), size, hashed).await
+            }
+            (MessageKind::Delta, Some(out)) => {
+                receive_delta(
+                    reader,
+                    writer,
+                    opts.storage.as_ref(),
+                    &file.dest_path,
+                    PartWriter::new(out, opts.io_backend),
+                    size,
+                    file.block_size.unwrap(),
+                )
+                .await
+            }
+            (MessageKind::Resume, Some(out)) => {
+                receive_resume(
+                    reader,
+                    writer,
+                    opts.storage.as_ref(),
+                    file,
+                    tmp_path,
+                    out,
+                    opts.io_backend,
+                )
+                .await
+            }
+            (MessageKind::Delta | MessageKind::Resume, None) => {
+                unreachable!("only whole files stage in memory")
+            }
+            (MessageKind::Append, _) => unreachable!("appends in place are handled above"),
+            (MessageKind::Range, _) => unreachable!("ranges are put together above"),
+            (MessageKind::Multicast, _) => unreachable!("multicast files are filled in above"),
+            (
+                MessageKind::Delete
+                | MessageKind::Rename
+                | MessageKind::Symlink
+                | MessageKind::HardLink
+                | MessageKind::Manifest
+                | MessageKind::List
+                | MessageKind::Transaction
+                | MessageKind::Heartbeat
+                | MessageKind::Batch
+                | MessageKind::Archive,
+                _,
+            ) => unreachable!("handled by serve_conn"),
+        }
+    }
+
+    /// How far to sync the file before it is ACKed
+    fn durability(&self, file: &Offered) -> Durability {
+        if file.confirm == Confirm::Durable {
+            Durability::Full
+        } else {
+            self.opts.durability
+        }
+    }
+
+    /// Check what arrived against its checksum and put it in place, or
+    /// leave the destination's copy if the content is the same
+    async fn finish(&mut self, file: Offered, arrived: Arrived<'a>) -> Result<()> {
+        let opts = self.opts;
+        let Arrived {
+            mut part,
+            tmp_path,
+            mut assembled,
+            received,
+            checksum,
+            local,
+            data,
+        } = arrived;
+        let (kind, seq, size) = (file.kind, file.seq, file.size);
+        let name = file.name.clone();
+        // The sender doesn't wait for verification; later problems are only
+        // logged. A transaction is only answered once complete.
+        let acked = file.confirm == Confirm::Received && self.txn.is_none();
+        let written = if file.moves_aside
+            && self
+                .params
+                .features
+                .contains(handshake::Features::CONFLICTS)
+        {
+            Ack::MovedAside
+        } else {
+            Ack::Ok
+        };
+        if acked {
+            self.writer.write_ack(seq, written).await?;
+        }
+
+        // Verify checksum
+        let verify_start = Instant::now();
+        let (got, unchanged) = match &received {
+            Received::Part(got) | Received::Memory(got, _) => (
+                *got,
+                matches!(
+                    kind,
+                    MessageKind::File
+                        | MessageKind::Dedup
+                        | MessageKind::Range
+                        | MessageKind::Multicast
+                ) && got.is_some_and(|got| {
+                    is_identical(&file.dest_path, size, got.as_bytes(), opts.hash_threads)
+                }),
+            ),
+            Received::Existing(got) => (Some(*got), true),
+        };
+        let verify = verify_start.elapsed();
+        if got.is_some_and(|got| got.as_bytes() != &checksum) {
+            part.discard();
+            if !acked {
+                self.writer
+                    .explain("the received data doesn't match its checksum");
+                let _ = answer(&mut self.writer, &mut self.txn, seq, self.damaged).await;
+            }
+            warn!("[!] Invalid checksum for {}", name.display(); name = &name, seq = seq);
+            METRICS.failed.inc();
+            return Ok(());
+        }
+        // Sent again because its ACK never reached the sender
+        if let Some(tid) = file.transfer_id
+            && let Some(got) = got
+            && opts
+                .applied
+                .as_ref()
+                .is_some_and(|applied| applied.contains(&self.id, &tid, &got))
+        {
+            part.discard();
+            if let Some(stripe) = assembled.take() {
+                stripe.keep();
+            }
+            if !acked {
+                answer(&mut self.writer, &mut self.txn, seq, Ack::Ok).await?;
+            }
+            METRICS.unchanged.inc();
+            info!(
+                "[=] Already applied {} ({} bytes): a resend of the same transfer",
+                name.display(),
+                size;
+                name = &name,
+                seq = seq,
+                size = size
+            );
+            return Ok(());
+        }
+        if unchanged {
+            part.discard();
+            if let Some(stripe) = assembled.take() {
+                stripe.keep();
+            }
+            if let Some(version) = file.version {
+                opts.versions.claim(&file.dest_path, version);
+            }
+            if let Err(e) = apply_meta(
+                &file.dest_path,
+                &file.meta,
+                file.xattrs.as_deref(),
+                &opts.preserve,
+            ) {
+                warn!("[!] Failed to apply attributes to {}: {e}", name.display());
+            }
+            if !acked {
+                answer(&mut self.writer, &mut self.txn, seq, Ack::Unchanged).await?;
+            }
+            if let Some(index) = &opts.dedup
+                && let Some(got) = got
+            {
+                index.add(got.as_bytes(), &file.dest_path);
+            }
+            let total = file.total_start.elapsed();
+            METRICS.unchanged.inc();
+            info!(
+                "[=] Unchanged {} ({} bytes) | Total: {:.2?}",
+                name.display(),
+                size,
+                total;
+                name = &name,
+                seq = seq,
+                size = size,
+                total_ms = total
+            );
+            return Ok(());
+        }
+
+        if let Received::Memory(_, data) = received
+            && let Err(e) = opts
+                .storage
+                .create(&tmp_path, size, false)
+                .and_then(|mut f| f.write_all(&data))
+        {
+            warn!("[!] Failed to write {}: {e}", name.display(); name = &name, seq = seq);
+            METRICS.failed.inc();
+            if !acked {
+                self.writer.explain(&e);
+                let ack = if e.kind() == std::io::ErrorKind::StorageFull {
+                    Ack::NoSpace
+                } else {
+                    Ack::Failed
+                };
+                answer(&mut self.writer, &mut self.txn, seq, ack).await?;
+            }
+            return Ok(());
+        }
+
+        let staged = Staged {
+            part,
+            durability: self.durability(&file),
+            dest_path: file.dest_path,
+            name: name.clone(),
+            size,
+            meta: file.meta,
+            xattrs: file.xattrs,
+            checksum: got,
+            moves_aside: file.moves_aside,
+            version: file.version,
+            growth: file.growth,
+            transfer: file.transfer_id.map(|tid| (self.id.clone(), tid)),
+        };
+        if let Some(txn) = &mut self.txn
+            && txn.batch.is_none()
+        {
+            txn.staged.push(staged);
+            txn.record(written);
+            return Ok(());
+        }
+        let rename_start = Instant::now();
+        let hook = match staged.install(&self.root, opts).await {
+            Ok(Some(hook)) => hook,
+            Ok(None) => {
+                if let Some(stripe) = assembled.take() {
+                    stripe.keep();
+                }
+                if !acked {
+                    answer(&mut self.writer, &mut self.txn, seq, Ack::Superseded).await?;
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                warn!(
+                    "[!] Failed to put {} in place: {e:#}",
+                    name.display();
+                    name = &name,
+                    seq = seq
+                );
+                METRICS.failed.inc();
+                if !acked {
+                    self.writer.explain(format_args!("{e:#}"));
+                    let _ = answer(&mut self.writer, &mut self.txn, seq, Ack::Failed).await;
+                }
+                return Ok(());
+            }
+        };
+        let rename = rename_start.elapsed();
+        if let Some(stripe) = assembled.take() {
+            stripe.keep();
+        }
+        if !acked {
+            answer(&mut self.writer, &mut self.txn, seq, written).await?;
+        }
+        opts.record(Ack::Ok, &self.id, &hook.name, Change::Received(&hook));
+        if let Some(index) = &opts.dedup
+            && let Some(checksum) = hook.checksum
+        {
+            index.add(checksum.as_bytes(), &hook.path);
+        }
+        if let Some(hooks) = &opts.hooks {
+            let _ = hooks.send(hook);
+        }
+        METRICS.received.inc();
+        if !local {
+            METRICS.bytes.add(size);
+        }
+        METRICS
+            .duration
+            .observe(file.header_end.elapsed().as_secs_f64());
+        METRICS.size.observe(size as f64);
+        let header = file.header_end.duration_since(file.total_start);
+        let total = file.total_start.elapsed();
+        info!(
+            "[+] OK {} ({} bytes) | Header: {:.2?} | Data: {:.2?} | Verify: {:.2?} | Rename: {:.2?} | Total: {:.2?}",
+            name.display(),
+            size,
+            header,
+            data,
+            verify,
+            rename,
+            total;
+            name = &name,
+            seq = seq,
+            size = size,
+            header_ms = header,
+            data_ms = data,
+            verify_ms = verify,
+            rename_ms = rename,
+            total_ms = total
+        );
+        Ok(())
+    }
+}
+
+/// Flush a received file's data to disk as `durability` requires
+fn sync_file(path: &Path, durability: Durability) -> std::io::Result<()> {
+    match durability {
+        Durability::None => Ok(()),
+        Durability::Fdatasync => File::open(path)?.sync_data(),
+        Durability::Full => File::open(path)?.sync_all(),
+    }
+}
+
+/// Copy the selected source attributes onto a received file
+fn apply_meta(
+    path: &Path,
+    meta: &FileMeta,
+    xattrs: Option<&[xattr::Xattr]>,
+    preserve: &[Preserve],
+) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    // chown first: it may clear setuid/setgid bits set by the chmod
+    if preserve.contains(&Preserve::Owner) {
+        std::os::unix::fs::chown(path, Some(meta.uid), Some(meta.gid))?;
+    }
+    if preserve.contains(&Preserve::Mode) {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(meta.mode))?;
+    }
+    if preserve.contains(&Preserve::Mtime) {
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(meta.mtime())?;
+    }
+    if let Some(attrs) = xattrs.filter(|_| preserve.contains(&Preserve::Xattrs)) {
+        xattr::write(&File::open(path)?, attrs)?;
+    }
+    Ok(())
+}
+
+/// Remove a file or directory tree propagated from the sender
+async fn delete_path(
+    storage: Arc<dyn Storage>,
+    root: &Path,
+    name: &Path,
+    backups: Option<&Backups>,
+) -> Ack {
+    let path = match check_name(root, name) {
+        Ok(path) => path,
+        Err(e) => {
+            warn!("[!] Rejected delete of {:?}: {e}", name);
+            return Ack::RejectedName;
+        }
+    };
+    // Moves our copy away, so there is nothing left to delete here
+    let kept = match backups.map(|backups| backups.save(&path, true)) {
+        Some(Err(e)) => {
+            warn!(
+                "[!] Failed to back up {} before deleting it: {e}",
+                name.display()
+            );
+            return Ack::Failed;
+        }
+        kept => kept.is_some(),
+    };
+    let owned = name.to_path_buf();
+    let deleted = tokio::task::spawn_blocking(move || storage.delete(&owned, &path))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    match deleted {
+        Ok(()) if kept => {
+            info!("[-] Deleted {} (kept in --backup-dir)", name.display());
+            Ack::Ok
+        }
+        Ok(()) => {
+            info!("[-] Deleted {}", name.display());
+            Ack::Ok
+        }
+        Err(e) => {
+            warn!("[!] Failed to delete {}: {e}", name.display());
+            Ack::Failed
+        }
+    }
+}
+
+/// Move a path propagated from the sender; fails if the source is missing so
+/// the sender can fall back to sending the content
+fn rename_path(
+    storage: &dyn Storage,
+    root: &Path,
+    from: &Path,
+    to: &Path,
+    backups: Option<&Backups>,
+) -> Ack {
+    let (old, new) = match (check_name(root, from), resolve_dest(root, to)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("[!] Rejected rename {:?} -> {:?}: {e}", from, to);
+            return Ack::RejectedName;
+        }
+    };
+    // Whatever the rename replaces
+    if let Some(backups) = backups
+        && old.exists()
+        && let Err(e) = backups.save(&new, true)
+    {
+        warn!(
+            "[!] Failed to back up {} before renaming over it: {e}",
+            to.display()
+        );
+        return Ack::Failed;
+    }
+    match storage.rename(&old, &new) {
+        Ok(()) => {
+            info!("[>] Renamed {} -> {}", from.display(), to.display());
+            Ack::Ok
+        }
+        Err(e) => {
+            warn!(
+                "[!] Failed to rename {} -> {}: {e}",
+                from.display(),
+                to.display()
+            );
+            Ack::Failed
+        }
+    }
+}
+
+/// Create or replace a symbolic link propagated from the sender. The target
+/// is stored as sent: paths are never resolved through received links, so it
+/// can't lead writes outside the destination directory.
+fn create_symlink(root: &Path, tmp: &Path, name: &Path, target: &Path) -> Ack {
+    let path = match resolve_dest(root, name) {
+        Ok(path) => path,
+        Err(e) => {
+            warn!("[!] Rejected symlink {:?}: {e}", name);
+            return Ack::RejectedName;
+        }
+    };
+    // Created aside and renamed over the old entry, like received files
+    let res = part_path(tmp, name).and_then(|tmp_path| {
+        let _ = std::fs::remove_file(&tmp_path);
+        let res = create_part(&tmp_path, |tmp_path| {
+            std::os::unix::fs::symlink(target, tmp_path)
+        })
+        .and_then(|()| std::fs::rename(&tmp_path, &path))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp_path);
+        });
+        prune_tmp_dirs(tmp, &tmp_path);
+        res
+    });
+    match res {
+        Ok(()) => {
+            info!("[+] Linked {} -> {}", name.display(), target.display());
+            Ack::Ok
+        }
+        Err(e) => {
+            warn!("[!] Failed to create symlink {}: {e}", name.display());
+            Ack::Failed
+        }
+    }
+}
+
+/// Make `name` another name of the file `existing`; fails if there is no such
+/// file, so the sender can send the content instead
+fn hard_link(root: &Path, tmp: &Path, name: &Path, existing: &Path) -> Ack {
+    use std::os::unix::fs::MetadataExt;
+    let (path, source) = match (resolve_dest(root, name), check_name(root, existing)) {
+        (Ok(path), Ok(source)) => (path, source),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("[!] Rejected hard link {:?} -> {:?}: {e}", name, existing);
+            return Ack::RejectedName;
+        }
+    };
+    let res = match (
+        std::fs::symlink_metadata(&source),
+        std::fs::symlink_metadata(&path),
+    ) {
+        (Ok(src), _) if !src.is_file() => Err(std::io::Error::other("not a regular file")),
+        (Ok(src), Ok(dst)) if src.dev() == dst.dev() && src.ino() == dst.ino() => Ok(()),
+        (Ok(_), _) => {
+            // Linked aside and renamed over the old entry, like received files
+            part_path(tmp, name).and_then(|tmp_path| {
+                let _ = std::fs::remove_file(&tmp_path);
+                let res = create_part(&tmp_path, |tmp_path| std::fs::hard_link(&source, tmp_path))
+                    .and_then(|()| std::fs::rename(&tmp_path, &path))
+                    .inspect_err(|_| {
+                        let _ = std::fs::remove_file(&tmp_path);
+                    });
+                prune_tmp_dirs(tmp, &tmp_path);
+                res
+            })
+        }
+        (Err(e), _) => Err(e),
+    };
+    match res {
+        Ok(()) => {
+            info!("[+] Hard link {} -> {}", name.display(), existing.display());
+            Ack::Ok
+        }
+        Err(e) => {
+            warn!(
+                "[!] Failed to hard link {} -> {}: {e}",
+                name.display(),
+                existing.display()
+            );
+            Ack::Failed
+        }
+    }
+}
+
+/// Indices of the manifest entries we lack or hold different content for.
+/// Names we would reject don't count: sending them would be refused too.
+fn stale_entries(root: &Path, entries: &[ManifestEntry]) -> Vec<u32> {
+    (0..)
+        .zip(entries)
+        .filter(|(_, entry)| {
+            check_name(root, &entry.name)
+                .is_ok_and(|path| !is_identical(&path, entry.size, &entry.checksum, 1))
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// What a transfer produced, with the checksum of the file it describes
+enum Received {
+    /// New content in the `.part` file, hashed unless it came without a
+    /// checksum
+    Part(Option<blake3::Hash>),
+    /// The destination's current copy, left untouched
+    Existing(blake3::Hash),
+    /// New content held in memory, not yet written anywhere
+    Memory(Option<blake3::Hash>, Vec<u8>),
+}
+
+/// BLAKE3 of the file at `path`, with up to `threads` threads on the
+/// blocking pool
+async fn hash_file(path: &Path, threads: usize) -> Result<blake3::Hash> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let file = File::open(&path).with_context(|| format!("Open {}", path.display()))?;
+        let data = unsafe { Mmap::map(&file)? };
+        Ok(parallel_hash::hash(&data, threads))
+    })
+    .await?
+}
+
+/// Does `path` already hold a regular file with this size and checksum?
+/// Hashed with up to `threads` threads.
+fn is_identical(path: &Path, size: u64, checksum: &[u8; 32], threads: usize) -> bool {
+    // symlink_metadata: a link is never considered up to date
+    if !std::fs::symlink_metadata(path).is_ok_and(|m| m.is_file() && m.len() == size) {
+        return false;
+    }
+    if size == 0 {
+        return blake3::hash(&[]).as_bytes() == checksum;
+    }
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    match unsafe { Mmap::map(&file) } {
+        Ok(data) => parallel_hash::hash(&data, threads).as_bytes() == checksum,
+        Err(_) => false,
+    }
+}
+
+/// Turn a file message down with `ack` before any of it is written: right
+/// away where the protocol allows, after skipping the payload otherwise
+async fn refuse<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FrameReader<R>,
+    writer: &mut FrameWriter<W>,
+    txn: &mut Option<Transaction>,
+    kind: MessageKind,
+    seq: u32,
+    size: u64,
+    ack: Ack,
+) -> Result<()> {
+    if matches!(kind, MessageKind::File | MessageKind::Range) {
+        answer(writer, txn, seq, ack).await?;
+        discard(reader, writer, kind, size).await?;
+    } else {
+        writer.write_refused(kind).await?;
+        answer(writer, txn, seq, ack).await?;
+    }
+    Ok(())
+}
+
+/// ACK a file message, or note how it went for the transaction it belongs to
+async fn answer<W: AsyncWrite + Unpin>(
+    writer: &mut FrameWriter<W>,
+    txn: &mut Option<Transaction>,
+    seq: u32,
+    ack: Ack,
+) -> Result<()> {
+    match txn {
+        Some(txn) => {
+            txn.record(ack);
+            Ok(())
+        }
+        None => writer.write_ack(seq, ack).await,
+    }
+}
+
+/// A free `NAME.conflict-TIMESTAMP[.N]` next to `path`
+fn conflict_path(path: &Path) -> PathBuf {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    free_path(format!("{}.conflict-{secs}", path.display()))
+}
+
+/// `base`, or `base.N` for the first N that doesn't exist yet
+fn free_path(base: String) -> PathBuf {
+    let mut candidate = PathBuf::from(&base);
+    let mut n = 1;
+    while candidate.symlink_metadata().is_ok() {
+        candidate = PathBuf::from(format!("{base}.{n}"));
+        n += 1;
+    }
+    candidate
+}
+
+impl Backups {
+    fn new(dir: &Path, dest_dir: &Path, keep: Option<usize>) -> Result<Self> {
+        let dir = beside_dest(dir, dest_dir, "--backup-dir")?;
+        Ok(Self {
+            dir,
+            dest_dir: dest_dir.to_path_buf(),
+            keep,
+        })
+    }
+
+    /// Keep the current version of `path`, if any, in this second's
+    /// snapshot: moved there when `remove` is set, else hard linked so the
+    /// caller can replace it
+    fn save(&self, path: &Path, remove: bool) -> std::io::Result<()> {
+        let Ok(meta) = std::fs::symlink_metadata(path) else {
+            return Ok(());
+        };
+        // Only the move keeps a directory; linking is for files
+        if meta.is_dir() && !remove {
+            return Ok(());
+        }
+        let rel = path.strip_prefix(&self.dest_dir).unwrap_or(path);
+        let stamp = log::timestamp(std::time::SystemTime::now());
+        let snapshot = self.dir.join(format!("{}Z", &stamp[..19]));
+        let target = free_path(snapshot.join(rel).display().to_string());
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if remove {
+            std::fs::rename(path, &target)?;
+        } else {
+            std::fs::hard_link(path, &target)?;
+        }
+        debug!("[*] Backed up {} to {}", rel.display(), target.display());
+        self.prune();
+        Ok(())
+    }
+
+    /// Drop the oldest snapshots beyond `--backup-keep`
+    fn prune(&self) {
+        let Some(keep) = self.keep else { return };
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut snapshots: Vec<_> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect();
+        // Named by timestamp, so they sort oldest first
+        snapshots.sort();
+        let excess = snapshots.len().saturating_sub(keep);
+        for old in &snapshots[..excess] {
+            if let Err(e) = std::fs::remove_dir_all(old) {
+                warn!("[!] Failed to remove old backup {}: {e}", old.display());
+            }
+        }
+    }
+}
+
+/// Consume the rest of a message we refused, keeping the stream in sync
+async fn discard<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FrameReader<R>,
+    writer: &mut FrameWriter<W>,
+    kind: MessageKind,
+    size: u64,
+) -> Result<()> {
+    match kind {
+        MessageKind::File | MessageKind::Range => skip_payload(reader, size).await?,
+        // Refused before the payload
+        MessageKind::Dedup | MessageKind::Multicast => {
+            writer.write_refused(kind).await?;
+            return Ok(());
+        }
+        MessageKind::Delta => {
+            // No blocks to reuse, so the sender streams only literal data
+            writer.write_block_hashes(&[]).await?;
+            loop {
+                match reader.read_delta_op().await? {
+                    DeltaOp::Data { len } => skip_payload(reader, len as u64).await?,
+                    DeltaOp::Copy { .. } => {}
+                    DeltaOp::End => break,
+                }
+            }
+        }
+        MessageKind::Resume | MessageKind::Append => {
+            // Nothing to resume from, so the sender starts at zero
+            writer
+                .write_resume_offer(0, blake3::hash(&[]).as_bytes())
+                .await?;
+            let start = reader.read_u64().await?;
+            skip_payload(reader, size.saturating_sub(start)).await?;
+        }
+        MessageKind::Delete
+        | MessageKind::Rename
+        | MessageKind::Symlink
+        | MessageKind::HardLink
+        | MessageKind::Manifest
+        | MessageKind::List
+        | MessageKind::Transaction
+        | MessageKind::Heartbeat
+        | MessageKind::Batch
+        | MessageKind::Archive => {
+            return Ok(());
+        }
+    }
+    reader.read_checksum().await?;
+    Ok(())
+}
+
+/// Offer the destination's copy to an append message and, unless the sender
+/// starts over (`None`), write the new bytes onto its end as they arrive.
+/// Returns where they started, or how to answer a failed append; the copy
+/// is cut back to its old length if the bytes don't verify.
+async fn receive_append<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FrameReader<R>,
+    writer: &mut FrameWriter<W>,
+    dest_path: &Path,
+    size: u64,
+    in_place: bool,
+) -> Result<Option<Result<u64, Ack>>> {
+    // symlink_metadata: never append through a link planted at the path
+    let len = match std::fs::symlink_metadata(dest_path) {
+        Ok(md) if in_place && md.is_file() && md.len() <= size => md.len(),
+        _ => 0,
+    };
+    let mut tail = vec![0; len.min(APPEND_CHECK) as usize];
+    if len > 0 {
+        let mut f = File::open(dest_path)?;
+        f.seek(SeekFrom::Start(len - tail.len() as u64))?;
+        f.read_exact(&mut tail)?;
+    }
+    writer
+        .write_resume_offer(len, blake3::hash(&tail).as_bytes())
+        .await?;
+    let start = reader.read_u64().await?;
+    anyhow::ensure!(
+        start == 0 || start == len,
+        "Append at {start} to a copy of {len} bytes"
+    );
+    if start == 0 {
+        return Ok(None);
+    }
+
+    let mut f = OpenOptions::new().append(true).open(dest_path)?;
+    let mut hasher = Hasher::new();
+    let mut failed = None;
+    let read = read_payload(reader, size - start, |data| {
+        hasher.update(data);
+        if failed.is_none()
+            && let Err(e) = f.write_all(data)
+        {
+            failed = Some(e);
+        }
+        Ok(())
+    })
+    .await;
+    let verified = match read {
+        Ok(()) => reader
+            .read_checksum()
+            .await
+            .map(|chk| hasher.finalize().as_bytes() == &chk),
+        Err(e) => Err(e),
+    };
+    let ack = match (verified, failed) {
+        (Ok(true), None) => return Ok(Some(Ok(start))),
+        (Ok(_), Some(e)) if e.kind() == std::io::ErrorKind::StorageFull => Ack::NoSpace,
+        (Ok(_), _) => Ack::Failed,
+        (Err(e), _) => {
+            let _ = f.set_len(start);
+            return Err(e);
+        }
+    };
+    f.set_len(start)?;
+    Ok(Some(Err(ack)))
+}
+
+async fn skip_payload<R: AsyncRead + Unpin>(reader: &mut FrameReader<R>, len: u64) -> Result<()> {
+    read_payload(reader, len, |_| Ok(())).await
+}
+
+/// Read `len` payload bytes, handing each verified chunk to `sink`
+async fn read_payload<R: AsyncRead + Unpin>(
+    reader: &mut FrameReader<R>,
+    len: u64,
+    mut sink: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut remaining = len;
+    while remaining > 0 {
+        let data = reader.read_chunk(remaining).await?;
+        sink(data)?;
+        remaining -= data.len() as u64;
+    }
+    Ok(())
+}
+
+/// Where the temporary file for the already checked `name` goes in the
+/// temporary area `tmp`, with its directory created
+fn part_path(tmp: &Path, name: &Path) -> std::io::Result<PathBuf> {
+    let mut path = tmp.join(name).into_os_string();
+    path.push(".part");
+    let path = PathBuf::from(path);
+    std::fs::create_dir_all(path.parent().unwrap_or(tmp))?;
+    Ok(path)
+}
+
+/// Create the temporary file at `path` with `create`, recreating its
+/// directory if another transfer or the sweep pruned it in the meantime
+pub(crate) fn create_part<T>(
+    path: &Path,
+    mut create: impl FnMut(&Path) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut retries = 3;
+    loop {
+        match create(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && retries > 0 => {
+                retries -= 1;
+                std::fs::create_dir_all(path.parent().unwrap_or(path))?;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Remove the directories between the temporary area `tmp` and the
+/// temporary file `path` that are left empty now that the file is gone
+fn prune_tmp_dirs(tmp: &Path, path: &Path) {
+    for dir in path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| *dir != tmp && dir.starts_with(tmp))
+    {
+        // Fails unless empty
+        if std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Remove temporary files that haven't been written to for `max_age` from
+/// every temporary area, and the directories that leaves empty: left by a
+/// crash, or by a sender that never came back to resume. Returns how many
+/// files went.
+fn sweep_tmp_areas(opts: &ReceiveOptions, max_age: Duration) -> usize {
+    // Echo markers stay under the destination even with --tmp-dir
+    let mut areas = vec![opts.dest_dir.join(TMP_DIR)];
+    areas.extend(opts.tmp_dir.clone());
+    areas.extend(opts.also_into.iter().map(|dir| dir.join(TMP_DIR)));
+    if opts.per_sender_dir
+        && let Ok(entries) = std::fs::read_dir(&opts.dest_dir)
+    {
+        areas.extend(
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path().join(TMP_DIR)),
+        );
+    }
+    areas
+        .iter()
+        .filter(|area| area.is_dir())
+        .map(|area| sweep(area, max_age))
+        .sum()
+}
+
+/// [`sweep_tmp_areas`] for one directory, also removing the directories
+/// that leaves empty
+fn sweep(dir: &Path, max_age: Duration) -> usize {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => {
+                removed += sweep(&path, max_age);
+                // Fails unless empty
+                let _ = std::fs::remove_dir(&path);
+            }
+            Ok(_) => {
+                let stale = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|t| t.elapsed().is_ok_and(|age| age >= max_age));
+                if stale && std::fs::remove_file(&path).is_ok() {
+                    removed += 1;
+                }
+            }
+            Err(_) => {}
+        }
+    }
+    removed
+}
+
+/// Stream a whole-file payload into the temporary file `f`
+async fn receive_full<R: AsyncRead + Unpin>(
+    reader: &mut FrameReader<R>,
+    mut f: PartWriter,
+    size: u64,
+    hashed: bool,
+) -> Result<Received> {
+    let mut hasher = hashed.then(Hasher::new);
+    f.write_payload(reader, size, |data| {
+        if let Some(hasher) = &mut hasher {
+            hasher.update(data);
+        }
+    })
+    .await?;
+    f.finish().await?;
+    Ok(Received::Part(hasher.map(|h| h.finalize())))
+}
+
+/// Buffer a whole-file payload of at most `--stage-max` bytes
+async fn receive_memory<R: AsyncRead + Unpin>(
+    reader: &mut FrameReader<R>,
+    size: u64,
+    hashed: bool,
+) -> Result<Received> {
+    let mut data = Vec::with_capacity(size as usize);
+    read_payload(reader, size, |chunk| {
+        data.extend_from_slice(chunk);
+        Ok(())
+    })
+    .await?;
+    Ok(Received::Memory(hashed.then(|| blake3::hash(&data)), data))
+}
+
+/// Continue `file`'s payload in `tmp_path` (open as `f`, written with
+/// `io_backend` once positioned) from the offset the sender accepts. Without
+/// a partial copy the copy `storage` keeps at the file's destination is
+/// offered, so an unchanged file is not sent again.
+async fn receive_resume<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FrameReader<R>,
+    writer: &mut FrameWriter<W>,
+    storage: &dyn Storage,
+    file: &Offered,
+    tmp_path: &Path,
+    mut f: File,
+    io_backend: IoBackend,
+) -> Result<Received> {
+    let (dest_path, size) = (&file.dest_path, file.size);
+    // Offer what an earlier attempt left behind; the sender checks its hash
+    // symlink_metadata: never read through a link planted at the path
+    let base = if std::fs::symlink_metadata(tmp_path).is_ok_and(|m| m.is_file() && m.len() > 0) {
+        Some((File::open(tmp_path)?, false))
+    } else {
+        match storage.open(dest_path) {
+            Ok(Some(stored)) if stored.metadata()?.len() == size => Some((stored, true)),
+            _ => None,
+        }
+    };
+    let mut hasher = Hasher::new();
+    let mut have = 0;
+    if let Some((base, _)) = &base {
+        let data = unsafe { Mmap::map(base)? };
+        let prefix = &data[..data.len().min(size as usize)];
+        hasher.update(prefix);
+        have = prefix.len() as u64;
+    }
+    writer
+        .write_resume_offer(have, hasher.clone().finalize().as_bytes())
+        .await?;
+
+    let start = reader.read_u64().await?;
+    anyhow::ensure!(
+        start == 0 || start == have,
+        "Resume from {start} while offering {have} bytes"
+    );
+    match base {
+        _ if start == 0 => {
+            hasher.reset();
+        }
+        Some((_, true)) => return Ok(Received::Existing(hasher.finalize())),
+        _ => info!(
+            "[*] Resuming {} at {start} of {size} bytes",
+            tmp_path.display()
+        ),
+    }
+    if f.metadata()?.len() != start {
+        // Shrinking drops the reservation past the new end
+        f.set_len(start)?;
+        preallocate(&f, size)?;
+    }
+    f.seek(SeekFrom::Start(start))?;
+    let mut f = PartWriter::new(f, io_backend);
+    f.write_payload(reader, size - start, |data| {
+        hasher.update(data);
+    })
+    .await?;
+    f.finish().await?;
+    Ok(Received::Part(Some(hasher.finalize())))
+}
+
+/// Rebuild a file in the temporary file `f` from the copy `storage` keeps at
+/// `dest_path` and a delta stream over `block_size`-byte blocks. A delta that
+/// copies the whole old file in order writes nothing.
+async fn receive_delta<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut FrameReader<R>,
+    writer: &mut FrameWriter<W>,
+    storage: &dyn Storage,
+    dest_path: &Path,
+    mut f: PartWriter,
+    size: u64,
+    block_size: u32,
+) -> Result<Received> {
+    let block_size = block_size as usize;
+
+    // Hash our current copy so the sender knows which blocks it can skip
+    let old = match storage.open(dest_path) {
+        Ok(Some(f)) => Some(unsafe { Mmap::map(&f)? }),
+        _ => None,
+    };
+    let old_data: &[u8] = old.as_deref().unwrap_or_default();
+    writer
+        .write_block_hashes(&block_hashes(old_data, block_size))
+        .await?;
+
+    let mut hasher = Hasher::new();
+    let mut written = 0u64;
+    // While every op so far copied old data in place, the first `written`
+    // bytes are only hashed and not yet written
+    let mut in_place = true;
+    loop {
+        let op = reader.read_delta_op().await?;
+        let stays_in_place = matches!(op, DeltaOp::Copy { index, .. }
+            if (index as usize).saturating_mul(block_size) as u64 == written);
+        if in_place && !stays_in_place && !matches!(op, DeltaOp::End) {
+            f.write_all(&old_data[..written as usize]).await?;
+            in_place = false;
+        }
+        match op {
+            DeltaOp::Copy { index, count } => {
+                let start = (index as usize).saturating_mul(block_size);
+                let end = start
+                    .saturating_add((count as usize).saturating_mul(block_size))
+                    .min(old_data.len());
+                anyhow::ensure!(start < end, "Delta copy out of range");
+                let block = &old_data[start..end];
+                if !in_place {
+                    f.write_all(block).await?;
+                }
+                hasher.update(block);
+                written += block.len() as u64;
+            }
+            DeltaOp::Data { len } => {
+                f.write_payload(reader, len as u64, |data| {
+                    hasher.update(data);
+                })
+                .await?;
+                written += len as u64;
+            }
+            DeltaOp::End => break,
+        }
+        anyhow::ensure!(written <= size, "Delta produced more than {size} bytes");
+    }
+    anyhow::ensure!(written == size, "Delta produced {written} of {size} bytes");
+    if in_place && written == old_data.len() as u64 {
+        return Ok(Received::Existing(hasher.finalize()));
+    }
+    if in_place {
+        f.write_all(&old_data[..written as usize]).await?;
+    }
+    f.finish().await?;
+    Ok(Received::Part(Some(hasher.finalize())))
+}
+
+/// Writes a transfer's data to its temporary file with the chosen backend
+enum PartWriter {
+    Sync(File),
+    Thread {
+        tx: tokio::sync::mpsc::Sender<Vec<u8>>,
+        /// Buffers the thread is done with, to read the next chunks into
+        free: std::sync::mpsc::Receiver<Vec<u8>>,
+        handle: std::thread::JoinHandle<std::io::Result<()>>,
+    },
+}
+
+impl PartWriter {
+    /// Buffers queued for the writer thread before the connection waits
+    const QUEUE: usize = 4;
+    /// Largest buffer handed to the writer thread at once
+    const PIECE: usize = 1024 * 1024;
+
+    fn new(mut file: File, backend: IoBackend) -> Self {
+        match backend {
+            IoBackend::Sync => Self::Sync(file),
+            IoBackend::Thread => {
+                let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(Self::QUEUE);
+                let (free_tx, free) = std::sync::mpsc::channel();
+                let handle = std::thread::spawn(move || {
+                    while let Some(buf) = rx.blocking_recv() {
+                        file.write_all(&buf)?;
+                        let _ = free_tx.send(buf);
+                    }
+                    file.flush()
+                });
+                Self::Thread { tx, free, handle }
+            }
+        }
+    }
+
+    /// Read `len` payload bytes from `reader` into the file, handing each
+    /// verified chunk to `hash` too. The writer thread gets the buffers the
+    /// chunks were read into, so they are never copied.
+    async fn write_payload<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut FrameReader<R>,
+        len: u64,
+        mut hash: impl FnMut(&[u8]),
+    ) -> Result<()> {
+        let (tx, free) = match self {
+            Self::Sync(file) => {
+                return read_payload(reader, len, |data| {
+                    file.write_all(data)?;
+                    hash(data);
+                    Ok(())
+                })
+                .await;
+            }
+            Self::Thread { tx, free, .. } => (tx, free),
+        };
+        let mut remaining = len;
+        while remaining > 0 {
+            let mut buf = free.try_recv().unwrap_or_default();
+            reader.read_chunk_into(remaining, &mut buf).await?;
+            hash(&buf);
+            remaining -= buf.len() as u64;
+            // Only fails once the thread stopped on a write error, which
+            // `finish` reports; the payload is still read to its end
+            let _ = tx.send(buf).await;
+        }
+        Ok(())
+    }
+
+    /// Write `data`, which isn't payload, e.g. blocks of the old copy a
+    /// delta reuses
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Self::Sync(file) => file.write_all(data)?,
+            Self::Thread { tx, free, .. } => {
+                for piece in data.chunks(Self::PIECE) {
+                    let mut buf = free.try_recv().unwrap_or_default();
+                    buf.clear();
+                    buf.extend_from_slice(piece);
+                    if tx.send(buf).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for every write to reach the file
+    async fn finish(self) -> Result<()> {
+        match self {
+            Self::Sync(mut file) => file.flush()?,
+            Self::Thread { tx, handle, .. } => {
+                drop(tx);
+                tokio::task::spawn_blocking(|| handle.join())
+                    .await?
+                    .map_err(|_| anyhow::anyhow!("Writer thread panicked"))??;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handshake::Features,
+        receiver::{Receiver, ReceiverOptions},
+    };
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+    type Reader = FrameReader<OwnedReadHalf>;
+    type Writer = FrameWriter<OwnedWriteHalf>;
+
+    /// Start a receiver into `dest` with `args` and connect to it as a
+    /// sender offering `features`, through the handshake
+    async fn connect(dest: &Path, args: &[&str], features: Features) -> (Reader, Writer) {
+        let opts = ReceiverOptions::from_args(args).unwrap();
+        let receiver = Receiver::bind("127.0.0.1:0", dest, opts).await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+        tokio::spawn(receiver.serve(|_| {}));
+        let (mut reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let params = handshake::connect(&mut reader, &mut writer, features, 1024)
+            .await
+            .unwrap();
+        auth::connect(&mut reader, &mut writer, None, params.features)
+            .await
+            .unwrap();
+        let (mut reader, mut writer) = (FrameReader::new(reader), FrameWriter::new(writer));
+        reader.set_features(params.features);
+        writer.set_features(params.features);
+        (reader, writer)
+    }
+
+    fn header(name: &str, size: usize, mtime_sec: i64) -> ProtocolHeader {
+        ProtocolHeader {
+            name: PathBuf::from(name),
+            size: size as u64,
+            meta: FileMeta {
+                mode: 0o644,
+                uid: unsafe { libc::getuid() },
+                gid: unsafe { libc::getgid() },
+                mtime_sec,
+                mtime_nsec: 0,
+            },
+            confirm: None,
+            xattrs: None,
+            version: None,
+            transfer_id: None,
+        }
+    }
+
+    /// Write `data` in chunks, then `checksum` as the trailer
+    async fn write_payload(writer: &mut Writer, data: &[u8], checksum: blake3::Hash) {
+        for chunk in data.chunks(1024) {
+            writer.write_chunk(chunk).await.unwrap();
+        }
+        writer.write_checksum(checksum.as_bytes()).await.unwrap();
+    }
+
+    /// Write a file message for `data` under `header`
+    async fn write_file(writer: &mut Writer, seq: u32, header: &ProtocolHeader, data: &[u8]) {
+        writer
+            .write_header(MessageKind::File, seq, header)
+            .await
+            .unwrap();
+        write_payload(writer, data, blake3::hash(data)).await;
+    }
+
+    #[tokio::test]
+    async fn resumes_continue_only_from_the_offered_offset() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-resume-{}", std::process::id()));
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        for name in ["good", "bad"] {
+            let part = part_path(&dir.join(TMP_DIR), Path::new(name)).unwrap();
+            std::fs::write(part, &data[..1000]).unwrap();
+        }
+        let (mut reader, mut writer) = connect(&dir, &[], Features::RESUME).await;
+
+        let header = header("good", data.len(), 0);
+        writer
+            .write_header(MessageKind::Resume, 1, &header)
+            .await
+            .unwrap();
+        let (have, hash) = reader.read_resume_offer().await.unwrap().unwrap();
+        assert_eq!(have, 1000);
+        assert_eq!(hash, *blake3::hash(&data[..1000]).as_bytes());
+        writer.write_u64(have).await.unwrap();
+        write_payload(&mut writer, &data[1000..], blake3::hash(&data)).await;
+        assert_eq!(reader.read_ack().await.unwrap(), (1, Ack::Ok));
+        assert!(std::fs::read(dir.join("good")).unwrap() == data);
+
+        // Any offset but the one offered or zero could splice mismatched
+        // bytes into the file
+        let header = ProtocolHeader {
+            name: PathBuf::from("bad"),
+            ..header
+        };
+        writer
+            .write_header(MessageKind::Resume, 2, &header)
+            .await
+            .unwrap();
+        assert_eq!(reader.read_resume_offer().await.unwrap().unwrap().0, 1000);
+        writer.write_u64(500).await.unwrap();
+        assert!(reader.read_ack().await.is_err());
+        assert!(!dir.join("bad").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn conflicts_follow_the_policy() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-conflict-{}", std::process::id()));
+        // Far in the future, so newer than the copy on disk
+        let newer = 4_000_000_000;
+        let cases = [
+            ("skip", Features::CONFLICTS, 0, Ack::Kept),
+            // A watcher from before the conflict ACKs
+            ("skip", Features::DELTA, newer, Ack::Unchanged),
+            ("newer-wins", Features::CONFLICTS, 0, Ack::Kept),
+            ("newer-wins", Features::CONFLICTS, newer, Ack::Ok),
+            ("rename", Features::CONFLICTS, 0, Ack::MovedAside),
+            ("overwrite", Features::CONFLICTS, 0, Ack::Ok),
+        ];
+        for (i, (policy, features, mtime, expected)) in cases.into_iter().enumerate() {
+            let dest = dir.join(i.to_string());
+            std::fs::create_dir_all(&dest).unwrap();
+            std::fs::write(dest.join("a"), b"mine").unwrap();
+            let args = ["--on-conflict", policy];
+            let (mut reader, mut writer) = connect(&dest, &args, features).await;
+            write_file(&mut writer, 1, &header("a", 6, mtime), b"theirs").await;
+            let (_, ack) = reader.read_ack().await.unwrap();
+            assert_eq!(ack, expected, "{policy} {mtime}");
+
+            let kept = matches!(ack, Ack::Kept | Ack::Unchanged);
+            let content = if kept { &b"mine"[..] } else { b"theirs" };
+            assert_eq!(std::fs::read(dest.join("a")).unwrap(), content, "{policy}");
+            let aside: Vec<_> = std::fs::read_dir(&dest)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|name| name.starts_with("a.conflict-"))
+                .collect();
+            assert_eq!(aside.len(), usize::from(policy == "rename"), "{policy}");
+            if let [name] = aside.as_slice() {
+                assert_eq!(std::fs::read(dest.join(name)).unwrap(), b"mine");
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backups_keep_the_newest_snapshots() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-backups-{}", std::process::id()));
+        let (dest, backup_dir) = (dir.join("dest"), dir.join("backups"));
+        std::fs::create_dir_all(dest.join("sub")).unwrap();
+        for day in 1..=3 {
+            let old = backup_dir.join(format!("2020-01-0{day}T00:00:00Z"));
+            std::fs::create_dir_all(&old).unwrap();
+            std::fs::write(old.join("a"), b"older").unwrap();
+        }
+        let dest = dest.canonicalize().unwrap();
+        std::fs::write(dest.join("sub/a"), b"old").unwrap();
+        let backups = Backups::new(&backup_dir, &dest, Some(2)).unwrap();
+
+        // A file about to be replaced is linked into this second's snapshot
+        backups.save(&dest.join("sub/a"), false).unwrap();
+        assert!(dest.join("sub/a").is_file());
+        let snapshots = |dir: &Path| {
+            let mut names: Vec<_> = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect();
+            names.sort();
+            names
+        };
+        let kept = snapshots(&backup_dir);
+        assert_eq!(kept.len(), 2);
+        assert!(kept[0].ends_with("2020-01-03T00:00:00Z"));
+        assert_eq!(std::fs::read(kept[1].join("sub/a")).unwrap(), b"old");
+
+        // A deleted one is moved there, beside what the second already holds
+        backups.save(&dest.join("sub/a"), true).unwrap();
+        assert!(!dest.join("sub/a").exists());
+        let saved: usize = snapshots(&backup_dir)
+            .iter()
+            .filter(|snapshot| !snapshot.ends_with("2020-01-03T00:00:00Z"))
+            .map(|snapshot| snapshots(&snapshot.join("sub")).len())
+            .sum();
+        assert_eq!(saved, 2);
+        assert!(snapshots(&backup_dir).len() <= 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn older_versions_never_replace_newer_ones() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-versions-{}", std::process::id()));
+        let (mut reader, mut writer) = connect(&dir, &[], Features::VERSIONS).await;
+        let cases = [
+            (1, 20, &b"second"[..], Ack::Ok),
+            // A retry of the first send, overtaken by the second
+            (2, 10, b"first", Ack::Superseded),
+            (3, 20, b"second", Ack::Unchanged),
+            (4, 30, b"third", Ack::Ok),
+        ];
+        for (seq, version, data, expected) in cases {
+            let header = ProtocolHeader {
+                version: Some(version),
+                ..header("a", data.len(), 0)
+            };
+            write_file(&mut writer, seq, &header, data).await;
+            assert_eq!(reader.read_ack().await.unwrap(), (seq, expected));
+        }
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"third");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn transactions_with_a_damaged_member_change_nothing() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-txn-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("data"), b"old").unwrap();
+        let (mut reader, mut writer) = connect(&dir, &[], Features::TRANSACTIONS).await;
+
+        writer.write_transaction(7, 2).await.unwrap();
+        write_file(&mut writer, 7, &header("data", 3, 0), b"new").await;
+        // The marker arrives, but not as its checksum says
+        writer
+            .write_header(MessageKind::File, 7, &header("data.ok", 2, 0))
+            .await
+            .unwrap();
+        write_payload(&mut writer, b"ok", blake3::hash(b"no")).await;
+        assert_eq!(reader.read_ack().await.unwrap(), (7, Ack::Failed));
+
+        // The member that verified was rolled back with the rest
+        assert_eq!(std::fs::read(dir.join("data")).unwrap(), b"old");
+        assert!(!dir.join("data.ok").exists());
+        assert!(!dir.join(TMP_DIR).join("data.part").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn transactions_end_at_their_count() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-txn-count-{}", std::process::id()));
+        let (mut reader, mut writer) = connect(&dir, &[], Features::TRANSACTIONS).await;
+
+        writer.write_transaction(1, 1).await.unwrap();
+        write_file(&mut writer, 1, &header("a", 1, 0), b"a").await;
+        assert_eq!(reader.read_ack().await.unwrap(), (1, Ack::Ok));
+        // A file past the count is not taken as a member: it gets its own ACK
+        write_file(&mut writer, 2, &header("b", 1, 0), b"b").await;
+        assert_eq!(reader.read_ack().await.unwrap(), (2, Ack::Ok));
+        assert!(dir.join("a").is_file() && dir.join("b").is_file());
+
+        // Anything but a file while members are due drops the connection,
+        // and the members it had with it
+        writer.write_transaction(3, 2).await.unwrap();
+        write_file(&mut writer, 3, &header("c", 1, 0), b"c").await;
+        writer.write_delete(4, Path::new("a")).await.unwrap();
+        assert!(reader.read_ack().await.is_err());
+        assert!(!dir.join("c").exists());
+        assert!(dir.join("a").is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
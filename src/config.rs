@@ -0,0 +1,614 @@
+//! Config file support.
+//!
+//! Config files use a TOML subset: `key = value` pairs, `[table]` and
+//! `[[array-of-tables]]` headers, inline tables, arrays, strings (basic and
+//! literal), integers, floats and booleans. Top-level keys are named after
+//! the command-line flags (`watch_dir` or `watch-dir` for `--watch-dir`) and
+//! are turned into arguments placed before the real ones, so anything given
+//! on the command line wins.
+
+use anyhow::{Context, Result};
+use std::{collections::BTreeMap, ffi::OsString, path::Path};
+
+/// A parsed TOML value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+pub type Table = BTreeMap<String, Value>;
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Render a scalar the way it would be typed on the command line
+    fn to_arg(&self) -> Option<String> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Integer(i) => Some(i.to_string()),
+            Value::Float(f) => Some(f.to_string()),
+            Value::Bool(b) => Some(b.to_string()),
+            Value::Array(_) | Value::Table(_) => None,
+        }
+    }
+}
+
+/// Parse the command line, first loading `--config` if given. Returns the
+/// matches (to tell which flags came from the command line) and the config
+/// file contents for sections the caller handles itself.
+pub fn parse_args<T: clap::Parser>() -> Result<(T, clap::ArgMatches, Table)> {
+    parse_with(std::env::args_os().collect(), |cmd, argv| {
+        Ok(cmd.get_matches_from(argv))
+    })
+}
+
+/// [`parse_args`] again for a reload, re-reading the config file. Errors are
+/// returned instead of exiting the process.
+pub fn reparse_args<T: clap::Parser>() -> Result<(T, clap::ArgMatches, Table)> {
+    parse_from(std::env::args_os().collect())
+}
+
+/// [`reparse_args`] over `cli`, whose first item is the program name, instead
+/// of the process's arguments
+pub fn parse_from<T: clap::Parser>(cli: Vec<OsString>) -> Result<(T, clap::ArgMatches, Table)> {
+    parse_with(cli, |cmd, argv| Ok(cmd.try_get_matches_from(argv)?))
+}
+
+fn parse_with<T: clap::Parser>(
+    cli: Vec<OsString>,
+    get_matches: impl Fn(clap::Command, Vec<OsString>) -> Result<clap::ArgMatches>,
+) -> Result<(T, clap::ArgMatches, Table)> {
+    let cmd = T::command().args_override_self(true);
+    let matches = get_matches(cmd.clone(), cli.clone())?;
+    let mut table = Table::new();
+    let matches = match matches.get_one::<std::path::PathBuf>("config") {
+        Some(path) => {
+            table = load(path)?;
+            let mut argv = cli[..1].to_vec();
+            argv.extend(to_args(&table, &cmd)?);
+            argv.extend_from_slice(&cli[1..]);
+            get_matches(cmd, argv)?
+        }
+        None => matches,
+    };
+    Ok((T::from_arg_matches(&matches)?, matches, table))
+}
+
+/// Read and parse a config file
+pub fn load(path: &Path) -> Result<Table> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("Read {}", path.display()))?;
+    parse(&text).with_context(|| format!("Parse {}", path.display()))
+}
+
+/// Turn the top-level keys of `table` into command-line arguments for `cmd`.
+/// Tables (sections) are skipped; the caller handles the ones it knows.
+pub fn to_args(table: &Table, cmd: &clap::Command) -> Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        if matches!(value, Value::Table(_)) || is_table_array(value) || key == "config" {
+            continue;
+        }
+        let flag = key.replace('_', "-");
+        let arg = cmd
+            .get_arguments()
+            .find(|a| a.get_long() == Some(flag.as_str()))
+            .with_context(|| format!("Unknown config key {key:?}"))?;
+        let takes_value = arg.get_action().takes_values();
+        match value {
+            Value::Bool(b) if !takes_value => {
+                if *b {
+                    args.push(format!("--{flag}").into());
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    let item = item.to_arg().with_context(|| {
+                        format!("Config key {key:?}: nested arrays are not supported")
+                    })?;
+                    args.push(format!("--{flag}={item}").into());
+                }
+            }
+            scalar => {
+                anyhow::ensure!(takes_value, "Config key {key:?} must be a boolean");
+                args.push(format!("--{flag}={}", scalar.to_arg().unwrap_or_default()).into());
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Is this an array of tables (`[[name]]`)?
+pub fn is_table_array(value: &Value) -> bool {
+    matches!(value, Value::Array(items) if !items.is_empty() && items.iter().all(|v| matches!(v, Value::Table(_))))
+}
+
+/// Parse a TOML document
+pub fn parse(text: &str) -> Result<Table> {
+    let mut p = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    p.document()
+        .map_err(|e| anyhow::anyhow!("line {}: {e}", p.line))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        anyhow::ensure!(self.eat(c), "expected '{c}'");
+        Ok(())
+    }
+
+    /// Skip spaces and tabs on the current line
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    /// Skip whitespace, newlines and comments
+    fn skip_all(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.bump();
+                }
+                Some('#') => self.skip_comment(),
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.bump();
+        }
+    }
+
+    /// Only whitespace or a comment may follow until the end of the line
+    fn end_of_line(&mut self) -> Result<()> {
+        self.skip_ws();
+        if self.peek() == Some('#') {
+            self.skip_comment();
+        }
+        self.eat('\r');
+        anyhow::ensure!(
+            self.peek().is_none() || self.eat('\n'),
+            "expected end of line"
+        );
+        Ok(())
+    }
+
+    fn document(&mut self) -> Result<Table> {
+        let mut root = Table::new();
+        // Path of the table that key/value pairs currently go into
+        let mut current: Vec<String> = Vec::new();
+        loop {
+            self.skip_all();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.bump();
+                    let array = self.eat('[');
+                    self.skip_ws();
+                    let path = self.key_path()?;
+                    self.skip_ws();
+                    self.expect(']')?;
+                    if array {
+                        self.expect(']')?;
+                    }
+                    self.end_of_line()?;
+                    let (last, parents) = path.split_last().context("empty table name")?;
+                    let parent = table_at(&mut root, parents)?;
+                    if array {
+                        match parent
+                            .entry(last.clone())
+                            .or_insert_with(|| Value::Array(Vec::new()))
+                        {
+                            Value::Array(items) => items.push(Value::Table(Table::new())),
+                            _ => anyhow::bail!("{last:?} is not an array of tables"),
+                        }
+                    } else {
+                        anyhow::ensure!(!parent.contains_key(last), "duplicate table {last:?}");
+                        parent.insert(last.clone(), Value::Table(Table::new()));
+                    }
+                    current = path;
+                }
+                Some(_) => {
+                    let (key, value) = self.key_value()?;
+                    self.end_of_line()?;
+                    let table = table_at(&mut root, &current)?;
+                    anyhow::ensure!(!table.contains_key(&key), "duplicate key {key:?}");
+                    table.insert(key, value);
+                }
+            }
+        }
+    }
+
+    fn key_value(&mut self) -> Result<(String, Value)> {
+        let key = self.key()?;
+        self.skip_ws();
+        self.expect('=')?;
+        self.skip_ws();
+        let value = self.value()?;
+        Ok((key, value))
+    }
+
+    fn key_path(&mut self) -> Result<Vec<String>> {
+        let mut path = vec![self.key()?];
+        loop {
+            self.skip_ws();
+            if !self.eat('.') {
+                return Ok(path);
+            }
+            self.skip_ws();
+            path.push(self.key()?);
+        }
+    }
+
+    fn key(&mut self) -> Result<String> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let start = self.pos;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    self.bump();
+                }
+                anyhow::ensure!(self.pos > start, "expected a key");
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek().context("expected a value")? {
+            '"' => Ok(Value::String(self.basic_string()?)),
+            '\'' => Ok(Value::String(self.literal_string()?)),
+            '[' => {
+                self.bump();
+                let mut items = Vec::new();
+                loop {
+                    self.skip_all();
+                    if self.eat(']') {
+                        return Ok(Value::Array(items));
+                    }
+                    items.push(self.value()?);
+                    self.skip_all();
+                    if !self.eat(',') {
+                        self.skip_all();
+                        self.expect(']')?;
+                        return Ok(Value::Array(items));
+                    }
+                }
+            }
+            '{' => {
+                self.bump();
+                let mut table = Table::new();
+                self.skip_ws();
+                if self.eat('}') {
+                    return Ok(Value::Table(table));
+                }
+                loop {
+                    self.skip_ws();
+                    let (key, value) = self.key_value()?;
+                    anyhow::ensure!(!table.contains_key(&key), "duplicate key {key:?}");
+                    table.insert(key, value);
+                    self.skip_ws();
+                    if self.eat('}') {
+                        return Ok(Value::Table(table));
+                    }
+                    self.expect(',')?;
+                }
+            }
+            _ => self.scalar(),
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | '.' | ':'))
+        {
+            self.bump();
+        }
+        let raw: String = self.chars[start..self.pos].iter().collect();
+        match raw.as_str() {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            "" => anyhow::bail!("expected a value"),
+            _ => {}
+        }
+        let digits = raw.replace('_', "");
+        if let Ok(i) = digits.parse::<i64>() {
+            return Ok(Value::Integer(i));
+        }
+        if let Some(hex) = digits.strip_prefix("0x")
+            && let Ok(i) = i64::from_str_radix(hex, 16)
+        {
+            return Ok(Value::Integer(i));
+        }
+        if let Some(oct) = digits.strip_prefix("0o")
+            && let Ok(i) = i64::from_str_radix(oct, 8)
+        {
+            return Ok(Value::Integer(i));
+        }
+        if let Ok(f) = digits.parse::<f64>() {
+            return Ok(Value::Float(f));
+        }
+        anyhow::bail!("invalid value {raw:?}")
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.bump().context("unterminated string")? {
+                '"' => return Ok(out),
+                '\n' => anyhow::bail!("unterminated string"),
+                '\\' => match self.bump().context("unterminated string")? {
+                    'n' => out.push('\n'),
+                    't' => out.push('\t'),
+                    'r' => out.push('\r'),
+                    '"' => out.push('"'),
+                    '\\' => out.push('\\'),
+                    c @ ('u' | 'U') => {
+                        let len = if c == 'u' { 4 } else { 8 };
+                        let digits: Option<Vec<u32>> = (0..len)
+                            .map(|_| self.bump().and_then(|c| c.to_digit(16)))
+                            .collect();
+                        let code = digits
+                            .context("invalid unicode escape")?
+                            .into_iter()
+                            .fold(0, |code, d| code * 16 + d);
+                        out.push(char::from_u32(code).context("invalid unicode escape")?);
+                    }
+                    c => anyhow::bail!("invalid escape '\\{c}'"),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        self.expect('\'')?;
+        let mut out = String::new();
+        loop {
+            match self.bump().context("unterminated string")? {
+                '\'' => return Ok(out),
+                '\n' => anyhow::bail!("unterminated string"),
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+/// Walk to the table at `path`, descending into the last element of arrays
+/// of tables
+fn table_at<'a>(root: &'a mut Table, path: &[String]) -> Result<&'a mut Table> {
+    let mut table = root;
+    for key in path {
+        let value = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match value {
+            Value::Table(t) => t,
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(t)) => t,
+                _ => anyhow::bail!("{key:?} is not a table"),
+            },
+            _ => anyhow::bail!("{key:?} is not a table"),
+        };
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(pairs: &[(&str, Value)]) -> Table {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn parses_documents() {
+        let cases: Vec<(&str, Table)> = vec![
+            ("", Table::new()),
+            ("# only a comment\n\n", Table::new()),
+            (
+                "watch_dir = \"/data\"  # trailing comment\r\n",
+                table(&[("watch_dir", string("/data"))]),
+            ),
+            (
+                "a = 1_000\nb = -7\nc = 0x1f\nd = 0o17\ne = 2.5\nf = true\ng = false",
+                table(&[
+                    ("a", Value::Integer(1000)),
+                    ("b", Value::Integer(-7)),
+                    ("c", Value::Integer(31)),
+                    ("d", Value::Integer(15)),
+                    ("e", Value::Float(2.5)),
+                    ("f", Value::Bool(true)),
+                    ("g", Value::Bool(false)),
+                ]),
+            ),
+            (
+                r#"a = "tab\there \"quoted\" back\\slash \u00e9\U0001F600""#,
+                table(&[(
+                    "a",
+                    string("tab\there \"quoted\" back\\slash \u{e9}\u{1F600}"),
+                )]),
+            ),
+            (
+                r#"a = 'C:\no\escapes "here"'"#,
+                table(&[("a", string(r#"C:\no\escapes "here""#))]),
+            ),
+            (
+                r#""quoted key" = 1"#,
+                table(&[("quoted key", Value::Integer(1))]),
+            ),
+            (
+                "exclude = [\n  \"*.swp\", # editors\n  'tmp/',\n]",
+                table(&[(
+                    "exclude",
+                    Value::Array(vec![string("*.swp"), string("tmp/")]),
+                )]),
+            ),
+            (
+                "a = []\nb = [[1], [2, 3]]",
+                table(&[
+                    ("a", Value::Array(vec![])),
+                    (
+                        "b",
+                        Value::Array(vec![
+                            Value::Array(vec![Value::Integer(1)]),
+                            Value::Array(vec![Value::Integer(2), Value::Integer(3)]),
+                        ]),
+                    ),
+                ]),
+            ),
+            (
+                "dest = { addr = \"h:1\", delta = true }",
+                table(&[(
+                    "dest",
+                    Value::Table(table(&[
+                        ("addr", string("h:1")),
+                        ("delta", Value::Bool(true)),
+                    ])),
+                )]),
+            ),
+            (
+                "top = 1\n[a.b]\nc = 2\n[ d ]\ne = 3",
+                table(&[
+                    ("top", Value::Integer(1)),
+                    (
+                        "a",
+                        Value::Table(table(&[(
+                            "b",
+                            Value::Table(table(&[("c", Value::Integer(2))])),
+                        )])),
+                    ),
+                    ("d", Value::Table(table(&[("e", Value::Integer(3))]))),
+                ]),
+            ),
+            (
+                "[[dest]]\naddr = \"a:1\"\n\n[[dest]]\naddr = \"b:2\"\npriority = 1\n[[dest]]",
+                table(&[(
+                    "dest",
+                    Value::Array(vec![
+                        Value::Table(table(&[("addr", string("a:1"))])),
+                        Value::Table(table(&[
+                            ("addr", string("b:2")),
+                            ("priority", Value::Integer(1)),
+                        ])),
+                        Value::Table(Table::new()),
+                    ]),
+                )]),
+            ),
+            (
+                "[[sender]]\nid = \"x\"\n[sender.limits]\nrate = 5",
+                table(&[(
+                    "sender",
+                    Value::Array(vec![Value::Table(table(&[
+                        ("id", string("x")),
+                        (
+                            "limits",
+                            Value::Table(table(&[("rate", Value::Integer(5))])),
+                        ),
+                    ]))]),
+                )]),
+            ),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse(text).unwrap(), expected, "parsing {text:?}");
+        }
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        let cases = [
+            ("a = 1\nb = \n", "line 2: expected a value"),
+            ("a = 1\na = 2", "line 2: duplicate key \"a\""),
+            ("[t]\n[t]", "line 2: duplicate table \"t\""),
+            ("a = 1\n\n[[a]]", "line 3: \"a\" is not an array of tables"),
+            ("a = \"open\nb = 1", "line 2: unterminated string"),
+            ("a = 'open", "line 1: unterminated string"),
+            ("a = \"\\q\"", "line 1: invalid escape '\\q'"),
+            ("a = \"\\u+041\"", "line 1: invalid unicode escape"),
+            ("a = \"\\uD800\"", "line 1: invalid unicode escape"),
+            ("a = 1 b = 2", "line 1: expected end of line"),
+            ("a = nope", "line 1: invalid value \"nope\""),
+            ("a = [1, 2", "line 1: expected ']'"),
+            ("a = { b = 1 c = 2 }", "line 1: expected ','"),
+            ("\n\n[a\nb = 1", "line 3: expected ']'"),
+            ("= 1", "line 1: expected a key"),
+        ];
+        for (text, message) in cases {
+            let err = parse(text).expect_err(text);
+            assert_eq!(err.to_string(), message, "parsing {text:?}");
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Local control socket for steering a running watcher with `fast-sync ctl`.
+//!
+//! A client connects, writes one command line (`status`, `pause`,
+//! `resync /some/path`, ...) and reads the answer until the server closes
+//! the connection. Answers to commands that failed start with `error:`.
+
+use anyhow::{Context, Result};
+use std::{os::unix::fs::PermissionsExt, path::Path};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+
+/// Longest command line accepted
+const MAX_COMMAND_LEN: u64 = 4096;
+
+/// A command received on the socket, answered through `reply`
+pub struct Request {
+    pub command: String,
+    pub reply: oneshot::Sender<String>,
+}
+
+/// Listen on `path`, replacing a stale socket left by an earlier run. Only
+/// the owner may connect. Commands arrive on the returned channel.
+pub fn serve(path: &Path) -> Result<mpsc::Receiver<Request>> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if std::os::unix::fs::FileTypeExt::is_socket(&meta.file_type()) => {
+            std::fs::remove_file(path)?
+        }
+        Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Bind control socket {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let Ok((conn, _)) = listener.accept().await else {
+                continue;
+            };
+            tokio::spawn(respond(conn, tx.clone()));
+        }
+    });
+    Ok(rx)
+}
+
+async fn respond(conn: UnixStream, tx: mpsc::Sender<Request>) {
+    let (reader, mut writer) = conn.into_split();
+    let mut line = String::new();
+    if BufReader::new(reader.take(MAX_COMMAND_LEN))
+        .read_line(&mut line)
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let (reply, answer) = oneshot::channel();
+    let command = line.trim().to_string();
+    if tx.send(Request { command, reply }).await.is_err() {
+        return;
+    }
+    if let Ok(text) = answer.await {
+        let _ = writer.write_all(text.as_bytes()).await;
+    }
+}
+
+/// Send `command` to the socket at `path` and return the answer
+pub async fn send(path: &Path, command: &str) -> Result<String> {
+    let mut conn = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("Connect to {}", path.display()))?;
+    conn.write_all(format!("{command}\n").as_bytes()).await?;
+    let mut answer = String::new();
+    conn.read_to_string(&mut answer).await?;
+    Ok(answer)
+}
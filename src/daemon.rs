@@ -0,0 +1,99 @@
+//! Running as a service: detaching from the terminal with `--daemon`, and the
+//! systemd notification protocol for `Type=notify` units with a watchdog.
+
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsStr,
+    os::unix::{ffi::OsStrExt, net::UnixDatagram},
+    path::Path,
+    time::Duration,
+};
+
+/// Fork into the background and leave the controlling terminal. stdin and
+/// stdout go to `/dev/null`; stderr too if it is a terminal, so a log
+/// redirected to a file keeps being written. The working directory is kept
+/// so relative paths in arguments still resolve.
+///
+/// Must run before any threads are started, i.e. before the tokio runtime.
+pub fn daemonize(pid_file: Option<&Path>) -> Result<()> {
+    fork()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(std::io::Error::last_os_error()).context("setsid");
+    }
+    // Again, so the daemon is not a session leader and can't reacquire a terminal
+    fork()?;
+    let null = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    let fd = std::os::fd::AsRawFd::as_raw_fd(&null);
+    for target in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if target == libc::STDERR_FILENO && unsafe { libc::isatty(target) } == 0 {
+            continue;
+        }
+        if unsafe { libc::dup2(fd, target) } == -1 {
+            return Err(std::io::Error::last_os_error()).context("dup2");
+        }
+    }
+    if let Some(path) = pid_file {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Continue in a child process; the parent exits
+fn fork() -> Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("fork"),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// Send `state` (e.g. `READY=1`) to the service manager, if one is listening
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Ok(sock) = UnixDatagram::unbound() else {
+        return;
+    };
+    let path = path.as_encoded_bytes();
+    // A leading '@' names a socket in the abstract namespace
+    let _ = match path.strip_prefix(b"@") {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| sock.send_to_addr(state.as_bytes(), &addr))
+        }
+        None => sock.send_to(state.as_bytes(), OsStr::from_bytes(path)),
+    };
+}
+
+/// How often the service manager expects a `WATCHDOG=1`, if it does
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // Meant for another process of the unit
+    if let Ok(pid) = std::env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Tell the service manager we are up and keep its watchdog fed from the
+/// runtime, so a wedged runtime gets the service restarted
+pub fn ready() {
+    notify("READY=1");
+    if let Some(interval) = watchdog_interval() {
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval / 2);
+            loop {
+                ticks.tick().await;
+                notify("WATCHDOG=1");
+            }
+        });
+    }
+}
@@ -0,0 +1,459 @@
+//! Finding receivers on the local network with mDNS / DNS-SD.
+//!
+//! A client run with `--advertise` answers queries for the
+//! [`SERVICE`] with a PTR record naming its instance, plus the instance's SRV
+//! (port and host), an empty TXT and the host's A records, as RFC 6762 and
+//! RFC 6763 lay out. A watcher with `--discover` asks for the service from
+//! an ordinary port, which gets it the answers by unicast, and sends to
+//! every instance it learns of. Only IPv4 is covered, and only as much DNS
+//! as that takes: the parser follows name compression, since other
+//! responders use it, but the encoder doesn't compress.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    os::fd::{AsRawFd, FromRawFd},
+};
+
+/// The DNS-SD service receivers advertise
+pub const SERVICE: &str = "_fastsync._tcp.local";
+
+/// Where mDNS queries and multicast answers go
+pub const MDNS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// How long others may cache an advertisement, in seconds
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on records only their owner answers for, so caches replace them
+const CACHE_FLUSH: u16 = 0x8000;
+/// Compression pointers followed in one name, so a loop ends
+const MAX_JUMPS: usize = 16;
+
+/// Can `name` be advertised: one DNS label, of printable characters?
+pub fn valid_name(name: &str) -> bool {
+    (1..=63).contains(&name.len()) && !name.contains('.') && !name.chars().any(char::is_control)
+}
+
+/// A query for the [`SERVICE`]
+pub fn query() -> Vec<u8> {
+    let mut packet = header(0, 1, 0);
+    push_name(&mut packet, SERVICE);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+/// Is `packet` a query a [`SERVICE`] instance should answer?
+pub fn asks_for_service(packet: &[u8]) -> bool {
+    let Some((flags, questions)) =
+        parse_header(packet).map(|(flags, counts, _)| (flags, counts[0]))
+    else {
+        return false;
+    };
+    if flags & 0x8000 != 0 {
+        return false;
+    }
+    let mut pos = 12;
+    for _ in 0..questions {
+        let Some((name, end)) = read_name(packet, pos) else {
+            return false;
+        };
+        let Some(qtype) = read_u16(packet, end) else {
+            return false;
+        };
+        if name.eq_ignore_ascii_case(SERVICE) && matches!(qtype, TYPE_PTR | TYPE_ANY) {
+            return true;
+        }
+        pos = end + 4;
+    }
+    false
+}
+
+/// One receiver's answer to [`query`]
+pub struct Advertisement<'a> {
+    /// Instance name, see [`valid_name`]
+    pub name: &'a str,
+    /// First label of the host name
+    pub host: &'a str,
+    pub port: u16,
+    pub addrs: &'a [Ipv4Addr],
+}
+
+impl Advertisement<'_> {
+    pub fn encode(&self) -> Vec<u8> {
+        let instance = format!("{}.{SERVICE}", self.name);
+        let target = format!("{}.local", self.host);
+        let mut packet = header(0x8400, 0, 3 + self.addrs.len() as u16);
+        push_record(
+            &mut packet,
+            SERVICE,
+            TYPE_PTR,
+            CLASS_IN,
+            &name_bytes(&instance),
+        );
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&self.port.to_be_bytes());
+        srv.extend_from_slice(&name_bytes(&target));
+        push_record(
+            &mut packet,
+            &instance,
+            TYPE_SRV,
+            CLASS_IN | CACHE_FLUSH,
+            &srv,
+        );
+        push_record(
+            &mut packet,
+            &instance,
+            TYPE_TXT,
+            CLASS_IN | CACHE_FLUSH,
+            &[0],
+        );
+        for addr in self.addrs {
+            push_record(
+                &mut packet,
+                &target,
+                TYPE_A,
+                CLASS_IN | CACHE_FLUSH,
+                &addr.octets(),
+            );
+        }
+        packet
+    }
+}
+
+/// A [`SERVICE`] instance an answer told of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    pub name: String,
+    pub port: u16,
+    /// Of its host, if the answer carried them
+    pub addrs: Vec<Ipv4Addr>,
+}
+
+/// The instances an answer to [`query`] describes in full: named by a PTR
+/// record, with an SRV record in the same answer
+pub fn found(packet: &[u8]) -> Vec<Found> {
+    let Some((flags, counts, mut pos)) = parse_header(packet) else {
+        return Vec::new();
+    };
+    if flags & 0x8000 == 0 {
+        return Vec::new();
+    }
+    let (mut instances, mut srvs, mut addrs) = (Vec::new(), Vec::new(), Vec::new());
+    for _ in 0..counts[1] as usize + counts[2] as usize + counts[3] as usize {
+        let Some((name, end)) = read_name(packet, pos) else {
+            break;
+        };
+        let (Some(rtype), Some(len)) = (read_u16(packet, end), read_u16(packet, end + 8)) else {
+            break;
+        };
+        let data = end + 10;
+        let Some(rdata) = packet.get(data..data + len as usize) else {
+            break;
+        };
+        match rtype {
+            TYPE_PTR if name.eq_ignore_ascii_case(SERVICE) => {
+                instances.extend(read_name(packet, data).map(|(n, _)| n))
+            }
+            TYPE_SRV if rdata.len() > 6 => {
+                let port = u16::from_be_bytes([rdata[4], rdata[5]]);
+                srvs.extend(read_name(packet, data + 6).map(|(target, _)| (name, port, target)));
+            }
+            TYPE_A if rdata.len() == 4 => {
+                addrs.push((name, Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])))
+            }
+            _ => {}
+        }
+        pos = data + len as usize;
+    }
+    let suffix = format!(".{SERVICE}");
+    instances
+        .iter()
+        .filter_map(|instance| {
+            // None where a multibyte character straddles the split
+            let (name, rest) =
+                instance.split_at_checked(instance.len().checked_sub(suffix.len())?)?;
+            if !rest.eq_ignore_ascii_case(&suffix) {
+                return None;
+            }
+            let (_, port, target) = srvs
+                .iter()
+                .find(|(n, ..)| n.eq_ignore_ascii_case(instance))?;
+            let addrs = addrs
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case(target))
+                .map(|&(_, a)| a)
+                .collect();
+            Some(Found {
+                name: name.to_string(),
+                port: *port,
+                addrs,
+            })
+        })
+        .collect()
+}
+
+/// A socket on the mDNS port, joined to its group, that shares the port
+/// with any other responder on the host
+pub fn responder_socket() -> io::Result<UdpSocket> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let on: libc::c_int = 1;
+    let len = std::mem::size_of_val(&on) as libc::socklen_t;
+    if unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            (&on as *const libc::c_int).cast(),
+            len,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: MDNS.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(Ipv4Addr::UNSPECIFIED).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    let addr_len = std::mem::size_of_val(&addr) as libc::socklen_t;
+    if unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            (&addr as *const libc::sockaddr_in).cast(),
+            addr_len,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    socket.join_multicast_v4(MDNS.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    Ok(socket)
+}
+
+/// Where an answer to a query from `from` goes: straight back to a
+/// one-shot querier, which doesn't listen on the mDNS port, to the group
+/// otherwise
+pub fn reply_to(from: SocketAddr) -> SocketAddr {
+    if from.port() == MDNS.port() {
+        SocketAddr::V4(MDNS)
+    } else {
+        from
+    }
+}
+
+/// IPv4 addresses of the host's interfaces that are up, but loopback
+pub fn local_addrs() -> Vec<Ipv4Addr> {
+    let mut addrs = Vec::new();
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return addrs;
+    }
+    let mut entry = list;
+    while let Some(ifa) = unsafe { entry.as_ref() } {
+        let up = ifa.ifa_flags & libc::IFF_UP as u32 != 0
+            && ifa.ifa_flags & libc::IFF_LOOPBACK as u32 == 0;
+        if up
+            && let Some(sa) = unsafe { ifa.ifa_addr.as_ref() }
+            && sa.sa_family == libc::AF_INET as libc::sa_family_t
+        {
+            let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+            addrs.push(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)));
+        }
+        entry = ifa.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(list) };
+    addrs
+}
+
+fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(512);
+    for field in [0, flags, questions, answers, 0, 0] {
+        packet.extend_from_slice(&field.to_be_bytes());
+    }
+    packet
+}
+
+/// Flags, the four section counts and where the questions start
+fn parse_header(packet: &[u8]) -> Option<(u16, [u16; 4], usize)> {
+    let field = |i: usize| read_u16(packet, i * 2);
+    Some((field(1)?, [field(2)?, field(3)?, field(4)?, field(5)?], 12))
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        packet.get(pos..pos + 2)?.try_into().unwrap(),
+    ))
+}
+
+fn name_bytes(name: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len() + 2);
+    push_name(&mut out, name);
+    out
+}
+
+fn push_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn push_record(out: &mut Vec<u8>, name: &str, rtype: u16, class: u16, rdata: &[u8]) {
+    push_name(out, name);
+    out.extend_from_slice(&rtype.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&TTL.to_be_bytes());
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+}
+
+/// The dotted name at `pos` and where the record goes on after it
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    for _ in 0..MAX_JUMPS {
+        loop {
+            let len = *packet.get(pos)? as usize;
+            match len {
+                0 => return Some((labels.join("."), end.unwrap_or(pos + 1))),
+                // A pointer to the rest of the name elsewhere
+                0xc0.. => {
+                    let target = (len & 0x3f) << 8 | *packet.get(pos + 1)? as usize;
+                    end.get_or_insert(pos + 2);
+                    pos = target;
+                    break;
+                }
+                0x40.. => return None,
+                _ => {
+                    labels.push(
+                        String::from_utf8_lossy(packet.get(pos + 1..pos + 1 + len)?).into_owned(),
+                    );
+                    pos += 1 + len;
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An answer with a PTR to `instance` and its SRV record on port 5001
+    fn answer(instance: &str) -> Vec<u8> {
+        let mut packet = header(0x8400, 0, 2);
+        push_record(
+            &mut packet,
+            SERVICE,
+            TYPE_PTR,
+            CLASS_IN,
+            &name_bytes(instance),
+        );
+        let mut srv = vec![0, 0, 0, 0, 0x13, 0x89];
+        srv.extend_from_slice(&name_bytes("host.local"));
+        push_record(&mut packet, instance, TYPE_SRV, CLASS_IN, &srv);
+        packet
+    }
+
+    #[test]
+    fn advertisements_round_trip() {
+        let addrs = [Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(192, 168, 1, 9)];
+        let ad = Advertisement {
+            name: "backup 1",
+            host: "nas",
+            port: 5001,
+            addrs: &addrs,
+        };
+        assert_eq!(
+            found(&ad.encode()),
+            vec![Found {
+                name: "backup 1".to_string(),
+                port: 5001,
+                addrs: addrs.to_vec()
+            }]
+        );
+        assert!(asks_for_service(&query()));
+        assert!(!asks_for_service(&ad.encode()), "answers are not queries");
+        assert!(found(&query()).is_empty(), "queries are not answers");
+    }
+
+    #[test]
+    fn instance_names_must_end_in_the_service() {
+        assert_eq!(
+            found(&answer(&format!("r\u{e9}cepteur.{SERVICE}")))[0].name,
+            "r\u{e9}cepteur"
+        );
+        assert_eq!(
+            found(&answer(&format!("x.{}", SERVICE.to_uppercase())))[0].name,
+            "x"
+        );
+        // A multibyte character right where the suffix would start
+        for instance in [
+            format!("a\u{e9}{}", &SERVICE[1..]),
+            format!("\u{1F600}{}", &SERVICE[2..]),
+            format!("\u{e9}{}", &SERVICE[1..]),
+            "_tcp.local".to_string(),
+            "x._other._tcp.local".to_string(),
+        ] {
+            assert!(found(&answer(&instance)).is_empty(), "{instance:?}");
+        }
+    }
+
+    #[test]
+    fn malformed_packets_are_ignored() {
+        let ad = Advertisement {
+            name: "r",
+            host: "h",
+            port: 1,
+            addrs: &[Ipv4Addr::LOCALHOST],
+        }
+        .encode();
+        for end in 0..ad.len() {
+            let _ = found(&ad[..end]);
+            let _ = asks_for_service(&ad[..end]);
+        }
+        // A compression pointer to itself ends instead of looping
+        let mut looped = header(0x8400, 0, 1);
+        looped.extend_from_slice(&[0xc0, 12]);
+        assert!(found(&looped).is_empty());
+        assert_eq!(read_name(&looped, 12), None);
+        // Labels of 64 bytes and up are not labels
+        assert_eq!(read_name(&[0x40, b'a'], 0), None);
+        // Answer counts far beyond what the packet holds
+        let mut counts = header(0x8400, 0, u16::MAX);
+        counts[8..12].copy_from_slice(&[0xff; 4]);
+        assert!(found(&counts).is_empty());
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..5000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let len = (state % 600) as usize;
+            let mut packet: Vec<u8> = (0..len)
+                .map(|i| (state >> (i % 56)) as u8 ^ i as u8)
+                .collect();
+            if packet.len() > 2 {
+                packet[2] = 0x84;
+            }
+            let _ = found(&packet);
+            let _ = asks_for_service(&packet);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Echo markers for changes a receiver applies that can't be tagged with
+//! [`xattr::RECEIVED`](crate::xattr::RECEIVED): deletes, renames and
+//! symlinks.
+//!
+//! A receiver running with `--mark-received` creates an empty marker named
+//! after the change under `.fast-sync-tmp/echo/` in its root just before
+//! applying it. A watcher running with `--ignore-received` on the same
+//! directory removes the marker instead of sending the change back. Markers
+//! older than [`TTL`] no longer count, so one left behind by a change that
+//! caused no event can't swallow a later local one.
+
+use std::{
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Inside the receiver's reserved temporary directory
+const DIR: &str = ".fast-sync-tmp/echo";
+
+/// How long a marker waits for the watcher
+pub const TTL: Duration = Duration::from_secs(60);
+
+/// A change, with names relative to the receiver's root
+pub enum Op<'a> {
+    Delete(&'a Path),
+    Rename(&'a Path, &'a Path),
+    Symlink(&'a Path),
+}
+
+impl Op<'_> {
+    fn marker(&self, root: &Path) -> PathBuf {
+        let mut hasher = blake3::Hasher::new();
+        let names: Vec<&Path> = match *self {
+            Op::Delete(name) => {
+                hasher.update(b"D");
+                vec![name]
+            }
+            Op::Rename(from, to) => {
+                hasher.update(b"R");
+                vec![from, to]
+            }
+            Op::Symlink(name) => {
+                hasher.update(b"L");
+                vec![name]
+            }
+        };
+        for name in names {
+            hasher.update(&[0]);
+            hasher.update(name.as_os_str().as_bytes());
+        }
+        root.join(DIR).join(hasher.finalize().to_hex().as_str())
+    }
+}
+
+/// Leave a marker for `op` in `root`; call before applying it
+pub fn record(root: &Path, op: &Op) -> io::Result<()> {
+    let marker = op.marker(root);
+    std::fs::create_dir_all(marker.parent().unwrap())?;
+    std::fs::File::create(marker).map(drop)
+}
+
+/// Remove the marker for `op` in `root`, if any; `true` if it was fresh
+pub fn consume(root: &Path, op: &Op) -> bool {
+    let marker = op.marker(root);
+    let fresh = std::fs::metadata(&marker)
+        .and_then(|m| m.modified())
+        .is_ok_and(|t| t.elapsed().is_ok_and(|age| age < TTL));
+    std::fs::remove_file(&marker).is_ok() && fresh
+}
+
+/// The nearest receiver root above `path`
+pub fn root_of(path: &Path) -> Option<&Path> {
+    path.ancestors().skip(1).find(|dir| dir.join(DIR).is_dir())
+}
@@ -0,0 +1,287 @@
+//! gitignore-style glob matching for include/exclude rules.
+//!
+//! - A pattern without a `/` matches the file or directory name at any depth
+//!   (`*.swp`, `.#*`).
+//! - A pattern containing a `/` is anchored to the root (`build/out`,
+//!   `/logs/*.gz`).
+//! - A trailing `/` only matches directories (`target/`).
+//! - `*` and `?` match within one path component, `**` matches any number of
+//!   components and `[a-z]` / `[!0-9]` match character classes.
+
+use anyhow::Result;
+use std::path::Path;
+
+/// One compiled glob pattern
+#[derive(Debug, Clone)]
+pub struct Glob {
+    /// Pattern split into path components
+    parts: Vec<String>,
+    /// No `/` in the pattern: match the last component at any depth
+    basename: bool,
+    /// Trailing `/`: only directories match
+    dir_only: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let dir_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+        let basename = !trimmed.contains('/');
+        let trimmed = trimmed.trim_start_matches('/');
+        anyhow::ensure!(!trimmed.is_empty(), "Empty glob pattern {pattern:?}");
+        let parts: Vec<String> = trimmed.split('/').map(str::to_string).collect();
+        for part in &parts {
+            validate_component(part)
+                .map_err(|e| anyhow::anyhow!("Invalid glob {pattern:?}: {e}"))?;
+        }
+        Ok(Self {
+            parts,
+            basename,
+            dir_only,
+        })
+    }
+
+    /// Does the root-relative path `rel` match?
+    pub fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let names: Vec<String> = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        if self.basename {
+            names
+                .last()
+                .is_some_and(|name| match_component(self.parts[0].as_bytes(), name.as_bytes()))
+        } else {
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            let parts: Vec<&str> = self.parts.iter().map(String::as_str).collect();
+            match_parts(&parts, &names)
+        }
+    }
+}
+
+/// Include/exclude rule set applied to root-relative paths
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: include
+                .iter()
+                .map(|p| Glob::new(p))
+                .collect::<Result<_>>()?,
+            exclude: exclude
+                .iter()
+                .map(|p| Glob::new(p))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Should the directory `rel` be skipped entirely?
+    pub fn excludes_dir(&self, rel: &Path) -> bool {
+        self.exclude.iter().any(|g| g.matches(rel, true))
+    }
+
+    /// Should the file `rel` be sent? It must not be excluded (itself or via a
+    /// parent directory) and, if any include patterns are set, match one of them.
+    pub fn allows_file(&self, rel: &Path) -> bool {
+        if self.exclude.iter().any(|g| g.matches(rel, false)) {
+            return false;
+        }
+        if rel
+            .ancestors()
+            .skip(1)
+            .any(|dir| !dir.as_os_str().is_empty() && self.excludes_dir(dir))
+        {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|g| g.matches(rel, false))
+    }
+}
+
+/// Match pattern components against path components, `**` spanning any number
+fn match_parts(parts: &[&str], names: &[&str]) -> bool {
+    match parts.split_first() {
+        None => names.is_empty(),
+        Some((&"**", rest)) => (0..=names.len()).any(|skip| match_parts(rest, &names[skip..])),
+        Some((part, rest)) => match names.split_first() {
+            Some((name, names)) => {
+                match_component(part.as_bytes(), name.as_bytes()) && match_parts(rest, names)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Wildcard match of a single path component
+fn match_component(pat: &[u8], name: &[u8]) -> bool {
+    match pat.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_component(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_component(rest, &name[1..]),
+        Some((b'[', _)) => {
+            let Some((&c, name_rest)) = name.split_first() else {
+                return false;
+            };
+            let (matched, pat_rest) = match_class(&pat[1..], c);
+            matched && match_component(pat_rest, name_rest)
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            name.first() == Some(&rest[0]) && match_component(&rest[1..], &name[1..])
+        }
+        Some((&p, rest)) => name.first() == Some(&p) && match_component(rest, &name[1..]),
+    }
+}
+
+/// Match `c` against the class starting after `[`, returning the result and
+/// the pattern after the closing `]`
+fn match_class(pat: &[u8], c: u8) -> (bool, &[u8]) {
+    let (negate, mut pat) = match pat.first() {
+        Some(b'!' | b'^') => (true, &pat[1..]),
+        _ => (false, pat),
+    };
+    let mut matched = false;
+    let mut first = true;
+    while let Some((&p, rest)) = pat.split_first() {
+        if p == b']' && !first {
+            return (matched != negate, rest);
+        }
+        first = false;
+        if rest.len() >= 2 && rest[0] == b'-' && rest[1] != b']' {
+            matched |= (p..=rest[1]).contains(&c);
+            pat = &rest[2..];
+        } else {
+            matched |= p == c;
+            pat = rest;
+        }
+    }
+    (false, pat)
+}
+
+fn validate_component(part: &str) -> Result<()> {
+    let bytes = part.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'[' => {
+                // The first character after `[` / `[!` may be a literal `]`
+                let mut j = i + 1;
+                if matches!(bytes.get(j), Some(b'!' | b'^')) {
+                    j += 1;
+                }
+                j += 1;
+                while j < bytes.len() && bytes[j] != b']' {
+                    j += 1;
+                }
+                anyhow::ensure!(j < bytes.len(), "unclosed '['");
+                i = j + 1;
+            }
+            _ => i += 1,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match() {
+        let cases = [
+            // Unanchored: the last component at any depth
+            ("*.swp", "a.swp", false, true),
+            ("*.swp", "dir/sub/a.swp", false, true),
+            ("*.swp", "a.swpx", false, false),
+            ("*", "dir/file", false, true),
+            // Anchored: any `/` ties the pattern to the root
+            ("build/out", "build/out", false, true),
+            ("build/out", "x/build/out", false, false),
+            ("/logs/*.gz", "logs/a.gz", false, true),
+            ("/logs/*.gz", "x/logs/a.gz", false, false),
+            ("/logs/*.gz", "logs/sub/a.gz", false, false),
+            ("/top", "top", false, true),
+            ("/top", "dir/top", false, false),
+            // Trailing `/`: directories only
+            ("target/", "target", true, true),
+            ("target/", "target", false, false),
+            ("target/", "crate/target", true, true),
+            ("/target/", "crate/target", true, false),
+            // `**` spans any number of components, none included
+            ("**/cache", "cache", true, true),
+            ("**/cache", "a/b/cache", true, true),
+            ("a/**/z", "a/z", false, true),
+            ("a/**/z", "a/b/c/z", false, true),
+            ("a/**/z", "b/a/z", false, false),
+            ("a/**/*.o", "a/b/c.o", false, true),
+            ("a/*/z", "a/b/c/z", false, false),
+            // `?`, classes and their negation
+            ("?.txt", "a.txt", false, true),
+            ("?.txt", "ab.txt", false, false),
+            ("[a-c]*", "beta", false, true),
+            ("[a-c]*", "delta", false, false),
+            ("[!0-9]*", "x1", false, true),
+            ("[!0-9]*", "1x", false, false),
+            ("[^0-9]*", "1x", false, false),
+            ("[]]x", "]x", false, true),
+            ("[!]]x", "]x", false, false),
+            ("[!]]x", "ax", false, true),
+            ("[a-]", "-", false, true),
+            // Escapes
+            ("\\*", "*", false, true),
+            ("\\*", "a", false, false),
+            ("\\[x]", "[x]", false, true),
+        ];
+        for (pattern, path, is_dir, expected) in cases {
+            let glob = Glob::new(pattern).unwrap();
+            assert_eq!(
+                glob.matches(Path::new(path), is_dir),
+                expected,
+                "{pattern:?} against {path:?} (dir: {is_dir})"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_globs_are_refused() {
+        for pattern in ["", "/", "//", "a/[b", "[!"] {
+            assert!(Glob::new(pattern).is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn filters_combine_includes_and_excludes() {
+        let patterns = |list: &[&str]| list.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let filter = Filter::new(
+            &patterns(&["*.rs", "/Cargo.toml"]),
+            &patterns(&["target/", "*.tmp.rs"]),
+        )
+        .unwrap();
+        let cases = [
+            ("src/main.rs", true),
+            ("Cargo.toml", true),
+            ("sub/Cargo.toml", false),
+            ("README.md", false),
+            // Excludes win over includes, also through a parent directory
+            ("a.tmp.rs", false),
+            ("target/debug/build.rs", false),
+            ("crate/target/x.rs", false),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(filter.allows_file(Path::new(path)), expected, "{path:?}");
+        }
+        assert!(filter.excludes_dir(Path::new("target")));
+        assert!(!filter.excludes_dir(Path::new("src")));
+
+        let open = Filter::new(&[], &patterns(&["*.swp"])).unwrap();
+        assert!(open.allows_file(Path::new("any/file")));
+        assert!(!open.allows_file(Path::new("any/.file.swp")));
+    }
+}
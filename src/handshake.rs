@@ -0,0 +1,362 @@
+//! Protocol version and feature negotiation, the first exchange on every
+//! connection.
+//!
+//! The sender opens with a hello and the receiver answers with one in the
+//! same layout:
+//!
+//! ```text
+//! [u8; 4] "FSYN" | u16 version | u32 feature bits | u32 chunk size
+//! ```
+//!
+//! The receiver answers with the lower of the two versions, the features both
+//! sides support and a chunk size no larger than the proposal or
+//! [`MAX_CHUNK_SIZE`]. If it cannot speak any version the sender offers it
+//! answers with version 0 and closes the connection. Senders skip features
+//! missing from the answer, e.g. falling back to whole-file sends without
+//! [`Features::DELTA`].
+//!
+//! With [`Features::SENDER_ID`] agreed, the sender names itself once
+//! authenticated (`u8 len | id`), so a receiver can keep each sender's files
+//! apart.
+
+use anyhow::Result;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAGIC: [u8; 4] = *b"FSYN";
+
+/// Newest protocol version this build speaks
+pub const VERSION: u16 = 1;
+
+/// Oldest protocol version this build still speaks
+pub const MIN_VERSION: u16 = 1;
+
+/// Largest chunk a receiver accepts, bounding its buffers
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Optional protocol features, as a bit set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// [`MessageKind::Delta`](crate::MessageKind::Delta) transfers
+    pub const DELTA: Self = Self(1 << 0);
+    /// [`MessageKind::Resume`](crate::MessageKind::Resume) transfers
+    pub const RESUME: Self = Self(1 << 1);
+    /// Headers carry the [`Confirm`](crate::Confirm) level the sender wants
+    pub const CONFIRM: Self = Self(1 << 2);
+    /// [`MessageKind::Manifest`](crate::MessageKind::Manifest) comparisons
+    pub const SCRUB: Self = Self(1 << 3);
+    /// [`MessageKind::List`](crate::MessageKind::List) requests
+    pub const LIST: Self = Self(1 << 4);
+    /// [`MessageKind::Symlink`](crate::MessageKind::Symlink) messages
+    pub const LINKS: Self = Self(1 << 5);
+    /// Headers carry extended attributes and ACLs
+    pub const XATTRS: Self = Self(1 << 6);
+    /// [`MessageKind::HardLink`](crate::MessageKind::HardLink) messages
+    pub const HARD_LINKS: Self = Self(1 << 7);
+    /// The sender announces an id after authenticating, see [`send_id`]
+    pub const SENDER_ID: Self = Self(1 << 8);
+    /// The receiver may answer [`Ack::Kept`](crate::Ack::Kept) and
+    /// [`Ack::MovedAside`](crate::Ack::MovedAside)
+    pub const CONFLICTS: Self = Self(1 << 9);
+    /// [`MessageKind::Transaction`](crate::MessageKind::Transaction) groups
+    pub const TRANSACTIONS: Self = Self(1 << 10);
+    /// Headers carry a version, and the receiver may answer
+    /// [`Ack::Superseded`](crate::Ack::Superseded)
+    pub const VERSIONS: Self = Self(1 << 11);
+    /// The receiver may answer [`Ack::OverQuota`](crate::Ack::OverQuota)
+    pub const QUOTAS: Self = Self(1 << 12);
+    /// Names may be any bytes rather than only UTF-8
+    pub const RAW_NAMES: Self = Self(1 << 13);
+    /// [`MessageKind::Heartbeat`](crate::MessageKind::Heartbeat) messages
+    pub const HEARTBEAT: Self = Self(1 << 14);
+    /// [`MessageKind::Batch`](crate::MessageKind::Batch) messages
+    pub const BATCH: Self = Self(1 << 15);
+    /// [`MessageKind::Archive`](crate::MessageKind::Archive) streams
+    pub const ARCHIVE: Self = Self(1 << 16);
+    /// [`MessageKind::Dedup`](crate::MessageKind::Dedup) messages
+    pub const DEDUP: Self = Self(1 << 17);
+    /// The receiver may answer [`Ack::Forbidden`](crate::Ack::Forbidden)
+    pub const POLICY: Self = Self(1 << 18);
+    /// [`MessageKind::Append`](crate::MessageKind::Append) messages
+    pub const APPEND: Self = Self(1 << 19);
+    /// Headers carry a transfer id that stays the same when a file is resent
+    pub const TRANSFER_IDS: Self = Self(1 << 20);
+    /// Failure ACKs carry a reason, and damaged files have an ACK of their own
+    pub const ACK_REASONS: Self = Self(1 << 21);
+    /// Payload chunks and whole-file messages carry no content checksums;
+    /// only agreed when both ends run with `--integrity tcp-only`
+    pub const TCP_ONLY: Self = Self(1 << 22);
+    /// [`MessageKind::Range`](crate::MessageKind::Range) messages, for
+    /// sending a large file over several connections at once
+    pub const RANGES: Self = Self(1 << 23);
+    /// [`MessageKind::Multicast`](crate::MessageKind::Multicast) messages;
+    /// only agreed when the receiver listens with `--multicast`
+    pub const MULTICAST: Self = Self(1 << 24);
+    /// The receiver proves it holds the PSK too, see [`auth`](crate::auth)
+    pub const MUTUAL_AUTH: Self = Self(1 << 25);
+    /// Everything this build implements
+    pub const ALL: Self = Self(
+        Self::DELTA.0
+            | Self::RESUME.0
+            | Self::CONFIRM.0
+            | Self::SCRUB.0
+            | Self::LIST.0
+            | Self::LINKS.0
+            | Self::XATTRS.0
+            | Self::HARD_LINKS.0
+            | Self::SENDER_ID.0
+            | Self::CONFLICTS.0
+            | Self::TRANSACTIONS.0
+            | Self::VERSIONS.0
+            | Self::QUOTAS.0
+            | Self::RAW_NAMES.0
+            | Self::HEARTBEAT.0
+            | Self::BATCH.0
+            | Self::ARCHIVE.0
+            | Self::DEDUP.0
+            | Self::POLICY.0
+            | Self::APPEND.0
+            | Self::TRANSFER_IDS.0
+            | Self::ACK_REASONS.0
+            | Self::TCP_ONLY.0
+            | Self::RANGES.0
+            | Self::MULTICAST.0
+            | Self::MUTUAL_AUTH.0,
+    );
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersect(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = [
+            (Self::DELTA, "delta"),
+            (Self::RESUME, "resume"),
+            (Self::CONFIRM, "confirm"),
+            (Self::SCRUB, "scrub"),
+            (Self::LIST, "list"),
+            (Self::LINKS, "links"),
+            (Self::XATTRS, "xattrs"),
+            (Self::HARD_LINKS, "hard-links"),
+            (Self::SENDER_ID, "sender-id"),
+            (Self::CONFLICTS, "conflicts"),
+            (Self::TRANSACTIONS, "transactions"),
+            (Self::VERSIONS, "versions"),
+            (Self::QUOTAS, "quotas"),
+            (Self::RAW_NAMES, "raw-names"),
+            (Self::HEARTBEAT, "heartbeat"),
+            (Self::BATCH, "batch"),
+            (Self::ARCHIVE, "archive"),
+            (Self::DEDUP, "dedup"),
+            (Self::POLICY, "policy"),
+            (Self::APPEND, "append"),
+            (Self::TRANSFER_IDS, "transfer-ids"),
+            (Self::ACK_REASONS, "ack-reasons"),
+            (Self::TCP_ONLY, "tcp-only"),
+            (Self::RANGES, "ranges"),
+            (Self::MULTICAST, "multicast"),
+            (Self::MUTUAL_AUTH, "mutual-auth"),
+        ]
+        .into_iter()
+        .filter(|&(feature, _)| self.contains(feature))
+        .map(|(_, name)| name)
+        .collect();
+        if names.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&names.join(","))
+        }
+    }
+}
+
+/// What both sides agreed on
+#[derive(Debug, Clone, Copy)]
+pub struct Params {
+    pub version: u16,
+    pub features: Features,
+    pub chunk_size: usize,
+}
+
+struct Hello {
+    version: u16,
+    features: u32,
+    chunk_size: u32,
+}
+
+async fn write_hello<W: AsyncWrite + Unpin>(writer: &mut W, hello: &Hello) -> Result<()> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&hello.version.to_be_bytes());
+    buf.extend_from_slice(&hello.features.to_be_bytes());
+    buf.extend_from_slice(&hello.chunk_size.to_be_bytes());
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+async fn read_hello<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Hello> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    anyhow::ensure!(
+        magic == MAGIC,
+        "Peer is not speaking the fast-sync protocol"
+    );
+    Ok(Hello {
+        version: reader.read_u16().await?,
+        features: reader.read_u32().await?,
+        chunk_size: reader.read_u32().await?,
+    })
+}
+
+/// Receiver side: answer the sender's hello, refusing versions we don't speak
+pub async fn accept<R, W>(reader: &mut R, writer: &mut W) -> Result<Params>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    accept_only(reader, writer, Features::ALL).await
+}
+
+/// [`accept`] for a receiver that only handles the `supported` features
+pub async fn accept_only<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    supported: Features,
+) -> Result<Params>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let theirs = read_hello(reader).await?;
+    let version = theirs.version.min(VERSION);
+    if version < MIN_VERSION {
+        let _ = write_hello(
+            writer,
+            &Hello {
+                version: 0,
+                features: 0,
+                chunk_size: 0,
+            },
+        )
+        .await;
+        anyhow::bail!(
+            "Peer speaks protocol v{}, we need v{MIN_VERSION} to v{VERSION}",
+            theirs.version
+        );
+    }
+    anyhow::ensure!(theirs.chunk_size > 0, "Peer proposed a zero chunk size");
+    let features = Features(theirs.features).intersect(supported.intersect(Features::ALL));
+    let chunk_size = theirs.chunk_size.min(MAX_CHUNK_SIZE);
+    write_hello(
+        writer,
+        &Hello {
+            version,
+            features: features.0,
+            chunk_size,
+        },
+    )
+    .await?;
+    Ok(Params {
+        version,
+        features,
+        chunk_size: chunk_size as usize,
+    })
+}
+
+/// Sender side: offer our newest version, `features` and `chunk_size`, and
+/// return what the receiver agreed to
+pub async fn connect<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    features: Features,
+    chunk_size: u32,
+) -> Result<Params>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let offered = features.intersect(Features::ALL);
+    write_hello(
+        writer,
+        &Hello {
+            version: VERSION,
+            features: offered.0,
+            chunk_size,
+        },
+    )
+    .await?;
+    let theirs = read_hello(reader).await?;
+    anyhow::ensure!(
+        theirs.version != 0,
+        "Destination does not speak protocol v{MIN_VERSION} to v{VERSION}"
+    );
+    anyhow::ensure!(
+        (MIN_VERSION..=VERSION).contains(&theirs.version),
+        "Destination chose protocol v{} we never offered",
+        theirs.version
+    );
+    anyhow::ensure!(
+        theirs.chunk_size > 0 && theirs.chunk_size <= chunk_size,
+        "Destination chose chunk size {} for a proposal of {chunk_size}",
+        theirs.chunk_size
+    );
+    Ok(Params {
+        version: theirs.version,
+        features: Features(theirs.features).intersect(offered),
+        chunk_size: theirs.chunk_size as usize,
+    })
+}
+
+/// Can `id` name a sender? It must work as a single directory name.
+pub fn valid_sender_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= u8::MAX as usize
+        && id != "."
+        && id != ".."
+        && !id.contains(['/', '\0'])
+}
+
+/// Sender side, after authenticating: announce `id`
+pub async fn send_id<W: AsyncWrite + Unpin>(writer: &mut W, id: &str) -> Result<()> {
+    anyhow::ensure!(valid_sender_id(id), "Invalid sender id {id:?}");
+    let mut buf = vec![id.len() as u8];
+    buf.extend_from_slice(id.as_bytes());
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Receiver side: the id announced with [`send_id`]
+pub async fn read_id<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut buf = vec![0u8; reader.read_u8().await? as usize];
+    reader.read_exact(&mut buf).await?;
+    let id = String::from_utf8(buf).map_err(|_| anyhow::anyhow!("Sender id is not UTF-8"))?;
+    anyhow::ensure!(valid_sender_id(&id), "Invalid sender id {id:?}");
+    Ok(id)
+}
+
+/// This host's name, the default sender id
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&buf[..len])
+        .ok()
+        .map(str::to_string)
+        .filter(|id| valid_sender_id(id))
+}
@@ -0,0 +1,288 @@
+//! Just enough HTTP/1.1 for the receiver's `--http` ingest endpoint, one
+//! request per connection with a `Content-Length` body, for opening
+//! [`websocket`](crate::websocket) connections and for the watcher's
+//! `--proxy` tunnels.
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest request or status line and headers taken
+pub const MAX_HEAD: usize = 16 * 1024;
+
+/// A request's head
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path and query as sent, still percent-encoded
+    pub target: String,
+    headers: Headers,
+}
+
+/// A response's head
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    /// The text after the status code
+    pub reason: String,
+    headers: Headers,
+}
+
+#[derive(Debug)]
+struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Response {
+    /// The value of the first header called `name`, in any case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+}
+
+impl Request {
+    /// The value of the first header called `name`, in any case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Does the value of header `name` list `token`, as `Connection` and
+    /// `Upgrade` do?
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    }
+
+    /// The announced body length; `None` without one, an error for chunked
+    /// bodies, which aren't supported
+    pub fn content_length(&self) -> Result<Option<u64>> {
+        anyhow::ensure!(
+            self.header("Transfer-Encoding").is_none(),
+            "Chunked request bodies are not supported"
+        );
+        self.header("Content-Length")
+            .map(|len| {
+                len.parse()
+                    .with_context(|| format!("Invalid Content-Length {len:?}"))
+            })
+            .transpose()
+    }
+}
+
+/// Read a request's head from `conn`. Returns it with whatever of the body
+/// arrived along with it.
+pub async fn read_request<R: AsyncRead + Unpin>(conn: &mut R) -> Result<(Request, Vec<u8>)> {
+    let (line, headers, rest) = read_head(conn).await?;
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!("Malformed request line");
+    };
+    anyhow::ensure!(
+        version.starts_with("HTTP/1."),
+        "Unsupported protocol {version:?}"
+    );
+    Ok((
+        Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            headers,
+        },
+        rest,
+    ))
+}
+
+/// Read a response's head from `conn`, with whatever followed it
+pub async fn read_response<R: AsyncRead + Unpin>(conn: &mut R) -> Result<(Response, Vec<u8>)> {
+    let (line, headers, rest) = read_head(conn).await?;
+    let mut parts = line.splitn(3, ' ');
+    let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("Malformed status line");
+    };
+    anyhow::ensure!(
+        version.starts_with("HTTP/1."),
+        "Unsupported protocol {version:?}"
+    );
+    let status = status
+        .parse()
+        .with_context(|| format!("Malformed status line {line:?}"))?;
+    let reason = parts.next().unwrap_or_default().to_string();
+    Ok((
+        Response {
+            status,
+            reason,
+            headers,
+        },
+        rest,
+    ))
+}
+
+/// The first line and headers of a request or response, and what came after
+async fn read_head<R: AsyncRead + Unpin>(conn: &mut R) -> Result<(String, Headers, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        anyhow::ensure!(buf.len() < MAX_HEAD, "HTTP head over {MAX_HEAD} bytes");
+        let mut chunk = [0u8; 4096];
+        let n = conn.read(&mut chunk).await?;
+        anyhow::ensure!(n > 0, "Connection closed in the HTTP head");
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let head = std::str::from_utf8(&buf[..end]).context("HTTP head is not UTF-8")?;
+    let mut lines = head.split("\r\n");
+    let first = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .map(|line| {
+            let (name, value) = line
+                .split_once(':')
+                .with_context(|| format!("Malformed header {line:?}"))?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect::<Result<_>>()?;
+    Ok((first, Headers(headers), buf.split_off(end + 4)))
+}
+
+/// Send an interim `100 Continue`, for clients that wait for it before the
+/// body
+pub async fn send_continue<W: AsyncWrite + Unpin>(conn: &mut W) -> std::io::Result<()> {
+    conn.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await
+}
+
+/// Send the response and end the exchange; the connection closes after it
+pub async fn respond<W: AsyncWrite + Unpin>(
+    conn: &mut W,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> std::io::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    conn.write_all(response.as_bytes()).await?;
+    conn.shutdown().await
+}
+
+/// Undo percent-encoding; `None` if an escape is malformed
+pub fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        // Not from_str_radix, which takes a sign too
+        let digit = |b: u8| (b as char).to_digit(16);
+        out.push((digit(bytes.next()?)? * 16 + digit(bytes.next()?)?) as u8);
+    }
+    Some(out)
+}
+
+/// Ask the HTTP proxy on `conn` for a tunnel to `authority` (HOST:PORT)
+/// with CONNECT, with `USER:PASS` Basic credentials if given
+pub async fn connect_tunnel<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    authority: &str,
+    credentials: Option<&str>,
+) -> Result<()> {
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(credentials) = credentials {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(credentials.as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    conn.write_all(request.as_bytes()).await?;
+    let (response, early) = read_response(conn)
+        .await
+        .context("Read the proxy's answer")?;
+    anyhow::ensure!(
+        (200..300).contains(&response.status),
+        "Proxy refused the tunnel to {authority}: {} {}",
+        response.status,
+        response.reason
+    );
+    // The destination doesn't speak first
+    anyhow::ensure!(
+        early.is_empty(),
+        "Proxy sent data before the tunnel to {authority} opened"
+    );
+    Ok(())
+}
+
+/// Standard base64, padded
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_matches_rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, encoded) in vectors {
+            assert_eq!(base64(input.as_bytes()), encoded, "base64 of {input:?}");
+        }
+        assert_eq!(base64(&[0xfb, 0xff, 0xbf]), "+/+/");
+    }
+
+    #[test]
+    fn percent_decode_undoes_escapes() {
+        let vectors: [(&str, Option<&[u8]>); 8] = [
+            ("plain/name.txt", Some(b"plain/name.txt")),
+            ("a%20b", Some(b"a b")),
+            ("%2Fetc%2f", Some(b"/etc/")),
+            ("%ff%00", Some(b"\xff\x00")),
+            ("%", None),
+            ("%2", None),
+            ("%zz", None),
+            ("%+1", None),
+        ];
+        for (input, decoded) in vectors {
+            assert_eq!(
+                percent_decode(input).as_deref(),
+                decoded,
+                "decoding {input:?}"
+            );
+        }
+    }
+}
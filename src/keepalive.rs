@@ -0,0 +1,35 @@
+//! TCP keepalive, so the kernel notices a peer that went away without
+//! closing the connection.
+
+use std::{io, os::fd::AsRawFd};
+
+/// Probe timing, in seconds
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// Quiet time before the first probe
+    pub idle: u32,
+    /// Time between probes
+    pub interval: u32,
+    /// Unanswered probes before the connection is dropped
+    pub count: u32,
+}
+
+impl Keepalive {
+    pub fn apply(&self, sock: &impl AsRawFd) -> io::Result<()> {
+        let fd = sock.as_raw_fd();
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, self.idle)?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, self.interval)?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, self.count)
+    }
+}
+
+fn setsockopt(fd: i32, level: i32, name: i32, value: u32) -> io::Result<()> {
+    let value = value.min(i32::MAX as u32) as libc::c_int;
+    let len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if unsafe { libc::setsockopt(fd, level, name, (&value as *const libc::c_int).cast(), len) } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
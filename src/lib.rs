@@ -0,0 +1,1512 @@
+//! Wire protocol shared by the `watcher` (sender) and `client` (receiver),
+//! which are the [`watcher`] and [`client`] modules. Programs that embed
+//! fast-sync use them through a [`sender::Sender`] or a
+//! [`receiver::Receiver`] instead of running either.
+//!
+//! Each connection opens with the [`handshake`] that agrees on a protocol
+//! version, features and payload chunk size, then with the [`auth`]
+//! handshake. After that, every message starts with a [`MessageKind`] byte
+//! and a sequence number, followed by a header:
+//!
+//! ```text
+//! u8 kind | u32 seq | u16 name_len | name | u64 size
+//!   | u32 mode | u32 uid | u32 gid | i64 mtime_sec | u32 mtime_nsec
+//!   [| u8 confirm] [| u16 count | count * (u16 len | name | u32 len | value)]
+//!   [| u64 version] [| [u8; 16] transfer id]
+//! ```
+//!
+//! The [`Confirm`] byte is only present when both sides agreed to
+//! [`Features::CONFIRM`](handshake::Features::CONFIRM), and the extended
+//! attributes (see [`xattr`]) only with
+//! [`Features::XATTRS`](handshake::Features::XATTRS). With
+//! [`Features::VERSIONS`](handshake::Features::VERSIONS) the header ends with
+//! the version of the content, which only grows for a given path; the
+//! receiver answers [`Ack::Superseded`] to a version older than the one it
+//! has. With [`Features::TRANSFER_IDS`](handshake::Features::TRANSFER_IDS) it
+//! ends with an id the sender keeps when it resends the file, e.g. after a
+//! lost ACK, so the receiver can tell a resend of a file it already applied
+//! from a new change.
+//!
+//! Names are the path's raw bytes relative to the watched / destination
+//! directory, with `/` separators whatever the sender's platform. They are
+//! only guaranteed to be UTF-8 when
+//! [`Features::RAW_NAMES`](handshake::Features::RAW_NAMES) was not agreed.
+//!
+//! [`MessageKind::Delete`], [`MessageKind::Rename`], [`MessageKind::Symlink`]
+//! and [`MessageKind::HardLink`] carry only names instead of a header, and
+//! [`MessageKind::Manifest`] a list of [`ManifestEntry`]s that the receiver
+//! answers with the indices of those it lacks or holds different content for
+//! (`u32 count | count * u32 index`). [`MessageKind::List`] carries nothing;
+//! the receiver answers with manifests of its whole tree, ending with an empty
+//! one. [`MessageKind::Heartbeat`] carries nothing either and only gets its
+//! ACK. For [`MessageKind::File`] the payload follows the header. For
+//! [`MessageKind::Delta`] the sender appends a `u32` block size, the receiver
+//! answers with the BLAKE3 hash of every block of its current copy
+//! (`u32 count | count * [u8; 32]`) and the sender then streams [`DeltaOp`]s
+//! that rebuild the new file from old blocks and literal data. [`MessageKind::Resume`]
+//! works the same way with the receiver offering the partial copy it kept from
+//! an earlier attempt. [`MessageKind::Dedup`] announces the content's checksum
+//! first, so a receiver that already has that content somewhere makes the file
+//! locally instead. [`MessageKind::Append`] ships only the bytes a growing
+//! file gained since the receiver's copy, which the receiver writes onto the
+//! end of that copy in place. [`MessageKind::Range`] carries one part of a
+//! large file, so several connections can share it. [`MessageKind::Multicast`]
+//! follows a file's content sent to a whole [`multicast`] group at once; the
+//! receiver says which byte ranges it missed and the sender fills in just
+//! those. A receiver that can't take the file answers [`REFUSED_BLOCKS`],
+//! [`REFUSED_OFFER`] or [`DedupReply::Refused`] instead, and the message ends
+//! there.
+//!
+//! Payload bytes (a file's data or a delta's literals) travel as chunks of at
+//! most the agreed size, each `u32 len | data | [u8; 32] BLAKE3(data)`, so
+//! corruption is caught as soon as the damaged chunk arrives. Every file
+//! message ends with the `[u8; 32]` BLAKE3 checksum of the whole file, so the
+//! sender can hash while it streams. With [`handshake::Features::TCP_ONLY`]
+//! chunks are just `u32 len | data` and a plain `File` message's checksum is
+//! all zeros, which the receiver doesn't check. The receiver finishes with an
+//! [`Ack`] byte and the message's sequence number (`u8 ack | u32 seq`),
+//! followed by `u16 len | reason` for a failure when the peers agreed to
+//! [`handshake::Features::ACK_REASONS`]. Messages are
+//! handled in order, so the sender may keep several in flight and match the
+//! ACKs as they come back. The `File` messages of a
+//! [`MessageKind::Transaction`] get no ACK of their own; the receiver keeps
+//! them as temporary files until the last one verified, renames them all into
+//! place in order and ACKs the transaction. Those of a [`MessageKind::Batch`]
+//! are put in place one by one, and the batch's ACK carries their statuses.
+//! A [`MessageKind::Archive`] works like a batch of any length, for seeding a
+//! new destination without a round trip per file.
+//!
+//! All integers are big-endian.
+
+// inotify, sendfile(2), xattrs and Unix ownership are used throughout
+#[cfg(not(target_os = "linux"))]
+compile_error!("fast-sync only supports Linux");
+
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsString,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub mod auth;
+pub mod client;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod discovery;
+pub mod echo;
+pub mod filter;
+pub mod handshake;
+pub mod http;
+pub mod keepalive;
+pub mod log;
+pub mod manifest;
+pub mod metrics;
+pub mod multicast;
+pub mod parallel_hash;
+pub mod receiver;
+pub mod reflink;
+pub mod sender;
+pub mod shutdown;
+pub mod storage;
+pub mod throttle;
+pub mod timeout;
+pub mod transfer_log;
+pub mod watcher;
+pub mod websocket;
+pub mod xattr;
+
+/// Parse a byte count with an optional `K`, `M` or `G` suffix (powers of
+/// 1024), e.g. `64M`
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, scale) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .parse()
+        .with_context(|| format!("Invalid size {s:?}"))?;
+    value
+        .checked_mul(scale)
+        .with_context(|| format!("Size {s:?} is too large"))
+}
+
+/// Length of the BLAKE3 checksum that ends every file message
+pub const CHECKSUM_LEN: usize = 32;
+
+/// Block count a receiver answers a delta with, or run count a multicast,
+/// when it refuses the file; the message ends there and the [`Ack`] follows
+pub const REFUSED_BLOCKS: u32 = u32::MAX;
+
+/// Smallest delta block size a receiver hashes its copy in; it refuses
+/// deltas with smaller blocks
+pub const MIN_DELTA_BLOCK_SIZE: u32 = 512;
+
+/// Length a receiver offers in reply to a resume when it refuses the file;
+/// the message ends there and the [`Ack`] follows
+pub const REFUSED_OFFER: u64 = u64::MAX;
+
+/// Bytes at the end of the receiver's copy whose hash an append offer
+/// carries, so a sender only appends to a copy that ends like its file
+pub const APPEND_CHECK: u64 = 64 * 1024;
+
+/// Length of an ACK on the wire: `u8 ack | u32 seq`
+pub const ACK_LEN: usize = 5;
+
+/// Longest reason a failure ACK carries, in bytes
+pub const MAX_REASON: usize = 1024;
+
+/// Status byte the receiver answers each transfer with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Ack {
+    /// Transfer failed (e.g. checksum mismatch)
+    Failed = 0x00,
+    /// File received and verified
+    Ok = 0x01,
+    /// Name refused: absolute, contains `..` or escapes the destination
+    RejectedName = 0x02,
+    /// Destination already had identical content; nothing was written
+    Unchanged = 0x03,
+    /// Not enough disk space for the file; sent before its data is read
+    NoSpace = 0x04,
+    /// The receiver's conflict policy kept its own copy; nothing was written
+    Kept = 0x05,
+    /// File written after the receiver moved its previous copy aside
+    MovedAside = 0x06,
+    /// The receiver already has a newer version of the file; nothing was
+    /// written
+    Superseded = 0x07,
+    /// Taking the file would put the sender over its quota on the receiver;
+    /// sent before its data is read
+    OverQuota = 0x08,
+    /// The receiver's policy doesn't let this sender write the path; sent
+    /// before its data is read
+    Forbidden = 0x09,
+    /// The received data didn't match its checksum; worth resending
+    ChecksumMismatch = 0x0a,
+}
+
+impl Ack {
+    /// The transfer didn't go through; with [`handshake::Features::ACK_REASONS`]
+    /// the ACK is followed by `u16 len | reason`
+    pub fn is_failure(self) -> bool {
+        matches!(
+            self,
+            Ack::Failed
+                | Ack::RejectedName
+                | Ack::NoSpace
+                | Ack::OverQuota
+                | Ack::Forbidden
+                | Ack::ChecksumMismatch
+        )
+    }
+}
+
+/// Decode an ACK into the sequence number it answers and its status
+pub fn decode_ack(buf: [u8; ACK_LEN]) -> Result<(u32, Ack)> {
+    let ack = Ack::try_from(buf[0])?;
+    Ok((u32::from_be_bytes(buf[1..].try_into().unwrap()), ack))
+}
+
+impl TryFrom<u8> for Ack {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x00 => Ok(Self::Failed),
+            0x01 => Ok(Self::Ok),
+            0x02 => Ok(Self::RejectedName),
+            0x03 => Ok(Self::Unchanged),
+            0x04 => Ok(Self::NoSpace),
+            0x05 => Ok(Self::Kept),
+            0x06 => Ok(Self::MovedAside),
+            0x07 => Ok(Self::Superseded),
+            0x08 => Ok(Self::OverQuota),
+            0x09 => Ok(Self::Forbidden),
+            0x0a => Ok(Self::ChecksumMismatch),
+            other => anyhow::bail!("Unknown ACK code 0x{other:02x}"),
+        }
+    }
+}
+
+/// As transfer logs name it
+impl std::fmt::Display for Ack {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Ack::Failed => "failed",
+            Ack::Ok => "ok",
+            Ack::RejectedName => "rejected-name",
+            Ack::Unchanged => "unchanged",
+            Ack::NoSpace => "no-space",
+            Ack::Kept => "kept",
+            Ack::MovedAside => "moved-aside",
+            Ack::Superseded => "superseded",
+            Ack::OverQuota => "over-quota",
+            Ack::Forbidden => "forbidden",
+            Ack::ChecksumMismatch => "checksum-mismatch",
+        })
+    }
+}
+
+/// Type of a message sent by the watcher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    /// Whole file: header followed by the payload
+    File = 0x01,
+    /// Block-based delta against the receiver's existing copy
+    Delta = 0x02,
+    /// Remove a path: `u16 len | name`
+    Delete = 0x03,
+    /// Move a path: `u16 len | old name | u16 len | new name`
+    Rename = 0x04,
+    /// Whole file that may continue the receiver's partial copy: header,
+    /// then the receiver's offer (`u64 len | [u8; 32] BLAKE3 of those bytes`),
+    /// the sender's `u64` start offset and the payload from there on
+    Resume = 0x05,
+    /// Files the sender has, for the receiver to compare against its copies:
+    /// `u32 count | count * (u16 len | name | u64 size | [u8; 32] BLAKE3)`
+    Manifest = 0x06,
+    /// Ask for the receiver's own manifest
+    List = 0x07,
+    /// Create a symbolic link: `u16 len | name | u16 len | target`
+    Symlink = 0x08,
+    /// Make a path another name of a file the receiver has:
+    /// `u16 len | name | u16 len | existing name`
+    HardLink = 0x09,
+    /// Files to put in place together: `u16 count`, then that many `File`
+    /// messages. One ACK, with the transaction's sequence number, answers
+    /// the whole group.
+    Transaction = 0x0a,
+    /// Nothing beyond the sequence number; the receiver ACKs it. Sent on
+    /// idle connections to find out whether the receiver is still there.
+    Heartbeat = 0x0b,
+    /// Small files sent back to back: `u16 count`, then that many `File`
+    /// messages, each put in place on its own. One ACK, with the batch's
+    /// sequence number, answers them all and is followed by every member's
+    /// own status (`count * u8 ack`), in order.
+    Batch = 0x0c,
+    /// A whole tree streamed at once, for seeding a destination: any number
+    /// of `File` messages, closed by a second `Archive` frame with the same
+    /// sequence number. Each member is put in place on its own; the receiver
+    /// answers with the indices of those it did not store
+    /// (`u32 count | count * u32 index`), then one ACK.
+    Archive = 0x0d,
+    /// A file announced by content: the `File` header, then the content's
+    /// `[u8; 32]` BLAKE3 checksum. The receiver answers with a
+    /// [`DedupReply`]; only after [`DedupReply::Send`] do the payload and the
+    /// checksum trailer follow, as for `File`.
+    Dedup = 0x0e,
+    /// New bytes at the end of a growing file: the `File` header with the
+    /// new size, then the receiver's offer (`u64 len | [u8; 32] BLAKE3` of
+    /// the last [`APPEND_CHECK`] bytes of its copy), the sender's `u64`
+    /// start offset (`len`, or 0 to send the whole file) and the payload from
+    /// there on. The checksum trailer covers only the bytes sent.
+    Append = 0x0f,
+    /// One part of a large file sent over several connections at once: the
+    /// `File` header with the whole file's size, then a [`RangeHeader`]
+    /// (`[u8; 16] stripe id | [u8; 32] BLAKE3 of the whole file | u64 offset
+    /// | u64 len`), the payload of those bytes and their BLAKE3 checksum. The
+    /// receiver puts the ranges of a stripe together and ACKs each; the ACK of
+    /// the one that completes the file answers for the whole file.
+    Range = 0x10,
+    /// A file whose content just went out to the `--multicast` group: the
+    /// `File` header, then the `[u8; 16]` [`multicast::id`] of that send and
+    /// the `[u8; 32]` BLAKE3 of the whole file. The receiver answers with
+    /// the byte runs it still lacks (`u32 count | count * (u64 offset | u64
+    /// len)`, in order), and the sender sends the payload of each in turn,
+    /// without a checksum trailer: the receiver checks the whole file
+    /// against the one it was given.
+    Multicast = 0x11,
+}
+
+/// Where the payload of a [`MessageKind::Range`] goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeHeader {
+    /// The same for every range of one send of a file
+    pub stripe: [u8; 16],
+    /// BLAKE3 checksum of the whole file
+    pub checksum: [u8; CHECKSUM_LEN],
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// A receiver's answer to a [`MessageKind::Dedup`] header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupReply {
+    /// Send the payload
+    Send = 0x00,
+    /// The receiver made the file from content it already had; the ACK
+    /// follows
+    Have = 0x01,
+    /// The receiver can't take the file; the ACK follows and says why
+    Refused = 0x02,
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x01 => Ok(Self::File),
+            0x02 => Ok(Self::Delta),
+            0x03 => Ok(Self::Delete),
+            0x04 => Ok(Self::Rename),
+            0x05 => Ok(Self::Resume),
+            0x06 => Ok(Self::Manifest),
+            0x07 => Ok(Self::List),
+            0x08 => Ok(Self::Symlink),
+            0x09 => Ok(Self::HardLink),
+            0x0a => Ok(Self::Transaction),
+            0x0b => Ok(Self::Heartbeat),
+            0x0c => Ok(Self::Batch),
+            0x0d => Ok(Self::Archive),
+            0x0e => Ok(Self::Dedup),
+            0x0f => Ok(Self::Append),
+            0x10 => Ok(Self::Range),
+            0x11 => Ok(Self::Multicast),
+            other => anyhow::bail!("Unknown message kind 0x{other:02x}"),
+        }
+    }
+}
+
+/// One instruction of a delta stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaOp {
+    /// Copy `count` blocks of the old file starting at block `index`
+    Copy { index: u64, count: u32 },
+    /// `len` literal bytes follow
+    Data { len: u32 },
+    /// The new file is complete
+    End,
+}
+
+const DELTA_COPY: u8 = 0x00;
+const DELTA_DATA: u8 = 0x01;
+const DELTA_END: u8 = 0xff;
+
+/// BLAKE3 hash of every `block_size` block of `data` (the last one may be short)
+pub fn block_hashes(data: &[u8], block_size: usize) -> Vec<[u8; CHECKSUM_LEN]> {
+    data.chunks(block_size)
+        .map(|block| *blake3::hash(block).as_bytes())
+        .collect()
+}
+
+/// Header announcing one file transfer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolHeader {
+    /// Path relative to the watched / destination directory
+    pub name: PathBuf,
+    /// Payload size in bytes
+    pub size: u64,
+    /// Source file attributes
+    pub meta: FileMeta,
+    /// When the sender wants the ACK; `None` if the peer doesn't negotiate it
+    pub confirm: Option<Confirm>,
+    /// Extended attributes of the source; `None` if the peer doesn't
+    /// negotiate them
+    pub xattrs: Option<Vec<xattr::Xattr>>,
+    /// Version of the content, ordering sends of the same path; `None` if
+    /// the peer doesn't negotiate it
+    pub version: Option<u64>,
+    /// Same for every send of one change; `None` if the peer doesn't
+    /// negotiate it
+    pub transfer_id: Option<[u8; 16]>,
+}
+
+/// How far the receiver gets with a file before acknowledging it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+#[repr(u8)]
+pub enum Confirm {
+    /// Once the data is in, before it is verified; a bad file is only logged
+    Received = 0x00,
+    /// After the checksum matched and the file was renamed into place
+    #[default]
+    Verified = 0x01,
+    /// After the file and its directory were synced to disk as well
+    Durable = 0x02,
+}
+
+/// What checks that file content arrived intact
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Integrity {
+    /// BLAKE3 checksums of every payload chunk and every file
+    #[default]
+    Full,
+    /// Only TCP's own checksums and the file's size, for trusted links;
+    /// takes effect when both ends ask for it
+    TcpOnly,
+}
+
+impl TryFrom<u8> for Confirm {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0x00 => Ok(Self::Received),
+            0x01 => Ok(Self::Verified),
+            0x02 => Ok(Self::Durable),
+            other => anyhow::bail!("Unknown confirmation level 0x{other:02x}"),
+        }
+    }
+}
+
+/// Longest name accepted: Linux's `PATH_MAX`, well within the `u16` length
+pub const MAX_NAME_LEN: usize = 4096;
+
+/// Block hashes or missing runs read at a time
+const HASH_BATCH: usize = 64 * 1024;
+
+/// Largest number of entries in one manifest message
+pub const MAX_MANIFEST_ENTRIES: usize = 4096;
+
+/// One file of a [`MessageKind::Manifest`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub name: PathBuf,
+    pub size: u64,
+    pub checksum: [u8; CHECKSUM_LEN],
+}
+
+/// File attributes the receiver can optionally apply
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileMeta {
+    /// Permission bits (`st_mode & 0o7777`)
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Modification time as seconds + nanoseconds since the Unix epoch
+    pub mtime_sec: i64,
+    pub mtime_nsec: u32,
+}
+
+impl FileMeta {
+    pub fn from_metadata(md: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            mode: md.mode() & 0o7777,
+            uid: md.uid(),
+            gid: md.gid(),
+            mtime_sec: md.mtime(),
+            mtime_nsec: md.mtime_nsec() as u32,
+        }
+    }
+
+    pub fn mtime(&self) -> std::time::SystemTime {
+        let offset = std::time::Duration::new(self.mtime_sec.unsigned_abs(), self.mtime_nsec);
+        if self.mtime_sec >= 0 {
+            std::time::UNIX_EPOCH + offset
+        } else {
+            std::time::UNIX_EPOCH - offset
+        }
+    }
+}
+
+/// Encoded header size excluding the name
+const HEADER_FIXED_LEN: usize = 2 + 8 + 4 + 4 + 4 + 8 + 4;
+
+/// Serialize a header into its wire representation, without the
+/// confirmation byte and extended attributes
+pub fn encode_header(header: &ProtocolHeader) -> Result<Vec<u8>> {
+    let meta = &header.meta;
+    let mut buf = Vec::with_capacity(HEADER_FIXED_LEN + header.name.as_os_str().len());
+    push_name(&mut buf, &header.name)?;
+    buf.extend_from_slice(&header.size.to_be_bytes());
+    buf.extend_from_slice(&meta.mode.to_be_bytes());
+    buf.extend_from_slice(&meta.uid.to_be_bytes());
+    buf.extend_from_slice(&meta.gid.to_be_bytes());
+    buf.extend_from_slice(&meta.mtime_sec.to_be_bytes());
+    buf.extend_from_slice(&meta.mtime_nsec.to_be_bytes());
+    Ok(buf)
+}
+
+fn push_name(buf: &mut Vec<u8>, name: &Path) -> Result<()> {
+    let bytes = name.as_os_str().as_bytes();
+    anyhow::ensure!(
+        bytes.len() <= MAX_NAME_LEN,
+        "Name too long: {}",
+        name.display()
+    );
+    buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// A name length read off the wire, if it is one we accept
+fn check_name_len(len: u16, max: usize) -> Result<usize> {
+    let len = len as usize;
+    anyhow::ensure!(len > 0, "Empty name");
+    anyhow::ensure!(len <= max, "Name of {len} bytes is too long");
+    Ok(len)
+}
+
+/// Parse a header from the start of `buf`, returning it and the bytes
+/// consumed; the confirmation byte and extended attributes are left to the
+/// caller
+pub fn decode_header(buf: &[u8]) -> Result<(ProtocolHeader, usize)> {
+    let mut cur = Cursor { buf, pos: 0 };
+    let name_len = check_name_len(u16::from_be_bytes(cur.array()?), MAX_NAME_LEN)?;
+    let name = PathBuf::from(OsString::from_vec(cur.take(name_len)?.to_vec()));
+    let size = u64::from_be_bytes(cur.array()?);
+    let meta = FileMeta {
+        mode: u32::from_be_bytes(cur.array()?),
+        uid: u32::from_be_bytes(cur.array()?),
+        gid: u32::from_be_bytes(cur.array()?),
+        mtime_sec: i64::from_be_bytes(cur.array()?),
+        mtime_nsec: u32::from_be_bytes(cur.array()?),
+    };
+    Ok((
+        ProtocolHeader {
+            name,
+            size,
+            meta,
+            confirm: None,
+            xattrs: None,
+            version: None,
+            transfer_id: None,
+        },
+        cur.pos,
+    ))
+}
+
+/// Bounds-checked reads from a byte slice
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| anyhow::anyhow!("Truncated header"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+}
+
+/// Reading side of a connection
+pub struct FrameReader<R> {
+    inner: R,
+    /// Holds one payload chunk; its length is the agreed chunk size
+    chunk: Vec<u8>,
+    /// Headers carry a [`Confirm`] byte
+    confirm: bool,
+    /// Headers carry extended attributes
+    xattrs: bool,
+    /// Headers end with a version
+    versions: bool,
+    /// Headers carry a transfer id
+    transfer_ids: bool,
+    /// Failure ACKs carry a reason
+    reasons: bool,
+    /// Payload chunks end with their checksum
+    chunk_checksums: bool,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            chunk: Vec::new(),
+            confirm: false,
+            xattrs: false,
+            versions: false,
+            transfer_ids: false,
+            reasons: false,
+            chunk_checksums: true,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Accept payload chunks of up to `size` bytes
+    pub fn set_chunk_size(&mut self, size: usize) {
+        self.chunk.resize(size, 0);
+    }
+
+    /// Parse headers as laid out for the agreed `features`
+    pub fn set_features(&mut self, features: handshake::Features) {
+        self.confirm = features.contains(handshake::Features::CONFIRM);
+        self.xattrs = features.contains(handshake::Features::XATTRS);
+        self.versions = features.contains(handshake::Features::VERSIONS);
+        self.transfer_ids = features.contains(handshake::Features::TRANSFER_IDS);
+        self.reasons = features.contains(handshake::Features::ACK_REASONS);
+        self.chunk_checksums = !features.contains(handshake::Features::TCP_ONLY);
+    }
+
+    /// Read the next message kind and sequence number, or `None` if the peer
+    /// closed the connection
+    pub async fn read_kind(&mut self) -> Result<Option<(MessageKind, u32)>> {
+        let mut kind = [0u8; 1];
+        match self.inner.read_exact(&mut kind).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => return Err(e.into()),
+            Err(_) => return Ok(None),
+        }
+        let kind = MessageKind::try_from(kind[0])?;
+        Ok(Some((kind, self.inner.read_u32().await?)))
+    }
+
+    pub async fn read_header(&mut self) -> Result<ProtocolHeader> {
+        let mut len_buf = [0u8; 2];
+        self.inner.read_exact(&mut len_buf).await?;
+        let name_len = check_name_len(u16::from_be_bytes(len_buf), MAX_NAME_LEN)?;
+        let mut buf = vec![0u8; HEADER_FIXED_LEN + name_len];
+        buf[..2].copy_from_slice(&len_buf);
+        self.inner.read_exact(&mut buf[2..]).await?;
+        let (mut header, _) = decode_header(&buf)?;
+        if self.confirm {
+            header.confirm = Some(Confirm::try_from(self.inner.read_u8().await?)?);
+        }
+        if self.xattrs {
+            let count = self.inner.read_u16().await? as usize;
+            anyhow::ensure!(
+                count <= xattr::MAX_XATTRS,
+                "Header with {count} extended attributes"
+            );
+            let mut attrs = Vec::with_capacity(count);
+            for _ in 0..count {
+                let name = self.read_bytes(xattr::MAX_NAME_LEN).await?;
+                let name = String::from_utf8(name).context("Attribute name not UTF-8")?;
+                let len = self.inner.read_u32().await? as usize;
+                anyhow::ensure!(
+                    len <= xattr::MAX_VALUE_LEN,
+                    "Extended attribute {name} of {len} bytes"
+                );
+                let mut value = vec![0u8; len];
+                self.inner.read_exact(&mut value).await?;
+                attrs.push(xattr::Xattr { name, value });
+            }
+            header.xattrs = Some(attrs);
+        }
+        if self.versions {
+            header.version = Some(self.inner.read_u64().await?);
+        }
+        if self.transfer_ids {
+            let mut id = [0; 16];
+            self.inner.read_exact(&mut id).await?;
+            header.transfer_id = Some(id);
+        }
+        Ok(header)
+    }
+
+    pub async fn read_u16(&mut self) -> Result<u16> {
+        Ok(self.inner.read_u16().await?)
+    }
+
+    pub async fn read_u32(&mut self) -> Result<u32> {
+        Ok(self.inner.read_u32().await?)
+    }
+
+    pub async fn read_u64(&mut self) -> Result<u64> {
+        Ok(self.inner.read_u64().await?)
+    }
+
+    /// Read the receiver's reply to a resume message: how many bytes of a
+    /// partial copy it has and their hash, or `None` if it refused the file
+    pub async fn read_resume_offer(&mut self) -> Result<Option<(u64, [u8; CHECKSUM_LEN])>> {
+        let len = self.inner.read_u64().await?;
+        let hash = self.read_checksum().await?;
+        Ok((len != REFUSED_OFFER).then_some((len, hash)))
+    }
+
+    /// Read where a range message's payload goes, after its header
+    pub async fn read_range(&mut self) -> Result<RangeHeader> {
+        let mut stripe = [0; 16];
+        self.inner.read_exact(&mut stripe).await?;
+        let checksum = self.read_checksum().await?;
+        let offset = self.inner.read_u64().await?;
+        let len = self.inner.read_u64().await?;
+        Ok(RangeHeader {
+            stripe,
+            checksum,
+            offset,
+            len,
+        })
+    }
+
+    /// Read which multicast send a multicast message follows and the
+    /// checksum of its content, after its header
+    pub async fn read_multicast(&mut self) -> Result<([u8; 16], [u8; CHECKSUM_LEN])> {
+        let mut id = [0; 16];
+        self.inner.read_exact(&mut id).await?;
+        Ok((id, self.read_checksum().await?))
+    }
+
+    /// Read the byte runs a receiver lacks of a `size`-byte multicast file,
+    /// or `None` if it refused the file
+    pub async fn read_missing(&mut self, size: u64) -> Result<Option<Vec<(u64, u64)>>> {
+        let count = self.inner.read_u32().await?;
+        if count == REFUSED_BLOCKS {
+            return Ok(None);
+        }
+        let mut left = count as usize;
+        let mut runs = Vec::with_capacity(left.min(HASH_BATCH));
+        let mut raw = vec![0u8; left.min(HASH_BATCH) * 16];
+        let mut end = 0;
+        while left > 0 {
+            let batch = &mut raw[..left.min(HASH_BATCH) * 16];
+            self.inner.read_exact(batch).await?;
+            for run in batch.chunks_exact(16) {
+                let offset = u64::from_be_bytes(run[..8].try_into().unwrap());
+                let len = u64::from_be_bytes(run[8..].try_into().unwrap());
+                anyhow::ensure!(
+                    offset >= end && len > 0 && offset.checked_add(len).is_some_and(|e| e <= size),
+                    "Missing run of {len} bytes at {offset} in a file of {size}"
+                );
+                end = offset + len;
+                runs.push((offset, len));
+            }
+            left -= batch.len() / 16;
+        }
+        Ok(Some(runs))
+    }
+
+    pub async fn read_dedup_reply(&mut self) -> Result<DedupReply> {
+        match self.inner.read_u8().await? {
+            0x00 => Ok(DedupReply::Send),
+            0x01 => Ok(DedupReply::Have),
+            0x02 => Ok(DedupReply::Refused),
+            other => anyhow::bail!("Unknown dedup reply 0x{other:02x}"),
+        }
+    }
+
+    /// Read the checksum that ends a file message
+    pub async fn read_checksum(&mut self) -> Result<[u8; CHECKSUM_LEN]> {
+        let mut hash = [0u8; CHECKSUM_LEN];
+        self.inner.read_exact(&mut hash).await?;
+        Ok(hash)
+    }
+
+    /// Read a length-prefixed name (delete / rename / link messages)
+    pub async fn read_name(&mut self) -> Result<PathBuf> {
+        Ok(PathBuf::from(OsString::from_vec(
+            self.read_bytes(MAX_NAME_LEN).await?,
+        )))
+    }
+
+    async fn read_bytes(&mut self, max: usize) -> Result<Vec<u8>> {
+        let len = check_name_len(self.inner.read_u16().await?, max)?;
+        let mut buf = vec![0u8; len];
+        self.inner.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Read a manifest's entries, after its kind and sequence number or as
+    /// one part of a listing
+    pub async fn read_manifest(&mut self) -> Result<Vec<ManifestEntry>> {
+        let count = self.inner.read_u32().await? as usize;
+        anyhow::ensure!(
+            count <= MAX_MANIFEST_ENTRIES,
+            "Manifest of {count} entries is too large"
+        );
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let name = self.read_name().await?;
+            let size = self.inner.read_u64().await?;
+            let checksum = self.read_checksum().await?;
+            entries.push(ManifestEntry {
+                name,
+                size,
+                checksum,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Read the receiver's answer to a manifest of `count` entries: the
+    /// indices of those it needs sent
+    pub async fn read_stale(&mut self, count: usize) -> Result<Vec<usize>> {
+        let n = self.inner.read_u32().await? as usize;
+        anyhow::ensure!(n <= count, "{n} stale entries in a manifest of {count}");
+        let mut stale = Vec::with_capacity(n);
+        for _ in 0..n {
+            let index = self.inner.read_u32().await? as usize;
+            anyhow::ensure!(
+                index < count,
+                "Stale entry {index} in a manifest of {count}"
+            );
+            stale.push(index);
+        }
+        Ok(stale)
+    }
+
+    /// Read the receiver's block hashes for a delta transfer, or `None` if it
+    /// refused the file
+    pub async fn read_block_hashes(&mut self) -> Result<Option<Vec<[u8; CHECKSUM_LEN]>>> {
+        let count = self.inner.read_u32().await?;
+        if count == REFUSED_BLOCKS {
+            return Ok(None);
+        }
+        // Read in batches, so a bogus count can't reserve gigabytes up front
+        let mut left = count as usize;
+        let mut hashes = Vec::with_capacity(left.min(HASH_BATCH));
+        let mut raw = vec![0u8; left.min(HASH_BATCH) * CHECKSUM_LEN];
+        while left > 0 {
+            let batch = &mut raw[..left.min(HASH_BATCH) * CHECKSUM_LEN];
+            self.inner.read_exact(batch).await?;
+            hashes.extend(
+                batch
+                    .chunks_exact(CHECKSUM_LEN)
+                    .map(|h| <[u8; CHECKSUM_LEN]>::try_from(h).unwrap()),
+            );
+            left -= batch.len() / CHECKSUM_LEN;
+        }
+        Ok(Some(hashes))
+    }
+
+    pub async fn read_delta_op(&mut self) -> Result<DeltaOp> {
+        match self.inner.read_u8().await? {
+            DELTA_COPY => {
+                let index = self.inner.read_u64().await?;
+                let count = self.inner.read_u32().await?;
+                Ok(DeltaOp::Copy { index, count })
+            }
+            DELTA_DATA => Ok(DeltaOp::Data {
+                len: self.inner.read_u32().await?,
+            }),
+            DELTA_END => Ok(DeltaOp::End),
+            other => anyhow::bail!("Unknown delta op 0x{other:02x}"),
+        }
+    }
+
+    /// Read one payload chunk of at most `remaining` bytes and verify its
+    /// checksum if it has one, returning the data
+    pub async fn read_chunk(&mut self, remaining: u64) -> Result<&[u8]> {
+        let len = self.read_chunk_len(remaining).await?;
+        self.inner.read_exact(&mut self.chunk[..len]).await?;
+        let data = &self.chunk[..len];
+        verify_chunk(&mut self.inner, self.chunk_checksums, data).await?;
+        Ok(data)
+    }
+
+    /// [`read_chunk`](Self::read_chunk) into `buf`, which ends up holding
+    /// just the chunk, for callers that hand the data on without copying it
+    pub async fn read_chunk_into(&mut self, remaining: u64, buf: &mut Vec<u8>) -> Result<()> {
+        let len = self.read_chunk_len(remaining).await?;
+        buf.resize(len, 0);
+        self.inner.read_exact(buf).await?;
+        verify_chunk(&mut self.inner, self.chunk_checksums, buf).await
+    }
+
+    /// Read a chunk's length and check it against the chunk size and
+    /// `remaining`
+    async fn read_chunk_len(&mut self, remaining: u64) -> Result<usize> {
+        let len = self.inner.read_u32().await? as usize;
+        let chunk_size = self.chunk.len();
+        anyhow::ensure!(
+            len > 0 && len <= chunk_size && len as u64 <= remaining,
+            "Chunk of {len} bytes with {remaining} left and a chunk size of {chunk_size}"
+        );
+        Ok(len)
+    }
+
+    /// Read the receiver's ACK and the sequence number it answers
+    pub async fn read_ack(&mut self) -> Result<(u32, Ack)> {
+        let (seq, ack, _) = self.read_ack_reason().await?;
+        Ok((seq, ack))
+    }
+
+    /// [`read_ack`](Self::read_ack), with the reason a failure came with
+    pub async fn read_ack_reason(&mut self) -> Result<(u32, Ack, Option<String>)> {
+        let mut buf = [0u8; ACK_LEN];
+        self.inner.read_exact(&mut buf).await?;
+        let (seq, ack) = decode_ack(buf)?;
+        if !(self.reasons && ack.is_failure()) {
+            return Ok((seq, ack, None));
+        }
+        let len = self.inner.read_u16().await? as usize;
+        anyhow::ensure!(len <= MAX_REASON, "ACK reason of {len} bytes");
+        let mut reason = vec![0u8; len];
+        self.inner.read_exact(&mut reason).await?;
+        Ok((
+            seq,
+            ack,
+            Some(String::from_utf8_lossy(&reason).into_owned()),
+        ))
+    }
+}
+
+/// Read the checksum that follows a chunk's `data`, if chunks carry one,
+/// and check it
+async fn verify_chunk<R: AsyncRead + Unpin>(
+    inner: &mut R,
+    checksums: bool,
+    data: &[u8],
+) -> Result<()> {
+    if checksums {
+        let mut hash = [0u8; CHECKSUM_LEN];
+        inner.read_exact(&mut hash).await?;
+        anyhow::ensure!(
+            blake3::hash(data) == blake3::Hash::from(hash),
+            "Chunk checksum mismatch"
+        );
+    }
+    Ok(())
+}
+
+/// Writing side of a connection
+pub struct FrameWriter<W> {
+    inner: W,
+    /// Failure ACKs carry a reason
+    reasons: bool,
+    /// What the next failure ACK says
+    reason: Option<String>,
+    /// Payload chunks end with their checksum
+    chunk_checksums: bool,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            reasons: false,
+            reason: None,
+            chunk_checksums: true,
+        }
+    }
+
+    /// Lay ACKs and chunks out as the agreed `features` have them
+    pub fn set_features(&mut self, features: handshake::Features) {
+        self.reasons = features.contains(handshake::Features::ACK_REASONS);
+        self.chunk_checksums = !features.contains(handshake::Features::TCP_ONLY);
+    }
+
+    /// Say why the next failure ACK is sent, unless a reason is already
+    /// waiting. Any ACK written clears it; a failure without one carries the
+    /// ACK's name.
+    pub fn explain(&mut self, reason: impl std::fmt::Display) {
+        if self.reasons && self.reason.is_none() {
+            self.reason = Some(reason.to_string());
+        }
+    }
+
+    /// The underlying stream, for writing chunk data past the framing (e.g.
+    /// with `sendfile`); nothing is buffered in between
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Start a message: kind byte followed by the header, ending with its
+    /// confirmation level, extended attributes, version and transfer id if it
+    /// has them
+    pub async fn write_header(
+        &mut self,
+        kind: MessageKind,
+        seq: u32,
+        header: &ProtocolHeader,
+    ) -> Result<()> {
+        let mut buf = vec![kind as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        buf.extend_from_slice(&encode_header(header)?);
+        buf.extend(header.confirm.map(|confirm| confirm as u8));
+        if let Some(attrs) = &header.xattrs {
+            anyhow::ensure!(
+                attrs.len() <= xattr::MAX_XATTRS,
+                "{} has too many extended attributes",
+                header.name.display()
+            );
+            buf.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+            for attr in attrs {
+                anyhow::ensure!(
+                    attr.value.len() <= xattr::MAX_VALUE_LEN,
+                    "Extended attribute {} too large",
+                    attr.name
+                );
+                push_name(&mut buf, Path::new(&attr.name))?;
+                buf.extend_from_slice(&(attr.value.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&attr.value);
+            }
+        }
+        buf.extend(header.version.map(u64::to_be_bytes).into_iter().flatten());
+        buf.extend(header.transfer_id.into_iter().flatten());
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_delete(&mut self, seq: u32, name: &Path) -> Result<()> {
+        let mut buf = vec![MessageKind::Delete as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        push_name(&mut buf, name)?;
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_rename(&mut self, seq: u32, from: &Path, to: &Path) -> Result<()> {
+        let mut buf = vec![MessageKind::Rename as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        push_name(&mut buf, from)?;
+        push_name(&mut buf, to)?;
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_symlink(&mut self, seq: u32, name: &Path, target: &Path) -> Result<()> {
+        let mut buf = vec![MessageKind::Symlink as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        push_name(&mut buf, name)?;
+        push_name(&mut buf, target)?;
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_hard_link(&mut self, seq: u32, name: &Path, existing: &Path) -> Result<()> {
+        let mut buf = vec![MessageKind::HardLink as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        push_name(&mut buf, name)?;
+        push_name(&mut buf, existing)?;
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_manifest(&mut self, seq: u32, entries: &[ManifestEntry]) -> Result<()> {
+        let mut buf = vec![MessageKind::Manifest as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        self.inner.write_all(&buf).await?;
+        self.write_entries(entries).await
+    }
+
+    /// Open a transaction of `count` files, which follow as `File` messages
+    pub async fn write_transaction(&mut self, seq: u32, count: u16) -> Result<()> {
+        let mut buf = vec![MessageKind::Transaction as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        buf.extend_from_slice(&count.to_be_bytes());
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_batch(&mut self, seq: u32, count: u16) -> Result<()> {
+        let mut buf = vec![MessageKind::Batch as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        buf.extend_from_slice(&count.to_be_bytes());
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Open or close an archive stream
+    pub async fn write_archive(&mut self, seq: u32) -> Result<()> {
+        let mut buf = vec![MessageKind::Archive as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// ACK a batch along with its members' statuses
+    pub async fn write_batch_ack(&mut self, seq: u32, statuses: &[Ack]) -> Result<()> {
+        self.reason = None;
+        let mut buf = vec![Ack::Ok as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        buf.extend(statuses.iter().map(|&ack| ack as u8));
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_heartbeat(&mut self, seq: u32) -> Result<()> {
+        let mut buf = vec![MessageKind::Heartbeat as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_list(&mut self, seq: u32) -> Result<()> {
+        let mut buf = vec![MessageKind::List as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Write a manifest's entries; a listing is a series of these ending
+    /// with an empty one
+    pub async fn write_entries(&mut self, entries: &[ManifestEntry]) -> Result<()> {
+        anyhow::ensure!(
+            entries.len() <= MAX_MANIFEST_ENTRIES,
+            "Manifest of {} entries is too large",
+            entries.len()
+        );
+        let mut buf = (entries.len() as u32).to_be_bytes().to_vec();
+        for entry in entries {
+            push_name(&mut buf, &entry.name)?;
+            buf.extend_from_slice(&entry.size.to_be_bytes());
+            buf.extend_from_slice(&entry.checksum);
+        }
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Answer a manifest with the indices of the entries to send
+    pub async fn write_stale(&mut self, stale: &[u32]) -> Result<()> {
+        let mut buf = Vec::with_capacity(4 + stale.len() * 4);
+        buf.extend_from_slice(&(stale.len() as u32).to_be_bytes());
+        for index in stale {
+            buf.extend_from_slice(&index.to_be_bytes());
+        }
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_u32(&mut self, value: u32) -> Result<()> {
+        self.inner.write_u32(value).await?;
+        Ok(())
+    }
+
+    pub async fn write_u64(&mut self, value: u64) -> Result<()> {
+        self.inner.write_u64(value).await?;
+        Ok(())
+    }
+
+    /// Offer the first `len` bytes of a partial copy
+    pub async fn write_resume_offer(&mut self, len: u64, hash: &[u8; CHECKSUM_LEN]) -> Result<()> {
+        let mut buf = len.to_be_bytes().to_vec();
+        buf.extend_from_slice(hash);
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_block_hashes(&mut self, hashes: &[[u8; CHECKSUM_LEN]]) -> Result<()> {
+        let mut buf = Vec::with_capacity(4 + hashes.len() * CHECKSUM_LEN);
+        let count = u32::try_from(hashes.len())?;
+        anyhow::ensure!(count != REFUSED_BLOCKS, "Too many blocks");
+        buf.extend_from_slice(&count.to_be_bytes());
+        for h in hashes {
+            buf.extend_from_slice(h);
+        }
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Say where a range message's payload goes, after its header
+    pub async fn write_range(&mut self, range: &RangeHeader) -> Result<()> {
+        let mut buf = range.stripe.to_vec();
+        buf.extend_from_slice(&range.checksum);
+        buf.extend_from_slice(&range.offset.to_be_bytes());
+        buf.extend_from_slice(&range.len.to_be_bytes());
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Say which multicast send a multicast message follows, after its header
+    pub async fn write_multicast(
+        &mut self,
+        id: &[u8; 16],
+        checksum: &[u8; CHECKSUM_LEN],
+    ) -> Result<()> {
+        let mut buf = id.to_vec();
+        buf.extend_from_slice(checksum);
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Answer a multicast message with the byte runs still to send
+    pub async fn write_missing(&mut self, runs: &[(u64, u64)]) -> Result<()> {
+        let count = u32::try_from(runs.len())?;
+        anyhow::ensure!(count != REFUSED_BLOCKS, "Too many missing runs");
+        let mut buf = Vec::with_capacity(4 + runs.len() * 16);
+        buf.extend_from_slice(&count.to_be_bytes());
+        for (offset, len) in runs {
+            buf.extend_from_slice(&offset.to_be_bytes());
+            buf.extend_from_slice(&len.to_be_bytes());
+        }
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    pub async fn write_dedup_reply(&mut self, reply: DedupReply) -> Result<()> {
+        self.inner.write_u8(reply as u8).await?;
+        Ok(())
+    }
+
+    /// Answer a delta, resume, dedup, append or multicast message with a
+    /// refusal; its ACK follows
+    pub async fn write_refused(&mut self, kind: MessageKind) -> Result<()> {
+        match kind {
+            MessageKind::Delta | MessageKind::Multicast => {
+                self.inner.write_u32(REFUSED_BLOCKS).await?
+            }
+            MessageKind::Resume | MessageKind::Append => {
+                self.write_resume_offer(REFUSED_OFFER, &[0; CHECKSUM_LEN])
+                    .await?
+            }
+            MessageKind::Dedup => self.write_dedup_reply(DedupReply::Refused).await?,
+            _ => anyhow::bail!("{kind:?} messages cannot be refused"),
+        }
+        Ok(())
+    }
+
+    /// End a file message with the checksum of the whole file
+    pub async fn write_checksum(&mut self, checksum: &[u8; CHECKSUM_LEN]) -> Result<()> {
+        self.inner.write_all(checksum).await?;
+        Ok(())
+    }
+
+    /// Write a delta instruction; `Data` must be followed by its payload chunks
+    pub async fn write_delta_op(&mut self, op: DeltaOp) -> Result<()> {
+        let mut buf = Vec::with_capacity(13);
+        match op {
+            DeltaOp::Copy { index, count } => {
+                buf.push(DELTA_COPY);
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&count.to_be_bytes());
+            }
+            DeltaOp::Data { len } => {
+                buf.push(DELTA_DATA);
+                buf.extend_from_slice(&len.to_be_bytes());
+            }
+            DeltaOp::End => buf.push(DELTA_END),
+        }
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Write one payload chunk: `u32 len | data | [u8; 32] BLAKE3(data)`
+    pub async fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        let len = u32::try_from(data.len()).context("Chunk too large")?;
+        self.inner.write_u32(len).await?;
+        self.inner.write_all(data).await?;
+        self.end_chunk(data).await
+    }
+
+    /// Start a payload chunk of `len` bytes written straight to the stream by
+    /// the caller, who then ends it with [`end_chunk`](Self::end_chunk)
+    pub async fn write_chunk_header(&mut self, len: u32) -> Result<()> {
+        self.inner.write_u32(len).await?;
+        Ok(())
+    }
+
+    /// End a payload chunk holding `data`, with its checksum unless the peers
+    /// agreed to go without
+    pub async fn end_chunk(&mut self, data: &[u8]) -> Result<()> {
+        if self.chunk_checksums {
+            self.inner.write_all(blake3::hash(data).as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// [`write_chunk`](Self::write_chunk) for a chunk whose checksum was
+    /// computed ahead
+    pub async fn write_hashed_chunk(&mut self, data: &[u8], checksum: &blake3::Hash) -> Result<()> {
+        let len = u32::try_from(data.len()).context("Chunk too large")?;
+        self.inner.write_u32(len).await?;
+        self.inner.write_all(data).await?;
+        self.end_hashed_chunk(checksum).await
+    }
+
+    /// [`end_chunk`](Self::end_chunk) for a chunk whose checksum was computed
+    /// ahead
+    pub async fn end_hashed_chunk(&mut self, checksum: &blake3::Hash) -> Result<()> {
+        if self.chunk_checksums {
+            self.inner.write_all(checksum.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn write_ack(&mut self, seq: u32, ack: Ack) -> Result<()> {
+        let mut buf = vec![ack as u8];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        let reason = self.reason.take();
+        if self.reasons && ack.is_failure() {
+            let mut reason = reason.unwrap_or_else(|| ack.to_string());
+            let mut end = reason.len().min(MAX_REASON);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+            buf.extend_from_slice(&(reason.len() as u16).to_be_bytes());
+            buf.extend_from_slice(reason.as_bytes());
+        }
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str) -> ProtocolHeader {
+        ProtocolHeader {
+            name: PathBuf::from(name),
+            size: 1234,
+            meta: FileMeta {
+                mode: 0o644,
+                uid: 1000,
+                gid: 1000,
+                mtime_sec: 1_700_000_000,
+                mtime_nsec: 5,
+            },
+            confirm: None,
+            xattrs: None,
+            version: None,
+            transfer_id: None,
+        }
+    }
+
+    /// A header's wire bytes with its name length replaced by `len`
+    fn with_name_len(len: u16) -> Vec<u8> {
+        let mut buf = encode_header(&header("a")).unwrap();
+        buf[..2].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    /// Read every message in `stream` the way a receiver does, until it
+    /// ends or something in it is refused
+    async fn read_all(stream: &[u8]) -> Result<()> {
+        let mut reader = FrameReader::new(stream);
+        reader.set_chunk_size(1024);
+        reader.set_features(handshake::Features::ALL);
+        while let Some((kind, _)) = reader.read_kind().await? {
+            match kind {
+                MessageKind::Delete => drop(reader.read_name().await?),
+                MessageKind::Rename | MessageKind::Symlink | MessageKind::HardLink => {
+                    reader.read_name().await?;
+                    reader.read_name().await?;
+                }
+                MessageKind::Manifest => drop(reader.read_manifest().await?),
+                MessageKind::Transaction | MessageKind::Batch => drop(reader.read_u16().await?),
+                MessageKind::List | MessageKind::Heartbeat | MessageKind::Archive => {}
+                kind => {
+                    let header = reader.read_header().await?;
+                    match kind {
+                        MessageKind::Dedup => drop(reader.read_checksum().await?),
+                        MessageKind::Range => drop(reader.read_range().await?),
+                        MessageKind::Multicast => drop(reader.read_multicast().await?),
+                        MessageKind::Delta => {
+                            reader.read_u32().await?;
+                            while let op @ (DeltaOp::Copy { .. } | DeltaOp::Data { .. }) =
+                                reader.read_delta_op().await?
+                            {
+                                if let DeltaOp::Data { len } = op {
+                                    let mut left = len as u64;
+                                    while left > 0 {
+                                        left -= reader.read_chunk(left).await?.len() as u64;
+                                    }
+                                }
+                            }
+                            reader.read_checksum().await?;
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    let mut left = header.size;
+                    while left > 0 {
+                        left -= reader.read_chunk(left).await?.len() as u64;
+                    }
+                    reader.read_checksum().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let sent = header("dir/file.txt");
+        let buf = encode_header(&sent).unwrap();
+        assert_eq!(decode_header(&buf).unwrap(), (sent, buf.len()));
+    }
+
+    #[test]
+    fn names_of_zero_or_too_many_bytes_are_refused() {
+        assert!(decode_header(&with_name_len(0)).is_err());
+        let mut long = with_name_len(MAX_NAME_LEN as u16 + 1);
+        long.resize(HEADER_FIXED_LEN + MAX_NAME_LEN + 1, 0);
+        assert!(decode_header(&long).is_err());
+        assert!(encode_header(&header(&"a".repeat(MAX_NAME_LEN + 1))).is_err());
+        assert!(encode_header(&header(&"a".repeat(MAX_NAME_LEN))).is_ok());
+    }
+
+    #[tokio::test]
+    async fn framed_names_of_zero_or_too_many_bytes_are_refused() {
+        for len in [0, MAX_NAME_LEN as u16 + 1, u16::MAX] {
+            let mut stream = len.to_be_bytes().to_vec();
+            stream.resize(2 + len as usize, b'a');
+            assert!(
+                FrameReader::new(&stream[..]).read_name().await.is_err(),
+                "name of {len} bytes"
+            );
+            assert!(
+                FrameReader::new(&stream[..]).read_header().await.is_err(),
+                "header name of {len} bytes"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_are_refused() {
+        let buf = encode_header(&header("dir/file.txt")).unwrap();
+        for end in 0..buf.len() {
+            assert!(decode_header(&buf[..end]).is_err(), "header cut at {end}");
+            assert!(
+                FrameReader::new(&buf[..end]).read_header().await.is_err(),
+                "framed header cut at {end}"
+            );
+        }
+        let mut reader = FrameReader::new(&buf[..]);
+        reader.set_features(handshake::Features::CONFIRM);
+        assert!(
+            reader.read_header().await.is_err(),
+            "header without its confirmation byte"
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_manifests_are_refused() {
+        for count in [MAX_MANIFEST_ENTRIES as u32 + 1, u32::MAX] {
+            let stream = count.to_be_bytes();
+            assert!(
+                FrameReader::new(&stream[..]).read_manifest().await.is_err(),
+                "manifest of {count}"
+            );
+        }
+        let mut stream = 3u32.to_be_bytes().to_vec();
+        stream.extend_from_slice(&1u32.to_be_bytes());
+        stream.extend_from_slice(&0u32.to_be_bytes());
+        stream.extend_from_slice(&1u32.to_be_bytes());
+        assert!(
+            FrameReader::new(&stream[..]).read_stale(2).await.is_err(),
+            "more stale entries than sent"
+        );
+        let stream = [0, 0, 0, 1, 0, 0, 0, 2];
+        assert!(
+            FrameReader::new(&stream[..]).read_stale(2).await.is_err(),
+            "stale index past the manifest"
+        );
+    }
+
+    #[tokio::test]
+    async fn random_streams_are_refused() {
+        // xorshift64, seeded so a failure reproduces
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            let len = (next() % 4096) as usize;
+            let mut stream: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // Mostly valid kinds, so the readers behind them get exercised
+            if let Some(kind) = stream.first_mut() {
+                *kind = (next() % 0x12) as u8;
+            }
+            assert!(
+                read_all(&stream).await.is_err() || stream.is_empty(),
+                "stream {stream:02x?} was accepted"
+            );
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! Leveled, structured logging to stderr.
+//!
+//! Events carry a human-readable message plus typed fields. The text format
+//! prints just the message, as in `[+] OK name (3 bytes) | ...`; the JSON
+//! format prints one object per line with a timestamp, the level, the `[tag]`
+//! split off the message and every field, including those of the enclosing
+//! [`in_span`] scopes:
+//!
+//! ```text
+//! {"ts":"2025-01-01T12:00:00.000Z","level":"info","tag":"+","msg":"OK a.txt ...","dest":"10.0.0.2:5001","name":"a.txt","size":3}
+//! ```
+
+use std::{
+    fmt::{self, Write as _},
+    future::Future,
+    io::Write as _,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Plain lines for a terminal
+    Text,
+    /// One JSON object per line, for journald / ELK
+    Json,
+}
+
+/// Field value attached to an event
+#[derive(Debug, Clone)]
+pub enum Value {
+    Str(String),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Self::Str(v.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Self::Str(v)
+    }
+}
+
+impl From<&String> for Value {
+    fn from(v: &String) -> Self {
+        Self::Str(v.clone())
+    }
+}
+
+impl From<&std::path::Path> for Value {
+    fn from(v: &std::path::Path) -> Self {
+        Self::Str(v.to_string_lossy().into_owned())
+    }
+}
+
+impl From<&std::path::PathBuf> for Value {
+    fn from(v: &std::path::PathBuf) -> Self {
+        v.as_path().into()
+    }
+}
+
+impl From<u64> for Value {
+    fn from(v: u64) -> Self {
+        Self::U64(v)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(v: u32) -> Self {
+        Self::U64(v.into())
+    }
+}
+
+impl From<u16> for Value {
+    fn from(v: u16) -> Self {
+        Self::U64(v.into())
+    }
+}
+
+impl From<usize> for Value {
+    fn from(v: usize) -> Self {
+        Self::U64(v as u64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Self::I64(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Self::F64(v)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Self::Bool(v)
+    }
+}
+
+/// Durations are logged in milliseconds
+impl From<Duration> for Value {
+    fn from(v: Duration) -> Self {
+        Self::F64(v.as_secs_f64() * 1000.0)
+    }
+}
+
+pub type Fields = Vec<(&'static str, Value)>;
+
+static CONFIG: OnceLock<(Level, Format)> = OnceLock::new();
+
+tokio::task_local! {
+    static SPAN: Fields;
+}
+
+/// Set the level and format; later calls are ignored
+pub fn init(level: Level, format: Format) {
+    let _ = CONFIG.set((level, format));
+}
+
+fn config() -> (Level, Format) {
+    CONFIG.get().copied().unwrap_or((Level::Info, Format::Text))
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= config().0
+}
+
+/// Run `fut` with `fields` attached to every event it logs
+pub async fn in_span<F: Future>(fields: Fields, fut: F) -> F::Output {
+    let mut all = SPAN.try_with(Clone::clone).unwrap_or_default();
+    all.extend(fields);
+    SPAN.scope(all, fut).await
+}
+
+/// Write one event; use the [`info!`](crate::info) family of macros instead
+pub fn emit(level: Level, msg: fmt::Arguments, fields: &[(&'static str, Value)]) {
+    let line = match config().1 {
+        Format::Text => msg.to_string(),
+        Format::Json => {
+            let msg = msg.to_string();
+            let (tag, msg) = match msg.strip_prefix('[').and_then(|m| m.split_once("] ")) {
+                Some((tag, rest)) => (Some(tag), rest),
+                None => (None, msg.as_str()),
+            };
+            let mut out = String::from("{");
+            push_pair(&mut out, "ts", &Value::Str(timestamp(SystemTime::now())));
+            push_pair(
+                &mut out,
+                "level",
+                &Value::from(format!("{level:?}").to_lowercase()),
+            );
+            if let Some(tag) = tag {
+                push_pair(&mut out, "tag", &Value::from(tag));
+            }
+            push_pair(&mut out, "msg", &Value::from(msg));
+            let _ = SPAN.try_with(|span| {
+                for (key, value) in span {
+                    push_pair(&mut out, key, value);
+                }
+            });
+            for (key, value) in fields {
+                push_pair(&mut out, key, value);
+            }
+            out.push('}');
+            out
+        }
+    };
+    let _ = writeln!(std::io::stderr().lock(), "{line}");
+}
+
+fn push_pair(out: &mut String, key: &str, value: &Value) {
+    if !out.ends_with('{') {
+        out.push(',');
+    }
+    push_json_str(out, key);
+    out.push(':');
+    match value {
+        Value::Str(s) => push_json_str(out, s),
+        Value::U64(n) => write!(out, "{n}").unwrap(),
+        Value::I64(n) => write!(out, "{n}").unwrap(),
+        Value::F64(n) if n.is_finite() => write!(out, "{n:.3}").unwrap(),
+        Value::F64(_) => out.push_str("null"),
+        Value::Bool(b) => write!(out, "{b}").unwrap(),
+    }
+}
+
+fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// RFC 3339 UTC timestamp with milliseconds
+pub fn timestamp(t: SystemTime) -> String {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+/// Log an event at `level`: a format string and its arguments, then
+/// optionally `; key = value, ...` fields
+#[macro_export]
+macro_rules! event {
+    ($level:expr, $fmt:literal $(, $arg:expr)* $(; $($key:ident = $value:expr),+)? $(,)?) => {
+        if $crate::log::enabled($level) {
+            $crate::log::emit(
+                $level,
+                format_args!($fmt $(, $arg)*),
+                &[$($((stringify!($key), $crate::log::Value::from($value))),+)?],
+            );
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($t:tt)*) => { $crate::event!($crate::log::Level::Error, $($t)*) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($t:tt)*) => { $crate::event!($crate::log::Level::Warn, $($t)*) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($t:tt)*) => { $crate::event!($crate::log::Level::Info, $($t)*) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($t:tt)*) => { $crate::event!($crate::log::Level::Debug, $($t)*) };
+}
+
+/// Build span [`Fields`](crate::log::Fields) from `key = value` pairs
+#[macro_export]
+macro_rules! fields {
+    ($($key:ident = $value:expr),* $(,)?) => {
+        vec![$((stringify!($key), $crate::log::Value::from($value))),*]
+    };
+}
@@ -0,0 +1,54 @@
+//! Checksums of directory trees, as exchanged in
+//! [`MessageKind::Manifest`](crate::MessageKind::Manifest) and
+//! [`MessageKind::List`](crate::MessageKind::List) messages.
+
+use crate::{CHECKSUM_LEN, ManifestEntry, filter::Filter};
+use memmap2::Mmap;
+use std::{fs::File, path::Path};
+
+/// Size and BLAKE3 checksum of a file
+pub fn checksum(path: &Path) -> std::io::Result<(u64, [u8; CHECKSUM_LEN])> {
+    checksum_file(&File::open(path)?)
+}
+
+/// [`checksum`] of an open file
+pub fn checksum_file(file: &File) -> std::io::Result<(u64, [u8; CHECKSUM_LEN])> {
+    let size = file.metadata()?.len();
+    let hash = match size {
+        0 => blake3::hash(&[]),
+        _ => blake3::hash(&unsafe { Mmap::map(file)? }),
+    };
+    Ok((size, *hash.as_bytes()))
+}
+
+/// Every regular file below `root` that `filter` admits, named relative to
+/// `root` with `/` separators. Symlinks are not followed; files that vanish
+/// while walking are left out.
+pub fn build(root: &Path, filter: &Filter) -> Vec<ManifestEntry> {
+    let mut entries = Vec::new();
+    walk(root, Path::new(""), filter, &mut entries);
+    entries
+}
+
+fn walk(root: &Path, rel: &Path, filter: &Filter, entries: &mut Vec<ManifestEntry>) {
+    let Ok(dir) = std::fs::read_dir(root.join(rel)) else {
+        return;
+    };
+    for entry in dir.flatten() {
+        let rel = rel.join(entry.file_name());
+        match entry.file_type() {
+            Ok(t) if t.is_dir() && !filter.excludes_dir(&rel) => walk(root, &rel, filter, entries),
+            Ok(t) if t.is_file() && filter.allows_file(&rel) => {
+                let Ok((size, checksum)) = checksum(&root.join(&rel)) else {
+                    continue;
+                };
+                entries.push(ManifestEntry {
+                    name: rel,
+                    size,
+                    checksum,
+                });
+            }
+            _ => {}
+        }
+    }
+}
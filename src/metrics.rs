@@ -0,0 +1,293 @@
+//! Prometheus metrics: counters, gauges and histograms in a process-wide
+//! [`registry`], rendered in the text exposition format by [`serve`] on
+//! `GET /metrics`.
+
+use anyhow::{Context, Result};
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Bucket bounds for durations, in seconds
+pub const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+/// Bucket bounds for transfer sizes, in bytes
+pub const SIZE_BUCKETS: &[f64] = &[
+    1024.0,
+    16384.0,
+    262_144.0,
+    1_048_576.0,
+    16_777_216.0,
+    268_435_456.0,
+    1_073_741_824.0,
+];
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Per-bucket (non-cumulative) counts, plus one for `+Inf`
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: Mutex<f64>,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: Mutex::new(0.0),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        let i = self
+            .bounds
+            .iter()
+            .position(|&b| v <= b)
+            .unwrap_or(self.bounds.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        *self.sum.lock().unwrap() += v;
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: &'static str,
+    help: &'static str,
+    /// Pre-rendered `key="value",...` without braces
+    labels: String,
+    metric: Metric,
+}
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    entries: Mutex<Vec<Entry>>,
+}
+
+/// The process-wide registry
+pub fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+impl Registry {
+    fn register(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        metric: Metric,
+    ) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| {
+                format!(
+                    "{k}=\"{}\"",
+                    v.replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        self.entries.lock().unwrap().push(Entry {
+            name,
+            help,
+            labels,
+            metric,
+        });
+    }
+
+    pub fn counter(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+    ) -> Arc<Counter> {
+        let c = Arc::new(Counter::default());
+        self.register(name, help, labels, Metric::Counter(c.clone()));
+        c
+    }
+
+    pub fn gauge(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+    ) -> Arc<Gauge> {
+        let g = Arc::new(Gauge::default());
+        self.register(name, help, labels, Metric::Gauge(g.clone()));
+        g
+    }
+
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        bounds: &'static [f64],
+    ) -> Arc<Histogram> {
+        let h = Arc::new(Histogram::new(bounds));
+        self.register(name, help, labels, Metric::Histogram(h.clone()));
+        h
+    }
+
+    /// Render every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let entries = self.entries.lock().unwrap();
+        let mut names: Vec<&'static str> = Vec::new();
+        for e in entries.iter() {
+            if !names.contains(&e.name) {
+                names.push(e.name);
+            }
+        }
+        let mut out = String::new();
+        for name in names {
+            let mut family = entries.iter().filter(|e| e.name == name).peekable();
+            let first = family.peek().unwrap();
+            let kind = match first.metric {
+                Metric::Counter(_) => "counter",
+                Metric::Gauge(_) => "gauge",
+                Metric::Histogram(_) => "histogram",
+            };
+            let _ = writeln!(out, "# HELP {name} {}", first.help);
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for e in family {
+                let braces = |extra: &str| match (e.labels.is_empty(), extra.is_empty()) {
+                    (true, true) => String::new(),
+                    (true, false) => format!("{{{extra}}}"),
+                    (false, true) => format!("{{{}}}", e.labels),
+                    (false, false) => format!("{{{},{extra}}}", e.labels),
+                };
+                match &e.metric {
+                    Metric::Counter(c) => {
+                        let _ =
+                            writeln!(out, "{name}{} {}", braces(""), c.0.load(Ordering::Relaxed));
+                    }
+                    Metric::Gauge(g) => {
+                        let _ =
+                            writeln!(out, "{name}{} {}", braces(""), g.0.load(Ordering::Relaxed));
+                    }
+                    Metric::Histogram(h) => {
+                        let mut cumulative = 0;
+                        for (i, bucket) in h.buckets.iter().enumerate() {
+                            cumulative += bucket.load(Ordering::Relaxed);
+                            let le = h
+                                .bounds
+                                .get(i)
+                                .map_or("+Inf".to_string(), |b| b.to_string());
+                            let _ = writeln!(
+                                out,
+                                "{name}_bucket{} {cumulative}",
+                                braces(&format!("le=\"{le}\""))
+                            );
+                        }
+                        let _ =
+                            writeln!(out, "{name}_sum{} {}", braces(""), *h.sum.lock().unwrap());
+                        let _ = writeln!(
+                            out,
+                            "{name}_count{} {}",
+                            braces(""),
+                            h.count.load(Ordering::Relaxed)
+                        );
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Serve `GET /metrics` on `addr` until the process exits
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Bind metrics listener on {addr}"))?;
+    tokio::spawn(async move {
+        loop {
+            let Ok((conn, _)) = listener.accept().await else {
+                continue;
+            };
+            tokio::spawn(respond(conn));
+        }
+    });
+    Ok(())
+}
+
+async fn respond(mut conn: TcpStream) {
+    let mut req = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") && req.len() < 8192 {
+        match conn.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => req.extend_from_slice(&buf[..n]),
+        }
+    }
+    let line = String::from_utf8_lossy(&req);
+    let mut parts = line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", registry().render()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = conn.write_all(response.as_bytes()).await;
+}
@@ -0,0 +1,346 @@
+//! Datagrams of the `--multicast` distribution mode.
+//!
+//! A watcher with many receivers on one network sends a large file's
+//! content to a multicast group (or broadcast address) once. Every receiver
+//! listening there keeps what reaches it, and the file's
+//! [`MessageKind::Multicast`](crate::MessageKind::Multicast) message on each
+//! TCP connection then only carries what that receiver missed. Every `group`
+//! data packets are followed by a parity packet, their XOR, from which a
+//! receiver rebuilds any one of them it lost without asking.
+//!
+//! ```text
+//! [u8; 16] id | u64 size | u32 index | u8 group | u8 kind | data [| [u8; 32] tag]
+//! ```
+//!
+//! Data packet `index` holds the file's bytes from `index * PACKET_LEN`;
+//! parity packet `index` covers data packets `index * group` on, each
+//! zero-padded to [`PACKET_LEN`]. With a PSK every datagram ends with a
+//! keyed BLAKE3 tag of the rest, so nobody else on the network can plant
+//! content. The whole file is checked against the checksum from the TCP
+//! connection either way.
+
+use crate::CHECKSUM_LEN;
+
+/// Data bytes per datagram; with the header and tag a datagram fits a
+/// 1500-byte Ethernet frame
+pub const PACKET_LEN: usize = 1400;
+
+/// Largest datagram on the wire
+pub const MAX_DATAGRAM: usize = HEADER_LEN + PACKET_LEN + TAG_LEN;
+
+const HEADER_LEN: usize = 16 + 8 + 4 + 1 + 1;
+const TAG_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Data = 0x00,
+    Parity = 0x01,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Datagram<'a> {
+    /// The send this belongs to, see [`id`]
+    pub id: [u8; 16],
+    /// Of the whole file
+    pub size: u64,
+    pub index: u32,
+    /// Data packets per parity packet, 0 for none
+    pub group: u8,
+    pub kind: Kind,
+    pub data: &'a [u8],
+}
+
+/// Id of the multicast send of content with `checksum` under `transfer_id`,
+/// so a later change under the same transfer id is another send
+pub fn id(transfer_id: &[u8; 16], checksum: &[u8; CHECKSUM_LEN]) -> [u8; 16] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(transfer_id).update(checksum);
+    hasher.finalize().as_bytes()[..16].try_into().unwrap()
+}
+
+/// Key of the datagram tags, from the handshake key derived from the PSK
+pub fn tag_key(key: &[u8; 32]) -> [u8; 32] {
+    blake3::derive_key("fast-sync multicast datagram tags", key)
+}
+
+/// Data packets of a `size`-byte file
+pub fn packets(size: u64) -> u64 {
+    size.div_ceil(PACKET_LEN as u64)
+}
+
+/// Bytes in data packet `index` of a `size`-byte file
+pub fn data_len(size: u64, index: u64) -> usize {
+    size.saturating_sub(index * PACKET_LEN as u64)
+        .min(PACKET_LEN as u64) as usize
+}
+
+/// XOR `data` into `parity`
+pub fn xor_into(parity: &mut [u8], data: &[u8]) {
+    for (p, d) in parity.iter_mut().zip(data) {
+        *p ^= d;
+    }
+}
+
+impl Datagram<'_> {
+    /// Write the datagram into `buf`, tagged with `key`
+    pub fn encode(&self, key: Option<&[u8; 32]>, buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.extend_from_slice(&self.id);
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.index.to_be_bytes());
+        buf.push(self.group);
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(self.data);
+        if let Some(key) = key {
+            let tag = blake3::keyed_hash(key, buf);
+            buf.extend_from_slice(tag.as_bytes());
+        }
+    }
+}
+
+/// Parse a datagram whose tag checks out with `key` and whose packet fits
+/// its file; anything else is `None`
+pub fn decode<'a>(buf: &'a [u8], key: Option<&[u8; 32]>) -> Option<Datagram<'a>> {
+    let buf = match key {
+        Some(key) => {
+            let (body, tag) = buf.split_at_checked(buf.len().checked_sub(TAG_LEN)?)?;
+            // blake3::Hash equality is constant-time
+            let tag = blake3::Hash::from_bytes(tag.try_into().unwrap());
+            (blake3::keyed_hash(key, body) == tag).then_some(body)?
+        }
+        None => buf,
+    };
+    let (head, data) = buf.split_at_checked(HEADER_LEN)?;
+    let id = head[..16].try_into().unwrap();
+    let size = u64::from_be_bytes(head[16..24].try_into().unwrap());
+    let index = u32::from_be_bytes(head[24..28].try_into().unwrap());
+    let group = head[28];
+    let kind = match head[29] {
+        0x00 => Kind::Data,
+        0x01 => Kind::Parity,
+        _ => return None,
+    };
+    let packets = packets(size);
+    let fits = match kind {
+        Kind::Data => (index as u64) < packets && data.len() == data_len(size, index as u64),
+        Kind::Parity => {
+            group > 0 && (index as u64) < packets.div_ceil(group as u64) && data.len() == PACKET_LEN
+        }
+    };
+    fits.then_some(Datagram {
+        id,
+        size,
+        index,
+        group,
+        kind,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: [u8; 16] = [7; 16];
+    const KEY: [u8; 32] = [9; 32];
+
+    fn encoded(datagram: &Datagram, key: Option<&[u8; 32]>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        datagram.encode(key, &mut buf);
+        buf
+    }
+
+    /// Every datagram of `content`, with a parity packet after each `group`
+    /// data packets, the way the watcher sends them
+    fn datagrams(content: &[u8], group: u8) -> Vec<Vec<u8>> {
+        let size = content.len() as u64;
+        let packets = packets(size) as usize;
+        let mut sent = Vec::new();
+        let mut parity = vec![0; PACKET_LEN];
+        for (index, data) in content.chunks(PACKET_LEN).enumerate() {
+            let datagram = Datagram {
+                id: ID,
+                size,
+                index: index as u32,
+                group,
+                kind: Kind::Data,
+                data,
+            };
+            sent.push(encoded(&datagram, Some(&KEY)));
+            xor_into(&mut parity, data);
+            if (index + 1) % group as usize == 0 || index + 1 == packets {
+                let parity_packet = Datagram {
+                    index: (index / group as usize) as u32,
+                    kind: Kind::Parity,
+                    data: &parity,
+                    ..datagram
+                };
+                sent.push(encoded(&parity_packet, Some(&KEY)));
+                parity.fill(0);
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn datagrams_round_trip() {
+        let data = [0xab; 100];
+        let datagram = Datagram {
+            id: ID,
+            size: PACKET_LEN as u64 + 100,
+            index: 1,
+            group: 4,
+            kind: Kind::Data,
+            data: &data,
+        };
+        for key in [None, Some(&KEY)] {
+            let buf = encoded(&datagram, key);
+            assert!(buf.len() <= MAX_DATAGRAM);
+            let decoded = decode(&buf, key).unwrap();
+            assert_eq!(decoded.id, ID);
+            assert_eq!(decoded.size, datagram.size);
+            assert_eq!(decoded.index, 1);
+            assert_eq!(decoded.group, 4);
+            assert_eq!(decoded.kind, Kind::Data);
+            assert_eq!(decoded.data, data);
+        }
+
+        let buf = encoded(&datagram, Some(&KEY));
+        assert!(decode(&buf, Some(&[1; 32])).is_none(), "wrong key");
+        assert!(
+            decode(&buf[..buf.len() - 1], Some(&KEY)).is_none(),
+            "cut short"
+        );
+        let mut tampered = buf.clone();
+        tampered[HEADER_LEN] ^= 1;
+        assert!(decode(&tampered, Some(&KEY)).is_none(), "tampered");
+        let untagged = encoded(&datagram, None);
+        assert!(decode(&untagged, Some(&KEY)).is_none(), "untagged");
+    }
+
+    #[test]
+    fn parity_rebuilds_one_lost_packet_per_group() {
+        let content: Vec<u8> = (0..PACKET_LEN * 9 + 123)
+            .map(|i| (i * 31 % 251) as u8)
+            .collect();
+        let group = 4;
+        let sent = datagrams(&content, group);
+        let datagrams: Vec<_> = sent
+            .iter()
+            .map(|buf| decode(buf, Some(&KEY)).unwrap())
+            .collect();
+
+        // Lose the second packet of every group, the short last one included
+        let lost = |d: &Datagram| d.kind == Kind::Data && d.index % group as u32 == 1;
+        let mut rebuilt = vec![0; content.len()];
+        for d in datagrams
+            .iter()
+            .filter(|d| d.kind == Kind::Data && !lost(d))
+        {
+            let at = d.index as usize * PACKET_LEN;
+            rebuilt[at..at + d.data.len()].copy_from_slice(d.data);
+        }
+        let parities = datagrams.iter().filter(|d| d.kind == Kind::Parity);
+        assert_eq!(
+            parities.clone().count(),
+            packets(content.len() as u64).div_ceil(4) as usize
+        );
+        for parity in parities {
+            let first = parity.index as u64 * group as u64;
+            let members = first..(first + group as u64).min(packets(content.len() as u64));
+            let lost = first + 1;
+            assert!(members.contains(&lost));
+            let mut packet = parity.data.to_vec();
+            for i in members.filter(|&i| i != lost) {
+                let at = i as usize * PACKET_LEN;
+                xor_into(
+                    &mut packet,
+                    &rebuilt[at..at + data_len(content.len() as u64, i)],
+                );
+            }
+            let at = lost as usize * PACKET_LEN;
+            let len = data_len(content.len() as u64, lost);
+            rebuilt[at..at + len].copy_from_slice(&packet[..len]);
+        }
+        assert_eq!(rebuilt, content);
+    }
+
+    #[test]
+    fn datagrams_that_dont_fit_their_file_are_refused() {
+        let full = [0; PACKET_LEN];
+        let size = PACKET_LEN as u64 * 2 + 10;
+        let datagram = Datagram {
+            id: ID,
+            size,
+            index: 0,
+            group: 2,
+            kind: Kind::Data,
+            data: &full,
+        };
+        assert!(decode(&encoded(&datagram, None), None).is_some());
+        let last = Datagram {
+            index: 2,
+            data: &full[..10],
+            ..datagram
+        };
+        assert!(decode(&encoded(&last, None), None).is_some());
+        let parity = Datagram {
+            index: 1,
+            kind: Kind::Parity,
+            ..datagram
+        };
+        assert!(decode(&encoded(&parity, None), None).is_some());
+
+        let refused = [
+            (
+                "index past the last packet",
+                Datagram {
+                    index: 3,
+                    ..datagram
+                },
+            ),
+            (
+                "short packet",
+                Datagram {
+                    data: &full[..10],
+                    ..datagram
+                },
+            ),
+            (
+                "long last packet",
+                Datagram {
+                    index: 2,
+                    ..datagram
+                },
+            ),
+            (
+                "parity past the last group",
+                Datagram { index: 2, ..parity },
+            ),
+            ("parity without groups", Datagram { group: 0, ..parity }),
+            (
+                "short parity",
+                Datagram {
+                    data: &full[..10],
+                    ..parity
+                },
+            ),
+            (
+                "empty file",
+                Datagram {
+                    size: 0,
+                    data: &[],
+                    ..datagram
+                },
+            ),
+        ];
+        for (why, datagram) in refused {
+            assert!(decode(&encoded(&datagram, None), None).is_none(), "{why}");
+        }
+
+        let mut unknown_kind = encoded(&datagram, None);
+        unknown_kind[HEADER_LEN - 1] = 2;
+        assert!(decode(&unknown_kind, None).is_none(), "unknown kind");
+        assert!(decode(&[0; HEADER_LEN - 1], None).is_none(), "short header");
+    }
+}
@@ -0,0 +1,90 @@
+//! BLAKE3 over several threads, for files large enough that hashing them on
+//! one core holds up the transfer.
+//!
+//! The input is split along BLAKE3's own tree, so the result is the hash
+//! [`blake3::hash`] gives for any number of threads.
+
+use blake3::{
+    Hasher,
+    hazmat::{
+        ChainingValue, HasherExt, Mode, left_subtree_len, merge_subtrees_non_root,
+        merge_subtrees_root,
+    },
+};
+
+/// Inputs smaller than this are hashed on one thread whatever `--hash-threads`
+/// says; starting threads would cost more than it saves
+pub const PARALLEL_MIN: u64 = 4 << 20;
+
+/// Smallest part of the input given a thread of its own
+const MIN_SUBTREE: usize = 1 << 20;
+
+/// BLAKE3 of `data`, with up to `threads` threads
+pub fn hash(data: &[u8], threads: usize) -> blake3::Hash {
+    if threads <= 1 || data.len() <= MIN_SUBTREE {
+        return blake3::hash(data);
+    }
+    let (left, right) = split(data, 0, threads);
+    merge_subtrees_root(&left, &right, Mode::Hash)
+}
+
+/// Chaining value of the subtree holding `data`, which starts at `offset`
+fn subtree(data: &[u8], offset: u64, threads: usize) -> ChainingValue {
+    if threads <= 1 || data.len() <= MIN_SUBTREE {
+        return Hasher::new()
+            .set_input_offset(offset)
+            .update(data)
+            .finalize_non_root();
+    }
+    let (left, right) = split(data, offset, threads);
+    merge_subtrees_non_root(&left, &right, Mode::Hash)
+}
+
+/// Hash both children of the subtree holding `data` at once, sharing
+/// `threads` between them. The left one is never the smaller.
+fn split(data: &[u8], offset: u64, threads: usize) -> (ChainingValue, ChainingValue) {
+    let (left, right) = data.split_at(left_subtree_len(data.len() as u64) as usize);
+    let right_offset = offset + left.len() as u64;
+    std::thread::scope(|scope| {
+        let left = scope.spawn(|| subtree(left, offset, threads - threads / 2));
+        let right = subtree(right, right_offset, threads / 2);
+        (left.join().expect("hashing thread panicked"), right)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_blake3_for_any_number_of_threads() {
+        let sizes = [
+            0,
+            1,
+            1024,
+            1025,
+            MIN_SUBTREE - 1,
+            MIN_SUBTREE,
+            MIN_SUBTREE + 1,
+            2 * MIN_SUBTREE,
+            3 * MIN_SUBTREE,
+            3 * MIN_SUBTREE + 1025,
+            PARALLEL_MIN as usize,
+            33 * MIN_SUBTREE + 4097,
+        ];
+        let data: Vec<u8> = (0..*sizes.last().unwrap())
+            .map(|i| (i % 251) as u8)
+            .collect();
+        for size in sizes {
+            let data = &data[..size];
+            let expected = blake3::hash(data);
+            for threads in [1, 2, 3, 4, 7, 16] {
+                assert_eq!(
+                    hash(data, threads),
+                    expected,
+                    "{size} bytes, {threads} threads"
+                );
+            }
+        }
+    }
+}
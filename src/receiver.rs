@@ -0,0 +1,487 @@
+//! Receiving side of the protocol for programs that embed fast-sync instead
+//! of running the `client`.
+//!
+//! A [`Receiver`] accepts senders and applies what they send to its
+//! destination directory the way the [`client`](crate::client) does, with its
+//! options, and hands every applied change to the program as an [`Event`].
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use fast_sync::receiver::{Event, Receiver, ReceiverOptions};
+//!
+//! let opts = ReceiverOptions::from_args(["--psk-file", "/etc/fast-sync.psk"])?;
+//! let receiver = Receiver::bind("0.0.0.0:5001", "/srv/incoming", opts).await?;
+//! receiver
+//!     .serve(|event| {
+//!         if let Event::Received { path, .. } = event {
+//!             println!("got {}", path.display());
+//!         }
+//!     })
+//!     .await
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsString,
+    net::{SocketAddr, UdpSocket},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    sync::watch,
+};
+
+use crate::{
+    MAX_NAME_LEN,
+    client::{self, Args, ReceiveOptions},
+    config,
+    storage::Storage,
+};
+
+/// Directory in each destination root that holds the temporary files of
+/// transfers in progress, out of the synced tree, unless the `client` has
+/// `--tmp-dir`
+pub const TMP_DIR: &str = ".fast-sync-tmp";
+
+/// How a [`Receiver`] receives: the `client`'s options
+#[derive(Debug)]
+pub struct ReceiverOptions {
+    args: Args,
+    table: config::Table,
+}
+
+impl ReceiverOptions {
+    /// Options as the `client` takes them on its command line, e.g.
+    /// `["--psk-file", "/etc/fast-sync.psk", "--preserve", "mode"]`,
+    /// `--config` files and their `[[sender]]` tables included. Those about
+    /// listening, dialing and serving HTTP are ignored.
+    pub fn from_args<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let argv = std::iter::once(OsString::from("client"))
+            .chain(args.into_iter().map(Into::into))
+            .collect();
+        let (args, _, table) = config::parse_from::<Args>(argv)?;
+        Ok(Self { args, table })
+    }
+}
+
+impl Default for ReceiverOptions {
+    fn default() -> Self {
+        Self::from_args::<_, OsString>([]).expect("the client's defaults parse")
+    }
+}
+
+/// A change a sender made, after it was applied
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A file was received, verified and put in place at `path`; without a
+    /// checksum under `--integrity tcp-only`
+    Received {
+        sender: String,
+        name: PathBuf,
+        path: PathBuf,
+        size: u64,
+        checksum: Option<[u8; 32]>,
+    },
+    /// `name` was removed
+    Deleted { sender: String, name: PathBuf },
+    /// `from` was moved to `to`
+    Renamed {
+        sender: String,
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// `name` was made a symlink to `target`
+    Linked {
+        sender: String,
+        name: PathBuf,
+        target: PathBuf,
+    },
+    /// `name` was made another name of `existing`
+    HardLinked {
+        sender: String,
+        name: PathBuf,
+        existing: PathBuf,
+    },
+    /// `name` grew in place to `size` bytes
+    Appended {
+        sender: String,
+        name: PathBuf,
+        size: u64,
+    },
+}
+
+/// Listening socket and where received files go
+pub struct Receiver {
+    listener: TcpListener,
+    opts: ReceiveOptions,
+    multicast: Option<UdpSocket>,
+    stale_part_age: Duration,
+}
+
+impl Receiver {
+    /// Listen on `addr` for senders writing into `dest_dir`, created if
+    /// missing
+    pub async fn bind(
+        addr: impl ToSocketAddrs,
+        dest_dir: impl AsRef<Path>,
+        mut opts: ReceiverOptions,
+    ) -> Result<Self> {
+        opts.args.dest_dir = dest_dir.as_ref().to_path_buf();
+        let addr = tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .context("No address to listen on")?;
+        let listener = client::listen(addr).with_context(|| format!("Listen on {addr}"))?;
+        // The `--on-received` runner goes on by itself
+        let (receive, _, multicast) = client::receive_options(&opts.args, &opts.table)?;
+        Ok(Self {
+            listener,
+            opts: receive,
+            multicast,
+            stale_part_age: Duration::from_secs(opts.args.stale_part_secs),
+        })
+    }
+
+    /// Keep received files in `storage` instead of the destination
+    /// directory, which still holds the temporary area
+    pub fn with_storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.opts.storage = Arc::new(storage);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept senders until accepting fails, serving each on its own task and
+    /// calling `handler` with every change applied. A connection that breaks
+    /// the protocol is logged and dropped.
+    pub async fn serve<F>(self, handler: F) -> Result<()>
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        let Self {
+            listener,
+            mut opts,
+            multicast,
+            stale_part_age,
+        } = self;
+        opts.on_change = Some(Box::new(handler));
+        let opts = Arc::new(opts);
+        let helpers = client::spawn_helpers(&opts, multicast, stale_part_age);
+        // Never set: connections last until their senders leave
+        let (_stop, stop) = watch::channel(false);
+        let accepted = loop {
+            match listener.accept().await {
+                Ok((conn, peer)) => {
+                    tokio::spawn(client::serve_peer(conn, peer, opts.clone(), stop.clone()));
+                }
+                Err(e) => break e,
+            }
+        };
+        helpers.abort();
+        Err(accepted.into())
+    }
+}
+
+/// Is the sender-supplied `rel` a name a receiver takes: relative, without
+/// `..`, no longer than [`MAX_NAME_LEN`] and outside [`TMP_DIR`]?
+pub fn valid_name(rel: &Path) -> Result<()> {
+    anyhow::ensure!(
+        rel.as_os_str().len() <= MAX_NAME_LEN,
+        "longer than {MAX_NAME_LEN} bytes"
+    );
+    anyhow::ensure!(
+        !rel.as_os_str().is_empty() && rel.components().all(|c| matches!(c, Component::Normal(_))),
+        "must be a relative path without '..'"
+    );
+    anyhow::ensure!(
+        !rel.starts_with(TMP_DIR),
+        "{TMP_DIR} is reserved for temporary files"
+    );
+    Ok(())
+}
+
+/// Map a sender-supplied name to a path inside `root` without touching the
+/// filesystem beyond lookups. `root` must be canonical.
+pub fn check_name(root: &Path, rel: &Path) -> Result<PathBuf> {
+    valid_name(rel)?;
+    let dest_path = root.join(rel);
+
+    // Check the deepest existing ancestor, so a symlinked directory can't
+    // lead us outside the root
+    let mut existing = dest_path.parent().unwrap_or(root);
+    while !existing.exists() {
+        existing = existing.parent().unwrap_or(root);
+    }
+    anyhow::ensure!(
+        std::fs::canonicalize(existing)?.starts_with(root),
+        "escapes the destination directory"
+    );
+    Ok(dest_path)
+}
+
+/// [`check_name`], then create the parent directories
+pub fn resolve_dest(root: &Path, name: &Path) -> Result<PathBuf> {
+    let dest_path = check_name(root, name)?;
+    let parent = dest_path.parent().unwrap_or(root);
+    std::fs::create_dir_all(parent)?;
+    anyhow::ensure!(
+        std::fs::canonicalize(parent)?.starts_with(root),
+        "escapes the destination directory"
+    );
+    Ok(dest_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Ack, parallel_hash,
+        sender::{Sender, SenderOptions},
+        storage::UploadCommand,
+    };
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn names_stay_inside_the_destination() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-names-{}", std::process::id()));
+        let (root, outside) = (dir.join("root"), dir.join("outside"));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        for name in ["a.txt", "sub/a.txt", "new/deeper/a.txt", "sub/./a.txt"] {
+            let path = resolve_dest(&root, Path::new(name)).unwrap();
+            assert!(path.starts_with(&root), "{name}");
+        }
+        let long = "a/".repeat(MAX_NAME_LEN / 2) + "a";
+        for name in [
+            "",
+            "/etc/passwd",
+            "..",
+            "../a.txt",
+            "sub/../../a.txt",
+            "sub/..",
+            "out/a.txt",
+            "out/new/a.txt",
+            ".fast-sync-tmp/a.txt",
+            &long,
+        ] {
+            assert!(check_name(&root, Path::new(name)).is_err(), "{name}");
+            assert!(resolve_dest(&root, Path::new(name)).is_err(), "{name}");
+        }
+
+        // Raw names are bytes: not UTF-8, and with `/` and `..` in them
+        // like any other
+        let raw = |name: &[u8]| Path::new(std::ffi::OsStr::from_bytes(name)).to_path_buf();
+        for name in [
+            &b"caf\xe9.txt"[..],
+            b"sub/\xff\xfe",
+            b"new/\xff/a",
+            b"..\xff",
+            b"\xff..",
+        ] {
+            let path = resolve_dest(&root, &raw(name)).unwrap();
+            assert!(path.starts_with(&root), "{name:?}");
+            assert_eq!(path, root.join(raw(name)), "{name:?}");
+        }
+        for name in [
+            &b"/\xff"[..],
+            b"../\xff",
+            b"\xff/../../a",
+            b"sub/\xff/../../../a",
+            b"out/\xff",
+        ] {
+            assert!(check_name(&root, &raw(name)).is_err(), "{name:?}");
+            assert!(resolve_dest(&root, &raw(name)).is_err(), "{name:?}");
+        }
+        // Nothing was created on the way to refusing them
+        assert!(!outside.join("new").exists());
+        assert!(!root.join("a").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn senders_and_receivers_embed() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-embed-{}", std::process::id()));
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub/a.txt"), b"hello").unwrap();
+
+        let receiver = Receiver::bind("127.0.0.1:0", &dest, ReceiverOptions::default())
+            .await
+            .unwrap();
+        let addr = receiver.local_addr().unwrap().to_string();
+        let (tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(receiver.serve(move |event| {
+            let _ = tx.send(event);
+        }));
+
+        let root = format!("{}:in", src.display());
+        let opts = SenderOptions::from_args(["--watch-dir", &root]).unwrap();
+        let mut sender = Sender::connect(&addr, &opts).await.unwrap();
+        let ack = sender.send_file(src.join("sub/a.txt")).await.unwrap();
+        assert_eq!(ack, Some(Ack::Ok));
+        assert_eq!(std::fs::read(dest.join("in/sub/a.txt")).unwrap(), b"hello");
+        match events.recv().await.unwrap() {
+            Event::Received {
+                name, size, path, ..
+            } => {
+                assert_eq!(name, Path::new("in/sub/a.txt"));
+                assert_eq!(size, 5);
+                assert_eq!(path, dest.canonicalize().unwrap().join("in/sub/a.txt"));
+            }
+            other => panic!("{other:?}"),
+        }
+
+        // Unchanged content isn't sent again
+        let ack = sender.send_file(src.join("sub/a.txt")).await.unwrap();
+        assert_eq!(ack, Some(Ack::Unchanged));
+
+        let (from, to) = (src.join("sub/a.txt"), src.join("b.txt"));
+        assert_eq!(sender.rename(&from, &to).await.unwrap(), Ack::Ok);
+        assert!(dest.join("in/b.txt").is_file());
+        assert!(matches!(events.recv().await, Some(Event::Renamed { .. })));
+        assert_eq!(sender.delete(&to).await.unwrap(), Ack::Ok);
+        assert!(!dest.join("in/b.txt").exists());
+        assert!(matches!(events.recv().await, Some(Event::Deleted { .. })));
+
+        // Gone before it was sent
+        assert_eq!(sender.send_file(src.join("none")).await.unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deltas_rebuild_changed_files() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-delta-{}", std::process::id()));
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        let receiver = Receiver::bind("127.0.0.1:0", &dest, ReceiverOptions::default())
+            .await
+            .unwrap();
+        let addr = receiver.local_addr().unwrap().to_string();
+        tokio::spawn(receiver.serve(|_| {}));
+        let root = src.display().to_string();
+        // Files no larger than a block go whole
+        let opts = SenderOptions::from_args([
+            "--watch-dir",
+            &root,
+            "--delta",
+            "--delta-block-size",
+            "512",
+        ])
+        .unwrap();
+        let mut sender = Sender::connect(&addr, &opts).await.unwrap();
+
+        let old: Vec<u8> = (0..4000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut inserted = old.clone();
+        inserted.insert(1000, 0xff);
+        let mut grown = old.clone();
+        grown.extend((0..3000u32).map(|i| (i % 13) as u8));
+        let cases = [
+            ("unchanged", old.clone(), Ack::Unchanged),
+            ("inserted", inserted, Ack::Ok),
+            ("truncated", old[..1500].to_vec(), Ack::Ok),
+            ("grown", grown, Ack::Ok),
+        ];
+        for (name, new, expected) in cases {
+            std::fs::write(dest.join(name), &old).unwrap();
+            std::fs::write(src.join(name), &new).unwrap();
+            let ack = sender.send_file(src.join(name)).await.unwrap();
+            assert_eq!(ack, Some(expected), "{name}");
+            assert!(std::fs::read(dest.join(name)).unwrap() == new, "{name}");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn blocking_work_leaves_a_current_thread_runtime_alone() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-blocking-{}", std::process::id()));
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        std::fs::create_dir_all(&src).unwrap();
+        let data: Vec<u8> = (0..parallel_hash::PARALLEL_MIN as u32 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(src.join("big"), &data).unwrap();
+
+        // Quota counts, --hash-threads, the writer thread and --pre-send
+        // all block
+        let opts = ReceiverOptions::from_args([
+            "--per-sender-dir",
+            "--quota",
+            "1G",
+            "--hash-threads",
+            "2",
+            "--io-backend",
+            "thread",
+        ])
+        .unwrap();
+        let receiver = Receiver::bind("127.0.0.1:0", &dest, opts).await.unwrap();
+        let addr = receiver.local_addr().unwrap().to_string();
+        let (tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(receiver.serve(move |event| {
+            let _ = tx.send(event);
+        }));
+        let root = src.display().to_string();
+        let opts = SenderOptions::from_args(["--watch-dir", &root, "--pre-send", "cat"]).unwrap();
+        let mut sender = Sender::connect(&addr, &opts).await.unwrap();
+        let ack = sender.send_file(src.join("big")).await.unwrap();
+        assert_eq!(ack, Some(Ack::Ok));
+        match events.recv().await.unwrap() {
+            Event::Received { path, .. } => assert!(std::fs::read(path).unwrap() == data),
+            other => panic!("{other:?}"),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_commands_store_objects() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-upload-{}", std::process::id()));
+        let (src, dest, store) = (dir.join("src"), dir.join("dest"), dir.join("store"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(src.join("sub/a.txt"), b"hello").unwrap();
+
+        let object = format!(
+            "{}/\"$(echo \"$FASTSYNC_NAME\" | tr / _)\"",
+            store.display()
+        );
+        let storage = UploadCommand::new(format!(
+            "test \"$FASTSYNC_CHECKSUM\" = {} && cat > {object}",
+            blake3::hash(b"hello").to_hex()
+        ))
+        .with_delete(format!("rm {object}"));
+        let receiver = Receiver::bind("127.0.0.1:0", &dest, ReceiverOptions::default())
+            .await
+            .unwrap()
+            .with_storage(storage);
+        let addr = receiver.local_addr().unwrap().to_string();
+        tokio::spawn(receiver.serve(|_| {}));
+
+        let root = src.display().to_string();
+        let opts = SenderOptions::from_args(["--watch-dir", &root, "--delta"]).unwrap();
+        let mut sender = Sender::connect(&addr, &opts).await.unwrap();
+        let ack = sender.send_file(src.join("sub/a.txt")).await.unwrap();
+        assert_eq!(ack, Some(Ack::Ok));
+        assert_eq!(std::fs::read(store.join("sub_a.txt")).unwrap(), b"hello");
+        assert!(!dest.join("sub/a.txt").exists());
+        assert!(!dest.join(TMP_DIR).join("sub").exists());
+
+        // Objects can't be moved; the sender sends the content again
+        let (from, to) = (src.join("sub/a.txt"), src.join("b.txt"));
+        assert_ne!(sender.rename(&from, &to).await.unwrap(), Ack::Ok);
+        assert_eq!(sender.delete(&from).await.unwrap(), Ack::Ok);
+        assert!(!store.join("sub_a.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
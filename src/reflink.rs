@@ -0,0 +1,103 @@
+//! Local file copies that share blocks where the filesystem allows.
+//!
+//! On btrfs, XFS and other filesystems with reflinks a clone costs no data
+//! I/O and no extra space until one of the copies changes. Elsewhere
+//! `copy_file_range(2)` still keeps the data in the kernel, and a plain copy
+//! is the last resort.
+
+use std::{
+    fs::File,
+    io::{self, Seek},
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+/// How [`copy`] made the copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Shares the source's blocks (`FICLONE`)
+    Reflink,
+    /// Copied within the kernel (`copy_file_range(2)`)
+    Kernel,
+    /// Read and written through userspace
+    Userspace,
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Method::Reflink => "reflink",
+            Method::Kernel => "copy_file_range",
+            Method::Userspace => "copy",
+        })
+    }
+}
+
+/// Errors that mean "not here", as opposed to a failed copy
+fn unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(
+            libc::EOPNOTSUPP
+                | libc::ENOTTY
+                | libc::EXDEV
+                | libc::EINVAL
+                | libc::ENOSYS
+                | libc::EBADF
+        )
+    )
+}
+
+/// Copy `src` to a new or truncated `dst`, cheapest way first
+pub fn copy(src: &Path, dst: &Path) -> io::Result<Method> {
+    let from = File::open(src)?;
+    let to = File::create(dst)?;
+    if unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) } == 0 {
+        return Ok(Method::Reflink);
+    }
+    let e = io::Error::last_os_error();
+    if !unsupported(&e) {
+        return Err(e);
+    }
+    let len = from.metadata()?.len();
+    match copy_range(&from, &to, len) {
+        Ok(()) => return Ok(Method::Kernel),
+        Err(e) if !unsupported(&e) => return Err(e),
+        // Start over: a partial kernel copy may have left bytes behind
+        Err(_) => {
+            to.set_len(0)?;
+            (&from).rewind()?;
+            (&to).rewind()?;
+        }
+    }
+    io::copy(&mut &from, &mut &to)?;
+    Ok(Method::Userspace)
+}
+
+fn copy_range(from: &File, to: &File, len: u64) -> io::Result<()> {
+    let mut left = len;
+    while left > 0 {
+        let chunk = left.min(1 << 30) as usize;
+        let n = unsafe {
+            libc::copy_file_range(
+                from.as_raw_fd(),
+                std::ptr::null_mut(),
+                to.as_raw_fd(),
+                std::ptr::null_mut(),
+                chunk,
+                0,
+            )
+        };
+        match n {
+            n if n < 0 => return Err(io::Error::last_os_error()),
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "source shrank while copying",
+                ));
+            }
+            n => left -= n as u64,
+        }
+    }
+    Ok(())
+}
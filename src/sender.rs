@@ -0,0 +1,128 @@
+//! Sending side of the protocol for programs that embed fast-sync instead of
+//! running the `watcher`.
+//!
+//! A [`Sender`] is one connection to a receiver. It sends files, deletes and
+//! renames one at a time, the way the [`watcher`](crate::watcher) sends them
+//! and with its options, and returns each [`Ack`]. Files under a
+//! `--watch-dir` go by their name relative to it, others by their file name.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use fast_sync::sender::{Sender, SenderOptions};
+//!
+//! let opts = SenderOptions::from_args(["--delta", "--watch-dir", "/var/spool/out:2025"])?;
+//! let mut sender = Sender::connect("10.0.0.2:5001", &opts).await?;
+//! let ack = sender.send_file("/var/spool/out/report.csv").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use std::{ffi::OsString, path::Path, time::Instant};
+
+use crate::{
+    Ack, config,
+    handshake::{Features, Params},
+    warn,
+    watcher::{self, Args, Destination, Job, Link, SendOptions},
+};
+
+/// How a [`Sender`] sends: the `watcher`'s options
+#[derive(Debug)]
+pub struct SenderOptions {
+    args: Args,
+}
+
+impl SenderOptions {
+    /// Options as the `watcher` takes them on its command line, e.g.
+    /// `["--delta", "--psk-file", "/etc/fast-sync.psk"]`, `--config` files
+    /// included. Those about watching and destinations are ignored, and
+    /// there is no watch root unless `--watch-dir` is given.
+    pub fn from_args<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let argv = std::iter::once(OsString::from("watcher"))
+            .chain(args.into_iter().map(Into::into))
+            .collect();
+        let (mut args, matches, _) = config::parse_from::<Args>(argv)?;
+        if matches.value_source("watch_dir") == Some(ValueSource::DefaultValue) {
+            args.watch_dir.clear();
+        }
+        Ok(Self { args })
+    }
+}
+
+impl Default for SenderOptions {
+    fn default() -> Self {
+        Self::from_args::<_, OsString>([]).expect("the watcher's defaults parse")
+    }
+}
+
+/// A connection to a receiver
+pub struct Sender {
+    link: Link,
+    dest: Destination,
+    opts: SendOptions,
+}
+
+impl Sender {
+    /// Connect to the receiver at `HOST[:PORT]`, agree on the protocol and
+    /// authenticate
+    pub async fn connect(addr: &str, opts: &SenderOptions) -> Result<Self> {
+        let dest = match Destination::parse_list(addr, opts.args.dest_port).as_slice() {
+            [dest] => dest.clone(),
+            _ => anyhow::bail!("{addr:?} is not one HOST[:PORT]"),
+        };
+        let opts = watcher::send_options(&opts.args)?;
+        let link = Link::connect(&dest, &opts)
+            .await
+            .with_context(|| format!("Connect to {addr}"))?;
+        Ok(Self { link, dest, opts })
+    }
+
+    /// What the receiver agreed to
+    pub fn params(&self) -> Params {
+        self.link.params()
+    }
+
+    /// Send the file at `path`, as a delta, resume or append where the
+    /// options and the receiver allow. `None` if there was nothing to send:
+    /// the file is gone or the options skip it.
+    pub async fn send_file(&mut self, path: impl AsRef<Path>) -> Result<Option<Ack>> {
+        let job = Job::File(path.as_ref().to_path_buf(), Instant::now());
+        self.send(job).await
+    }
+
+    /// Remove `path` on the receiver and, if it is a directory, everything
+    /// under it
+    pub async fn delete(&mut self, path: impl AsRef<Path>) -> Result<Ack> {
+        let job = Job::Delete(path.as_ref().to_path_buf());
+        self.send(job).await?.context("Delete not sent")
+    }
+
+    /// Move `from` to `to` on the receiver; fails if it has no `from`
+    pub async fn rename(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<Ack> {
+        let job = Job::Rename(from.as_ref().to_path_buf(), to.as_ref().to_path_buf());
+        self.send(job).await?.context("Rename not sent")
+    }
+
+    /// Check that the receiver is still there
+    pub async fn heartbeat(&mut self) -> Result<()> {
+        anyhow::ensure!(
+            self.params().features.contains(Features::HEARTBEAT),
+            "Receiver does not take heartbeats"
+        );
+        self.send(Job::Heartbeat).await.map(drop)
+    }
+
+    async fn send(&mut self, job: Job) -> Result<Option<Ack>> {
+        let answer = self.link.exchange(job, &self.dest, &self.opts).await?;
+        if let Some((ack, Some(reason))) = &answer {
+            warn!("[!] Receiver answered {ack:?}: {reason}");
+        }
+        Ok(answer.map(|(ack, _)| ack))
+    }
+}
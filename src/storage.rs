@@ -0,0 +1,230 @@
+//! Where a receiver keeps what it receives.
+//!
+//! Payloads always arrive in a temporary file in the receiver's temporary
+//! area, which resumes, ranges and parallel hashing work on; a [`Storage`]
+//! creates that file, hands out the stored copy deltas and resumes start
+//! from, and stores the file once it verified. [`LocalDir`] is the
+//! `client`'s default: rename the temporary file into the destination
+//! directory. [`UploadCommand`] pipes each verified file to a shell command
+//! instead, e.g. `aws s3 cp - "s3://bucket/$FASTSYNC_NAME"`, which turns the
+//! receiver into an ingest gateway for object stores.
+
+use anyhow::{Context, Result};
+use std::{
+    fs::{File, OpenOptions},
+    io::ErrorKind,
+    path::Path,
+};
+
+use crate::{handshake::Features, info};
+
+/// Backend of a receiver. Names have passed
+/// [`check_name`](crate::receiver::check_name), and `dest` is the path a
+/// name maps to in the destination directory. [`commit`](Self::commit) and
+/// [`delete`](Self::delete) run on tokio's blocking pool, so they may block.
+pub trait Storage: Send + Sync {
+    /// What the backend can serve: features that work on the copy kept at
+    /// `dest`, like deltas or appends, need [`LocalDir`]
+    fn features(&self) -> Features {
+        Features::ALL
+    }
+
+    /// Open the temporary file at `part` for a payload of `size` bytes, with
+    /// the space reserved. `resume` keeps what an earlier attempt wrote.
+    fn create(&self, part: &Path, size: u64, resume: bool) -> std::io::Result<File> {
+        let file = crate::client::create_part(part, |path| {
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(!resume)
+                .open(path)
+        })?;
+        preallocate(&file, size)?;
+        Ok(file)
+    }
+
+    /// The stored copy at `dest`, if the backend keeps one to read back
+    fn open(&self, dest: &Path) -> std::io::Result<Option<File>>;
+
+    /// Store the verified temporary file `part` as `name`, whose content has
+    /// BLAKE3 `checksum` unless the sender left it out. Returns whether it
+    /// is at `dest` now; whatever is left at `part` is removed afterwards.
+    fn commit(
+        &self,
+        part: &Path,
+        name: &Path,
+        dest: &Path,
+        checksum: Option<&blake3::Hash>,
+    ) -> Result<bool>;
+
+    /// Remove `name` and anything under it; fine if it doesn't exist
+    fn delete(&self, name: &Path, dest: &Path) -> std::io::Result<()>;
+
+    /// Move `from` to `to`. Failing makes a `watcher` send the content
+    /// under the new name instead.
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+}
+
+/// Files in the destination directory, renamed into place
+pub struct LocalDir;
+
+impl Storage for LocalDir {
+    fn open(&self, dest: &Path) -> std::io::Result<Option<File>> {
+        // symlink_metadata: never read through a link planted at `dest`
+        match std::fs::symlink_metadata(dest) {
+            Ok(md) if md.is_file() && md.len() > 0 => File::open(dest).map(Some),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn commit(
+        &self,
+        part: &Path,
+        _name: &Path,
+        dest: &Path,
+        _checksum: Option<&blake3::Hash>,
+    ) -> Result<bool> {
+        std::fs::rename(part, dest).with_context(|| format!("Rename into {}", dest.display()))?;
+        Ok(true)
+    }
+
+    fn delete(&self, _name: &Path, dest: &Path) -> std::io::Result<()> {
+        // symlink_metadata: remove a link itself, never what it points to
+        match std::fs::symlink_metadata(dest) {
+            Ok(md) if md.is_dir() => std::fs::remove_dir_all(dest),
+            Ok(_) => std::fs::remove_file(dest),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(from, to)
+    }
+}
+
+/// Hand each verified file to a shell command on its stdin, with
+/// `FASTSYNC_NAME`, `FASTSYNC_SIZE` and `FASTSYNC_CHECKSUM` (BLAKE3 as hex,
+/// empty without one) in its environment. Nothing stays in the destination
+/// directory, so a `watcher` sends whole files, and a renamed file again
+/// under its new name.
+pub struct UploadCommand {
+    upload: String,
+    delete: Option<String>,
+}
+
+impl UploadCommand {
+    /// Run `upload` for every file
+    pub fn new(upload: impl Into<String>) -> Self {
+        Self {
+            upload: upload.into(),
+            delete: None,
+        }
+    }
+
+    /// Run `delete`, with `FASTSYNC_NAME` set, for every delete. Without one,
+    /// deletes are acknowledged and the stored objects kept.
+    pub fn with_delete(mut self, delete: impl Into<String>) -> Self {
+        self.delete = Some(delete.into());
+        self
+    }
+}
+
+/// Run `cmd` through `sh -c`, failing unless it succeeds
+fn run(cmd: &str, stdin: Option<File>, env: &[(&str, &std::ffi::OsStr)]) -> std::io::Result<()> {
+    let mut command = std::process::Command::new("sh");
+    command.arg("-c").arg(cmd).envs(env.iter().copied());
+    if let Some(stdin) = stdin {
+        command.stdin(stdin);
+    }
+    let status = command.status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("`{cmd}` failed ({status})")));
+    }
+    Ok(())
+}
+
+impl Storage for UploadCommand {
+    fn features(&self) -> Features {
+        Features::ALL
+            .without(Features::DELTA)
+            .without(Features::APPEND)
+            .without(Features::SCRUB)
+            .without(Features::LIST)
+            .without(Features::LINKS)
+            .without(Features::HARD_LINKS)
+    }
+
+    fn open(&self, _dest: &Path) -> std::io::Result<Option<File>> {
+        Ok(None)
+    }
+
+    fn commit(
+        &self,
+        part: &Path,
+        name: &Path,
+        _dest: &Path,
+        checksum: Option<&blake3::Hash>,
+    ) -> Result<bool> {
+        let file = File::open(part).with_context(|| format!("Open {}", part.display()))?;
+        let size = file.metadata()?.len().to_string();
+        let hex = checksum.map(|c| c.to_hex().to_string()).unwrap_or_default();
+        let env = [
+            ("FASTSYNC_NAME", name.as_os_str()),
+            ("FASTSYNC_SIZE", size.as_ref()),
+            ("FASTSYNC_CHECKSUM", hex.as_ref()),
+        ];
+        run(&self.upload, Some(file), &env)
+            .with_context(|| format!("Upload {}", name.display()))?;
+        Ok(false)
+    }
+
+    fn delete(&self, name: &Path, _dest: &Path) -> std::io::Result<()> {
+        let Some(cmd) = &self.delete else {
+            info!(
+                "[*] Keeping the stored {}: no --upload-delete",
+                name.display()
+            );
+            return Ok(());
+        };
+        run(cmd, None, &[("FASTSYNC_NAME", name.as_os_str())])
+    }
+
+    fn rename(&self, _from: &Path, _to: &Path) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "uploaded files can't be renamed",
+        ))
+    }
+}
+
+/// Reserve disk space for `size` bytes without changing the file size, so a
+/// transfer can't run out of space halfway. Where the filesystem can't
+/// preallocate, at least check the free space.
+pub(crate) fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let no_space = || std::io::Error::from_raw_os_error(libc::ENOSPC);
+    let len = libc::off_t::try_from(size).map_err(|_| no_space())?;
+    if len == 0
+        || unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) } == 0
+    {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    if !matches!(err.raw_os_error(), Some(libc::EOPNOTSUPP | libc::ENOSYS)) {
+        return Err(err);
+    }
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), &mut st) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let free = st.f_bavail as u64 * st.f_frsize as u64;
+    let have = file.metadata()?.len();
+    if size.saturating_sub(have) > free {
+        Err(no_space())
+    } else {
+        Ok(())
+    }
+}
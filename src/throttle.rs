@@ -0,0 +1,61 @@
+//! Token-bucket rate limiting for payload writes.
+//!
+//! A [`RateLimiter`] is shared by every transfer it applies to. Callers
+//! [`acquire`](RateLimiter::acquire) bytes before writing them; the bucket
+//! holds up to one second of tokens and goes into debt for large requests, so
+//! concurrent writers are paced in the order they asked.
+
+use anyhow::{Context, Result};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Largest piece written between two token requests
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Bytes per second
+    rate: u64,
+    /// Available tokens (negative while in debt) and when they were counted
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Wait until `bytes` may be written
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            let rate = self.rate as f64;
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+            *tokens -= bytes as f64;
+            (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parse a rate in bytes per second with an optional `K`, `M` or `G` suffix
+/// (powers of 1024), e.g. `500K` or `10M`
+pub fn parse_rate(s: &str) -> Result<u64> {
+    let rate = crate::parse_size(s).with_context(|| format!("Invalid rate {s:?}"))?;
+    anyhow::ensure!(rate > 0, "Rate must be positive");
+    Ok(rate)
+}
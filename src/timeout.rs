@@ -0,0 +1,119 @@
+//! Stall timeouts and deadlines for one side of a connection.
+//!
+//! A [`Timed`] stream fails a read or write with
+//! [`TimedOut`](std::io::ErrorKind::TimedOut) once it has made no progress
+//! for its stall timeout, or when its deadline passes while it waits.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// Did `e` come from a [`Timed`] stream giving up?
+pub fn is_timeout(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+    })
+}
+
+pub struct Timed<S> {
+    inner: S,
+    /// Longest a read or write may wait without progress
+    stall: Option<Duration>,
+    deadline: Option<Instant>,
+    /// Armed when an operation first has to wait, cleared by progress
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Timed<S> {
+    pub fn new(inner: S, stall: Option<Duration>) -> Self {
+        Self {
+            inner,
+            stall,
+            deadline: None,
+            timer: None,
+        }
+    }
+
+    pub fn set_stall(&mut self, stall: Option<Duration>) {
+        self.stall = stall;
+        self.timer = None;
+    }
+
+    /// Fail every operation still waiting `after` from now; `None` clears it
+    pub fn set_deadline(&mut self, after: Option<Duration>) {
+        self.deadline = after.map(|after| Instant::now() + after);
+        self.timer = None;
+    }
+
+    fn poll_op<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(&mut S, &mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if let Poll::Ready(res) = op(&mut self.inner, cx) {
+            self.timer = None;
+            return Poll::Ready(res);
+        }
+        if self.timer.is_none() {
+            let stalled = self.stall.map(|stall| Instant::now() + stall);
+            let Some(at) = stalled.into_iter().chain(self.deadline).min() else {
+                return Poll::Pending;
+            };
+            self.timer = Some(Box::pin(tokio::time::sleep_until(at)));
+        }
+        match self.timer.as_mut().unwrap().as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                let msg = match self.stall {
+                    _ if self.deadline.is_some_and(|at| at <= Instant::now()) => {
+                        "Deadline passed".to_string()
+                    }
+                    Some(stall) => format!("No progress for {stall:?}"),
+                    None => "Timed out".to_string(),
+                };
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, msg)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Timed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_op(cx, |inner, cx| Pin::new(inner).poll_read(cx, buf))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Timed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_op(cx, |inner, cx| Pin::new(inner).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_op(cx, |inner, cx| Pin::new(inner).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_op(cx, |inner, cx| Pin::new(inner).poll_shutdown(cx))
+    }
+}
@@ -0,0 +1,81 @@
+//! `--transfer-log` files, written by both binaries and read back by
+//! `fast-sync history`.
+//!
+//! Each line is one change, tab-separated: an RFC 3339 time, the peer (the
+//! sender id on a receiver, the destination on a watcher), the action, the
+//! name and whatever else the writer records. Tabs, newlines, carriage
+//! returns and backslashes in fields are written as `\t`, `\n`, `\r` and
+//! `\\`, so every line splits cleanly and loads into SQLite or a
+//! spreadsheet as it is.
+
+use anyhow::{Context, Result};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{log, warn};
+
+/// An open transfer log, shared by all connections
+pub struct TransferLog(Mutex<File>);
+
+impl TransferLog {
+    /// Open `path` for appending, creating it if needed
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Open {}", path.display()))?;
+        Ok(Self(Mutex::new(file)))
+    }
+
+    /// Append one line of `fields` after the current time; a failed write
+    /// is logged, not fatal
+    pub fn write(&self, fields: &[&str]) {
+        let mut line = log::timestamp(SystemTime::now());
+        for field in fields {
+            line.push('\t');
+            line.push_str(&escape(field));
+        }
+        line.push('\n');
+        if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("[!] Failed to write to --transfer-log: {e}");
+        }
+    }
+}
+
+/// Keep a field on its line and column
+pub fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// A line's fields as they were written
+pub fn fields(line: &str) -> Vec<String> {
+    line.split('\t').map(unescape).collect()
+}
+
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
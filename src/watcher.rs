@@ -0,0 +1,5788 @@
+//! The `watcher`: watches directories and sends what changes in them to its
+//! destinations. The binary only calls [`main`]; a
+//! [`Sender`](crate::sender::Sender) sends over the same connections.
+
+use crate::{
+    ACK_LEN, APPEND_CHECK, Ack, Confirm, DedupReply, DeltaOp, FileMeta, FrameReader, FrameWriter,
+    Integrity, MAX_REASON, ManifestEntry, MessageKind, ProtocolHeader, RangeHeader, auth,
+    block_hashes,
+    config::{self, Value},
+    control, daemon, debug, decode_ack, discovery,
+    echo::{self, Op},
+    error, fields,
+    filter::Filter,
+    handshake::{self, Features, Params},
+    http, info,
+    keepalive::Keepalive,
+    log, manifest,
+    metrics::{self, Counter, Gauge, Histogram},
+    multicast::{self, Datagram, PACKET_LEN},
+    parallel_hash,
+    shutdown::Signals,
+    throttle::{self, RateLimiter},
+    transfer_log::TransferLog,
+    warn, websocket, xattr,
+};
+use anyhow::{Context, Result};
+use blake3::Hasher;
+use clap::{Parser, ValueEnum, parser::ValueSource};
+use inotify::{EventMask, EventOwned, Inotify, WatchDescriptor, WatchMask};
+use memmap2::Mmap;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Write as _,
+    fs::File,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc, LazyLock, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Interest, unix::AsyncFd},
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket, tcp::WriteHalf},
+    signal::unix::{SignalKind, signal},
+    sync::{OnceCell, mpsc, watch},
+    task::{JoinHandle, JoinSet},
+    time::{sleep, sleep_until},
+};
+
+/// File watcher and sender
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// TOML config file; command-line flags override its values. `[[dest]]`
+    /// tables define destinations with their own options
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Destinations as HOST:PORT (comma-separated); HOST is a name, an IPv4
+    /// address or a bracketed IPv6 address like [::1]:5001
+    #[arg(long, default_value = "10.0.0.2:5001")]
+    dests: String,
+
+    /// Also send to a receiver that connects to this IP:PORT (repeatable),
+    /// for receivers that can't accept connections and run with
+    /// --connect-to. Without --dests or [[dest]] tables these are the only
+    /// destinations.
+    #[arg(long)]
+    listen: Vec<SocketAddr>,
+
+    /// Also send to receivers on the local network that advertise themselves
+    /// with --advertise, found over mDNS as they appear. Without --dests or
+    /// [[dest]] tables these are the only destinations.
+    #[arg(long)]
+    discover: bool,
+
+    /// With --discover, only send to receivers advertised under one of these
+    /// names (repeatable or comma-separated)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "NAME",
+        requires = "discover"
+    )]
+    discover_allow: Vec<String>,
+
+    /// Port for destinations given without one
+    #[arg(long, default_value_t = 5001)]
+    pub(crate) dest_port: u16,
+
+    /// Reach the destinations this watcher dials through this HTTP proxy,
+    /// which opens a tunnel to each with CONNECT
+    #[arg(long, value_name = "[USER:PASS@]HOST:PORT", value_parser = parse_proxy)]
+    proxy: Option<Proxy>,
+
+    /// Carry the protocol in WebSocket frames to the destinations this
+    /// watcher dials, for networks that only pass HTTP. Their HOST:PORT is a
+    /// receiver's --http address, or that of a load balancer in front of it.
+    #[arg(long)]
+    websocket: bool,
+
+    /// Path the --websocket upgrade asks for, for load balancers that route
+    /// by it
+    #[arg(long, default_value = "/fast-sync", requires = "websocket")]
+    websocket_path: String,
+
+    /// Directory to watch (recursive) as DIR[:PREFIX], where PREFIX is the
+    /// path its files get under the destination directory (repeatable or
+    /// comma-separated)
+    #[arg(long, value_delimiter = ',', default_value = "/origen")]
+    pub(crate) watch_dir: Vec<String>,
+
+    /// Send every file already present in the watch directory before watching
+    #[arg(long)]
+    initial_sync: bool,
+
+    /// With --initial-sync, stream the files already present to each
+    /// destination as one archive instead of a message and an ACK per file,
+    /// for seeding a new destination
+    #[arg(long, requires = "initial_sync")]
+    seed_archive: bool,
+
+    /// Only send blocks that changed against the destination's existing copy
+    #[arg(long)]
+    delta: bool,
+
+    /// Block size in bytes used by --delta, at least 512
+    #[arg(
+        long,
+        default_value_t = 64 * 1024,
+        value_parser = clap::value_parser!(u32).range(crate::MIN_DELTA_BLOCK_SIZE as i64..)
+    )]
+    delta_block_size: u32,
+
+    /// Continue interrupted transfers of files at least this large from the
+    /// bytes the destination already has (K, M and G suffixes allowed)
+    #[arg(long, value_parser = crate::parse_size)]
+    resume_above: Option<u64>,
+
+    /// Announce the checksum of files at least this large before their
+    /// content, so a destination that already has the content, e.g. indexed
+    /// with --dedup-index, makes the file locally instead (K, M and G
+    /// suffixes allowed); takes precedence over --resume-above and --delta
+    #[arg(long, value_parser = crate::parse_size)]
+    dedup_above: Option<u64>,
+
+    /// Treat files matching this glob as append-only logs: each send ships
+    /// only the bytes added since the destination's copy (repeatable,
+    /// gitignore-style); takes precedence over --dedup-above, --resume-above
+    /// and --delta
+    #[arg(long)]
+    follow: Vec<String>,
+
+    /// Largest payload chunk to propose to destinations; each chunk carries
+    /// its own checksum (K and M suffixes allowed)
+    #[arg(long, value_parser = crate::parse_size, default_value = "1M")]
+    chunk_size: u64,
+
+    /// When destinations acknowledge a file: once received, once verified
+    /// and renamed into place, or once synced to disk as well
+    #[arg(long, value_enum, default_value_t = Confirm::Verified)]
+    confirm: Confirm,
+
+    /// Send payload data with sendfile(2) straight from the page cache
+    /// instead of copying it through userspace
+    #[arg(long)]
+    zero_copy: bool,
+
+    /// How file content is checked: with BLAKE3 checksums (full), or only by
+    /// TCP's checksums and the file's size (tcp-only), which saves the hashing
+    /// on trusted links when the destination asks for it too
+    #[arg(long, value_enum, default_value_t = Integrity::Full)]
+    integrity: Integrity,
+
+    /// Threads to compute a large file's checksum with, alongside sending it
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    hash_threads: u16,
+
+    /// Connections to split a large file over, each carrying one range of
+    /// it, for links where one TCP stream can't fill the pipe
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    streams: u16,
+
+    /// Smallest file split over --streams (K, M and G suffixes allowed);
+    /// --follow, --dedup-above, --resume-above, --delta and --multicast take
+    /// precedence
+    #[arg(long, value_parser = crate::parse_size, default_value = "64M")]
+    streams_above: u64,
+
+    /// Also send large files' content once to this multicast group or
+    /// broadcast address (ADDR:PORT), for every destination listening there
+    /// with --multicast; each then only gets what it missed over TCP.
+    /// Needs a PSK, which authenticates the datagrams
+    #[arg(long, value_name = "ADDR:PORT")]
+    multicast: Option<SocketAddr>,
+
+    /// Smallest file sent to the --multicast group (K, M and G suffixes
+    /// allowed); --follow, --dedup-above, --resume-above and --delta take
+    /// precedence
+    #[arg(long, value_parser = crate::parse_size, default_value = "1M")]
+    multicast_above: u64,
+
+    /// Bytes per second sent to the --multicast group (K, M and G suffixes
+    /// allowed); datagrams have no congestion control, so keep it under
+    /// what the network and the slowest receiver take
+    #[arg(long, value_parser = crate::parse_size, default_value = "50M")]
+    multicast_rate: u64,
+
+    /// Data packets per --multicast parity packet, from which a receiver
+    /// rebuilds any one of them it lost; 0 sends none
+    #[arg(long, default_value_t = 8)]
+    fec_group: u8,
+
+    /// Coalesce events per path and send once it has been quiet this long
+    #[arg(long, default_value_t = 0)]
+    debounce_ms: u64,
+
+    /// File events that trigger a send (comma-separated)
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "close-write,moved-to,create"
+    )]
+    event_mask: Vec<FileEvent>,
+
+    /// With `modify` in --event-mask, send a modified file once it has had
+    /// no writes for this long
+    #[arg(long, default_value_t = 1000)]
+    modify_quiet_ms: u64,
+
+    /// Hold back files matching GLOB until they look finished: `GLOB=MS`
+    /// until their size and mtime stayed the same for MS milliseconds,
+    /// `GLOB=lock` until nobody holds an flock(2) on them (repeatable; the
+    /// first matching rule applies)
+    #[arg(long, value_parser = parse_stable_rule)]
+    stable: Vec<StableRule>,
+
+    /// Pre-shared key to authenticate with the destinations
+    #[arg(long)]
+    psk: Option<String>,
+
+    /// File containing the pre-shared key
+    #[arg(long)]
+    psk_file: Option<PathBuf>,
+
+    /// Only send files matching this glob (repeatable, gitignore-style)
+    #[arg(long)]
+    include: Vec<String>,
+
+    /// Never watch or send paths matching this glob (repeatable, gitignore-style)
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Mirror deletions and renames to the destinations
+    #[arg(long)]
+    propagate_deletes: bool,
+
+    /// Don't send files a receiver running with --mark-received put here
+    /// and that haven't changed since, e.g. to not echo them back to a peer
+    #[arg(long)]
+    ignore_received: bool,
+
+    /// Shell command that file content is piped through before it is sent,
+    /// with FASTSYNC_PATH and FASTSYNC_NAME set; checksums cover its output
+    #[arg(long)]
+    pre_send: Option<String>,
+
+    /// Suffix of marker files, e.g. `.ok`: a file only goes out once its
+    /// marker exists, and the two reach destinations together, the marker
+    /// renamed into place last
+    #[arg(long, value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    transaction_marker: Option<String>,
+
+    /// Send `user.*` extended attributes and POSIX ACLs along with files
+    #[arg(long)]
+    xattrs: bool,
+
+    /// Send files with several names in the watch directories once and
+    /// recreate the other names as hard links
+    #[arg(long)]
+    hard_links: bool,
+
+    /// What to do with symlinks in the watch directories
+    #[arg(long, value_enum, default_value_t = Links::Skip)]
+    links: Links,
+
+    /// How changes are noticed: inotify events, or periodic scans of the tree
+    /// for filesystems that don't deliver events, like NFS and CIFS mounts
+    #[arg(long, value_enum, default_value_t = WatchBackend::Inotify)]
+    watch_backend: WatchBackend,
+
+    /// With --watch-backend poll, seconds between scans
+    #[arg(long, default_value_t = 10)]
+    poll_interval: u64,
+
+    /// With --watch-backend poll, also hash every file on each scan to catch
+    /// changes that keep the size and mtime
+    #[arg(long)]
+    poll_checksum: bool,
+
+    /// Every this many seconds, compare the checksums of all watched files
+    /// with the destinations' copies and resend what is missing or different
+    #[arg(long)]
+    scrub_interval: Option<u64>,
+
+    /// Watch, filter and hash as usual, but only log what would be sent to
+    /// which destination instead of connecting to any
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Directory for per-destination journals of files that failed to send
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Append a tab-separated line to this file for every answer a
+    /// destination gives: time, destination, action, name, size, blake3,
+    /// target and result
+    #[arg(long)]
+    transfer_log: Option<PathBuf>,
+
+    /// Cap on the combined send rate to all destinations, in bytes per second
+    /// (K, M and G suffixes allowed)
+    #[arg(long, value_parser = throttle::parse_rate)]
+    max_rate: Option<u64>,
+
+    /// Messages that may await their ACK per destination before sending stalls
+    #[arg(long, default_value_t = 16)]
+    window: usize,
+
+    /// Send files up to this size (K, M and G suffixes allowed) that are
+    /// queued together as batches, one message and one ACK for up to
+    /// --batch-files of them
+    #[arg(long, value_parser = crate::parse_size)]
+    batch_below: Option<u64>,
+
+    /// Most files in one batch, at least 2
+    #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u16).range(2..))]
+    batch_files: u16,
+
+    /// Send files matching this glob to each destination over a connection
+    /// of their own, so they never wait behind large transfers (repeatable,
+    /// gitignore-style)
+    #[arg(long)]
+    priority: Vec<String>,
+
+    /// Also send files smaller than this over the priority connection (K, M
+    /// and G suffixes allowed)
+    #[arg(long, value_parser = crate::parse_size)]
+    priority_below: Option<u64>,
+
+    /// Warn about files larger than this (K, M and G suffixes allowed) and
+    /// skip them or keep them off the priority connection, see --oversized
+    #[arg(long, value_parser = crate::parse_size)]
+    max_file_size: Option<u64>,
+
+    /// What to do with files over --max-file-size
+    #[arg(long, value_enum, default_value_t = Oversized::Skip)]
+    oversized: Oversized,
+
+    /// Drop a connection when a destination takes longer than this many
+    /// seconds to ACK a message or reply to it, and resend what was in flight
+    #[arg(long)]
+    ack_timeout: Option<u64>,
+
+    /// Drop a connection when writing one file to it takes longer than this
+    /// many seconds, and resend the file
+    #[arg(long)]
+    file_timeout: Option<u64>,
+
+    /// Longest wait between reconnect attempts to a destination, in seconds;
+    /// the wait doubles from half a second after each failure
+    #[arg(long, default_value_t = 60)]
+    reconnect_max_delay: u64,
+
+    /// Mark a destination down after this many failed connection attempts
+    /// in a row, and only try it every --reconnect-max-delay from then on
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_reconnects: Option<u32>,
+
+    /// Send a heartbeat on connections that have been idle for this many
+    /// seconds; one not ACKed within as long again (or --ack-timeout) drops
+    /// the connection
+    #[arg(long)]
+    heartbeat: Option<u64>,
+
+    /// Send TCP keepalive probes after a connection has been quiet for this
+    /// many seconds
+    #[arg(long)]
+    keepalive: Option<u32>,
+
+    /// Seconds between keepalive probes
+    #[arg(long, default_value_t = 10)]
+    keepalive_interval: u32,
+
+    /// Unanswered keepalive probes before a connection is dropped
+    #[arg(long, default_value_t = 3)]
+    keepalive_count: u32,
+
+    /// Detach from the terminal and run in the background
+    #[arg(long)]
+    daemon: bool,
+
+    /// With --daemon, write the daemon's process ID to this file
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+
+    /// Name announced to destinations, which keep each sender's files apart
+    /// with --per-sender-dir [default: this host's name]
+    #[arg(long)]
+    sender_id: Option<String>,
+
+    /// Accept `fast-sync ctl` commands (status, queue, pause, resume,
+    /// resync) on this Unix socket
+    #[arg(long)]
+    control: Option<PathBuf>,
+
+    /// Most verbose level to log
+    #[arg(long, value_enum, default_value_t = log::Level::Info)]
+    log_level: log::Level,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = log::Format::Text)]
+    log_format: log::Format,
+
+    /// Serve Prometheus metrics on this port at /metrics
+    #[arg(long)]
+    metrics_port: Option<u16>,
+}
+
+/// `--stable GLOB=CHECK`
+#[derive(Clone, Debug)]
+struct StableRule {
+    glob: String,
+    check: Stability,
+}
+
+/// When a file counts as no longer being written
+#[derive(Clone, Copy, Debug)]
+enum Stability {
+    /// Size and mtime unchanged for this long
+    Quiet(Duration),
+    /// No flock(2) held on it
+    Unlocked,
+}
+
+fn parse_stable_rule(s: &str) -> Result<StableRule> {
+    let (glob, check) = s
+        .rsplit_once('=')
+        .context("expected GLOB=MS or GLOB=lock")?;
+    anyhow::ensure!(!glob.is_empty(), "expected GLOB=MS or GLOB=lock");
+    let check = match check {
+        "lock" => Stability::Unlocked,
+        ms => Stability::Quiet(Duration::from_millis(
+            ms.parse()
+                .with_context(|| format!("Invalid stability check {ms:?}"))?,
+        )),
+    };
+    Ok(StableRule {
+        glob: glob.to_string(),
+        check,
+    })
+}
+
+/// `--proxy`
+#[derive(Clone, Debug)]
+struct Proxy {
+    host: String,
+    port: u16,
+    /// USER:PASS for Basic authentication
+    credentials: Option<String>,
+}
+
+fn parse_proxy(s: &str) -> Result<Proxy> {
+    let (credentials, addr) = match s.rsplit_once('@') {
+        Some((credentials, addr)) => (Some(credentials.to_string()), addr),
+        None => (None, s),
+    };
+    let dest = Destination::parse_group(addr, 0)
+        .pop()
+        .filter(|dest| dest.port != 0 && !addr.contains('|'))
+        .context("expected [USER:PASS@]HOST:PORT")?;
+    Ok(Proxy {
+        host: dest.host,
+        port: dest.port,
+        credentials,
+    })
+}
+
+/// What `--max-file-size` does with larger files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Oversized {
+    /// Don't send them
+    Skip,
+    /// Send them over the bulk connection, never the priority one
+    Bulk,
+}
+
+/// File event that `--event-mask` can send on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum FileEvent {
+    /// A writer closed the file
+    CloseWrite,
+    /// Moved in from outside the watched tree
+    MovedTo,
+    /// Created, possibly still empty
+    Create,
+    /// Written to, e.g. through a shared mapping that is never closed
+    Modify,
+}
+
+impl FileEvent {
+    fn watch(self) -> WatchMask {
+        match self {
+            Self::CloseWrite => WatchMask::CLOSE_WRITE,
+            Self::MovedTo => WatchMask::MOVED_TO,
+            Self::Create => WatchMask::CREATE,
+            Self::Modify => WatchMask::MODIFY,
+        }
+    }
+
+    fn event(self) -> EventMask {
+        match self {
+            Self::CloseWrite => EventMask::CLOSE_WRITE,
+            Self::MovedTo => EventMask::MOVED_TO,
+            Self::Create => EventMask::CREATE,
+            Self::Modify => EventMask::MODIFY,
+        }
+    }
+}
+
+/// How changes are noticed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum WatchBackend {
+    Inotify,
+    Poll,
+}
+
+/// How `--links` treats symlinks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Links {
+    /// Never send them
+    Skip,
+    /// Send what they point to as if it were there, descending into linked
+    /// directories
+    Follow,
+    /// Recreate the links themselves on the destination
+    Preserve,
+}
+
+/// How long a reconnect may take before the file is queued for later
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait after the first failed connection attempt; it doubles with each
+/// further one up to `--reconnect-max-delay`
+const RECONNECT_BASE: Duration = Duration::from_millis(500);
+/// Failed connection attempts after which a failover group member is down,
+/// unless `--max-reconnects` says otherwise
+const FAILOVER_AFTER: u32 = 3;
+
+/// How often a destination with queued files retries them
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a file under a `GLOB=lock` stability rule is probed while locked
+const LOCK_RETRY: Duration = Duration::from_millis(200);
+
+/// How often a watched directory that was unmounted or removed is checked
+/// for its return
+const ROOT_POLL: Duration = Duration::from_secs(2);
+
+/// Sends of one job (the first and one retry) before it goes to the journal
+const MAX_ATTEMPTS: u8 = 2;
+
+/// Smallest file whose chunks are hashed ahead on a thread of their own
+/// while it is sent
+const PIPELINE_MIN: u64 = 4 << 20;
+
+/// Chunks the hashing stage of a send may get ahead of the sending
+const HASH_AHEAD: usize = 16;
+
+/// Destinations with an open connection
+static CONNECTED: LazyLock<Arc<Gauge>> = LazyLock::new(|| {
+    metrics::registry().gauge(
+        "fastsync_destinations_connected",
+        "Destinations with an open connection",
+        &[],
+    )
+});
+
+/// Times the kernel dropped inotify events
+static OVERFLOWS: LazyLock<Arc<Counter>> = LazyLock::new(|| {
+    metrics::registry().counter(
+        "fastsync_inotify_overflows_total",
+        "inotify queue overflows that forced a rescan",
+        &[],
+    )
+});
+
+/// Metrics of one destination, labelled with its address
+struct DestMetrics {
+    sent: Arc<Counter>,
+    unchanged: Arc<Counter>,
+    failed: Arc<Counter>,
+    bytes: Arc<Counter>,
+    queue_depth: Arc<Gauge>,
+    connected: Arc<Gauge>,
+    health: Arc<Gauge>,
+    event_to_send: Arc<Histogram>,
+    duration: Arc<Histogram>,
+    size: Arc<Histogram>,
+}
+
+impl DestMetrics {
+    fn new(dest: &Destination) -> Self {
+        let r = metrics::registry();
+        let addr = dest.label();
+        let labels = [("dest", addr.as_str())];
+        Self {
+            sent: r.counter(
+                "fastsync_files_sent_total",
+                "Files acknowledged by the destination",
+                &labels,
+            ),
+            unchanged: r.counter(
+                "fastsync_files_unchanged_total",
+                "Files the destination already had",
+                &labels,
+            ),
+            failed: r.counter(
+                "fastsync_files_failed_total",
+                "Jobs given up on or journaled after failing",
+                &labels,
+            ),
+            bytes: r.counter(
+                "fastsync_bytes_sent_total",
+                "Payload bytes written",
+                &labels,
+            ),
+            queue_depth: r.gauge("fastsync_queue_depth", "Jobs waiting or in flight", &labels),
+            connected: r.gauge(
+                "fastsync_dest_connected",
+                "1 while connected to the destination",
+                &labels,
+            ),
+            health: r.gauge(
+                "fastsync_dest_health",
+                "0 up, 1 reconnecting, 2 down",
+                &labels,
+            ),
+            event_to_send: r.histogram(
+                "fastsync_event_to_send_seconds",
+                "Time from the first event to the start of the send",
+                &labels,
+                metrics::DURATION_BUCKETS,
+            ),
+            duration: r.histogram(
+                "fastsync_transfer_duration_seconds",
+                "Time from the start of the send to the ACK",
+                &labels,
+                metrics::DURATION_BUCKETS,
+            ),
+            size: r.histogram(
+                "fastsync_transfer_bytes",
+                "Payload bytes written per file",
+                &labels,
+                metrics::SIZE_BUCKETS,
+            ),
+        }
+    }
+}
+
+/// Per-transfer settings shared by every send
+pub(crate) struct SendOptions {
+    /// Watched directories; names are made relative to the one containing
+    /// the file
+    roots: Vec<WatchRoot>,
+    /// Block size for delta transfers, if enabled
+    delta_block_size: Option<u32>,
+    /// Smallest file sent as a resumable transfer, if enabled
+    resume_above: Option<u64>,
+    /// Smallest file announced by checksum first, if enabled
+    dedup_above: Option<u64>,
+    /// `--follow`: files whose appends are shipped on their own
+    follow: Option<Filter>,
+    /// Payload chunk size proposed in the handshake
+    chunk_size: u32,
+    /// Send chunk data with sendfile(2)
+    zero_copy: bool,
+    /// `--integrity`
+    integrity: Integrity,
+    hash_threads: usize,
+    /// `--streams`, with `--streams-above`
+    streams: (usize, u64),
+    multicast: Option<Multicaster>,
+    proxy: Option<Proxy>,
+    /// Path of the `--websocket` upgrade
+    websocket: Option<String>,
+    /// Default ACK point for destinations that don't set their own
+    confirm: Confirm,
+    /// Handshake key derived from the PSK
+    key: Option<[u8; 32]>,
+    /// Announced after authenticating, if the destination takes it
+    sender_id: Option<String>,
+    /// Include/exclude rules for paths relative to their watch root
+    filter: Shared<Filter>,
+    /// Send delete / rename messages
+    propagate_deletes: bool,
+    ignore_received: bool,
+    links: Links,
+    /// Send extended attributes with files
+    xattrs: bool,
+    /// Transform applied to file content
+    pre_send: Option<String>,
+    /// Suffix of the marker files sent together with their data file
+    transaction_marker: Option<String>,
+    /// Send hard links as such
+    hard_links: bool,
+    /// File events that trigger a send
+    events: Vec<FileEvent>,
+    /// `--scrub-interval` is set
+    scrubs: bool,
+    /// Shared by every destination
+    max_rate: Shared<Option<Arc<RateLimiter>>>,
+    /// Messages in flight per connection
+    window: usize,
+    /// `--batch-below`, with `--batch-files`
+    batch: Option<(u64, usize)>,
+    /// What the priority lanes carry, if there are any
+    priority: Option<Priority>,
+    /// `--max-file-size`, with `--oversized`
+    max_file_size: Option<(u64, Oversized)>,
+    /// `--stable` rules, in order
+    stable: Vec<(Filter, Stability)>,
+    ack_timeout: Option<Duration>,
+    file_timeout: Option<Duration>,
+    heartbeat: Option<Duration>,
+    keepalive: Option<Keepalive>,
+    reconnect_max_delay: Duration,
+    max_reconnects: Option<u32>,
+    /// Set by the `pause` control command: destinations hold their queues
+    paused: watch::Sender<bool>,
+    /// What transfer ids derive from, unique to this run
+    id_seed: [u8; 32],
+    id_epoch: Instant,
+    /// Last content version handed out, see [`SendOptions::next_version`]
+    last_version: AtomicU64,
+    transfer_log: Option<TransferLog>,
+}
+
+/// One watched directory and where its files go on the destination
+struct WatchRoot {
+    dir: PathBuf,
+    /// Relative path prepended to the names of files under `dir`
+    prefix: PathBuf,
+}
+
+impl WatchRoot {
+    /// Parse `DIR[:PREFIX]`
+    fn parse(spec: &str) -> Result<Self> {
+        let (dir, prefix) = spec.split_once(':').unwrap_or((spec, ""));
+        anyhow::ensure!(!dir.is_empty(), "Empty watch directory in {spec:?}");
+        let prefix = PathBuf::from(prefix.trim_matches('/'));
+        anyhow::ensure!(
+            prefix
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_))),
+            "Invalid remote prefix in {spec:?}: must be a relative path without '..'"
+        );
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            prefix,
+        })
+    }
+}
+
+/// Name of a path under no watch root, as a [`Sender`](crate::sender::Sender)
+/// may send: its file name
+fn outside_roots(path: &Path) -> &Path {
+    path.file_name().map_or(path, Path::new)
+}
+
+/// A setting SIGHUP can replace while tasks keep using the value they took
+struct Shared<T>(RwLock<Arc<T>>);
+
+impl<T> Shared<T> {
+    fn new(value: T) -> Self {
+        Self(RwLock::new(Arc::new(value)))
+    }
+
+    fn get(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn set(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// One destination and its own options
+#[derive(Clone)]
+pub(crate) struct Destination {
+    host: String,
+    port: u16,
+    /// Applied on top of the global include/exclude rules
+    filter: Filter,
+    /// Applied on top of the global `--max-rate`
+    max_rate: Option<Arc<RateLimiter>>,
+    /// Overrides `--confirm`
+    confirm: Option<Confirm>,
+    /// Failover group; a member only gets jobs while every member listed
+    /// before it is down
+    group: Option<String>,
+    /// Wait on HOST:PORT for the receiver to connect instead of dialing it
+    listen: bool,
+    /// Which of the destination's jobs this connection carries
+    lane: Lane,
+    /// Found by `--discover`, so a reload keeps it
+    discovered: bool,
+}
+
+/// With `--priority`, each destination gets two connections: one for the
+/// files that matter most right now and one for everything else
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Lane {
+    All,
+    Priority,
+    Bulk,
+}
+
+/// `--priority` and `--priority-below`
+struct Priority {
+    filter: Option<Filter>,
+    below: Option<u64>,
+}
+
+impl Priority {
+    /// Does `job` go over the priority connection? Deletes and symlinks
+    /// follow the files whose names match there; everything else is bulk
+    fn covers(&self, job: &Job, opts: &SendOptions) -> bool {
+        let matches = |path: &Path| {
+            self.filter
+                .as_ref()
+                .is_some_and(|f| f.allows_file(opts.rel(path)))
+        };
+        match job {
+            Job::File(path, _) => {
+                let size = std::fs::metadata(path).map_or(0, |m| m.len());
+                opts.max_file_size.is_none_or(|(max, _)| size <= max)
+                    && (matches(path) || self.below.is_some_and(|below| size < below))
+            }
+            Job::Delete(path) | Job::Symlink(path) => matches(path),
+            _ => false,
+        }
+    }
+}
+
+impl Destination {
+    /// Parse `--dests`: comma-separated HOST[:PORT], where HOST is a name,
+    /// an IPv4 address or an IPv6 address (in brackets if a port follows).
+    /// `PRIMARY|STANDBY|...` makes a failover group named after its first
+    /// member.
+    pub(crate) fn parse_list(list: &str, default_port: u16) -> Vec<Self> {
+        list.split(',')
+            .flat_map(|item| {
+                let mut members = Self::parse_group(item, default_port);
+                if members.len() > 1 {
+                    let name = format!("{}:{}", members[0].host, members[0].port);
+                    members
+                        .iter_mut()
+                        .for_each(|m| m.group = Some(name.clone()));
+                }
+                members
+            })
+            .collect()
+    }
+
+    fn parse_group(group: &str, default_port: u16) -> Vec<Self> {
+        group
+            .split('|')
+            .filter_map(|s| {
+                let s = s.trim();
+                let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+                    let (host, rest) = rest.split_once(']')?;
+                    match rest.strip_prefix(':') {
+                        Some(port) => (host, port.parse().ok()?),
+                        None if rest.is_empty() => (host, default_port),
+                        None => return None,
+                    }
+                } else if s.matches(':').count() > 1 {
+                    (s, default_port)
+                } else {
+                    match s.rsplit_once(':') {
+                        Some((host, port)) => (host, port.parse().ok()?),
+                        None => (s, default_port),
+                    }
+                };
+                (!host.is_empty()).then(|| Self {
+                    host: host.to_string(),
+                    port,
+                    filter: Filter::default(),
+                    max_rate: None,
+                    confirm: None,
+                    group: None,
+                    listen: false,
+                    lane: Lane::All,
+                    discovered: false,
+                })
+            })
+            .collect()
+    }
+
+    /// Parse a `[[dest]]` table: `addr = "host:port"` or `host` + `port`,
+    /// plus optional `include` / `exclude` lists, `max_rate`, `confirm`,
+    /// `group` and `listen`
+    fn from_table(table: &config::Table, default_port: u16) -> Result<Self> {
+        let strings = |key: &str| -> Result<Vec<String>> {
+            match table.get(key) {
+                None => Ok(Vec::new()),
+                Some(Value::String(s)) => Ok(vec![s.clone()]),
+                Some(Value::Array(items)) => items
+                    .iter()
+                    .map(|v| {
+                        v.as_str()
+                            .map(str::to_string)
+                            .with_context(|| format!("[[dest]] {key} must be strings"))
+                    })
+                    .collect(),
+                Some(_) => anyhow::bail!("[[dest]] {key} must be a string or list of strings"),
+            }
+        };
+        for key in table.keys() {
+            anyhow::ensure!(
+                matches!(
+                    key.as_str(),
+                    "addr"
+                        | "host"
+                        | "port"
+                        | "include"
+                        | "exclude"
+                        | "max_rate"
+                        | "confirm"
+                        | "group"
+                        | "listen"
+                ),
+                "Unknown [[dest]] key {key:?}"
+            );
+        }
+        let mut dest = match (table.get("addr"), table.get("host")) {
+            (Some(addr), None) => {
+                let addr = addr.as_str().context("[[dest]] addr must be a string")?;
+                Self::parse_group(addr, default_port)
+                    .pop()
+                    .filter(|_| !addr.contains('|'))
+                    .with_context(|| format!("Invalid [[dest]] addr {addr:?}"))?
+            }
+            (None, Some(host)) => Self {
+                host: host
+                    .as_str()
+                    .context("[[dest]] host must be a string")?
+                    .to_string(),
+                port: default_port,
+                filter: Filter::default(),
+                max_rate: None,
+                confirm: None,
+                group: None,
+                listen: false,
+                lane: Lane::All,
+                discovered: false,
+            },
+            _ => anyhow::bail!("[[dest]] needs exactly one of addr or host"),
+        };
+        if let Some(port) = table.get("port") {
+            dest.port = port
+                .as_integer()
+                .and_then(|p| u16::try_from(p).ok())
+                .context("[[dest]] port must be a port number")?;
+        }
+        dest.filter = Filter::new(&strings("include")?, &strings("exclude")?)?;
+        dest.max_rate = match table.get("max_rate") {
+            None => None,
+            Some(Value::String(s)) => Some(Arc::new(RateLimiter::new(throttle::parse_rate(s)?))),
+            Some(Value::Integer(n)) if *n > 0 => Some(Arc::new(RateLimiter::new(*n as u64))),
+            Some(_) => anyhow::bail!("[[dest]] max_rate must be a positive rate such as \"10M\""),
+        };
+        dest.confirm = match table.get("confirm") {
+            None => None,
+            Some(v) => Some(
+                v.as_str()
+                    .and_then(|s| Confirm::from_str(s, true).ok())
+                    .context(
+                        "[[dest]] confirm must be \"received\", \"verified\" or \"durable\"",
+                    )?,
+            ),
+        };
+        dest.group = match table.get("group") {
+            None => None,
+            Some(v) => Some(
+                v.as_str()
+                    .context("[[dest]] group must be a string")?
+                    .to_string(),
+            ),
+        };
+        dest.listen = match table.get("listen") {
+            None => false,
+            Some(v) => v.as_bool().context("[[dest]] listen must be a boolean")?,
+        };
+        Ok(dest)
+    }
+
+    /// When this destination should acknowledge files
+    fn confirm(&self, opts: &SendOptions) -> Confirm {
+        self.confirm.unwrap_or(opts.confirm)
+    }
+
+    /// Failed connection attempts in a row after which it is down
+    fn max_failures(&self, opts: &SendOptions) -> Option<u32> {
+        opts.max_reconnects
+            .or(self.group.is_some().then_some(FAILOVER_AFTER))
+    }
+
+    /// Rate limits that apply to sends to this destination
+    fn limits(&self, opts: &SendOptions) -> Vec<Arc<RateLimiter>> {
+        opts.max_rate
+            .get()
+            .iter()
+            .chain(&self.max_rate)
+            .cloned()
+            .collect()
+    }
+
+    /// `host:port`, and the lane if it is one
+    fn label(&self) -> String {
+        match self.lane {
+            Lane::Priority => format!("{}:{}/priority", self.host, self.port),
+            Lane::All | Lane::Bulk => format!("{}:{}", self.host, self.port),
+        }
+    }
+
+    /// Narrow a job to what this destination's filter and lane admit
+    fn admit(&self, job: Job, opts: &SendOptions) -> Option<Job> {
+        let job = self.admit_filtered(job, opts)?;
+        let priority = || opts.priority.as_ref().is_some_and(|p| p.covers(&job, opts));
+        match self.lane {
+            Lane::All => Some(job),
+            Lane::Priority => priority().then_some(job),
+            Lane::Bulk => (!priority()).then_some(job),
+        }
+    }
+
+    fn admit_filtered(&self, job: Job, opts: &SendOptions) -> Option<Job> {
+        let allows = |path: &Path, is_dir: bool| {
+            let rel = opts.rel(path);
+            if is_dir {
+                !self.filter.excludes_dir(rel)
+            } else {
+                self.filter.allows_file(rel)
+            }
+        };
+        match job {
+            Job::File(ref path, _) | Job::Symlink(ref path) => allows(path, false).then_some(job),
+            // Another name of a file this destination doesn't get: send the content
+            Job::HardLink(path, existing) => match (allows(&path, false), allows(&existing, false))
+            {
+                (true, true) => Some(Job::HardLink(path, existing)),
+                (true, false) => Some(Job::File(path, Instant::now())),
+                (false, _) => None,
+            },
+            Job::Delete(ref path) => allows(path, path.is_dir()).then_some(job),
+            Job::Rename(from, to) => {
+                let is_dir = to.is_dir();
+                match (allows(&from, is_dir), allows(&to, is_dir)) {
+                    (true, true) => Some(Job::Rename(from, to)),
+                    (true, false) => Some(Job::Delete(from)),
+                    (false, true) if !is_dir => opts.send_job(to, Instant::now()),
+                    // A directory we never sent: let the rename fall back to
+                    // sending its content
+                    (false, true) => Some(Job::Rename(from, to)),
+                    (false, false) => None,
+                }
+            }
+            Job::Heartbeat => Some(job),
+            Job::Scrub(entries) => {
+                let kept: Vec<_> = entries
+                    .iter()
+                    .filter(|e| allows(&e.path, false))
+                    .cloned()
+                    .collect();
+                (!kept.is_empty()).then(|| Job::Scrub(Arc::new(kept)))
+            }
+            Job::Transaction(ref paths, _) => paths.iter().all(|p| allows(p, false)).then_some(job),
+            Job::Batch(mut files) => {
+                files.retain(|(path, _)| allows(path, false));
+                (!files.is_empty()).then_some(Job::Batch(files))
+            }
+            Job::Archive(files, first_seen) => {
+                let kept: Vec<_> = files.iter().filter(|p| allows(p, false)).cloned().collect();
+                (!kept.is_empty()).then(|| Job::Archive(Arc::new(kept), first_seen))
+            }
+        }
+    }
+}
+
+/// Work item for a destination task
+#[derive(Debug, Clone)]
+pub(crate) enum Job {
+    /// Send a file's content; carries the time of its first event
+    File(PathBuf, Instant),
+    Delete(PathBuf),
+    Rename(PathBuf, PathBuf),
+    /// Recreate a symlink under `--links preserve`
+    Symlink(PathBuf),
+    /// Make the first path another name of the second, already sent file
+    HardLink(PathBuf, PathBuf),
+    /// Compare files with the destination's copies, resending those that differ
+    Scrub(Arc<Vec<ScrubEntry>>),
+    /// Send files to be put in place together, in this order
+    Transaction(Vec<PathBuf>, Instant),
+    /// Check that an idle destination is still there
+    Heartbeat,
+    /// Small files sent in one message; only formed right before sending
+    Batch(Vec<(PathBuf, Instant)>),
+    /// Files streamed as one archive under `--seed-archive`
+    Archive(Arc<Vec<PathBuf>>, Instant),
+}
+
+/// A file as the scrub found it
+#[derive(Debug, Clone)]
+pub(crate) struct ScrubEntry {
+    path: PathBuf,
+    size: u64,
+    checksum: [u8; 32],
+}
+
+impl std::fmt::Display for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Job::File(path, _) => write!(f, "send {}", path.display()),
+            Job::Delete(path) => write!(f, "delete {}", path.display()),
+            Job::Rename(from, to) => write!(f, "rename {} -> {}", from.display(), to.display()),
+            Job::Symlink(path) => write!(f, "link {}", path.display()),
+            Job::HardLink(path, existing) => {
+                write!(f, "hard link {} -> {}", path.display(), existing.display())
+            }
+            Job::Scrub(entries) => write!(f, "scrub of {} files", entries.len()),
+            Job::Heartbeat => write!(f, "heartbeat"),
+            Job::Batch(files) => write!(f, "batch of {} files", files.len()),
+            Job::Archive(files, _) => write!(f, "archive of {} files", files.len()),
+            Job::Transaction(paths, _) => {
+                let paths: Vec<_> = paths.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "send {} together", paths.join(", "))
+            }
+        }
+    }
+}
+
+impl SendOptions {
+    /// The watch root containing `path`, and `path` relative to it
+    fn root_of<'a>(&self, path: &'a Path) -> Option<(&WatchRoot, &'a Path)> {
+        self.roots
+            .iter()
+            .find_map(|root| path.strip_prefix(&root.dir).ok().map(|rel| (root, rel)))
+    }
+
+    fn rel<'a>(&self, path: &'a Path) -> &'a Path {
+        self.root_of(path).map_or(path, |(_, rel)| rel)
+    }
+
+    /// Name of `path` on the destination
+    fn remote_name(&self, path: &Path) -> String {
+        match self.root_of(path) {
+            Some((root, rel)) => root.prefix.join(rel).to_string_lossy().into_owned(),
+            None => outside_roots(path).to_string_lossy().into_owned(),
+        }
+    }
+
+    /// [`remote_name`](Self::remote_name) as sent: its raw bytes if the
+    /// destination takes them, otherwise made UTF-8
+    fn wire_name(&self, path: &Path, features: Features) -> PathBuf {
+        let name = match self.root_of(path) {
+            Some((root, rel)) => root.prefix.join(rel),
+            None => outside_roots(path).to_path_buf(),
+        };
+        if features.contains(Features::RAW_NAMES) {
+            name
+        } else {
+            PathBuf::from(name.to_string_lossy().into_owned())
+        }
+    }
+
+    fn excludes_dir(&self, dir: &Path) -> bool {
+        self.filter.get().excludes_dir(self.rel(dir))
+    }
+
+    /// Does an event on a file ask for it to be sent?
+    fn sends_on(&self, mask: EventMask) -> bool {
+        self.events.iter().any(|event| mask.contains(event.event()))
+    }
+
+    fn allows_file(&self, file: &Path) -> bool {
+        self.filter.get().allows_file(self.rel(file))
+    }
+
+    fn allows(&self, path: &Path, is_dir: bool) -> bool {
+        if is_dir {
+            !self.excludes_dir(path)
+        } else {
+            self.allows_file(path)
+        }
+    }
+
+    /// Version of content read from now on: nanoseconds since the epoch, so
+    /// versions keep growing across restarts, and past the last one handed
+    /// out in case the clock steps back
+    fn next_version(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let next = |last: u64| now.max(last + 1);
+        next(
+            self.last_version
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                    Some(next(last))
+                })
+                .unwrap(),
+        )
+    }
+
+    /// Id of every send of `path` for the change first seen at
+    /// `first_seen`: the same when it is retried, different for later changes
+    /// and in other runs
+    fn transfer_id(&self, path: &Path, first_seen: Instant) -> [u8; 16] {
+        use std::os::unix::ffi::OsStrExt;
+        let mut hasher = Hasher::new();
+        hasher.update(&self.id_seed);
+        hasher.update(path.as_os_str().as_bytes());
+        hasher.update(
+            &(first_seen
+                .saturating_duration_since(self.id_epoch)
+                .as_nanos() as u64)
+                .to_be_bytes(),
+        );
+        hasher.finalize().as_bytes()[..16].try_into().unwrap()
+    }
+
+    /// Features to offer destinations
+    fn features(&self) -> Features {
+        let mut features = Features::ALL;
+        if !self.xattrs {
+            features = features.without(Features::XATTRS);
+        }
+        if self.sender_id.is_none() {
+            features = features.without(Features::SENDER_ID);
+        }
+        if self.integrity == Integrity::Full {
+            features = features.without(Features::TCP_ONLY);
+        }
+        features
+    }
+
+    /// Size and checksum of `path` as it would be sent, after `--pre-send`
+    fn checksum(&self, path: &Path) -> Option<(u64, [u8; 32])> {
+        let Some(cmd) = &self.pre_send else {
+            return manifest::checksum(path).ok();
+        };
+        let source = File::open(path).ok()?;
+        let out = pre_send(cmd, &source, path, &self.remote_name(path)).ok()??;
+        manifest::checksum_file(&out).ok()
+    }
+
+    /// Under `--ignore-received`, was `job` a change a receiver applied
+    /// here? Consumes the receiver's marker.
+    fn is_echo(&self, job: &Job) -> bool {
+        if !self.ignore_received {
+            return false;
+        }
+        let Some(root) = (match job {
+            Job::Delete(path) | Job::Rename(path, _) | Job::Symlink(path) => echo::root_of(path),
+            _ => None,
+        }) else {
+            return false;
+        };
+        let rel = |path: &Path| {
+            path.strip_prefix(root)
+                .map(Path::to_path_buf)
+                .unwrap_or_default()
+        };
+        match job {
+            Job::Delete(path) => echo::consume(root, &Op::Delete(&rel(path))),
+            Job::Rename(from, to) => echo::consume(root, &Op::Rename(&rel(from), &rel(to))),
+            Job::Symlink(path) => echo::consume(root, &Op::Symlink(&rel(path))),
+            _ => false,
+        }
+    }
+
+    /// The job that sends `path` under `--links`, if it is something we send
+    fn send_job(&self, path: PathBuf, first_seen: Instant) -> Option<Job> {
+        match (path.is_symlink(), self.links) {
+            (true, Links::Preserve) => Some(Job::Symlink(path)),
+            (true, Links::Skip) => None,
+            _ if !path.is_file() => None,
+            _ if self.skips_oversized(&path) => None,
+            _ if self.ignore_received
+                && File::open(&path).is_ok_and(|f| xattr::is_received(&f)) =>
+            {
+                debug!(
+                    "[=] Not sending {}: received, unchanged since",
+                    path.display()
+                );
+                None
+            }
+            _ => match &self.transaction_marker {
+                Some(marker) => marked(path, marker, first_seen),
+                None => Some(Job::File(path, first_seen)),
+            },
+        }
+    }
+
+    /// How much longer a due file must wait before its `--stable` rule lets
+    /// it go, if at all. `snapshots` holds the size and mtime each waiting
+    /// file had when last looked at.
+    fn unsettled(
+        &self,
+        path: &Path,
+        snapshots: &mut HashMap<PathBuf, (u64, SystemTime)>,
+    ) -> Option<Duration> {
+        let rel = self.rel(path);
+        let &(_, check) = self
+            .stable
+            .iter()
+            .find(|(filter, _)| filter.allows_file(rel))?;
+        match check {
+            Stability::Quiet(quiet) => {
+                // Gone or unreadable: sending decides what that means
+                let md = std::fs::metadata(path).ok()?;
+                let snapshot = (md.len(), md.modified().ok()?);
+                if snapshots.get(path) == Some(&snapshot) {
+                    snapshots.remove(path);
+                    return None;
+                }
+                snapshots.insert(path.to_path_buf(), snapshot);
+                Some(quiet)
+            }
+            Stability::Unlocked => {
+                let file = File::open(path).ok()?;
+                // Shared, so only writers' exclusive locks keep it back; the
+                // probe's own lock goes with the descriptor
+                let locked =
+                    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } != 0;
+                locked.then_some(LOCK_RETRY)
+            }
+        }
+    }
+
+    /// Warn about a file over `--max-file-size`; is it to be skipped?
+    fn skips_oversized(&self, path: &Path) -> bool {
+        let Some((max, oversized)) = self.max_file_size else {
+            return false;
+        };
+        let Some(size) = std::fs::metadata(path)
+            .ok()
+            .map(|m| m.len())
+            .filter(|&size| size > max)
+        else {
+            return false;
+        };
+        match oversized {
+            Oversized::Skip => warn!(
+                "[!] Skipping {} ({size} bytes): over the --max-file-size of {max} bytes",
+                path.display()
+            ),
+            Oversized::Bulk => warn!(
+                "[!] {} ({size} bytes) is over the --max-file-size of {max} bytes; sending it in bulk",
+                path.display()
+            ),
+        }
+        oversized == Oversized::Skip
+    }
+
+    /// Is `path` a symlink to a directory that `--links follow` descends
+    /// into? Links to one of their own ancestors are not, which rules out cycles.
+    fn follows_dir(&self, path: &Path) -> bool {
+        if self.links != Links::Follow || !path.is_symlink() {
+            return false;
+        }
+        let Ok(target) = path.canonicalize() else {
+            return false;
+        };
+        target.is_dir()
+            && !path
+                .ancestors()
+                .skip(1)
+                .any(|a| a.canonicalize().is_ok_and(|a| a == target))
+    }
+
+    fn watch_mask(&self) -> WatchMask {
+        // New and moved-in directories are always needed to extend the watches
+        let mut mask = WatchMask::MOVED_TO | WatchMask::CREATE | WatchMask::ONLYDIR;
+        for event in &self.events {
+            mask |= event.watch();
+        }
+        if self.propagate_deletes {
+            mask |= WatchMask::DELETE | WatchMask::MOVED_FROM;
+        }
+        mask
+    }
+
+    /// Should a `size`-byte file go out as a resumable transfer over a link
+    /// with `features`?
+    fn resumes(&self, size: u64, features: Features) -> bool {
+        features.contains(Features::RESUME) && self.resume_above.is_some_and(|min| size >= min)
+    }
+
+    /// Should `path` only ship what it gained since the destination's copy?
+    fn follows(&self, path: &Path, features: Features) -> bool {
+        features.contains(Features::APPEND)
+            && self
+                .follow
+                .as_ref()
+                .is_some_and(|f| f.allows_file(self.rel(path)))
+    }
+
+    /// Should a `size`-byte file be announced by its checksum first?
+    fn dedups(&self, size: u64, features: Features) -> bool {
+        features.contains(Features::DEDUP) && self.dedup_above.is_some_and(|min| size >= min)
+    }
+
+    /// Should a `size`-byte file go to the `--multicast` group first?
+    fn multicasts(&self, size: u64, features: Features) -> bool {
+        features.contains(Features::MULTICAST)
+            && self.multicast.as_ref().is_some_and(|group| {
+                size >= group.above.max(1) && multicast::packets(size) <= u32::MAX as u64
+            })
+    }
+
+    /// Should a `size`-byte file be split over several connections?
+    fn stripes(&self, size: u64, features: Features) -> bool {
+        let (streams, above) = self.streams;
+        features.contains(Features::RANGES) && streams > 1 && size >= above.max(1)
+    }
+
+    /// Block size to send a `size`-byte file as a delta with, if any
+    fn delta_block(&self, size: u64, features: Features) -> Option<u32> {
+        let block_size = self
+            .delta_block_size
+            .filter(|_| features.contains(Features::DELTA))?;
+        (size > block_size as u64).then_some(block_size)
+    }
+}
+
+/// The options from `args` that every send shares
+pub(crate) fn send_options(args: &Args) -> Result<SendOptions> {
+    let roots = args
+        .watch_dir
+        .iter()
+        .map(|s| WatchRoot::parse(s))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, a) in roots.iter().enumerate() {
+        for b in &roots[i + 1..] {
+            anyhow::ensure!(
+                !a.dir.starts_with(&b.dir) && !b.dir.starts_with(&a.dir),
+                "Watch directories {} and {} overlap",
+                a.dir.display(),
+                b.dir.display()
+            );
+        }
+    }
+    let key = auth::load_key(args.psk.as_deref(), args.psk_file.as_deref())?;
+    Ok(SendOptions {
+        roots,
+        delta_block_size: args.delta.then_some(args.delta_block_size),
+        resume_above: args.resume_above,
+        dedup_above: args.dedup_above,
+        follow: (!args.follow.is_empty())
+            .then(|| Filter::new(&args.follow, &[]))
+            .transpose()?,
+        chunk_size: u32::try_from(args.chunk_size)
+            .ok()
+            .filter(|size| (1..=handshake::MAX_CHUNK_SIZE).contains(size))
+            .with_context(|| {
+                format!(
+                    "--chunk-size must be between 1 and {}",
+                    handshake::MAX_CHUNK_SIZE
+                )
+            })?,
+        zero_copy: args.zero_copy,
+        integrity: args.integrity,
+        hash_threads: args.hash_threads.into(),
+        streams: (args.streams.into(), args.streams_above),
+        multicast: args
+            .multicast
+            .map(|addr| Multicaster::new(addr, args, key.as_ref()))
+            .transpose()?,
+        proxy: args.proxy.clone(),
+        websocket: args.websocket.then(|| args.websocket_path.clone()),
+        confirm: args.confirm,
+        key,
+        sender_id: match &args.sender_id {
+            Some(id) => {
+                anyhow::ensure!(
+                    handshake::valid_sender_id(id),
+                    "--sender-id must be a valid file name"
+                );
+                Some(id.clone())
+            }
+            None => handshake::hostname(),
+        },
+        filter: Shared::new(Filter::new(&args.include, &args.exclude)?),
+        propagate_deletes: args.propagate_deletes,
+        ignore_received: args.ignore_received,
+        links: args.links,
+        xattrs: args.xattrs,
+        pre_send: args.pre_send.clone(),
+        transaction_marker: args.transaction_marker.clone(),
+        hard_links: args.hard_links,
+        events: args.event_mask.clone(),
+        scrubs: args.scrub_interval.is_some(),
+        max_rate: Shared::new(args.max_rate.map(|rate| Arc::new(RateLimiter::new(rate)))),
+        window: args.window.max(1),
+        batch: args
+            .batch_below
+            .map(|below| (below, args.batch_files as usize)),
+        priority: match (args.priority.is_empty(), args.priority_below) {
+            (true, None) => None,
+            (globs, below) => Some(Priority {
+                filter: (!globs)
+                    .then(|| Filter::new(&args.priority, &[]))
+                    .transpose()?,
+                below,
+            }),
+        },
+        max_file_size: args.max_file_size.map(|max| (max, args.oversized)),
+        stable: args
+            .stable
+            .iter()
+            .map(|rule| {
+                Ok((
+                    Filter::new(std::slice::from_ref(&rule.glob), &[])?,
+                    rule.check,
+                ))
+            })
+            .collect::<Result<_>>()?,
+        ack_timeout: args.ack_timeout.map(Duration::from_secs),
+        file_timeout: args.file_timeout.map(Duration::from_secs),
+        heartbeat: args.heartbeat.map(|secs| Duration::from_secs(secs.max(1))),
+        keepalive: args.keepalive.map(|idle| Keepalive {
+            idle,
+            interval: args.keepalive_interval,
+            count: args.keepalive_count,
+        }),
+        reconnect_max_delay: Duration::from_secs(args.reconnect_max_delay).max(RECONNECT_BASE),
+        max_reconnects: args.max_reconnects,
+        paused: watch::Sender::new(false),
+        id_seed: *blake3::hash(
+            format!("{:?} {}", SystemTime::now(), std::process::id()).as_bytes(),
+        )
+        .as_bytes(),
+        id_epoch: Instant::now(),
+        last_version: AtomicU64::new(0),
+        transfer_log: args
+            .transfer_log
+            .as_deref()
+            .map(TransferLog::open)
+            .transpose()?,
+    })
+}
+
+/// Run the `watcher` with the process's arguments
+pub fn main() -> Result<()> {
+    let (args, matches, table) = config::parse_args::<Args>()?;
+    if args.daemon {
+        daemon::daemonize(args.pid_file.as_deref())?;
+    }
+    tokio::runtime::Runtime::new()?.block_on(run(args, matches, table))
+}
+
+async fn run(args: Args, matches: clap::ArgMatches, table: config::Table) -> Result<()> {
+    log::init(args.log_level, args.log_format);
+    let mut signals = Signals::new()?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    if let Some(port) = args.metrics_port {
+        LazyLock::force(&CONNECTED);
+        LazyLock::force(&OVERFLOWS);
+        metrics::serve(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    }
+
+    let opts = send_options(&args)?;
+    let dests = lanes(destinations(&args, &matches, &table)?, &opts);
+
+    // One task per destination, each with its own connection and retry loop,
+    // so a slow link doesn't hold back the others
+    let opts = Arc::new(opts);
+    if let Some(dir) = args.state_dir.as_ref().filter(|_| !args.dry_run) {
+        std::fs::create_dir_all(dir).with_context(|| format!("Create {}", dir.display()))?;
+    }
+    let (queues, mut tasks): (Vec<_>, Vec<_>) = dests
+        .into_iter()
+        .map(|dest| spawn_destination(dest, &args, &opts))
+        .unzip();
+    link_groups(&queues, args.state_dir.is_some() || args.dry_run);
+    let queues = RefCell::new(queues);
+    let send_all = |job: Job| {
+        if opts.is_echo(&job) {
+            debug!("[=] Not sending {job}: applied by a receiver here");
+            return;
+        }
+        for queue in queues.borrow().iter() {
+            let _ = queue.tx.send(job.clone());
+        }
+    };
+    let mut hard_links = HardLinks::new(opts.hard_links);
+
+    // Watches are in place before the initial sync, so nothing written
+    // during it is missed
+    let mut watch: Box<dyn Watch> = match args.watch_backend {
+        WatchBackend::Inotify => Box::new(InotifyWatch::new(&opts)?),
+        WatchBackend::Poll => {
+            let interval = Duration::from_secs(args.poll_interval.max(1));
+            Box::new(PollWatch::new(opts.clone(), interval, args.poll_checksum))
+        }
+    };
+    if args.dry_run {
+        info!("[*] Dry run: logging what would be sent without connecting to any destination");
+    }
+    let mut control = args.control.as_deref().map(control::serve).transpose()?;
+    let started = Instant::now();
+    daemon::ready();
+
+    if args.initial_sync {
+        let jobs = initial_jobs(&opts, args.seed_archive, &mut hard_links);
+        jobs.into_iter().for_each(send_all);
+        info!("[*] Initial sync complete");
+    }
+    let mut discovered = args.discover.then(|| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(discover(args.discover_allow.clone(), tx));
+        rx
+    });
+
+    // Paths with pending events, sent once they have been quiet for the window
+    let debounce = Duration::from_millis(args.debounce_ms);
+    // Writes through a mapping may never be followed by a close, so a file
+    // that only saw MODIFY goes out once its writes stopped for a while
+    let modify_quiet = debounce.max(Duration::from_millis(args.modify_quiet_ms));
+    let mut pending: HashMap<PathBuf, (Instant, Instant)> = HashMap::new();
+    // Due files that `--stable` keeps waiting, with what they looked like
+    let mut snapshots = HashMap::new();
+    let mut scrub_timer = args.scrub_interval.map(|secs| {
+        let period = Duration::from_secs(secs.max(1));
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+    let mut scrubbing: Option<tokio::task::JoinHandle<()>> = None;
+    loop {
+        let next_due = pending.values().map(|&(_, due)| due).min();
+        tokio::select! {
+            _ = async { scrub_timer.as_mut().unwrap().tick().await }, if scrub_timer.is_some() => {
+                if scrubbing.as_ref().is_some_and(|task| !task.is_finished()) {
+                    warn!("[!] Previous scrub still running; skipping this one");
+                    continue;
+                }
+                let opts = opts.clone();
+                let queues: Vec<_> = queues.borrow().iter().map(|q| q.tx.clone()).collect();
+                let roots: Vec<_> = opts.roots.iter().map(|r| r.dir.clone()).collect();
+                scrubbing = Some(tokio::task::spawn_blocking(move || scrub(&opts, &roots, &queues)));
+            }
+            changes = watch.changes(&opts) => {
+                let now = Instant::now();
+                for change in changes? {
+                    match change {
+                        Change::Written(path) => note_event(&mut pending, path, now, debounce),
+                        Change::Modified(path) => note_event(&mut pending, path, now, modify_quiet),
+                        Change::Deleted(path, is_dir) => {
+                            forget_pending(&mut pending, &path);
+                            // Scans only see files; one the filter now excludes drops
+                            // out of a scan the same way a deleted one does
+                            if opts.propagate_deletes && opts.allows(&path, is_dir) {
+                                send_all(Job::Delete(path));
+                            }
+                        }
+                        Change::Renamed(old, new, is_dir) => {
+                            // Move the destination's copy instead of resending it
+                            if let Some(first_seen) = forget_pending(&mut pending, &old) {
+                                pending.insert(new.clone(), (first_seen, now + debounce));
+                            }
+                            let files = match (opts.allows(&old, is_dir), opts.allows(&new, is_dir)) {
+                                (true, true) => {
+                                    send_all(Job::Rename(old, new));
+                                    continue;
+                                }
+                                (true, false) => {
+                                    send_all(Job::Delete(old));
+                                    continue;
+                                }
+                                (false, true) if is_dir => walk_files(&new, &opts),
+                                (false, true) => vec![new],
+                                (false, false) => continue,
+                            };
+                            for file in files {
+                                note_event(&mut pending, file, now, debounce);
+                            }
+                        }
+                        Change::Lost(dir) => {
+                            forget_pending(&mut pending, &dir);
+                        }
+                        Change::Back(dir) => {
+                            if scrubbing.as_ref().is_some_and(|task| !task.is_finished()) {
+                                warn!(
+                                    "[!] A scrub is already running; {} is not reconciled",
+                                    dir.display()
+                                );
+                                continue;
+                            }
+                            let opts = opts.clone();
+                            let queues: Vec<_> = queues.borrow().iter().map(|q| q.tx.clone()).collect();
+                            scrubbing = Some(tokio::task::spawn_blocking(move || scrub(&opts, &[dir], &queues)));
+                        }
+                    }
+                }
+            }
+            _ = sleep_until(next_due.unwrap_or_else(Instant::now).into()), if next_due.is_some() => {
+                let now = Instant::now();
+                for (first_seen, path) in due_paths(&pending, now) {
+                    // Already went out with its transaction
+                    if pending.remove(&path).is_none() {
+                        continue;
+                    }
+                    if let Some(wait) = opts.unsettled(&path, &mut snapshots) {
+                        debug!(
+                            "[*] {} may still be written to; looking again in {wait:?}",
+                            path.display()
+                        );
+                        pending.insert(path, (first_seen, now + wait));
+                        continue;
+                    }
+                    if let Some(job) = opts.send_job(path, first_seen) {
+                        if let Job::Transaction(members, _) = &job {
+                            // A member still being written brings the
+                            // others along once it settles
+                            if members.iter().any(|m| pending.get(m).is_some_and(|&(_, due)| due > now)) {
+                                continue;
+                            }
+                            for member in members {
+                                pending.remove(member);
+                            }
+                        }
+                        hard_links.expand(job).into_iter().for_each(send_all);
+                    }
+                }
+                // Deleted or renamed while waiting
+                snapshots.retain(|path, _| pending.contains_key(path));
+            }
+            Some((name, addr)) = async { discovered.as_mut().unwrap().recv().await }, if discovered.is_some() => {
+                let known = queues.borrow().iter().any(|q| {
+                    let dest = q.dest.get();
+                    dest.port == addr.port() && dest.host.parse::<IpAddr>().is_ok_and(|ip| ip == addr.ip())
+                });
+                if known {
+                    debug!("[*] Discovered {name} at {addr}, which is already a destination");
+                    continue;
+                }
+                let Some(mut dest) = Destination::parse_group(&addr.to_string(), args.dest_port).pop() else { continue };
+                dest.discovered = true;
+                info!("[+] Discovered {name}; now sending to {}", dest.label());
+                // It missed what is already there, like every destination at startup
+                let jobs = match args.initial_sync {
+                    true => initial_jobs(&opts, args.seed_archive, &mut HardLinks::new(opts.hard_links)),
+                    false => Vec::new(),
+                };
+                let mut queues = queues.borrow_mut();
+                for dest in lanes(vec![dest], &opts) {
+                    let (queue, task) = spawn_destination(dest, &args, &opts);
+                    for job in jobs.iter().filter(|job| !opts.is_echo(job)) {
+                        let _ = queue.tx.send(job.clone());
+                    }
+                    queues.push(queue);
+                    tasks.push(task);
+                }
+                link_groups(&queues, args.state_dir.is_some() || args.dry_run);
+            }
+            Some(req) = async { control.as_mut().unwrap().recv().await }, if control.is_some() => {
+                let answer = answer_control(&req.command, &opts, &queues.borrow(), &mut pending, watch.watches(), started);
+                let _ = req.reply.send(answer);
+            }
+            _ = hangup.recv() => {
+                daemon::notify("RELOADING=1");
+                match reload(&args, &opts, &queues, &mut tasks) {
+                    Ok(()) => {
+                        watch.rewatch(&opts);
+                        info!(
+                            "[*] SIGHUP: reloaded filters, rate limits and {} destinations",
+                            queues.borrow().len()
+                        );
+                    }
+                    Err(e) => warn!("[!] SIGHUP: reload failed, keeping the current configuration: {e:#}"),
+                }
+                daemon::notify("READY=1");
+            }
+            sig = signals.recv() => {
+                daemon::notify("STOPPING=1");
+                info!("[*] {sig}: flushing {} pending files and draining queues", pending.len());
+                break;
+            }
+        }
+    }
+
+    // Send what is still being debounced, then let every destination task
+    // finish its queue and collect the outstanding ACKs
+    let due = pending
+        .into_iter()
+        .map(|(path, (first_seen, _))| (first_seen, path))
+        .collect();
+    for job in due_jobs(&opts, due) {
+        hard_links.expand(job).into_iter().for_each(send_all);
+    }
+    if opts.paused.send_replace(false) {
+        info!("[*] Resuming paused destinations so they drain");
+    }
+    drop(queues);
+    let drained = async {
+        for task in tasks {
+            let _ = task.await;
+        }
+    };
+    tokio::select! {
+        _ = drained => info!("[*] All destinations drained, exiting"),
+        sig = signals.recv() => warn!("[!] {sig} again: exiting without draining"),
+    }
+    if let Some(path) = &args.control {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
+
+/// Answer a command from the `--control` socket
+fn answer_control(
+    command: &str,
+    opts: &SendOptions,
+    queues: &[DestHandle],
+    pending: &mut HashMap<PathBuf, (Instant, Instant)>,
+    watches: usize,
+    started: Instant,
+) -> String {
+    let (name, arg) = command
+        .split_once(' ')
+        .map_or((command, ""), |(name, arg)| (name, arg.trim()));
+    let mut out = String::new();
+    match (name, arg) {
+        ("status", "") => {
+            let dirs: Vec<_> = opts
+                .roots
+                .iter()
+                .map(|r| r.dir.display().to_string())
+                .collect();
+            let state = if *opts.paused.borrow() {
+                "paused"
+            } else {
+                "running"
+            };
+            let _ = writeln!(out, "state: {state}");
+            let _ = writeln!(out, "uptime: {}s", started.elapsed().as_secs());
+            let _ = writeln!(
+                out,
+                "watching: {watches} directories under {}",
+                dirs.join(", ")
+            );
+            let _ = writeln!(out, "pending: {} files", pending.len());
+            for queue in queues {
+                let (dest, m) = (queue.dest.get(), &queue.metrics);
+                let link = match (m.connected.get(), m.health.get()) {
+                    (1.., _) => "connected",
+                    (_, 2) => "down",
+                    _ => "disconnected",
+                };
+                let up = |m: &Arc<DestMetrics>| m.health.get() != HealthState::Down as i64;
+                let link = if queue.ahead.get().iter().any(up) {
+                    format!("{link} standby")
+                } else {
+                    link.to_string()
+                };
+                let _ = writeln!(
+                    out,
+                    "{}: {link}, {} queued, {} sent, {} unchanged, {} failed",
+                    dest.label(),
+                    m.queue_depth.get(),
+                    m.sent.get(),
+                    m.unchanged.get(),
+                    m.failed.get()
+                );
+            }
+        }
+        ("queue", "") => {
+            let now = Instant::now();
+            let mut waiting: Vec<_> = pending.iter().collect();
+            waiting.sort_by_key(|(_, (_, due))| *due);
+            for (path, (first_seen, due)) in waiting {
+                let _ = writeln!(
+                    out,
+                    "{} (seen {}ms ago, due in {}ms)",
+                    path.display(),
+                    now.duration_since(*first_seen).as_millis(),
+                    due.saturating_duration_since(now).as_millis()
+                );
+            }
+            for queue in queues {
+                let dest = queue.dest.get();
+                let _ = writeln!(
+                    out,
+                    "{}: {} queued",
+                    dest.label(),
+                    queue.metrics.queue_depth.get()
+                );
+            }
+        }
+        ("pause", "") => {
+            if opts.paused.send_replace(true) {
+                out.push_str("already paused\n");
+            } else {
+                info!("[*] Paused: destinations hold their queues until resumed");
+                out.push_str("paused\n");
+            }
+        }
+        ("resume", "") => {
+            if opts.paused.send_replace(false) {
+                info!("[*] Resumed");
+                out.push_str("resumed\n");
+            } else {
+                out.push_str("not paused\n");
+            }
+        }
+        ("resync", path) if !path.is_empty() => {
+            let path = Path::new(path);
+            // Relative watch directories name files relative to our working directory
+            let cwd = std::env::current_dir().unwrap_or_default();
+            let path = path
+                .strip_prefix(&cwd)
+                .ok()
+                .filter(|rel| opts.root_of(rel).is_some())
+                .unwrap_or(path);
+            if opts.root_of(path).is_none() {
+                let _ = writeln!(
+                    out,
+                    "error: {} is not under a watched directory",
+                    path.display()
+                );
+                return out;
+            }
+            let files = if path.is_dir() {
+                walk_files(path, opts)
+            } else if path.symlink_metadata().is_ok() && opts.allows_file(path) {
+                vec![path.to_path_buf()]
+            } else {
+                Vec::new()
+            };
+            info!(
+                "[*] Resync of {} requested: {} files",
+                path.display(),
+                files.len()
+            );
+            let now = Instant::now();
+            let _ = writeln!(out, "queued {} files", files.len());
+            for file in files {
+                pending.entry(file).or_insert((now, now)).1 = now;
+            }
+        }
+        _ => {
+            let _ = writeln!(
+                out,
+                "error: unknown command {command:?}; expected status, queue, pause, resume or resync <path>"
+            );
+        }
+    }
+    out
+}
+
+/// The destinations to send to: `[[dest]]` tables from the config file,
+/// unless --dests was given, and those of --listen. Those --discover finds
+/// come later.
+fn destinations(
+    args: &Args,
+    matches: &clap::ArgMatches,
+    table: &config::Table,
+) -> Result<Vec<Destination>> {
+    let from_cli = matches.value_source("dests") == Some(ValueSource::CommandLine);
+    let mut dests = match table.get("dest") {
+        Some(Value::Array(items)) if !from_cli => items
+            .iter()
+            .map(|item| match item {
+                Value::Table(t) => Destination::from_table(t, args.dest_port),
+                _ => anyhow::bail!("[[dest]] entries must be tables"),
+            })
+            .collect::<Result<Vec<_>>>()?,
+        // --listen or --discover alone replaces the default
+        _ if (!args.listen.is_empty() || args.discover)
+            && matches.value_source("dests") == Some(ValueSource::DefaultValue) =>
+        {
+            Vec::new()
+        }
+        _ => Destination::parse_list(&args.dests, args.dest_port),
+    };
+    dests.extend(args.listen.iter().map(|addr| Destination {
+        host: addr.ip().to_string(),
+        port: addr.port(),
+        filter: Filter::default(),
+        max_rate: None,
+        confirm: None,
+        group: None,
+        listen: true,
+        lane: Lane::All,
+        discovered: false,
+    }));
+    let is_section = |v: &Value| matches!(v, Value::Table(_)) || config::is_table_array(v);
+    if let Some(section) = table
+        .iter()
+        .find(|(k, v)| *k != "dest" && is_section(v))
+        .map(|(k, _)| k)
+    {
+        anyhow::bail!("Unknown config section [{section}]");
+    }
+    Ok(dests)
+}
+
+/// Under `--priority`, split each destination into a bulk and a priority
+/// lane. Destinations that connect in keep one connection.
+fn lanes(dests: Vec<Destination>, opts: &SendOptions) -> Vec<Destination> {
+    if opts.priority.is_none() {
+        return dests;
+    }
+    let mut out = Vec::with_capacity(dests.len() * 2);
+    for dest in dests {
+        if dest.listen {
+            warn!(
+                "[!] {}:{} connects in; it gets no priority connection",
+                dest.host, dest.port
+            );
+            out.push(dest);
+            continue;
+        }
+        // Each lane fails over within its own group
+        let group = dest.group.as_ref().map(|g| format!("{g}/priority"));
+        out.push(Destination {
+            lane: Lane::Priority,
+            group,
+            ..dest.clone()
+        });
+        out.push(Destination {
+            lane: Lane::Bulk,
+            ..dest
+        });
+    }
+    out
+}
+
+/// A destination task's queue and the settings it sends with
+struct DestHandle {
+    dest: Arc<Shared<Destination>>,
+    tx: mpsc::UnboundedSender<Job>,
+    metrics: Arc<DestMetrics>,
+    /// Members of its failover group listed before it
+    ahead: Arc<Shared<Vec<Arc<DestMetrics>>>>,
+}
+
+/// Point each failover group member at the members it stands by for
+fn link_groups(queues: &[DestHandle], journaled: bool) {
+    let mut groups: HashMap<String, Vec<Arc<DestMetrics>>> = HashMap::new();
+    for queue in queues {
+        let Some(group) = queue.dest.get().group.clone() else {
+            queue.ahead.set(Vec::new());
+            continue;
+        };
+        let members = groups.entry(group).or_default();
+        queue.ahead.set(members.clone());
+        members.push(queue.metrics.clone());
+    }
+    if !journaled && groups.values().any(|members| members.len() > 1) {
+        warn!(
+            "[!] Without --state-dir, files a down primary misses are not sent to it when it is back"
+        );
+    }
+}
+
+/// Does a failover group member with `ahead` listed before it wait? A
+/// standby only takes over while everything ahead of it is down.
+fn standing_by(ahead: &[Arc<DestMetrics>]) -> bool {
+    ahead
+        .iter()
+        .any(|m| m.health.get() != HealthState::Down as i64)
+}
+
+fn spawn_destination(
+    dest: Destination,
+    args: &Args,
+    opts: &Arc<SendOptions>,
+) -> (DestHandle, JoinHandle<()>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let journal = args.state_dir.as_ref().map(|dir| match dest.lane {
+        Lane::Priority => {
+            RetryJournal::new(dir.join(format!("{}_{}.priority.queue", dest.host, dest.port)))
+        }
+        Lane::All | Lane::Bulk => {
+            RetryJournal::new(dir.join(format!("{}_{}.queue", dest.host, dest.port)))
+        }
+    });
+    let span = fields!(dest = dest.label());
+    let metrics = Arc::new(DestMetrics::new(&dest));
+    let dest = Arc::new(Shared::new(dest));
+    let ahead = Arc::new(Shared::new(Vec::new()));
+    let task = if args.dry_run {
+        tokio::spawn(dry_run_destination(dest.clone(), opts.clone(), rx))
+    } else {
+        let run = run_destination(
+            dest.clone(),
+            opts.clone(),
+            rx,
+            journal,
+            metrics.clone(),
+            ahead.clone(),
+        );
+        tokio::spawn(log::in_span(span, run))
+    };
+    (
+        DestHandle {
+            dest,
+            tx,
+            metrics,
+            ahead,
+        },
+        task,
+    )
+}
+
+/// Re-read the command line and config file on SIGHUP and apply what can
+/// change while running: the filters, rate limits and destinations. Removed
+/// destinations finish their queue first. Nothing is applied if anything
+/// fails to parse.
+fn reload(
+    args: &Args,
+    opts: &Arc<SendOptions>,
+    queues: &RefCell<Vec<DestHandle>>,
+    tasks: &mut Vec<JoinHandle<()>>,
+) -> Result<()> {
+    let (new_args, matches, table) = config::reparse_args::<Args>()?;
+    let filter = Filter::new(&new_args.include, &new_args.exclude)?;
+    let mut dests = lanes(destinations(&new_args, &matches, &table)?, opts);
+    let order: Vec<_> = dests
+        .iter()
+        .map(|d| (d.host.clone(), d.port, d.lane))
+        .collect();
+
+    opts.filter.set(filter);
+    let max_rate = new_args
+        .max_rate
+        .map(|rate| Arc::new(RateLimiter::new(rate)));
+    opts.max_rate
+        .set(keep_limit(&opts.max_rate.get(), max_rate));
+    let mut queues = queues.borrow_mut();
+    queues.retain(|queue| {
+        let current = queue.dest.get();
+        match dests.iter().position(|d| {
+            d.host == current.host && d.port == current.port && d.lane == current.lane
+        }) {
+            Some(i) => {
+                let mut dest = dests.remove(i);
+                dest.max_rate = keep_limit(&current.max_rate, dest.max_rate.take());
+                queue.dest.set(dest);
+                true
+            }
+            None if current.discovered => true,
+            None => {
+                info!(
+                    "[-] No longer sending to {}; draining its queue",
+                    current.label()
+                );
+                false
+            }
+        }
+    });
+    for dest in dests {
+        info!("[+] Now sending to {}", dest.label());
+        let (queue, task) = spawn_destination(dest, args, opts);
+        queues.push(queue);
+        tasks.push(task);
+    }
+    // Failover order follows the new config
+    queues.sort_by_key(|queue| {
+        let dest = queue.dest.get();
+        order.iter().position(|(host, port, lane)| {
+            *host == dest.host && *port == dest.port && *lane == dest.lane
+        })
+    });
+    link_groups(&queues, args.state_dir.is_some() || args.dry_run);
+    Ok(())
+}
+
+/// `new`, or `old` if it has the same rate so its bucket carries over
+fn keep_limit(
+    old: &Option<Arc<RateLimiter>>,
+    new: Option<Arc<RateLimiter>>,
+) -> Option<Arc<RateLimiter>> {
+    match (old, new) {
+        (Some(old), Some(new)) if old.rate() == new.rate() => Some(old.clone()),
+        (_, new) => new,
+    }
+}
+
+/// The names each multiply-linked file was sent under, for `--hard-links`
+struct HardLinks {
+    enabled: bool,
+    /// By device and inode
+    groups: HashMap<(u64, u64), LinkGroup>,
+}
+
+struct LinkGroup {
+    /// Known names, the one whose content was sent last first
+    names: Vec<PathBuf>,
+    /// Size and mtime of that content
+    sent: (u64, i64, i64),
+}
+
+impl HardLinks {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// The jobs that bring the destinations up to date with `job`: a new name
+    /// of content already sent becomes a hard link to it, and new content
+    /// goes out once and is linked under every other name. Writing a file
+    /// replaces the destination's copy, which breaks its links, so changed
+    /// content is always relinked.
+    fn expand(&mut self, job: Job) -> Vec<Job> {
+        use std::os::unix::fs::MetadataExt;
+        let Job::File(path, first_seen) = &job else {
+            return vec![job];
+        };
+        if !self.enabled {
+            return vec![job];
+        }
+        let Ok(md) = path.metadata() else {
+            return vec![job];
+        };
+        let key = (md.dev(), md.ino());
+        if md.nlink() < 2 {
+            self.groups.remove(&key);
+            return vec![job];
+        }
+        let content = (md.len(), md.mtime(), md.mtime_nsec());
+        let group = self.groups.entry(key).or_insert_with(|| LinkGroup {
+            names: Vec::new(),
+            sent: content,
+        });
+        // Forget names that were deleted or renamed since
+        group
+            .names
+            .retain(|name| name.metadata().is_ok_and(|m| (m.dev(), m.ino()) == key));
+        match group.names.first() {
+            Some(first) if group.sent == content => {
+                let first = first.clone();
+                if !group.names.contains(path) {
+                    group.names.push(path.clone());
+                }
+                if first == *path {
+                    vec![job]
+                } else {
+                    vec![Job::HardLink(path.clone(), first)]
+                }
+            }
+            _ => {
+                group.names.retain(|name| name != path);
+                group.names.insert(0, path.clone());
+                group.sent = content;
+                let links = group.names[1..]
+                    .iter()
+                    .map(|name| Job::HardLink(name.clone(), path.clone()));
+                std::iter::once(Job::File(path.clone(), *first_seen))
+                    .chain(links)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Checksums in one manifest message
+const SCRUB_BATCH: usize = 1024;
+
+/// Hash every watched file and hand the manifests to each destination task
+fn scrub(opts: &SendOptions, roots: &[PathBuf], queues: &[mpsc::UnboundedSender<Job>]) {
+    // Preserved links are not compared; their targets may not even exist
+    let files: Vec<_> = roots
+        .iter()
+        .flat_map(|dir| walk_files(dir, opts))
+        .filter(|path| opts.links != Links::Preserve || !path.is_symlink())
+        .collect();
+    info!(
+        "[*] Scrub: comparing {} files with the destinations",
+        files.len()
+    );
+    for batch in files.chunks(SCRUB_BATCH) {
+        let entries: Vec<_> = batch
+            .iter()
+            .filter_map(|path| {
+                let (size, checksum) = opts.checksum(path)?;
+                Some(ScrubEntry {
+                    path: path.clone(),
+                    size,
+                    checksum,
+                })
+            })
+            .collect();
+        let job = Job::Scrub(Arc::new(entries));
+        for tx in queues {
+            let _ = tx.send(job.clone());
+        }
+    }
+}
+
+/// Note an event for `path` at `now`: however many came before, it is sent
+/// once none came for `quiet`, and keeps the time of the first
+fn note_event(
+    pending: &mut HashMap<PathBuf, (Instant, Instant)>,
+    path: PathBuf,
+    now: Instant,
+    quiet: Duration,
+) {
+    pending.entry(path).or_insert((now, now)).1 = now + quiet;
+}
+
+/// Pending paths that are due at `now`, with their first event, oldest first
+fn due_paths(
+    pending: &HashMap<PathBuf, (Instant, Instant)>,
+    now: Instant,
+) -> Vec<(Instant, PathBuf)> {
+    let mut due: Vec<_> = pending
+        .iter()
+        .filter(|(_, (_, due))| *due <= now)
+        .map(|(path, &(first_seen, _))| (first_seen, path.clone()))
+        .collect();
+    due.sort();
+    due
+}
+
+/// Drop pending sends for `path` and anything below it, returning the
+/// earliest first-event time among them
+fn forget_pending(
+    pending: &mut HashMap<PathBuf, (Instant, Instant)>,
+    path: &Path,
+) -> Option<Instant> {
+    let mut first_seen = None;
+    pending.retain(|p, &mut (seen, _)| {
+        let keep = !p.starts_with(path);
+        if !keep {
+            first_seen = Some(first_seen.map_or(seen, |f: Instant| f.min(seen)));
+        }
+        keep
+    });
+    first_seen
+}
+
+/// What a [`Watch`] noticed under the watched trees
+enum Change {
+    /// Created, or written and closed
+    Written(PathBuf),
+    /// Written to without a close, maybe through a mapping: goes out once
+    /// its writes stopped for `--modify-quiet-ms`
+    Modified(PathBuf),
+    /// Deleted or moved out of the trees, and whether it was a directory
+    Deleted(PathBuf, bool),
+    /// Moved inside the trees from the first path to the second
+    Renamed(PathBuf, PathBuf, bool),
+    /// A watched directory went away or was unmounted
+    Lost(PathBuf),
+    /// A lost directory is back and watched again; its changed files were
+    /// reported just before, but what was deleted meanwhile needs a scrub
+    Back(PathBuf),
+}
+
+type Changes<'a> = Pin<Box<dyn Future<Output = Result<Vec<Change>>> + 'a>>;
+
+/// A watch backend: how a `watcher` hears about changes under its roots.
+/// Debouncing, filtering what goes out and sending stay in the main loop.
+trait Watch {
+    /// Wait for the next batch of changes. Dropping the future before it is
+    /// ready loses none, so it can sit in a `select!`.
+    fn changes<'a>(&'a mut self, opts: &'a SendOptions) -> Changes<'a>;
+
+    /// Cover the directories a reloaded filter lets in
+    fn rewatch(&mut self, opts: &SendOptions);
+
+    /// Directories watched, for `status`
+    fn watches(&self) -> usize;
+}
+
+/// `--watch-backend inotify`: a watch on every directory of the trees
+struct InotifyWatch {
+    inotify: AsyncFd<Inotify>,
+    wds: HashMap<WatchDescriptor, PathBuf>,
+    buf: [u8; 4096],
+    /// When the event queue was last read; events lost to an overflow are newer
+    drained_at: SystemTime,
+    /// Watched directories whose watch died, with the device they were
+    /// unmounted from (`None` if removed) and when
+    lost: HashMap<PathBuf, (Option<u64>, SystemTime)>,
+    root_poll: tokio::time::Interval,
+}
+
+impl InotifyWatch {
+    fn new(opts: &SendOptions) -> Result<Self> {
+        let drained_at = SystemTime::now();
+        let inotify = Inotify::init().context("init inotify")?;
+        let mut wds = HashMap::new();
+        for root in &opts.roots {
+            add_watches_recursive(&inotify, &root.dir, opts, &mut wds)?;
+        }
+        let dirs: Vec<_> = opts
+            .roots
+            .iter()
+            .map(|r| r.dir.display().to_string())
+            .collect();
+        info!(
+            "[*] Watching {} directories under {}",
+            wds.len(),
+            dirs.join(", ")
+        );
+        Ok(Self {
+            inotify: AsyncFd::new(inotify)?,
+            wds,
+            buf: [0; 4096],
+            drained_at,
+            lost: HashMap::new(),
+            root_poll: tokio::time::interval(ROOT_POLL),
+        })
+    }
+
+    /// Add watches on `dir` and below, logging a failure
+    fn watch(&mut self, dir: &Path, opts: &SendOptions) -> bool {
+        let added = add_watches_recursive(self.inotify.get_ref(), dir, opts, &mut self.wds);
+        if let Err(e) = &added {
+            warn!("[!] Failed to watch {}: {e}", dir.display());
+        }
+        added.is_ok()
+    }
+
+    fn events(&mut self, events: Vec<EventOwned>, opts: &SendOptions) -> Vec<Change> {
+        let read_at = SystemTime::now();
+        let mut changes = Vec::new();
+        // MOVED_FROM halves waiting for their MOVED_TO, by cookie
+        let mut moved_from: HashMap<u32, (PathBuf, bool)> = HashMap::new();
+        for ev in events {
+            if ev.mask.contains(EventMask::Q_OVERFLOW) {
+                OVERFLOWS.inc();
+                // A second of slack for coarse mtimes
+                let since = self.drained_at - Duration::from_secs(1);
+                warn!("[!] inotify queue overflowed; rescanning for files changed since then");
+                let queued = changes.len();
+                for root in &opts.roots {
+                    // Directories whose creation was lost need their watches too
+                    self.watch(&root.dir, opts);
+                    changes.extend(changed_since(&root.dir, since, opts));
+                }
+                info!(
+                    "[*] Rescan queued {} files; deletions and renames in the gap were not seen",
+                    changes.len() - queued
+                );
+                continue;
+            }
+            if ev.mask.intersects(EventMask::IGNORED | EventMask::UNMOUNT) {
+                let dir = match ev.mask.contains(EventMask::IGNORED) {
+                    true => self.wds.remove(&ev.wd),
+                    false => self.wds.get(&ev.wd).cloned(),
+                };
+                // A watched directory itself went away: its tree is
+                // watched again once it's back
+                if let Some(dir) = dir
+                    && opts.roots.iter().any(|r| r.dir == dir)
+                    && !self.lost.contains_key(&dir)
+                {
+                    let unmounted = ev.mask.contains(EventMask::UNMOUNT);
+                    let dev = unmounted.then(|| std::fs::metadata(&dir).map_or(0, |md| md.dev()));
+                    let how = if unmounted {
+                        "was unmounted"
+                    } else {
+                        "went away"
+                    };
+                    warn!("[!] {} {how}; waiting for it to come back", dir.display());
+                    changes.push(Change::Lost(dir.clone()));
+                    self.lost.insert(dir, (dev, read_at));
+                }
+                continue;
+            }
+            let Some(name) = ev.name else { continue };
+            let Some(dir) = self.wds.get(&ev.wd) else {
+                continue;
+            };
+            let full = dir.join(name);
+            let is_dir = ev.mask.contains(EventMask::ISDIR) || opts.follows_dir(&full);
+            if ev.mask.contains(EventMask::MOVED_FROM) {
+                moved_from.insert(ev.cookie, (full, is_dir));
+                continue;
+            }
+            if ev.mask.contains(EventMask::DELETE) {
+                changes.push(Change::Deleted(full, is_dir));
+                continue;
+            }
+            if let Some((old, _)) = ev
+                .mask
+                .contains(EventMask::MOVED_TO)
+                .then(|| moved_from.remove(&ev.cookie))
+                .flatten()
+            {
+                if is_dir && !opts.excludes_dir(&full) {
+                    self.watch(&full, opts);
+                }
+                changes.push(Change::Renamed(old, full, is_dir));
+            } else if is_dir {
+                // New or moved-in directory: watch it and pick up anything
+                // written into it before the watch was in place
+                if ev.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                    && !opts.excludes_dir(&full)
+                {
+                    self.watch(&full, opts);
+                    changes.extend(walk_files(&full, opts).into_iter().map(Change::Written));
+                }
+            } else if (opts.sends_on(ev.mask) || opts.links == Links::Preserve && full.is_symlink())
+                && opts.allows_file(&full)
+            {
+                changes.push(match ev.mask.contains(EventMask::MODIFY) {
+                    true => Change::Modified(full),
+                    false => Change::Written(full),
+                });
+            }
+        }
+        self.drained_at = read_at;
+        // Moved out of the tree: gone as far as the destination is concerned
+        for (_, (old, is_dir)) in moved_from {
+            if is_dir {
+                let stale: Vec<_> = self
+                    .wds
+                    .iter()
+                    .filter(|(_, path)| path.starts_with(&old))
+                    .map(|(wd, _)| wd.clone())
+                    .collect();
+                for wd in stale {
+                    let _ = self.inotify.get_ref().watches().remove(wd.clone());
+                    self.wds.remove(&wd);
+                }
+            }
+            changes.push(Change::Deleted(old, is_dir));
+        }
+        changes
+    }
+
+    /// Watch lost directories that are back again
+    fn found(&mut self, opts: &SendOptions) -> Vec<Change> {
+        let back: Vec<_> = self
+            .lost
+            .iter()
+            .filter(|&(dir, &(dev, _))| {
+                std::fs::metadata(dir)
+                    .is_ok_and(|md| md.is_dir() && dev.is_none_or(|dev| md.dev() != dev))
+            })
+            .map(|(dir, _)| dir.clone())
+            .collect();
+        let mut changes = Vec::new();
+        for dir in back {
+            let (_, since) = self.lost.remove(&dir).unwrap();
+            if let Err(e) = add_watches_recursive(self.inotify.get_ref(), &dir, opts, &mut self.wds)
+            {
+                warn!("[!] Failed to watch {} again: {e}", dir.display());
+                self.lost.insert(dir, (None, since));
+                continue;
+            }
+            // Whatever changed while it was gone went unseen
+            let queued = changes.len();
+            changes.extend(changed_since(&dir, since - Duration::from_secs(1), opts));
+            info!(
+                "[*] {} is back; watching it again and queued {} changed files",
+                dir.display(),
+                changes.len() - queued
+            );
+            changes.push(Change::Back(dir));
+        }
+        changes
+    }
+}
+
+impl Watch for InotifyWatch {
+    fn changes<'a>(&'a mut self, opts: &'a SendOptions) -> Changes<'a> {
+        Box::pin(async move {
+            tokio::select! {
+                events = read_events(&mut self.inotify, &mut self.buf) => Ok(self.events(events?, opts)),
+                _ = self.root_poll.tick(), if !self.lost.is_empty() => Ok(self.found(opts)),
+            }
+        })
+    }
+
+    fn rewatch(&mut self, opts: &SendOptions) {
+        for root in &opts.roots {
+            self.watch(&root.dir, opts);
+        }
+    }
+
+    fn watches(&self) -> usize {
+        self.wds.len()
+    }
+}
+
+/// Files under `dir` modified at or after `since`
+fn changed_since(dir: &Path, since: SystemTime, opts: &SendOptions) -> Vec<Change> {
+    walk_files(dir, opts)
+        .into_iter()
+        .filter(|file| {
+            std::fs::metadata(file)
+                .and_then(|m| m.modified())
+                .is_ok_and(|t| t >= since)
+        })
+        .map(Change::Written)
+        .collect()
+}
+
+/// Wait for the next batch of inotify events
+async fn read_events(inotify: &mut AsyncFd<Inotify>, buf: &mut [u8]) -> Result<Vec<EventOwned>> {
+    loop {
+        let mut guard = inotify.readable_mut().await?;
+        match guard.try_io(|fd| fd.get_mut().read_events(buf)) {
+            Ok(events) => return Ok(events?.map(|ev| ev.to_owned()).collect()),
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+/// Add a watch on `dir` and every non-excluded directory below it
+fn add_watches_recursive(
+    inotify: &Inotify,
+    dir: &Path,
+    opts: &SendOptions,
+    wds: &mut HashMap<WatchDescriptor, PathBuf>,
+) -> Result<()> {
+    let wd = inotify
+        .watches()
+        .add(dir, opts.watch_mask())
+        .with_context(|| format!("Watch {}", dir.display()))?;
+    wds.insert(wd, dir.to_path_buf());
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        if (entry.file_type().is_ok_and(|t| t.is_dir()) || opts.follows_dir(&path))
+            && !opts.excludes_dir(&path)
+        {
+            add_watches_recursive(inotify, &path, opts, wds)?;
+        }
+    }
+    Ok(())
+}
+
+/// Under `--transaction-marker`, `path` and its marker (or `path` and the data
+/// file it marks) go out as one transaction. A data file without a marker
+/// waits for it; a marker without a data file goes out alone.
+fn marked(path: PathBuf, marker: &str, first_seen: Instant) -> Option<Job> {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    if let Some(data) = path.as_os_str().as_bytes().strip_suffix(marker.as_bytes()) {
+        let data = PathBuf::from(std::ffi::OsStr::from_bytes(data));
+        return Some(if data.is_file() {
+            Job::Transaction(vec![data, path], first_seen)
+        } else {
+            Job::File(path, first_seen)
+        });
+    }
+    let mut with_marker = path.clone().into_os_string().into_vec();
+    with_marker.extend_from_slice(marker.as_bytes());
+    let with_marker = PathBuf::from(std::ffi::OsString::from_vec(with_marker));
+    if with_marker.is_file() {
+        Some(Job::Transaction(vec![path, with_marker], first_seen))
+    } else {
+        debug!(
+            "[*] Holding {} until {} appears",
+            path.display(),
+            with_marker.display()
+        );
+        None
+    }
+}
+
+/// The jobs that send `due` files, oldest first, each file once even when
+/// a transaction already took it along
+fn due_jobs(opts: &SendOptions, mut due: Vec<(Instant, PathBuf)>) -> Vec<Job> {
+    due.sort();
+    let mut taken = std::collections::HashSet::new();
+    let mut jobs = Vec::new();
+    for (first_seen, path) in due {
+        if taken.contains(&path) {
+            continue;
+        }
+        if let Some(job) = opts.send_job(path, first_seen) {
+            if let Job::Transaction(members, _) = &job {
+                taken.extend(members.iter().cloned());
+            }
+            jobs.push(job);
+        }
+    }
+    jobs
+}
+
+/// All regular files below `dir` that pass the filters, plus the symlinks
+/// `--links` sends
+fn walk_files(dir: &Path, opts: &SendOptions) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return files;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if (t.is_dir() || opts.follows_dir(&path)) && !opts.excludes_dir(&path) => {
+                files.extend(walk_files(&path, opts))
+            }
+            Ok(t) if t.is_file() && opts.allows_file(&path) => files.push(path),
+            Ok(t) if t.is_symlink() && opts.allows_file(&path) => match opts.links {
+                Links::Preserve => files.push(path),
+                Links::Follow if path.is_file() => files.push(path),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    files
+}
+
+/// Size, mtime and, with `--poll-checksum`, content hash of each file the
+/// last scan saw
+type Seen = HashMap<PathBuf, (u64, SystemTime, Option<[u8; 32]>)>;
+
+/// The jobs `--initial-sync` sends: every file already in the watched
+/// trees, as one archive with `--seed-archive`
+fn initial_jobs(opts: &SendOptions, seed_archive: bool, hard_links: &mut HardLinks) -> Vec<Job> {
+    let files: Vec<_> = opts
+        .roots
+        .iter()
+        .flat_map(|r| walk_files(&r.dir, opts))
+        .collect();
+    info!("[*] Initial sync: {} files", files.len());
+    let jobs = due_jobs(
+        opts,
+        files.into_iter().map(|f| (Instant::now(), f)).collect(),
+    );
+    let jobs: Vec<_> = jobs
+        .into_iter()
+        .flat_map(|job| hard_links.expand(job))
+        .collect();
+    if !seed_archive {
+        return jobs;
+    }
+    // Links and transactions follow the archive, which brings the files
+    // they refer to
+    let (files, rest): (Vec<_>, Vec<_>) = jobs
+        .into_iter()
+        .partition(|job| matches!(job, Job::File(..)));
+    let files = files.into_iter().map(|job| match job {
+        Job::File(path, _) => path,
+        _ => unreachable!(),
+    });
+    std::iter::once(Job::Archive(Arc::new(files.collect()), Instant::now()))
+        .chain(rest)
+        .collect()
+}
+
+/// How often `--discover` asks the local network for receivers
+const DISCOVER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Ask for advertised receivers every [`DISCOVER_INTERVAL`] and report each
+/// one `allow` lets in, by name and address, the first time it answers
+async fn discover(allow: Vec<String>, tx: mpsc::UnboundedSender<(String, SocketAddr)>) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("[!] --discover: failed to open a socket, finding no receivers: {e}");
+            return;
+        }
+    };
+    info!("[*] Looking for receivers over mDNS");
+    let query = discovery::query();
+    let mut seen = HashSet::new();
+    let mut buf = vec![0; 9000];
+    let mut ticks = tokio::time::interval(DISCOVER_INTERVAL);
+    loop {
+        let (n, from) = tokio::select! {
+            _ = ticks.tick() => {
+                if let Err(e) = socket.send_to(&query, discovery::MDNS).await {
+                    warn!("[!] --discover: failed to send an mDNS query: {e}");
+                }
+                continue;
+            }
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("[!] --discover: failed to receive: {e}");
+                    continue;
+                }
+            },
+        };
+        for receiver in discovery::found(&buf[..n]) {
+            // The answer's source reached us, so it is the address to use
+            // unless the answer names others
+            let ip = match receiver.addrs.first() {
+                Some(&addr) if !receiver.addrs.iter().any(|&a| IpAddr::V4(a) == from.ip()) => {
+                    IpAddr::V4(addr)
+                }
+                _ => from.ip(),
+            };
+            let addr = SocketAddr::new(ip, receiver.port);
+            if !seen.insert((receiver.name.clone(), addr)) {
+                continue;
+            }
+            if !allow.is_empty() && !allow.contains(&receiver.name) {
+                debug!(
+                    "[*] Discovered {} at {addr}, which --discover-allow leaves out",
+                    receiver.name
+                );
+                continue;
+            }
+            if tx.send((receiver.name, addr)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// `--watch-backend poll`: compare scans of the trees, for filesystems
+/// without inotify events, like network mounts written from other hosts
+struct PollWatch {
+    rx: mpsc::UnboundedReceiver<Change>,
+}
+
+impl PollWatch {
+    fn new(opts: Arc<SendOptions>, interval: Duration, checksums: bool) -> Self {
+        let dirs: Vec<_> = opts
+            .roots
+            .iter()
+            .map(|r| r.dir.display().to_string())
+            .collect();
+        info!(
+            "[*] Scanning {} every {}s",
+            dirs.join(", "),
+            interval.as_secs()
+        );
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(poll(opts, interval, checksums, tx));
+        Self { rx }
+    }
+}
+
+impl Watch for PollWatch {
+    fn changes<'a>(&'a mut self, _opts: &'a SendOptions) -> Changes<'a> {
+        Box::pin(async move {
+            let first = self.rx.recv().await.context("Scanning stopped")?;
+            let mut changes = vec![first];
+            while let Ok(change) = self.rx.try_recv() {
+                changes.push(change);
+            }
+            Ok(changes)
+        })
+    }
+
+    /// Every scan walks the trees with the current filter
+    fn rewatch(&mut self, _opts: &SendOptions) {}
+
+    fn watches(&self) -> usize {
+        0
+    }
+}
+
+/// Scan the watched trees every `interval` and report what changed since the
+/// scan before. The first scan only takes stock.
+async fn poll(
+    opts: Arc<SendOptions>,
+    interval: Duration,
+    checksums: bool,
+    tx: mpsc::UnboundedSender<Change>,
+) {
+    let mut seen: Option<Seen> = None;
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let opts = opts.clone();
+        let Ok((now_seen, changes)) =
+            tokio::task::spawn_blocking(move || scan(&opts, seen, checksums)).await
+        else {
+            return;
+        };
+        debug!(
+            "[*] Scan found {} changes in {} files",
+            changes.len(),
+            now_seen.len()
+        );
+        for change in changes {
+            if tx.send(change).is_err() {
+                return;
+            }
+        }
+        seen = Some(now_seen);
+    }
+}
+
+fn scan(opts: &SendOptions, mut before: Option<Seen>, checksums: bool) -> (Seen, Vec<Change>) {
+    let mut seen = Seen::new();
+    let mut changes = Vec::new();
+    for path in opts.roots.iter().flat_map(|r| walk_files(&r.dir, opts)) {
+        let Ok(meta) = std::fs::metadata(&path) else {
+            continue;
+        };
+        let (size, mtime) = (
+            meta.len(),
+            meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        );
+        let old = before.as_mut().and_then(|before| before.remove(&path));
+        let hash = || {
+            checksums
+                .then(|| manifest::checksum(&path).ok().map(|(_, sum)| sum))
+                .flatten()
+        };
+        let (changed, checksum) = match old {
+            // Stock-taking, or a new file
+            None => (before.is_some(), hash()),
+            Some((old_size, old_mtime, _)) if (old_size, old_mtime) != (size, mtime) => {
+                (true, hash())
+            }
+            Some((_, _, old_sum)) => {
+                let sum = hash();
+                (sum != old_sum, sum)
+            }
+        };
+        if changed {
+            changes.push(Change::Written(path.clone()));
+        }
+        seen.insert(path, (size, mtime, checksum));
+    }
+    changes.extend(
+        before
+            .into_iter()
+            .flat_map(|before| before.into_keys())
+            .map(|path| Change::Deleted(path, false)),
+    );
+    (seen, changes)
+}
+
+/// On-disk list of jobs that still have to be sent to one destination, so
+/// they survive restarts. Records are NUL-terminated paths prefixed with a
+/// tag byte; a rename record is followed by the new path.
+struct RetryJournal {
+    path: PathBuf,
+}
+
+impl RetryJournal {
+    fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn append(&self, job: &Job) {
+        use std::{io::Write, os::unix::ffi::OsStrExt};
+        let mut record = Vec::new();
+        let mut push = |tag: &[u8], path: &Path| {
+            record.extend_from_slice(tag);
+            record.extend_from_slice(path.as_os_str().as_bytes());
+            record.push(0);
+        };
+        match job {
+            Job::File(path, _) => push(b"F", path),
+            Job::Delete(path) => push(b"D", path),
+            Job::Symlink(path) => push(b"L", path),
+            Job::HardLink(path, existing) => {
+                push(b"H", path);
+                push(b"", existing);
+            }
+            Job::Rename(from, to) => {
+                push(b"R", from);
+                push(b"", to);
+            }
+            // The next scrub covers it
+            Job::Scrub(_) | Job::Heartbeat => return,
+            // Sending the marker brings its data file along again
+            Job::Transaction(paths, _) => push(b"F", paths.last().unwrap()),
+            Job::Batch(files) => {
+                for (path, _) in files {
+                    push(b"F", path);
+                }
+            }
+            Job::Archive(files, _) => {
+                for path in files.iter() {
+                    push(b"F", path);
+                }
+            }
+        }
+        let res = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(&record));
+        if let Err(e) = res {
+            warn!(
+                "[!] Failed to queue {} in {}: {e}",
+                job,
+                self.path.display()
+            );
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        std::fs::metadata(&self.path).map_or(true, |m| m.len() == 0)
+    }
+
+    /// Remove and return every queued job, oldest first, sending each file once
+    fn take(&self) -> Vec<Job> {
+        use std::os::unix::ffi::OsStrExt;
+        let Ok(raw) = std::fs::read(&self.path) else {
+            return Vec::new();
+        };
+        let _ = std::fs::remove_file(&self.path);
+        let to_path = |b: &[u8]| PathBuf::from(std::ffi::OsStr::from_bytes(b));
+        let mut seen = std::collections::HashSet::new();
+        let mut jobs = Vec::new();
+        let mut records = raw.split(|&b| b == 0).filter(|r| !r.is_empty());
+        while let Some(record) = records.next() {
+            let (tag, path) = record.split_at(1);
+            match tag {
+                b"F" if seen.insert(path.to_vec()) => {
+                    jobs.push(Job::File(to_path(path), Instant::now()))
+                }
+                b"D" => jobs.push(Job::Delete(to_path(path))),
+                b"L" => jobs.push(Job::Symlink(to_path(path))),
+                b"H" => {
+                    if let Some(existing) = records.next() {
+                        jobs.push(Job::HardLink(to_path(path), to_path(existing)));
+                    }
+                }
+                b"R" => {
+                    if let Some(to) = records.next() {
+                        jobs.push(Job::Rename(to_path(path), to_path(to)));
+                    }
+                }
+                _ => {}
+            }
+        }
+        jobs
+    }
+}
+
+/// Connect to one destination and stream it every queued job, keeping up to
+/// `--window` messages in flight. Jobs that fail twice go to the journal and
+/// are retried periodically.
+async fn run_destination(
+    dest: Arc<Shared<Destination>>,
+    opts: Arc<SendOptions>,
+    mut rx: mpsc::UnboundedReceiver<Job>,
+    journal: Option<RetryJournal>,
+    metrics: Arc<DestMetrics>,
+    ahead: Arc<Shared<Vec<Arc<DestMetrics>>>>,
+) {
+    let current = dest.get();
+    let mut paused = opts.paused.subscribe();
+    let mut session = Session {
+        link: None,
+        dest: current,
+        opts: &opts,
+        journal: journal.as_ref(),
+        backlog: VecDeque::new(),
+        metrics: &metrics,
+        health: Health::new(),
+    };
+    session.health.max_failures = session.dest.max_failures(&opts);
+    session.connect().await;
+    if session.link.is_some() {
+        info!(
+            "[*] Connected to {}:{}",
+            session.dest.host, session.dest.port
+        );
+    }
+    let mut was_standing_by = !ahead.get().is_empty();
+    loop {
+        session.dest = dest.get();
+        session.health.max_failures = session.dest.max_failures(&opts);
+        let has_queued = journal.as_ref().is_some_and(|j| !j.is_empty());
+        let in_flight = session.link.as_ref().map_or(0, |l| l.in_flight.len());
+        metrics
+            .queue_depth
+            .set((rx.len() + session.backlog.len() + in_flight) as i64);
+        metrics.connected.set(session.link.is_some() as i64);
+        let awaiting = session
+            .link
+            .as_ref()
+            .is_some_and(|l| !l.in_flight.is_empty());
+        let is_paused = *paused.borrow_and_update();
+        let heartbeat_at = session
+            .link
+            .as_ref()
+            .filter(|l| l.in_flight.is_empty() && l.params.features.contains(Features::HEARTBEAT))
+            .zip(opts.heartbeat)
+            .map(|(link, every)| link.progress + every);
+        // Not before the reconnect backoff allows
+        let retry_in = RETRY_INTERVAL.max(
+            session
+                .health
+                .retry_at
+                .saturating_duration_since(Instant::now()),
+        );
+        tokio::select! {
+            job = rx.recv(), if !is_paused => {
+                let Some(job) = job else { break };
+                let Some(job) = session.dest.admit(job, &opts) else { continue };
+                let standing_by = standing_by(&ahead.get());
+                if standing_by != was_standing_by {
+                    let (ip, port) = (&session.dest.host, session.dest.port);
+                    match standing_by {
+                        true => info!("[*] Handing back to the primary; {ip}:{port} is standing by again"),
+                        false => warn!("[!] Failing over to {ip}:{port}"),
+                    }
+                    was_standing_by = standing_by;
+                }
+                if standing_by {
+                    continue;
+                }
+                // Destination is down with a backlog: queue behind it and let
+                // the retry timer reconnect
+                if let Some(journal) = journal.as_ref().filter(|_| session.link.is_none() && has_queued) {
+                    journal.append(&job);
+                    continue;
+                }
+                if matches!(job, Job::File(..)) {
+                    // Optional: wait a few milliseconds for safety (some writers close+rename)
+                    sleep(Duration::from_millis(1)).await;
+                }
+                session.backlog.push_back((job, 0));
+                // Whatever is already queued behind it may join a batch
+                if let Some((_, most)) = opts.batch {
+                    while session.backlog.len() < most
+                        && let Ok(job) = rx.try_recv()
+                    {
+                        session.backlog.extend(session.dest.admit(job, &opts).map(|job| (job, 0)));
+                    }
+                }
+                session.pump().await;
+                if has_queued && session.link.is_some() {
+                    session.drain_journal().await;
+                }
+            }
+            _ = session.await_ack(), if awaiting => session.pump().await,
+            _ = tokio::time::sleep_until(heartbeat_at.unwrap_or_else(Instant::now).into()), if heartbeat_at.is_some() => {
+                session.backlog.push_back((Job::Heartbeat, 0));
+                session.pump().await;
+            }
+            _ = sleep(retry_in), if has_queued && !is_paused => session.drain_journal().await,
+            _ = paused.changed() => {}
+            // Keeps the queue depth current while jobs pile up
+            _ = sleep(Duration::from_secs(1)), if is_paused => {}
+        }
+    }
+    // No more jobs: collect the outstanding ACKs
+    while session
+        .link
+        .as_ref()
+        .is_some_and(|l| !l.in_flight.is_empty())
+    {
+        session.await_ack().await;
+        session.pump().await;
+    }
+}
+
+/// `--dry-run` stand-in for [`run_destination`]: log each job the
+/// destination would get, hashing files as a send would
+async fn dry_run_destination(
+    dest: Arc<Shared<Destination>>,
+    opts: Arc<SendOptions>,
+    mut rx: mpsc::UnboundedReceiver<Job>,
+) {
+    let mut paused = opts.paused.subscribe();
+    loop {
+        let is_paused = *paused.borrow_and_update();
+        let job = tokio::select! {
+            job = rx.recv(), if !is_paused => job,
+            _ = paused.changed() => continue,
+        };
+        let Some(job) = job else { break };
+        let dest = dest.get();
+        let (ip, port) = (&dest.host, dest.port);
+        let Some(job) = dest.admit(job, &opts) else {
+            continue;
+        };
+        match &job {
+            Job::File(path, _) => {
+                let name = opts.remote_name(path);
+                let (hashing, path) = (opts.clone(), path.clone());
+                let checksum = tokio::task::spawn_blocking(move || hashing.checksum(&path));
+                match checksum.await.ok().flatten() {
+                    Some((size, checksum)) => info!(
+                        "[dry-run] Would send {name} ({size} bytes, blake3 {}) to {ip}:{port}",
+                        blake3::Hash::from(checksum).to_hex();
+                        name = &name,
+                        size = size
+                    ),
+                    None => debug!("[*] Skipping {name}: unreadable"; name = &name),
+                }
+            }
+            Job::Delete(path) => info!(
+                "[dry-run] Would delete {} on {ip}:{port}",
+                opts.remote_name(path)
+            ),
+            Job::Rename(from, to) => info!(
+                "[dry-run] Would rename {} -> {} on {ip}:{port}",
+                opts.remote_name(from),
+                opts.remote_name(to)
+            ),
+            Job::Symlink(path) => {
+                let target = std::fs::read_link(path).unwrap_or_default();
+                info!(
+                    "[dry-run] Would link {} -> {} on {ip}:{port}",
+                    opts.remote_name(path),
+                    target.display()
+                );
+            }
+            Job::HardLink(path, existing) => info!(
+                "[dry-run] Would hard link {} -> {} on {ip}:{port}",
+                opts.remote_name(path),
+                opts.remote_name(existing)
+            ),
+            Job::Scrub(entries) => info!(
+                "[dry-run] Would compare {} files with {ip}:{port}",
+                entries.len()
+            ),
+            Job::Heartbeat => {}
+            Job::Transaction(paths, _) => {
+                let names: Vec<_> = paths.iter().map(|p| opts.remote_name(p)).collect();
+                info!(
+                    "[dry-run] Would send {} together to {ip}:{port}",
+                    names.join(", ")
+                )
+            }
+            Job::Batch(files) => info!(
+                "[dry-run] Would send {} files in a batch to {ip}:{port}",
+                files.len()
+            ),
+            Job::Archive(files, _) => info!(
+                "[dry-run] Would send {} files as an archive to {ip}:{port}",
+                files.len()
+            ),
+        }
+    }
+}
+
+/// Connection to a destination and the messages sent on it that still await
+/// their ACK, oldest first
+pub(crate) struct Link {
+    stream: TcpStream,
+    /// Version, features and chunk size agreed in the handshake
+    params: Params,
+    next_seq: u32,
+    in_flight: VecDeque<InFlight>,
+    /// ACK bytes read so far, kept here so a cancelled read loses nothing
+    acks: Vec<u8>,
+    /// When a message last went out or an ACK came in; `--ack-timeout`
+    /// counts from here
+    progress: Instant,
+    /// Extra connections for `--streams`, opened on first use
+    spare: Vec<(TcpStream, Params)>,
+}
+
+/// A message that was sent but not acknowledged yet
+struct InFlight {
+    seq: u32,
+    job: Job,
+    /// Sends of this job so far, including this one
+    attempts: u8,
+    started: Instant,
+    /// Set when file content was sent
+    transfer: Option<Transfer>,
+    /// Files a manifest found missing or different at the destination
+    stale: Vec<PathBuf>,
+    /// What each member of a batch that went out sent, with its place in
+    /// the batch
+    batched: Vec<(usize, Transfer)>,
+    /// The batch members' statuses, read with its ACK
+    statuses: Vec<Ack>,
+    /// Why the destination refused it, if it said
+    reason: Option<String>,
+}
+
+/// What writing a message produced, kept until its ACK
+struct Sent {
+    started: Instant,
+    transfer: Option<Transfer>,
+    stale: Vec<PathBuf>,
+    batched: Vec<(usize, Transfer)>,
+}
+
+/// What a file send wrote, logged once the ACK arrives
+struct Transfer {
+    name: String,
+    size: u64,
+    /// Payload bytes put on the wire
+    sent: u64,
+    data_start: Instant,
+    /// Blocks reused out of the total for delta transfers
+    reused: Option<(usize, usize)>,
+    /// Offset a resumed transfer continued from
+    resumed: Option<u64>,
+    /// Length of the destination's copy an append continued
+    appended: Option<u64>,
+    /// Payload went out with sendfile(2)
+    zero_copy: bool,
+    /// The destination made the file from content it had
+    deduped: bool,
+    /// Of the whole file, when known
+    checksum: Option<blake3::Hash>,
+    /// Split over several connections
+    striped: Option<Striped>,
+    /// Followed a send to the `--multicast` group
+    multicast: bool,
+}
+
+/// How the ranges of a striped file sent over the extra connections fared
+struct Striped {
+    streams: usize,
+    ack: Ack,
+    reason: Option<String>,
+}
+
+impl Link {
+    pub(crate) async fn connect(dest: &Destination, opts: &SendOptions) -> Result<Self> {
+        let (stream, params) = connect_bounded(dest, opts).await?;
+        CONNECTED.inc();
+        let (ip, port) = (&dest.host, dest.port);
+        debug!(
+            "[*] {ip}:{port} speaks protocol v{} with features {}",
+            params.version, params.features
+        );
+        if opts.delta_block_size.is_some() && !params.features.contains(Features::DELTA) {
+            warn!("[!] {ip}:{port} does not support delta transfers; sending whole files");
+        }
+        if opts.follow.is_some() && !params.features.contains(Features::APPEND) {
+            warn!("[!] {ip}:{port} does not support appends; sending --follow files whole");
+        }
+        if opts.dedup_above.is_some() && !params.features.contains(Features::DEDUP) {
+            warn!("[!] {ip}:{port} does not support dedup; sending content as usual");
+        }
+        if opts.resume_above.is_some() && !params.features.contains(Features::RESUME) {
+            warn!("[!] {ip}:{port} does not support resuming transfers");
+        }
+        if opts.scrubs && !params.features.contains(Features::SCRUB) {
+            warn!("[!] {ip}:{port} does not support scrubbing; it is left out of scrubs");
+        }
+        if opts.xattrs && !params.features.contains(Features::XATTRS) {
+            warn!(
+                "[!] {ip}:{port} does not support extended attributes; sending files without them"
+            );
+        }
+        if opts.hard_links && !params.features.contains(Features::HARD_LINKS) {
+            warn!(
+                "[!] {ip}:{port} does not support hard links; sending the content under every name"
+            );
+        }
+        if opts.links == Links::Preserve && !params.features.contains(Features::LINKS) {
+            warn!("[!] {ip}:{port} does not support symlinks; they are skipped");
+        }
+        if dest.confirm(opts) != Confirm::Verified && !params.features.contains(Features::CONFIRM) {
+            warn!(
+                "[!] {ip}:{port} does not support confirmation levels; it acknowledges files once verified"
+            );
+        }
+        if opts.integrity == Integrity::TcpOnly && !params.features.contains(Features::TCP_ONLY) {
+            warn!(
+                "[!] {ip}:{port} does not run with --integrity tcp-only; checksumming content as usual"
+            );
+        }
+        if opts.streams.0 > 1 && !params.features.contains(Features::RANGES) {
+            warn!(
+                "[!] {ip}:{port} does not support ranges; sending every file over one connection"
+            );
+        }
+        if opts.multicast.is_some() && !params.features.contains(Features::MULTICAST) {
+            warn!(
+                "[!] {ip}:{port} does not listen with --multicast; sending it everything over TCP"
+            );
+        }
+        Ok(Self {
+            stream,
+            params,
+            next_seq: 0,
+            in_flight: VecDeque::new(),
+            acks: Vec::new(),
+            progress: Instant::now(),
+            spare: Vec::new(),
+        })
+    }
+
+    pub(crate) fn params(&self) -> Params {
+        self.params
+    }
+
+    /// Send `job` with nothing else in flight and wait for its ACK, with the
+    /// reason the destination gave for a failure. `None` if there was
+    /// nothing to send, e.g. the file is gone.
+    pub(crate) async fn exchange(
+        &mut self,
+        job: Job,
+        dest: &Destination,
+        opts: &SendOptions,
+    ) -> Result<Option<(Ack, Option<String>)>> {
+        anyhow::ensure!(self.in_flight.is_empty(), "Messages still in flight");
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        let slot = Slot {
+            conn: &mut self.stream,
+            spare: Some(&mut self.spare),
+            seq,
+            params: &self.params,
+            allow_reply: true,
+        };
+        let sent = send_message(slot, &job, dest, opts).await?;
+        let Some(Sent {
+            started,
+            transfer,
+            stale,
+            batched,
+        }) = sent
+        else {
+            return Ok(None);
+        };
+        self.in_flight.push_back(InFlight {
+            seq,
+            job,
+            attempts: 1,
+            started,
+            transfer,
+            stale,
+            batched,
+            statuses: Vec::new(),
+            reason: None,
+        });
+        let (mut sent, ack) = self.next_ack().await?;
+        // The other ranges of a striped file were answered on their own
+        // connections
+        if let Some(striped) = sent.transfer.as_mut().and_then(|t| t.striped.take())
+            && outranks(striped.ack, ack)
+        {
+            return Ok(Some((striped.ack, striped.reason)));
+        }
+        Ok(Some((ack, sent.reason)))
+    }
+
+    /// Wait for the next ACK and pair it with the message it answers. Cancel-safe.
+    async fn next_ack(&mut self) -> Result<(InFlight, Ack)> {
+        // A batch's ACK is followed by its members' statuses
+        let statuses = self.in_flight.front().map_or(0, |sent| sent.batched.len());
+        let end = loop {
+            match self.reason_end()? {
+                Some(end) if self.acks.len() >= end + statuses => break end,
+                _ => anyhow::ensure!(
+                    self.stream.read_buf(&mut self.acks).await? > 0,
+                    "Connection closed"
+                ),
+            }
+        };
+        let (seq, ack) = decode_ack(self.acks[..ACK_LEN].try_into().unwrap())?;
+        let mut sent = self
+            .in_flight
+            .pop_front()
+            .context("ACK with nothing in flight")?;
+        anyhow::ensure!(
+            sent.seq == seq,
+            "ACK for #{seq} while expecting #{}",
+            sent.seq
+        );
+        sent.reason = (end > ACK_LEN)
+            .then(|| String::from_utf8_lossy(&self.acks[ACK_LEN + 2..end]).into_owned());
+        sent.statuses = self.acks[end..end + statuses]
+            .iter()
+            .map(|&b| Ack::try_from(b))
+            .collect::<Result<_>>()?;
+        self.acks.drain(..end + statuses);
+        self.progress = Instant::now();
+        Ok((sent, ack))
+    }
+
+    /// Where the buffered ACK ends, with the reason a failure carries, once
+    /// enough of it is in
+    fn reason_end(&self) -> Result<Option<usize>> {
+        let Some(&code) = self.acks.first() else {
+            return Ok(None);
+        };
+        if !(self.params.features.contains(Features::ACK_REASONS)
+            && Ack::try_from(code)?.is_failure())
+        {
+            return Ok(Some(ACK_LEN));
+        }
+        let Some(len) = self.acks.get(ACK_LEN..ACK_LEN + 2) else {
+            return Ok(None);
+        };
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        anyhow::ensure!(len <= MAX_REASON, "ACK reason of {len} bytes");
+        Ok(Some(ACK_LEN + 2 + len))
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        CONNECTED.dec();
+    }
+}
+
+/// Where a destination stands, from its recent connection attempts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HealthState {
+    Up,
+    /// Failed recently; reconnecting with backoff
+    Degraded,
+    /// Failed `--max-reconnects` times in a row (`FAILOVER_AFTER` for
+    /// failover group members); only probed every `--reconnect-max-delay`
+    Down,
+}
+
+/// Reconnect backoff of one destination
+struct Health {
+    /// Connection attempts that failed in a row
+    failures: u32,
+    /// No attempt is made before this
+    retry_at: Instant,
+    /// Failures that make it down
+    max_failures: Option<u32>,
+}
+
+impl Health {
+    fn new() -> Self {
+        Self {
+            failures: 0,
+            retry_at: Instant::now(),
+            max_failures: None,
+        }
+    }
+
+    fn state(&self) -> HealthState {
+        match self.failures {
+            0 => HealthState::Up,
+            n if self.max_failures.is_some_and(|max| n >= max) => HealthState::Down,
+            _ => HealthState::Degraded,
+        }
+    }
+
+    fn failed(&mut self, dest: &Destination, opts: &SendOptions, e: &anyhow::Error) {
+        let was = self.state();
+        self.failures += 1;
+        let state = self.state();
+        let delay = match state {
+            HealthState::Down => opts.reconnect_max_delay,
+            _ => RECONNECT_BASE
+                .saturating_mul(1 << (self.failures - 1).min(16))
+                .min(opts.reconnect_max_delay),
+        };
+        // Full jitter over the upper half, so senders that lost the same
+        // destination don't come back in lockstep
+        let delay = delay.mul_f64(0.5 + jitter() / 2.0);
+        self.retry_at = Instant::now() + delay;
+        let (ip, port) = (&dest.host, dest.port);
+        if state == HealthState::Down && was != HealthState::Down {
+            error!(
+                "[!] {ip}:{port} is down after {} failed connection attempts: {e}",
+                self.failures
+            );
+        } else if state == HealthState::Down {
+            debug!("[!] {ip}:{port} is still down: {e}");
+        } else {
+            warn!(
+                "[!] Reconnect failed for {ip}:{port} (attempt {}): {e}; next try in {delay:.1?}",
+                self.failures
+            );
+        }
+    }
+
+    fn connected(&mut self, dest: &Destination) {
+        if self.failures > 0 {
+            info!(
+                "[+] {}:{} is back after {} failed connection attempts",
+                dest.host, dest.port, self.failures
+            );
+        }
+        self.failures = 0;
+    }
+}
+
+/// A random fraction in [0, 1)
+fn jitter() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let bits = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Sending state of one destination task
+struct Session<'a> {
+    /// Refreshed before each job so a reload takes effect
+    dest: Arc<Destination>,
+    opts: &'a SendOptions,
+    journal: Option<&'a RetryJournal>,
+    link: Option<Link>,
+    /// Jobs waiting to be sent, with the number of sends already made
+    backlog: VecDeque<(Job, u8)>,
+    metrics: &'a DestMetrics,
+    health: Health,
+}
+
+impl Session<'_> {
+    /// Open the link, waiting out the backoff between attempts for up to
+    /// `RECONNECT_TIMEOUT`
+    async fn connect(&mut self) {
+        let give_up = Instant::now() + RECONNECT_TIMEOUT;
+        while self.link.is_none() && self.health.retry_at < give_up {
+            tokio::time::sleep_until(self.health.retry_at.into()).await;
+            match Link::connect(&self.dest, self.opts).await {
+                Ok(link) => {
+                    self.health.connected(&self.dest);
+                    self.link = Some(link);
+                }
+                Err(e) => self.health.failed(&self.dest, self.opts, &e),
+            }
+            self.metrics.health.set(self.health.state() as i64);
+        }
+    }
+
+    /// Send the backlog, waiting for ACKs whenever the window is full
+    async fn pump(&mut self) {
+        while !self.backlog.is_empty() {
+            if self.link.is_none() {
+                self.connect().await;
+                if self.link.is_none() {
+                    while let Some((job, _)) = self.backlog.pop_front() {
+                        self.fail(job);
+                    }
+                    return;
+                }
+            }
+            let link = self.link.as_ref().unwrap();
+            // Without hard links the other names get the content too
+            if let Some((job, _)) = self.backlog.front_mut()
+                && let Job::HardLink(path, _) = job
+                && !link.params.features.contains(Features::HARD_LINKS)
+            {
+                *job = Job::File(std::mem::take(path), Instant::now());
+            }
+            // Without transactions the members go out one by one, in order
+            if let Some((Job::Transaction(..), _)) = self.backlog.front()
+                && !link.params.features.contains(Features::TRANSACTIONS)
+            {
+                let Some((Job::Transaction(paths, first_seen), _)) = self.backlog.pop_front()
+                else {
+                    unreachable!()
+                };
+                for path in paths.into_iter().rev() {
+                    self.backlog.push_front((Job::File(path, first_seen), 0));
+                }
+                continue;
+            }
+            // Without archives the files go out one by one
+            if let Some((Job::Archive(..), _)) = self.backlog.front()
+                && !link.params.features.contains(Features::ARCHIVE)
+            {
+                let Some((Job::Archive(files, first_seen), _)) = self.backlog.pop_front() else {
+                    unreachable!()
+                };
+                let jobs = files
+                    .iter()
+                    .rev()
+                    .filter_map(|path| self.opts.send_job(path.clone(), first_seen));
+                for job in jobs.collect::<Vec<_>>() {
+                    self.backlog.push_front((job, 0));
+                }
+                continue;
+            }
+            // Small files queued back to back go out as one batch
+            if let Some((below, most)) = self.opts.batch
+                && link.params.features.contains(Features::BATCH)
+            {
+                let small = |job: &Job| {
+                    let Job::File(path, _) = job else {
+                        return false;
+                    };
+                    path.metadata()
+                        .is_ok_and(|md| md.is_file() && md.len() <= below)
+                        && !self.needs_reply(job, link.params.features)
+                };
+                let count = self
+                    .backlog
+                    .iter()
+                    .take(most)
+                    .take_while(|(job, _)| small(job))
+                    .count();
+                if count > 1 {
+                    let mut attempts = 0;
+                    let files = self
+                        .backlog
+                        .drain(..count)
+                        .filter_map(|(job, tries)| {
+                            attempts = attempts.max(tries);
+                            match job {
+                                Job::File(path, first_seen) => Some((path, first_seen)),
+                                _ => None,
+                            }
+                        })
+                        .collect();
+                    self.backlog.push_front((Job::Batch(files), attempts));
+                }
+            }
+            let job = &self.backlog.front().unwrap().0;
+            // Deltas, resumes and manifests need a reply from the receiver,
+            // so they cannot share the connection with unread ACKs
+            let full = link.in_flight.len() >= self.opts.window;
+            if full || (!link.in_flight.is_empty() && self.needs_reply(job, link.params.features)) {
+                self.await_ack().await;
+                continue;
+            }
+            let (job, attempts) = self.backlog.pop_front().unwrap();
+            let link = self.link.as_mut().unwrap();
+            let seq = link.next_seq;
+            link.next_seq = seq.wrapping_add(1);
+            let allow_reply = link.in_flight.is_empty();
+            let slot = Slot {
+                conn: &mut link.stream,
+                spare: Some(&mut link.spare),
+                seq,
+                params: &link.params,
+                allow_reply,
+            };
+            let send = send_message(slot, &job, &self.dest, self.opts);
+            let sent = match self.opts.file_timeout {
+                // An archive is many files; its members are not timed one by one
+                Some(limit) if !matches!(job, Job::Archive(..)) => {
+                    tokio::time::timeout(limit, send).await.unwrap_or_else(|_| {
+                        Err(anyhow::anyhow!("Sending took longer than --file-timeout"))
+                    })
+                }
+                _ => send.await,
+            };
+            link.progress = Instant::now();
+            match sent {
+                Ok(None) => {}
+                Ok(Some(Sent {
+                    started,
+                    transfer,
+                    stale,
+                    batched,
+                })) => link.in_flight.push_back(InFlight {
+                    seq,
+                    job,
+                    attempts: attempts + 1,
+                    started,
+                    transfer,
+                    stale,
+                    batched,
+                    statuses: Vec::new(),
+                    reason: None,
+                }),
+                Err(e) => {
+                    self.retry(job, attempts + 1);
+                    self.link_down(e);
+                }
+            }
+        }
+    }
+
+    /// Would sending `job` now start an exchange that needs a reply?
+    fn needs_reply(&self, job: &Job, features: Features) -> bool {
+        if matches!(job, Job::Scrub(_)) {
+            return features.contains(Features::SCRUB);
+        }
+        if matches!(job, Job::Archive(..)) {
+            return features.contains(Features::ARCHIVE);
+        }
+        let Job::File(path, _) = job else {
+            return false;
+        };
+        let Ok(md) = path.metadata() else {
+            return false;
+        };
+        self.opts.follows(path, features)
+            || self.opts.dedups(md.len(), features)
+            || self.opts.resumes(md.len(), features)
+            || self.opts.delta_block(md.len(), features).is_some()
+            || self.opts.multicasts(md.len(), features)
+    }
+
+    /// Read and handle one ACK. Cancel-safe.
+    async fn await_ack(&mut self) {
+        let Some(link) = self.link.as_mut() else {
+            return;
+        };
+        let heartbeat = self.opts.heartbeat.filter(|_| {
+            link.in_flight
+                .front()
+                .is_some_and(|f| matches!(f.job, Job::Heartbeat))
+        });
+        let next = match self.opts.ack_timeout.or(heartbeat) {
+            Some(limit) => tokio::time::timeout_at((link.progress + limit).into(), link.next_ack())
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("No ACK within {limit:?}"))),
+            None => link.next_ack().await,
+        };
+        match next {
+            Ok((sent, ack)) => self.acknowledged(sent, ack),
+            Err(e) => self.link_down(e),
+        }
+    }
+
+    fn acknowledged(&mut self, sent: InFlight, ack: Ack) {
+        // Each member is answered as if it went out on its own
+        let mut sent = match sent.job {
+            Job::Batch(files) => {
+                // Members left out had nothing to send, like a file that is
+                // gone before it goes out on its own
+                let members = sent.batched.into_iter().zip(sent.statuses);
+                for ((i, transfer), status) in members {
+                    let (path, first_seen) = files[i].clone();
+                    let member = InFlight {
+                        seq: sent.seq,
+                        job: Job::File(path, first_seen),
+                        attempts: sent.attempts,
+                        started: sent.started,
+                        transfer: Some(transfer),
+                        stale: Vec::new(),
+                        batched: Vec::new(),
+                        statuses: Vec::new(),
+                        reason: None,
+                    };
+                    self.acknowledged(member, status);
+                }
+                return;
+            }
+            job => InFlight { job, ..sent },
+        };
+        // The other ranges of a striped file were answered on their own
+        // connections
+        let mut ack = ack;
+        if let Some(striped) = sent.transfer.as_mut().and_then(|t| t.striped.as_mut())
+            && outranks(striped.ack, ack)
+        {
+            ack = striped.ack;
+            sent.reason = striped.reason.take();
+        }
+        self.log_answer(&sent, ack);
+        let (ip, port) = (self.dest.host.as_str(), self.dest.port);
+        let why = sent
+            .reason
+            .as_deref()
+            .map_or(String::new(), |reason| format!(": {reason}"));
+        let now = Instant::now();
+        match (&sent.job, ack) {
+            (Job::Heartbeat, _) => debug!("[*] {ip}:{port} answered a heartbeat"; seq = sent.seq),
+            (Job::File(full, event_time), Ack::Ok | Ack::Unchanged | Ack::MovedAside) => {
+                let Some(t) = &sent.transfer else { return };
+                let m = self.metrics;
+                if ack == Ack::Unchanged {
+                    m.unchanged.inc()
+                } else {
+                    m.sent.inc()
+                }
+                m.bytes.add(t.sent);
+                m.event_to_send
+                    .observe(sent.started.duration_since(*event_time).as_secs_f64());
+                m.duration
+                    .observe(now.duration_since(sent.started).as_secs_f64());
+                m.size.observe(t.sent as f64);
+                if ack == Ack::Unchanged {
+                    info!(
+                        "[=] Unchanged {} ({} bytes) | Total: {:.2?}",
+                        t.name,
+                        t.size,
+                        now.duration_since(sent.started);
+                        name = &t.name,
+                        seq = sent.seq,
+                        size = t.size,
+                        total_ms = now.duration_since(sent.started)
+                    );
+                } else {
+                    let mut delta = t
+                        .reused
+                        .map(|(copied, total)| format!(" | Delta: {copied}/{total} blocks reused"))
+                        .or(t
+                            .resumed
+                            .map(|offset| format!(" | Resumed at {offset} bytes")))
+                        .or(t
+                            .appended
+                            .map(|offset| format!(" | Appended at {offset} bytes")))
+                        .or(t
+                            .deduped
+                            .then(|| " | Copied from content the destination had".to_string()))
+                        .or(t
+                            .striped
+                            .as_ref()
+                            .map(|striped| format!(" | Striped over {} streams", striped.streams)))
+                        .or(t
+                            .multicast
+                            .then(|| format!(" | Multicast, {} bytes over TCP", t.sent)))
+                        .unwrap_or_default();
+                    if ack == Ack::MovedAside {
+                        delta.push_str(" | Previous copy moved aside");
+                    }
+                    info!(
+                        "[+] OK {} ({} bytes) | Header: {:.2?} | Data: {:.2?} | Total: {:.2?}{}",
+                        t.name,
+                        t.size,
+                        t.data_start.duration_since(sent.started),
+                        now.duration_since(t.data_start),
+                        now.duration_since(sent.started),
+                        delta;
+                        name = &t.name,
+                        seq = sent.seq,
+                        size = t.size,
+                        header_ms = t.data_start.duration_since(sent.started),
+                        data_ms = now.duration_since(t.data_start),
+                        total_ms = now.duration_since(sent.started),
+                        blocks_reused = t.reused.map_or(0, |(copied, _)| copied),
+                        resumed_at = t.resumed.unwrap_or(0)
+                    );
+                }
+                let send_duration = now.duration_since(sent.started);
+                let throughput = Some(t.sent)
+                    .filter(|&bytes| bytes > 0 && !send_duration.is_zero())
+                    .map(|bytes| {
+                        let path = if t.zero_copy { " (sendfile)" } else { "" };
+                        format!(
+                            " | Throughput: {}/s{path}",
+                            human_bytes(bytes as f64 / send_duration.as_secs_f64())
+                        )
+                    })
+                    .unwrap_or_default();
+                info!(
+                    "[latency] File: {} | Dest: {ip}:{port} | Event-to-send: {:.2?} | Send duration: {:.2?}{}",
+                    full.display(),
+                    sent.started.duration_since(*event_time),
+                    send_duration,
+                    throughput;
+                    file = full.display().to_string(),
+                    seq = sent.seq,
+                    bytes = t.sent,
+                    event_to_send_ms = sent.started.duration_since(*event_time),
+                    send_ms = send_duration,
+                    zero_copy = t.zero_copy
+                );
+            }
+            (Job::Delete(path), Ack::Ok | Ack::Unchanged) => {
+                info!("[-] Deleted {}", self.opts.remote_name(path); seq = sent.seq)
+            }
+            (Job::Symlink(path), Ack::Ok | Ack::Unchanged) => {
+                info!("[+] Linked {}", self.opts.remote_name(path); seq = sent.seq)
+            }
+            (Job::HardLink(path, existing), Ack::Ok | Ack::Unchanged) => info!(
+                "[+] Hard link {} -> {}",
+                self.opts.remote_name(path),
+                self.opts.remote_name(existing);
+                seq = sent.seq
+            ),
+            // The destination lacks the file to link to: send the content instead
+            (Job::HardLink(path, _), Ack::Failed | Ack::NoSpace) => {
+                if path.is_file() {
+                    self.backlog.push_front((Job::File(path.clone(), now), 0));
+                }
+            }
+            (Job::File(path, _), Ack::Kept) => {
+                info!("[=] {ip}:{port} kept its own copy of {}", self.opts.remote_name(path); seq = sent.seq)
+            }
+            // An earlier send lost the race to a later one: nothing to redo
+            (Job::File(path, _), Ack::Superseded) => {
+                info!("[=] {ip}:{port} already has a newer version of {}", self.opts.remote_name(path); seq = sent.seq)
+            }
+            (Job::Scrub(entries), Ack::Ok | Ack::Unchanged) => {
+                if !sent.stale.is_empty() {
+                    info!(
+                        "[*] Scrub: {} of {} files differ at {ip}:{port}; resending",
+                        sent.stale.len(),
+                        entries.len();
+                        seq = sent.seq
+                    );
+                }
+                for path in sent.stale {
+                    self.backlog.push_back((Job::File(path, now), 0));
+                }
+            }
+            (Job::Transaction(paths, _), Ack::Ok | Ack::Unchanged) => {
+                let names: Vec<_> = paths.iter().map(|p| self.opts.remote_name(p)).collect();
+                if ack == Ack::Unchanged {
+                    self.metrics.unchanged.add(paths.len() as u64);
+                    info!("[=] Unchanged {}", names.join(", "); seq = sent.seq);
+                } else {
+                    self.metrics.sent.add(paths.len() as u64);
+                    info!(
+                        "[+] Committed {} together | Total: {:.2?}",
+                        names.join(", "),
+                        now.duration_since(sent.started);
+                        seq = sent.seq,
+                        total_ms = now.duration_since(sent.started)
+                    );
+                }
+            }
+            (Job::Archive(files, _), Ack::Ok | Ack::Unchanged) => {
+                let stored = files.len() - sent.stale.len();
+                let bytes = sent.transfer.as_ref().map_or(0, |t| t.sent);
+                self.metrics.sent.add(stored as u64);
+                self.metrics.bytes.add(bytes);
+                info!(
+                    "[+] Archive of {stored} files ({}) to {ip}:{port} | Total: {:.2?}",
+                    human_bytes(bytes as f64),
+                    now.duration_since(sent.started);
+                    seq = sent.seq,
+                    bytes = bytes,
+                    total_ms = now.duration_since(sent.started)
+                );
+                if !sent.stale.is_empty() {
+                    warn!(
+                        "[!] {ip}:{port} did not store {} files of the archive; resending them",
+                        sent.stale.len();
+                        seq = sent.seq
+                    );
+                }
+                for path in sent.stale.into_iter().rev() {
+                    if let Some(job) = self.opts.send_job(path, now) {
+                        self.backlog.push_front((job, 0));
+                    }
+                }
+            }
+            (Job::Archive(..), ack) => {
+                warn!("[!] {ip}:{port} answered {} with {ack:?}{why}", sent.job; seq = sent.seq);
+                self.retry(sent.job, sent.attempts);
+            }
+            // The receiver's policy won't change by resending
+            (job, Ack::Forbidden) => {
+                warn!("[!] {ip}:{port} won't let this sender {job}{why}"; seq = sent.seq)
+            }
+            (Job::Transaction(..), Ack::RejectedName) => {
+                warn!("[!] Destination rejected a name in {}{why}", sent.job; seq = sent.seq)
+            }
+            (Job::Transaction(..), Ack::NoSpace | Ack::OverQuota) => {
+                warn!(
+                    "[!] {ip}:{port} has no room for {} ({ack:?}){why}",
+                    sent.job;
+                    seq = sent.seq
+                );
+                self.fail(sent.job);
+            }
+            (Job::Transaction(..), Ack::Failed | Ack::ChecksumMismatch) => {
+                warn!("[!] Destination reported failure for {}{why}", sent.job; seq = sent.seq);
+                self.retry(sent.job, sent.attempts);
+            }
+            (Job::Scrub(entries), ack) => {
+                warn!("[!] {ip}:{port} answered a scrub of {} files with {ack:?}{why}", entries.len(); seq = sent.seq)
+            }
+            (Job::Rename(from, to), Ack::Ok | Ack::Unchanged) => {
+                info!("[>] Renamed {} -> {}", self.opts.remote_name(from), self.opts.remote_name(to); seq = sent.seq)
+            }
+            // Resending would be refused again, so don't treat it as a failure
+            (
+                Job::File(path, _)
+                | Job::Delete(path)
+                | Job::Symlink(path)
+                | Job::HardLink(path, _),
+                Ack::RejectedName,
+            ) => {
+                warn!(
+                    "[!] Destination rejected name {}{why}",
+                    self.opts.remote_name(path)
+                )
+            }
+            (Job::Rename(from, to), Ack::RejectedName) => warn!(
+                "[!] Destination rejected rename {} -> {}{why}",
+                self.opts.remote_name(from),
+                self.opts.remote_name(to)
+            ),
+            // The destination has no copy to move: send the content instead
+            (Job::Rename(_, to), Ack::Failed | Ack::NoSpace) => {
+                let files = if to.is_dir() {
+                    walk_files(to, self.opts)
+                } else {
+                    vec![to.clone()]
+                };
+                for job in files
+                    .into_iter()
+                    .rev()
+                    .filter_map(|f| self.opts.send_job(f, now))
+                {
+                    if let Some(job) = self.dest.admit(job, self.opts) {
+                        self.backlog.push_front((job, 0));
+                    }
+                }
+            }
+            // Resending now would hit the same wall; the journal retries later
+            (Job::File(path, _), Ack::NoSpace) => {
+                warn!(
+                    "[!] {ip}:{port} has no space for {}{why}",
+                    self.opts.remote_name(path);
+                    seq = sent.seq
+                );
+                self.fail(sent.job);
+            }
+            (Job::File(path, _), Ack::OverQuota) => {
+                error!(
+                    "[!] {ip}:{port} refused {}: this sender is over its quota there",
+                    self.opts.remote_name(path);
+                    seq = sent.seq
+                );
+                self.fail(sent.job);
+            }
+            // Damaged on the way, so worth sending again as it is
+            (Job::File(path, _), Ack::ChecksumMismatch) => {
+                warn!(
+                    "[!] {} reached {ip}:{port} damaged; resending it",
+                    self.opts.remote_name(path);
+                    seq = sent.seq
+                );
+                self.retry(sent.job, sent.attempts);
+            }
+            (Job::File(path, _) | Job::Delete(path) | Job::Symlink(path), Ack::Failed)
+            | (Job::Delete(path) | Job::Symlink(path), Ack::NoSpace) => {
+                warn!(
+                    "[!] Destination reported failure for {}{why}",
+                    self.opts.remote_name(path);
+                    seq = sent.seq
+                );
+                self.retry(sent.job, sent.attempts);
+            }
+            // Only file messages meet the conflict policy and the quota, and
+            // carry versions
+            (Job::Batch(_), _) => unreachable!("split into its members above"),
+            (
+                job,
+                ack @ (Ack::Kept
+                | Ack::MovedAside
+                | Ack::Superseded
+                | Ack::OverQuota
+                | Ack::ChecksumMismatch),
+            ) => {
+                warn!("[!] {ip}:{port} answered {job} with {ack:?}{why}"; seq = sent.seq)
+            }
+        }
+    }
+
+    /// Note what the destination answered in the `--transfer-log`
+    fn log_answer(&self, sent: &InFlight, ack: Ack) {
+        let Some(log) = &self.opts.transfer_log else {
+            return;
+        };
+        let (dest, result) = (self.dest.label(), ack.to_string());
+        let name = |path| self.opts.remote_name(path);
+        let line = |action, name: String, size: String, checksum: String, target: String| {
+            log.write(&[&dest, action, &name, &size, &checksum, &target, &result]);
+        };
+        match &sent.job {
+            Job::File(path, _) => {
+                let t = sent.transfer.as_ref();
+                let size = t.map(|t| t.size.to_string()).unwrap_or_default();
+                let checksum = t
+                    .and_then(|t| t.checksum)
+                    .map(|c| c.to_hex().to_string())
+                    .unwrap_or_default();
+                line("sent", name(path), size, checksum, String::new());
+            }
+            Job::Delete(path) => line(
+                "deleted",
+                name(path),
+                String::new(),
+                String::new(),
+                String::new(),
+            ),
+            Job::Rename(from, to) => line(
+                "renamed",
+                name(from),
+                String::new(),
+                String::new(),
+                name(to),
+            ),
+            Job::Symlink(path) => line(
+                "symlink",
+                name(path),
+                String::new(),
+                String::new(),
+                String::new(),
+            ),
+            Job::HardLink(path, existing) => line(
+                "hard-link",
+                name(path),
+                String::new(),
+                String::new(),
+                name(existing),
+            ),
+            // Members the destination didn't store are resent and logged then
+            Job::Transaction(paths, _) => paths
+                .iter()
+                .for_each(|p| line("sent", name(p), String::new(), String::new(), String::new())),
+            Job::Archive(files, _) => files
+                .iter()
+                .filter(|p| !sent.stale.contains(p))
+                .for_each(|p| line("sent", name(p), String::new(), String::new(), String::new())),
+            Job::Scrub(_) | Job::Heartbeat | Job::Batch(_) => {}
+        }
+    }
+
+    /// The connection broke: everything in flight goes back to the backlog
+    fn link_down(&mut self, e: anyhow::Error) {
+        warn!(
+            "[!] Send error to {}:{}: {e}. Retrying...",
+            self.dest.host, self.dest.port
+        );
+        let Some(mut link) = self.link.take() else {
+            return;
+        };
+        for sent in std::mem::take(&mut link.in_flight).into_iter().rev() {
+            self.retry(sent.job, sent.attempts);
+        }
+    }
+
+    /// Put `job` at the front of the backlog unless it already had its retry
+    fn retry(&mut self, job: Job, attempts: u8) {
+        // A new connection answers the question a heartbeat asked
+        if matches!(job, Job::Heartbeat) {
+            return;
+        }
+        // Batched again, if still small, when they go out
+        if let Job::Batch(files) = job {
+            for (path, first_seen) in files.into_iter().rev() {
+                self.retry(Job::File(path, first_seen), attempts);
+            }
+            return;
+        }
+        if attempts < MAX_ATTEMPTS {
+            self.backlog.push_front((job, attempts));
+        } else {
+            self.fail(job);
+        }
+    }
+
+    fn fail(&self, job: Job) {
+        self.metrics.failed.inc();
+        match self.journal {
+            Some(journal) => journal.append(&job),
+            None => error!(
+                "[!] Giving up on {} for {}:{}",
+                job, self.dest.host, self.dest.port
+            ),
+        }
+    }
+
+    /// Resend journaled jobs; whatever fails again goes back to the journal
+    async fn drain_journal(&mut self) {
+        let Some(journal) = self.journal else { return };
+        let queued = journal.take();
+        info!(
+            "[*] Retrying {} queued jobs for {}:{}",
+            queued.len(),
+            self.dest.host,
+            self.dest.port
+        );
+        for job in queued {
+            let job = match job {
+                Job::File(path, first_seen) => self.opts.send_job(path, first_seen),
+                job => Some(job),
+            };
+            if let Some(job) = job {
+                self.backlog.push_back((job, 0));
+            }
+        }
+        self.pump().await;
+    }
+}
+
+/// Where one message goes on a destination connection
+struct Slot<'a> {
+    conn: &'a mut TcpStream,
+    /// Further connections a large file may be split over
+    spare: Option<&'a mut Vec<(TcpStream, Params)>>,
+    seq: u32,
+    params: &'a Params,
+    /// No ACKs are pending, so the message may wait for a reply
+    allow_reply: bool,
+}
+
+impl<'a> Slot<'a> {
+    /// A member of a transaction, archive or batch: it shares `seq` and
+    /// never waits for a reply of its own
+    fn member(conn: &'a mut TcpStream, seq: u32, params: &'a Params) -> Self {
+        Slot {
+            conn,
+            spare: None,
+            seq,
+            params,
+            allow_reply: false,
+        }
+    }
+}
+
+/// Write the message for `job` without waiting for its ACK, or `None` if
+/// there was nothing to send. A manifest is only sent when the slot allows
+/// a reply. A large file may be split over its spare connections as well.
+async fn send_message(
+    slot: Slot<'_>,
+    job: &Job,
+    dest: &Destination,
+    opts: &SendOptions,
+) -> Result<Option<Sent>> {
+    let Slot {
+        conn,
+        spare,
+        seq,
+        params,
+        allow_reply,
+    } = slot;
+    let started = Instant::now();
+    let mut writer = FrameWriter::new(&mut *conn);
+    let sent = |transfer| {
+        Some(Sent {
+            started,
+            transfer,
+            stale: Vec::new(),
+            batched: Vec::new(),
+        })
+    };
+    match job {
+        Job::File(full, first_seen) => {
+            let slot = Slot {
+                conn,
+                spare,
+                seq,
+                params,
+                allow_reply,
+            };
+            let transfer = send_file(slot, full, *first_seen, dest, opts).await?;
+            Ok(transfer.and_then(|t| sent(Some(t))))
+        }
+        Job::Delete(path) => {
+            writer
+                .write_delete(seq, &opts.wire_name(path, params.features))
+                .await?;
+            Ok(sent(None))
+        }
+        Job::Rename(from, to) => {
+            let (from, to) = (
+                opts.wire_name(from, params.features),
+                opts.wire_name(to, params.features),
+            );
+            writer.write_rename(seq, &from, &to).await?;
+            Ok(sent(None))
+        }
+        Job::HardLink(path, existing) => {
+            let (name, existing) = (
+                opts.wire_name(path, params.features),
+                opts.wire_name(existing, params.features),
+            );
+            writer.write_hard_link(seq, &name, &existing).await?;
+            Ok(sent(None))
+        }
+        Job::Symlink(path) => {
+            let name = opts.remote_name(path);
+            if !params.features.contains(Features::LINKS) {
+                return Ok(None);
+            }
+            let target = match std::fs::read_link(path) {
+                Ok(target) => target,
+                // Gone or replaced before we got to it
+                Err(e) => {
+                    debug!("[*] Skipping {}: {e}", name);
+                    return Ok(None);
+                }
+            };
+            if !params.features.contains(Features::RAW_NAMES) && target.to_str().is_none() {
+                warn!("[!] Skipping symlink {}: target is not UTF-8", name);
+                return Ok(None);
+            }
+            writer
+                .write_symlink(seq, &opts.wire_name(path, params.features), &target)
+                .await?;
+            Ok(sent(None))
+        }
+        Job::Scrub(entries) => {
+            if !allow_reply || !params.features.contains(Features::SCRUB) {
+                return Ok(None);
+            }
+            let manifest: Vec<_> = entries
+                .iter()
+                .map(|e| ManifestEntry {
+                    name: opts.wire_name(&e.path, params.features),
+                    size: e.size,
+                    checksum: e.checksum,
+                })
+                .collect();
+            writer.write_manifest(seq, &manifest).await?;
+            let stale = FrameReader::new(&mut *conn)
+                .read_stale(entries.len())
+                .await?;
+            let stale = stale.into_iter().map(|i| entries[i].path.clone()).collect();
+            Ok(Some(Sent {
+                started,
+                transfer: None,
+                stale,
+                batched: Vec::new(),
+            }))
+        }
+        Job::Heartbeat => {
+            writer.write_heartbeat(seq).await?;
+            Ok(sent(None))
+        }
+        Job::Transaction(paths, first_seen) => {
+            if let Some(gone) = paths.iter().find(|p| !p.is_file()) {
+                debug!(
+                    "[*] Skipping transaction: {} no longer exists",
+                    gone.display()
+                );
+                return Ok(None);
+            }
+            writer.write_transaction(seq, paths.len() as u16).await?;
+            // The receiver expects every member now: one we can't send drops the
+            // connection, and with it the members it already has
+            for path in paths {
+                let slot = Slot::member(conn, seq, params);
+                if send_file(slot, path, *first_seen, dest, opts)
+                    .await?
+                    .is_none()
+                {
+                    anyhow::bail!("{} could not be sent with its transaction", path.display());
+                }
+            }
+            Ok(sent(None))
+        }
+        Job::Archive(files, first_seen) => {
+            if !allow_reply || !params.features.contains(Features::ARCHIVE) {
+                return Ok(None);
+            }
+            writer.write_archive(seq).await?;
+            // Unlike a batch's, members can drop out: the archive ends with
+            // its closing frame, not a count
+            let mut members = Vec::new();
+            let mut bytes = 0;
+            for path in files.iter() {
+                let slot = Slot::member(conn, seq, params);
+                if let Some(t) = send_file(slot, path, *first_seen, dest, opts).await? {
+                    bytes += t.sent;
+                    members.push(path.clone());
+                }
+            }
+            FrameWriter::new(&mut *conn).write_archive(seq).await?;
+            let missing = FrameReader::new(&mut *conn)
+                .read_stale(members.len())
+                .await?;
+            let stale = missing.into_iter().map(|i| members[i].clone()).collect();
+            let transfer = Transfer {
+                name: job.to_string(),
+                size: bytes,
+                sent: bytes,
+                data_start: started,
+                reused: None,
+                resumed: None,
+                appended: None,
+                zero_copy: false,
+                deduped: false,
+                checksum: None,
+                striped: None,
+                multicast: false,
+            };
+            Ok(Some(Sent {
+                started,
+                transfer: Some(transfer),
+                stale,
+                batched: Vec::new(),
+            }))
+        }
+        Job::Batch(files) => {
+            // Like a transaction's, every announced member has to follow, so
+            // the count leaves out those with nothing to send
+            let mut sources = Vec::new();
+            for (i, (path, _)) in files.iter().enumerate() {
+                if let Some(source) = open_source(path, opts).await? {
+                    sources.push((i, source));
+                }
+            }
+            if sources.is_empty() {
+                return Ok(None);
+            }
+            writer.write_batch(seq, sources.len() as u16).await?;
+            let mut batched = Vec::new();
+            for (i, source) in sources {
+                let (path, first_seen) = &files[i];
+                let slot = Slot::member(conn, seq, params);
+                let transfer = send_source(slot, path, source, *first_seen, dest, opts).await?;
+                batched.push((i, transfer));
+            }
+            Ok(Some(Sent {
+                started,
+                transfer: None,
+                stale: Vec::new(),
+                batched,
+            }))
+        }
+    }
+}
+
+/// Run the `--pre-send` command `cmd` on `source`, returning its output as an
+/// unnamed temporary file, or `None` if the command failed
+fn pre_send(cmd: &str, source: &File, path: &Path, name: &str) -> Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let out = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_TMPFILE)
+        .open(std::env::temp_dir())
+        .context("Create --pre-send output file")?;
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("FASTSYNC_PATH", path)
+        .env("FASTSYNC_NAME", name)
+        .stdin(source.try_clone()?)
+        .stdout(out.try_clone()?)
+        .status()
+        .context("Run --pre-send")?;
+    if !status.success() {
+        warn!("[!] --pre-send for {} failed ({status}); not sending it", name; name = name);
+        return Ok(None);
+    }
+    Ok(Some(out))
+}
+
+/// BLAKE3 of a mapped file, with up to `threads` threads on the blocking pool
+async fn hash_mapped(mmap: &Arc<Mmap>, threads: usize) -> Result<blake3::Hash> {
+    let mmap = mmap.clone();
+    Ok(tokio::task::spawn_blocking(move || parallel_hash::hash(&mmap, threads)).await?)
+}
+
+/// `connect_dest`, giving up after `RECONNECT_TIMEOUT`
+async fn connect_bounded(dest: &Destination, opts: &SendOptions) -> Result<(TcpStream, Params)> {
+    let connect = async {
+        let stream = match dest.listen {
+            true => accept_dest(&dest.host, dest.port).await?,
+            false => connect_dest(&dest.host, dest.port, opts).await?,
+        };
+        negotiate(stream, opts).await
+    };
+    tokio::time::timeout(RECONNECT_TIMEOUT, connect)
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")))
+}
+
+/// Connect to the destination, through the `--proxy` and over a
+/// `--websocket` if set
+async fn connect_dest(host: &str, port: u16, opts: &SendOptions) -> Result<TcpStream> {
+    let authority = match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    };
+    let mut stream = match &opts.proxy {
+        Some(proxy) => {
+            let mut stream = dial(&proxy.host, proxy.port)
+                .await
+                .with_context(|| format!("Connect to --proxy {}:{}", proxy.host, proxy.port))?;
+            http::connect_tunnel(&mut stream, &authority, proxy.credentials.as_deref()).await?;
+            stream
+        }
+        None => dial(host, port).await?,
+    };
+    let Some(path) = &opts.websocket else {
+        return Ok(stream);
+    };
+    // The protocol runs over a loopback connection, which needs none
+    if let Some(keepalive) = &opts.keepalive {
+        keepalive.apply(&stream)?;
+    }
+    let early = websocket::connect(&mut stream, &authority, path).await?;
+    let (near, far) = websocket::loopback_pair().await?;
+    tokio::spawn(async move {
+        if let Err(e) = websocket::pump(stream, early, far, websocket::Role::Client).await {
+            debug!("[!] WebSocket to {authority} failed: {e:#}");
+        }
+    });
+    Ok(near)
+}
+
+/// Connect to the first of the host's addresses that accepts
+async fn dial(dest_ip: &str, dest_port: u16) -> Result<TcpStream> {
+    // Resolved on every attempt so a changed DNS record is picked up
+    let addrs = tokio::net::lookup_host((dest_ip, dest_port))
+        .await
+        .with_context(|| format!("Resolve {dest_ip}"))?;
+    for addr in addrs {
+        if let Some(stream) = connect_addr(addr).await {
+            return Ok(stream);
+        }
+    }
+    anyhow::bail!("connection refused")
+}
+
+/// Listeners of `--listen` destinations, kept across connections and reloads
+static LISTENERS: LazyLock<Mutex<HashMap<SocketAddr, Arc<TcpListener>>>> =
+    LazyLock::new(Default::default);
+
+/// Wait for the receiver of a `--listen` destination to dial in
+async fn accept_dest(ip: &str, port: u16) -> Result<TcpStream> {
+    let addr = SocketAddr::new(
+        ip.parse()
+            .with_context(|| format!("{ip} is not an IP address to listen on"))?,
+        port,
+    );
+    let listener = {
+        let mut listeners = LISTENERS.lock().unwrap();
+        match listeners.get(&addr) {
+            Some(listener) => listener.clone(),
+            None => {
+                let listener = std::net::TcpListener::bind(addr)
+                    .with_context(|| format!("Listen on {addr}"))?;
+                listener.set_nonblocking(true)?;
+                let listener = Arc::new(TcpListener::from_std(listener)?);
+                info!("[*] Listening on {addr} for a receiver to connect");
+                listeners.insert(addr, listener.clone());
+                listener
+            }
+        }
+    };
+    let (stream, peer) = listener.accept().await?;
+    stream.set_nodelay(true)?;
+    debug!("[*] Receiver {peer} connected on {addr}");
+    Ok(stream)
+}
+
+/// Negotiate the protocol on a new connection and authenticate
+async fn negotiate(mut stream: TcpStream, opts: &SendOptions) -> Result<(TcpStream, Params)> {
+    if let Some(keepalive) = &opts.keepalive {
+        keepalive.apply(&stream)?;
+    }
+    let (mut reader, mut writer) = stream.split();
+    let params =
+        handshake::connect(&mut reader, &mut writer, opts.features(), opts.chunk_size).await?;
+    auth::connect(&mut reader, &mut writer, opts.key.as_ref(), params.features).await?;
+    if let Some(id) = opts
+        .sender_id
+        .as_deref()
+        .filter(|_| params.features.contains(Features::SENDER_ID))
+    {
+        handshake::send_id(&mut writer, id).await?;
+    }
+    Ok((stream, params))
+}
+
+/// Open a TCP connection to one resolved address, with a socket of its family
+async fn connect_addr(addr: SocketAddr) -> Option<TcpStream> {
+    let socket = if addr.is_ipv6() {
+        TcpSocket::new_v6()
+    } else {
+        TcpSocket::new_v4()
+    }
+    .ok()?;
+    socket.set_nodelay(true).ok()?;
+    match socket.connect(addr).await {
+        Ok(stream) => Some(stream),
+        Err(e) => {
+            debug!("[!] Connect to {addr} failed: {e}");
+            None
+        }
+    }
+}
+
+/// Write one file's message; `None` if the file is gone. A delta or resume
+/// exchange is only started when `allow_reply` says no ACKs are pending and
+/// the destination agreed to the feature. `first_seen` identifies the
+/// change, so every send of it carries the same transfer id. With `spare`
+/// connections, which members of a group don't get, a large enough file is
+/// split over them and `conn` with `--streams`.
+/// A file opened for sending
+struct Source {
+    /// Taken before reading, so a later read always carries a later version
+    version: u64,
+    file: File,
+    md: std::fs::Metadata,
+    /// `--pre-send` output, sent instead of `file`
+    transformed: Option<File>,
+}
+
+/// Open `fullpath` for sending, or `None` if there is nothing to send: it is
+/// gone, over `--max-file-size` or its `--pre-send` failed
+async fn open_source(fullpath: &Path, opts: &SendOptions) -> Result<Option<Source>> {
+    let name = opts.remote_name(fullpath);
+    let version = opts.next_version();
+    let file = match File::open(fullpath) {
+        Ok(file) => file,
+        // Gone before we got to it: nothing to send, and not a link failure
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("[*] Skipping {}: no longer exists", name);
+            return Ok(None);
+        }
+        Err(e) => return Err(e).with_context(|| format!("Open {}", fullpath.display())),
+    };
+    let md = file.metadata()?;
+    // It may have grown since it was queued
+    if let Some((max, Oversized::Skip)) = opts.max_file_size
+        && md.len() > max
+    {
+        warn!(
+            "[!] Skipping {name} ({} bytes): over the --max-file-size of {max} bytes",
+            md.len()
+        );
+        return Ok(None);
+    }
+    let transformed = match &opts.pre_send {
+        Some(cmd) => {
+            let (cmd, source) = (cmd.clone(), file.try_clone()?);
+            let (path, owned) = (fullpath.to_path_buf(), name.clone());
+            let run = move || pre_send(&cmd, &source, &path, &owned);
+            match tokio::task::spawn_blocking(run).await?? {
+                Some(out) => Some(out),
+                None => return Ok(None),
+            }
+        }
+        None => None,
+    };
+    Ok(Some(Source {
+        version,
+        file,
+        md,
+        transformed,
+    }))
+}
+
+async fn send_file(
+    slot: Slot<'_>,
+    fullpath: &Path,
+    first_seen: Instant,
+    dest: &Destination,
+    opts: &SendOptions,
+) -> Result<Option<Transfer>> {
+    let Some(source) = open_source(fullpath, opts).await? else {
+        return Ok(None);
+    };
+    send_source(slot, fullpath, source, first_seen, dest, opts)
+        .await
+        .map(Some)
+}
+
+/// [`send_file`] for a file [`open_source`] opened
+async fn send_source(
+    slot: Slot<'_>,
+    fullpath: &Path,
+    source: Source,
+    first_seen: Instant,
+    dest: &Destination,
+    opts: &SendOptions,
+) -> Result<Transfer> {
+    let Slot {
+        conn,
+        spare,
+        seq,
+        params,
+        allow_reply,
+    } = slot;
+    let name = opts.remote_name(fullpath);
+    let Source {
+        version,
+        file: source,
+        md,
+        transformed,
+    } = source;
+    let file = transformed.as_ref().unwrap_or(&source);
+    let size = file.metadata()?.len();
+
+    // mmap to read once and with minimal latency; the checksum is computed
+    // as the data goes out and sent after it
+    let mmap = Arc::new(unsafe { Mmap::map(file)? });
+    let mut hasher = Hasher::new();
+    // Or, for a large file with --hash-threads, computed beside the sending
+    let mut parallel = None;
+    // Hashes a large file's chunks ahead of the sending
+    let mut pipeline = None;
+
+    let (reader, writer) = conn.split();
+    let mut reader = FrameReader::new(reader);
+    let mut writer = FrameWriter::new(writer);
+    writer.set_features(params.features);
+
+    // Header
+    let header = ProtocolHeader {
+        name: opts.wire_name(fullpath, params.features),
+        size,
+        meta: FileMeta::from_metadata(&md),
+        confirm: params
+            .features
+            .contains(Features::CONFIRM)
+            .then(|| dest.confirm(opts)),
+        xattrs: params.features.contains(Features::XATTRS).then(|| {
+            xattr::read(&source).unwrap_or_else(|e| {
+                warn!("[!] Failed to read extended attributes of {}: {e}", name);
+                Vec::new()
+            })
+        }),
+        version: params
+            .features
+            .contains(Features::VERSIONS)
+            .then_some(version),
+        transfer_id: params
+            .features
+            .contains(Features::TRANSFER_IDS)
+            .then(|| opts.transfer_id(fullpath, first_seen)),
+    };
+    // The ACK that follows explains the refusal
+    let refused = |name| Transfer {
+        name,
+        size,
+        sent: 0,
+        data_start: Instant::now(),
+        reused: None,
+        resumed: None,
+        appended: None,
+        zero_copy: false,
+        deduped: false,
+        checksum: None,
+        striped: None,
+        multicast: false,
+    };
+    let mut reused = None;
+    let mut resumed = None;
+    let mut appended = None;
+    // Cleared for a plain file message under --integrity tcp-only
+    let mut hashed = true;
+    let mut sent = 0;
+    let data_start;
+    let mut out = Outgoing {
+        chunk_size: params.chunk_size,
+        limits: dest.limits(opts),
+        sendfile: opts.zero_copy.then_some(file),
+        hashes: None,
+    };
+    let mut spare = spare.filter(|_| opts.stripes(size, params.features));
+    match opts.delta_block(size, params.features) {
+        _ if allow_reply && opts.follows(fullpath, params.features) => {
+            writer
+                .write_header(MessageKind::Append, seq, &header)
+                .await?;
+            let Some((len, theirs)) = reader.read_resume_offer().await? else {
+                return Ok(refused(name));
+            };
+            // Only append to a copy that ends the way ours does there
+            let start = match usize::try_from(len) {
+                Ok(len) if len > 0 && len <= mmap.len() => {
+                    let tail = &mmap[len - len.min(APPEND_CHECK as usize)..len];
+                    if blake3::hash(tail).as_bytes() == &theirs {
+                        len
+                    } else {
+                        0
+                    }
+                }
+                _ => 0,
+            };
+            writer.write_u64(start as u64).await?;
+
+            data_start = Instant::now();
+            write_throttled(
+                &mut writer,
+                &mut out,
+                &mmap[start..],
+                start as u64,
+                Some(&mut hasher),
+            )
+            .await?;
+            sent = (mmap.len() - start) as u64;
+            appended = (start > 0).then_some(start as u64);
+        }
+        _ if allow_reply && opts.dedups(size, params.features) => {
+            let checksum = *parallel_hash::hash(&mmap, opts.hash_threads).as_bytes();
+            writer
+                .write_header(MessageKind::Dedup, seq, &header)
+                .await?;
+            writer.write_checksum(&checksum).await?;
+            match reader.read_dedup_reply().await? {
+                DedupReply::Refused => return Ok(refused(name)),
+                // Nothing more to send, not even the trailer
+                DedupReply::Have => {
+                    let data_start = Instant::now();
+                    let checksum = Some(blake3::Hash::from(checksum));
+                    return Ok(Transfer {
+                        deduped: true,
+                        data_start,
+                        checksum,
+                        ..refused(name)
+                    });
+                }
+                DedupReply::Send => {
+                    data_start = Instant::now();
+                    write_throttled(&mut writer, &mut out, &mmap, 0, Some(&mut hasher)).await?;
+                    sent = size;
+                }
+            }
+        }
+        _ if allow_reply && opts.resumes(size, params.features) => {
+            writer
+                .write_header(MessageKind::Resume, seq, &header)
+                .await?;
+            let Some((len, theirs)) = reader.read_resume_offer().await? else {
+                return Ok(refused(name));
+            };
+            // Only continue from bytes that match ours
+            let start = match usize::try_from(len) {
+                Ok(len) if len <= mmap.len() => {
+                    hasher.update(&mmap[..len]);
+                    if hasher.clone().finalize().as_bytes() == &theirs {
+                        len
+                    } else {
+                        0
+                    }
+                }
+                _ => 0,
+            };
+            if start == 0 {
+                hasher.reset();
+            }
+            writer.write_u64(start as u64).await?;
+
+            data_start = Instant::now();
+            write_throttled(
+                &mut writer,
+                &mut out,
+                &mmap[start..],
+                start as u64,
+                Some(&mut hasher),
+            )
+            .await?;
+            sent = (mmap.len() - start) as u64;
+            resumed = (start > 0).then_some(start as u64);
+        }
+        Some(block_size) if allow_reply => {
+            writer
+                .write_header(MessageKind::Delta, seq, &header)
+                .await?;
+            writer.write_u32(block_size).await?;
+            let Some(theirs) = reader.read_block_hashes().await? else {
+                return Ok(refused(name));
+            };
+
+            // Data: matching blocks become copies, runs of changed blocks one literal
+            data_start = Instant::now();
+            let ours = block_hashes(&mmap, block_size as usize);
+            let block_size = block_size as usize;
+            let mut copied = 0;
+            let mut i = 0;
+            while i < ours.len() {
+                let start = i;
+                if theirs.get(i) == Some(&ours[i]) {
+                    while i < ours.len() && theirs.get(i) == Some(&ours[i]) {
+                        i += 1;
+                    }
+                    let count = (i - start) as u32;
+                    hasher.update(&mmap[start * block_size..(i * block_size).min(mmap.len())]);
+                    writer
+                        .write_delta_op(DeltaOp::Copy {
+                            index: start as u64,
+                            count,
+                        })
+                        .await?;
+                    copied += i - start;
+                } else {
+                    while i < ours.len()
+                        && theirs.get(i) != Some(&ours[i])
+                        && (i - start + 1) * block_size <= u32::MAX as usize
+                    {
+                        i += 1;
+                    }
+                    let offset = start * block_size;
+                    let data = &mmap[offset..(i * block_size).min(mmap.len())];
+                    writer
+                        .write_delta_op(DeltaOp::Data {
+                            len: data.len() as u32,
+                        })
+                        .await?;
+                    write_throttled(
+                        &mut writer,
+                        &mut out,
+                        data,
+                        offset as u64,
+                        Some(&mut hasher),
+                    )
+                    .await?;
+                    sent += data.len() as u64;
+                }
+            }
+            writer.write_delta_op(DeltaOp::End).await?;
+            reused = Some((copied, ours.len()));
+        }
+        _ if allow_reply && opts.multicasts(size, params.features) => {
+            let group = opts.multicast.as_ref().unwrap();
+            let hash = hash_mapped(&mmap, opts.hash_threads).await?;
+            let send = multicast::id(&opts.transfer_id(fullpath, first_seen), hash.as_bytes());
+            data_start = Instant::now();
+            group.transmit(send, &mmap, &name).await;
+            writer
+                .write_header(MessageKind::Multicast, seq, &header)
+                .await?;
+            writer.write_multicast(&send, hash.as_bytes()).await?;
+            let Some(missing) = reader.read_missing(size).await? else {
+                return Ok(refused(name));
+            };
+            for (offset, len) in missing {
+                let data = &mmap[offset as usize..(offset + len) as usize];
+                write_throttled(&mut writer, &mut out, data, offset, None).await?;
+                sent += len;
+            }
+            let zero_copy = out.sendfile.is_some() && sent > 0;
+            let checksum = Some(hash);
+            return Ok(Transfer {
+                sent,
+                data_start,
+                zero_copy,
+                checksum,
+                multicast: true,
+                ..refused(name)
+            });
+        }
+        _ if spare.is_some() => {
+            let spare = spare.take().unwrap();
+            let hash = hash_mapped(&mmap, opts.hash_threads).await?;
+            while spare.len() + 1 < opts.streams.0 {
+                match connect_bounded(dest, opts).await {
+                    Ok((stream, params)) if params.features.contains(Features::RANGES) => {
+                        spare.push((stream, params))
+                    }
+                    Ok(_) => break,
+                    Err(e) => {
+                        debug!(
+                            "[!] Failed to open another stream to {}:{}: {e}",
+                            dest.host, dest.port
+                        );
+                        break;
+                    }
+                }
+            }
+            // Chunk-aligned, so every range splits into whole chunks but the last
+            let chunk_len = out.chunk_len() as u64;
+            let per = size
+                .div_ceil(spare.len() as u64 + 1)
+                .next_multiple_of(chunk_len);
+            let stripe = opts.transfer_id(fullpath, first_seen);
+            let ranges: Vec<_> = (0..size)
+                .step_by(per as usize)
+                .map(|offset| RangeHeader {
+                    stripe,
+                    checksum: *hash.as_bytes(),
+                    offset,
+                    len: per.min(size - offset),
+                })
+                .collect();
+            let mut others = JoinSet::new();
+            for range in &ranges[1..] {
+                let (stream, params) = spare
+                    .pop()
+                    .expect("a spare connection for every range but the first");
+                let sendfile = out.sendfile.map(File::try_clone).transpose()?;
+                others.spawn(send_range(RangeSend {
+                    stream,
+                    params,
+                    seq,
+                    header: header.clone(),
+                    range: *range,
+                    data: mmap.clone(),
+                    sendfile,
+                    limits: out.limits.clone(),
+                }));
+            }
+            data_start = Instant::now();
+            let first = &ranges[0];
+            write_range(
+                &mut writer,
+                &mut out,
+                seq,
+                &header,
+                first,
+                &mmap[..first.len as usize],
+            )
+            .await?;
+            let mut striped = Striped {
+                streams: ranges.len(),
+                ack: Ack::Ok,
+                reason: None,
+            };
+            while let Some(done) = others.join_next().await {
+                let (stream, answer) = done?;
+                spare.extend(stream);
+                let (ack, reason) = answer.unwrap_or_else(|e| {
+                    (Ack::Failed, Some(format!("another stream broke: {e:#}")))
+                });
+                if outranks(ack, striped.ack) {
+                    striped.ack = ack;
+                    striped.reason = reason;
+                }
+            }
+            let zero_copy = out.sendfile.is_some();
+            let checksum = Some(hash);
+            let striped = Some(striped);
+            return Ok(Transfer {
+                name,
+                size,
+                sent: size,
+                data_start,
+                reused,
+                resumed,
+                appended,
+                zero_copy,
+                deduped: false,
+                checksum,
+                striped,
+                multicast: false,
+            });
+        }
+        _ => {
+            hashed = !params.features.contains(Features::TCP_ONLY);
+            writer.write_header(MessageKind::File, seq, &header).await?;
+
+            // Data
+            if hashed && opts.hash_threads > 1 && size >= parallel_hash::PARALLEL_MIN {
+                let (data, threads) = (mmap.clone(), opts.hash_threads);
+                parallel = Some(tokio::task::spawn_blocking(move || {
+                    parallel_hash::hash(&data, threads)
+                }));
+            }
+            if hashed && size >= PIPELINE_MIN {
+                let (tx, rx) = mpsc::channel(HASH_AHEAD);
+                let (data, chunk_len, whole) = (mmap.clone(), out.chunk_len(), parallel.is_none());
+                pipeline = Some(tokio::task::spawn_blocking(move || {
+                    hash_ahead(&data, chunk_len, whole, tx)
+                }));
+                out.hashes = Some(rx);
+            }
+
+            data_start = Instant::now();
+            let hasher =
+                Some(&mut hasher).filter(|_| hashed && parallel.is_none() && pipeline.is_none());
+            write_throttled(&mut writer, &mut out, &mmap, 0, hasher).await?;
+            sent = size;
+        }
+    }
+    let hash = match (parallel, pipeline) {
+        (Some(task), _) => task.await?,
+        (None, Some(task)) => task.await?.context("Hashing stopped")?,
+        (None, None) => hasher.finalize(),
+    };
+    writer
+        .write_checksum(if hashed { hash.as_bytes() } else { &[0; 32] })
+        .await?;
+    let zero_copy = out.sendfile.is_some() && sent > 0;
+    // An append's covers only what it added
+    let checksum = (hashed && appended.is_none()).then_some(hash);
+    Ok(Transfer {
+        name,
+        size,
+        sent,
+        data_start,
+        reused,
+        resumed,
+        appended,
+        zero_copy,
+        deduped: false,
+        checksum,
+        striped: None,
+        multicast: false,
+    })
+}
+
+/// Write one range of a striped file: the header, where the range goes,
+/// its payload `data` and the range's checksum
+async fn write_range(
+    writer: &mut FrameWriter<WriteHalf<'_>>,
+    out: &mut Outgoing<'_>,
+    seq: u32,
+    header: &ProtocolHeader,
+    range: &RangeHeader,
+    data: &[u8],
+) -> Result<()> {
+    writer.write_header(MessageKind::Range, seq, header).await?;
+    writer.write_range(range).await?;
+    let mut hasher = Hasher::new();
+    write_throttled(writer, out, data, range.offset, Some(&mut hasher)).await?;
+    writer.write_checksum(hasher.finalize().as_bytes()).await
+}
+
+/// One range of a striped file and the connection of its own it goes out on
+struct RangeSend {
+    stream: TcpStream,
+    params: Params,
+    seq: u32,
+    header: ProtocolHeader,
+    range: RangeHeader,
+    /// The whole file; only the range is sent
+    data: Arc<Mmap>,
+    sendfile: Option<File>,
+    limits: Vec<Arc<RateLimiter>>,
+}
+
+/// Send one range of a striped file and wait for its ACK. The connection
+/// comes back for the next file unless it broke.
+async fn send_range(
+    send: RangeSend,
+) -> (Option<(TcpStream, Params)>, Result<(Ack, Option<String>)>) {
+    let RangeSend {
+        mut stream,
+        params,
+        seq,
+        header,
+        range,
+        data,
+        sendfile,
+        limits,
+    } = send;
+    let answer = async {
+        let (reader, writer) = stream.split();
+        let mut reader = FrameReader::new(reader);
+        reader.set_features(params.features);
+        let mut writer = FrameWriter::new(writer);
+        writer.set_features(params.features);
+        let mut out = Outgoing {
+            chunk_size: params.chunk_size,
+            limits,
+            sendfile: sendfile.as_ref(),
+            hashes: None,
+        };
+        let (start, end) = (range.offset as usize, (range.offset + range.len) as usize);
+        write_range(
+            &mut writer,
+            &mut out,
+            seq,
+            &header,
+            &range,
+            &data[start..end],
+        )
+        .await?;
+        let (_, ack, reason) = reader.read_ack_reason().await?;
+        Ok((ack, reason))
+    }
+    .await;
+    match answer {
+        Ok(answer) => (Some((stream, params)), Ok(answer)),
+        Err(e) => (None, Err(e)),
+    }
+}
+
+/// Does `ack` say more about a striped file than `than`, the answer of
+/// another of its ranges? A failure outranks anything else, and anything
+/// a plain OK.
+fn outranks(ack: Ack, than: Ack) -> bool {
+    !than.is_failure() && (ack.is_failure() || than == Ack::Ok)
+}
+
+/// Sends remembered this long, for destinations that get to a file later
+const MULTICAST_MEMORY: Duration = Duration::from_secs(600);
+
+/// Sends file content to the `--multicast` group, once however many
+/// destinations take it
+struct Multicaster {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    /// `--multicast-above`
+    above: u64,
+    fec_group: u8,
+    limiter: RateLimiter,
+    /// Datagram tag key
+    key: [u8; 32],
+    /// Sends under way or done, by multicast id, and when they started
+    sends: Mutex<HashMap<[u8; 16], (Instant, MulticastSend)>>,
+}
+
+/// Done once the first destination to get to it has sent it
+type MulticastSend = Arc<OnceCell<()>>;
+
+impl Multicaster {
+    fn new(addr: SocketAddr, args: &Args, key: Option<&[u8; 32]>) -> Result<Self> {
+        // Receivers drop untagged datagrams
+        let key =
+            key.context("--multicast needs --psk or --psk-file, to authenticate datagrams")?;
+        let any = if addr.is_ipv4() {
+            IpAddr::from(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::from(Ipv6Addr::UNSPECIFIED)
+        };
+        let socket = std::net::UdpSocket::bind((any, 0)).context("Bind the --multicast socket")?;
+        if let IpAddr::V4(ip) = addr.ip()
+            && !ip.is_multicast()
+        {
+            socket.set_broadcast(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: UdpSocket::from_std(socket)?,
+            addr,
+            above: args.multicast_above,
+            fec_group: args.fec_group,
+            limiter: RateLimiter::new(args.multicast_rate),
+            key: multicast::tag_key(key),
+            sends: Mutex::default(),
+        })
+    }
+
+    /// Send the content `data` to the group as `id`, unless another
+    /// destination did already; one that is doing so is waited for. A
+    /// failure is only logged: the destinations then ask for everything.
+    async fn transmit(&self, id: [u8; 16], data: &[u8], name: &str) {
+        let once = {
+            let mut sends = self.sends.lock().unwrap();
+            sends.retain(|_, (at, _)| at.elapsed() < MULTICAST_MEMORY);
+            sends
+                .entry(id)
+                .or_insert_with(|| (Instant::now(), Arc::default()))
+                .1
+                .clone()
+        };
+        once.get_or_init(|| async {
+            let start = Instant::now();
+            match self.send(id, data).await {
+                Ok(()) => debug!(
+                    "[>] Multicast {name} to {} in {:.2?}",
+                    self.addr,
+                    start.elapsed()
+                ),
+                Err(e) => warn!("[!] Failed to multicast {name}: {e}"),
+            }
+        })
+        .await;
+    }
+
+    async fn send(&self, id: [u8; 16], data: &[u8]) -> std::io::Result<()> {
+        let size = data.len() as u64;
+        let packets = multicast::packets(size) as usize;
+        let group = self.fec_group as usize;
+        let mut buf = Vec::with_capacity(multicast::MAX_DATAGRAM);
+        let mut parity = vec![0; PACKET_LEN];
+        for (index, packet) in data.chunks(PACKET_LEN).enumerate() {
+            self.limiter.acquire(packet.len()).await;
+            let datagram = Datagram {
+                id,
+                size,
+                index: index as u32,
+                group: self.fec_group,
+                kind: multicast::Kind::Data,
+                data: packet,
+            };
+            datagram.encode(Some(&self.key), &mut buf);
+            self.socket.send_to(&buf, self.addr).await?;
+            if group == 0 {
+                continue;
+            }
+            multicast::xor_into(&mut parity, packet);
+            if (index + 1) % group == 0 || index + 1 == packets {
+                self.limiter.acquire(PACKET_LEN).await;
+                let index = (index / group) as u32;
+                let datagram = Datagram {
+                    index,
+                    kind: multicast::Kind::Parity,
+                    data: &parity,
+                    ..datagram
+                };
+                datagram.encode(Some(&self.key), &mut buf);
+                self.socket.send_to(&buf, self.addr).await?;
+                parity.fill(0);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How one transfer's payload chunks go out
+struct Outgoing<'a> {
+    chunk_size: usize,
+    limits: Vec<Arc<RateLimiter>>,
+    /// File to sendfile(2) chunk data from; cleared if the kernel can't
+    sendfile: Option<&'a File>,
+    /// The chunks' checksums, in order, from a [`hash_ahead`] task
+    hashes: Option<mpsc::Receiver<blake3::Hash>>,
+}
+
+impl Outgoing<'_> {
+    /// Smaller chunks keep throttled sends smooth
+    fn chunk_len(&self) -> usize {
+        if self.limits.is_empty() {
+            self.chunk_size
+        } else {
+            self.chunk_size.min(throttle::CHUNK_SIZE)
+        }
+    }
+}
+
+/// The hashing stage of a large file's send: reads `data` ahead of the
+/// sending, so disk, CPU and network work at once, and hands over the
+/// checksum of each `chunk_len` chunk. Returns the whole file's checksum too
+/// unless `whole` is false. Stops early once the sending gave up.
+fn hash_ahead(
+    data: &Mmap,
+    chunk_len: usize,
+    whole: bool,
+    hashes: mpsc::Sender<blake3::Hash>,
+) -> Option<blake3::Hash> {
+    let _ = data.advise(memmap2::Advice::Sequential);
+    let mut hasher = whole.then(Hasher::new);
+    for (i, chunk) in data.chunks(chunk_len).enumerate() {
+        // Have the disk busy with the chunks after those queued
+        let next = (i + HASH_AHEAD) * chunk_len;
+        if next < data.len() {
+            let _ = data.advise_range(
+                memmap2::Advice::WillNeed,
+                next,
+                chunk_len.min(data.len() - next),
+            );
+        }
+        if hashes.blocking_send(blake3::hash(chunk)).is_err() {
+            return None;
+        }
+        if let Some(hasher) = &mut hasher {
+            hasher.update(chunk);
+        }
+    }
+    hasher.map(|h| h.finalize())
+}
+
+/// Write payload bytes as chunks, pacing them to every rate limit and adding
+/// them to `hasher`, if any, on the way. `data` starts at `offset` in the file.
+async fn write_throttled(
+    writer: &mut FrameWriter<WriteHalf<'_>>,
+    out: &mut Outgoing<'_>,
+    data: &[u8],
+    mut offset: u64,
+    mut hasher: Option<&mut Hasher>,
+) -> Result<()> {
+    for chunk in data.chunks(out.chunk_len()) {
+        if let Some(hasher) = &mut hasher {
+            hasher.update(chunk);
+        }
+        let checksum = match &mut out.hashes {
+            Some(hashes) => Some(hashes.recv().await.context("Hashing stopped")?),
+            None => None,
+        };
+        for limit in &out.limits {
+            limit.acquire(chunk.len()).await;
+        }
+        match out.sendfile {
+            Some(file) => {
+                writer.write_chunk_header(chunk.len() as u32).await?;
+                let done = sendfile(writer.get_mut().as_ref(), file, offset, chunk.len()).await?;
+                if done < chunk.len() {
+                    debug!("[*] sendfile(2) not supported here; copying through userspace");
+                    writer.get_mut().write_all(&chunk[done..]).await?;
+                    out.sendfile = None;
+                }
+                match &checksum {
+                    Some(checksum) => writer.end_hashed_chunk(checksum).await?,
+                    None => writer.end_chunk(chunk).await?,
+                }
+            }
+            None => match &checksum {
+                Some(checksum) => writer.write_hashed_chunk(chunk, checksum).await?,
+                None => writer.write_chunk(chunk).await?,
+            },
+        }
+        offset += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// Copy `len` bytes of `file` from `offset` to `sock` within the kernel.
+/// Returns how many went out before sendfile(2) turned out not to work for
+/// this file, so the caller can write the rest itself.
+async fn sendfile(sock: &TcpStream, file: &File, offset: u64, len: usize) -> Result<usize> {
+    let mut off = offset as libc::off_t;
+    let mut done = 0;
+    while done < len {
+        sock.writable().await?;
+        let res = sock.try_io(Interest::WRITABLE, || {
+            let n =
+                unsafe { libc::sendfile(sock.as_raw_fd(), file.as_raw_fd(), &mut off, len - done) };
+            if n < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(n as usize)
+            }
+        });
+        match res {
+            Ok(0) => anyhow::bail!("File shrank while sending"),
+            Ok(n) => done += n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                ) => {}
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP)
+                ) =>
+            {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(done)
+}
+
+/// Format a byte count with a binary unit, e.g. `12.50 MiB`
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.2} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options as the `watcher` takes them on its command line
+    fn options(args: &[&str]) -> SendOptions {
+        let argv = ["watcher"].iter().chain(args).map(Into::into).collect();
+        let (args, _, _) = config::parse_from::<Args>(argv).unwrap();
+        send_options(&args).unwrap()
+    }
+
+    #[test]
+    fn events_for_a_path_coalesce() {
+        let mut pending = HashMap::new();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let quiet = Duration::from_millis(50);
+        let (a, b) = (PathBuf::from("/w/a"), PathBuf::from("/w/b"));
+        // An editor saving a file writes it several times in a row
+        for ms in [0, 10, 20] {
+            note_event(&mut pending, a.clone(), at(ms), quiet);
+        }
+        note_event(&mut pending, b.clone(), at(5), quiet);
+        assert_eq!(pending.len(), 2);
+
+        assert_eq!(due_paths(&pending, at(54)), []);
+        assert_eq!(due_paths(&pending, at(55)), [(at(5), b.clone())]);
+        // Due once the last event is a window old, first by its first event
+        assert_eq!(due_paths(&pending, at(70)), [(at(0), a), (at(5), b)]);
+
+        // What was pending under a moved directory goes with it, keeping
+        // the earliest first event
+        note_event(&mut pending, PathBuf::from("/w/d/x"), at(30), quiet);
+        note_event(&mut pending, PathBuf::from("/w/d/y/z"), at(1), quiet);
+        assert_eq!(forget_pending(&mut pending, Path::new("/w/d")), Some(at(1)));
+        assert_eq!(pending.len(), 2);
+    }
+
+    #[test]
+    fn retry_journals_replay_after_a_restart() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("10.0.0.2_5001.queue");
+        let file = |name: &str| Job::File(dir.join(name), Instant::now());
+        let journal = RetryJournal::new(path.clone());
+        assert!(journal.is_empty());
+        journal.append(&file("a"));
+        journal.append(&Job::Delete(dir.join("gone")));
+        journal.append(&Job::Rename(dir.join("old"), dir.join("new")));
+        journal.append(&file("a"));
+        journal.append(&Job::Batch(vec![
+            (dir.join("b"), Instant::now()),
+            (dir.join("c"), Instant::now()),
+        ]));
+        // Nothing to replay: the next scrub covers it
+        journal.append(&Job::Heartbeat);
+        drop(journal);
+
+        // A new process finds the queue where the last one left it
+        let journal = RetryJournal::new(path.clone());
+        assert!(!journal.is_empty());
+        let replayed: Vec<_> = journal
+            .take()
+            .into_iter()
+            .map(|job| match job {
+                Job::File(path, _) => format!("F {}", path.display()),
+                Job::Delete(path) => format!("D {}", path.display()),
+                Job::Rename(from, to) => format!("R {} {}", from.display(), to.display()),
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        let d = dir.display();
+        assert_eq!(
+            replayed,
+            [
+                format!("F {d}/a"),
+                format!("D {d}/gone"),
+                format!("R {d}/old {d}/new"),
+                format!("F {d}/b"),
+                format!("F {d}/c"),
+            ]
+        );
+        // Taken jobs are gone from the disk
+        assert!(journal.is_empty() && !path.exists());
+        assert!(journal.take().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reconnects_back_off_with_jitter() {
+        let opts = options(&["--reconnect-max-delay", "4", "--max-reconnects", "5"]);
+        let dest = Destination::parse_list("10.0.0.2", 5001).pop().unwrap();
+        let refused = anyhow::anyhow!("Connection refused");
+        let mut health = Health::new();
+        health.max_failures = dest.max_failures(&opts);
+        assert_eq!(health.state(), HealthState::Up);
+
+        // Doubling from half a second up to --reconnect-max-delay, and
+        // after --max-reconnects only probed that often
+        let schedule = [500, 1000, 2000, 4000, 4000, 4000];
+        for (attempt, full) in schedule.into_iter().enumerate() {
+            let before = Instant::now();
+            health.failed(&dest, &opts, &refused);
+            let after = Instant::now();
+            // Jittered over the upper half of the full delay
+            let full = Duration::from_millis(full);
+            assert!(health.retry_at - before >= full / 2, "attempt {attempt}");
+            assert!(health.retry_at - after < full, "attempt {attempt}");
+            let state = match attempt {
+                ..4 => HealthState::Degraded,
+                _ => HealthState::Down,
+            };
+            assert_eq!(health.state(), state, "attempt {attempt}");
+        }
+        health.connected(&dest);
+        assert_eq!(health.state(), HealthState::Up);
+    }
+
+    #[test]
+    fn standbys_take_over_in_group_order() {
+        let dests = Destination::parse_list("10.0.1.1|10.0.1.2:5002|10.0.1.3,10.0.1.4", 5001);
+        let groups: Vec<_> = dests.iter().map(|d| d.group.as_deref()).collect();
+        let group = Some("10.0.1.1:5001");
+        assert_eq!(groups, [group, group, group, None]);
+        let queues: Vec<_> = dests
+            .into_iter()
+            .map(|dest| DestHandle {
+                metrics: Arc::new(DestMetrics::new(&dest)),
+                dest: Arc::new(Shared::new(dest)),
+                tx: mpsc::unbounded_channel().0,
+                ahead: Arc::new(Shared::new(Vec::new())),
+            })
+            .collect();
+        link_groups(&queues, true);
+        let waiting =
+            || -> Vec<bool> { queues.iter().map(|q| standing_by(&q.ahead.get())).collect() };
+        let set = |i: usize, state: HealthState| queues[i].metrics.health.set(state as i64);
+        assert_eq!(waiting(), [false, true, true, false]);
+
+        // Only down counts: a primary that is reconnecting keeps its files
+        set(0, HealthState::Degraded);
+        assert_eq!(waiting(), [false, true, true, false]);
+        set(0, HealthState::Down);
+        assert_eq!(waiting(), [false, false, true, false]);
+        set(1, HealthState::Down);
+        assert_eq!(waiting(), [false, false, false, false]);
+        // Back in order once the primary recovers
+        set(0, HealthState::Up);
+        assert_eq!(waiting(), [false, true, true, false]);
+    }
+
+    #[test]
+    fn scans_find_new_changed_and_deleted_files() {
+        let dir = std::env::temp_dir().join(format!("fast-sync-scan-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["same", "grown", "gone", "sub/rewritten"] {
+            std::fs::write(dir.join(name), b"data").unwrap();
+        }
+        let opts = options(&["--watch-dir", &dir.display().to_string()]);
+        let names = |changes: Vec<Change>| -> Vec<String> {
+            let mut names: Vec<_> = changes
+                .into_iter()
+                .map(|change| match change {
+                    Change::Written(path) => format!("written {}", opts.rel(&path).display()),
+                    Change::Deleted(path, false) => {
+                        format!("deleted {}", opts.rel(&path).display())
+                    }
+                    _ => panic!("scans only see files written or deleted"),
+                })
+                .collect();
+            names.sort();
+            names
+        };
+
+        for checksums in [false, true] {
+            // The first scan only takes stock
+            let (seen, changes) = scan(&opts, None, checksums);
+            assert_eq!(seen.len(), 4);
+            assert!(changes.is_empty());
+
+            std::fs::write(dir.join("new"), b"data").unwrap();
+            std::fs::write(dir.join("grown"), b"more data").unwrap();
+            std::fs::rename(dir.join("gone"), dir.join("sub/gone")).unwrap();
+            // Same size and mtime: only a checksum tells
+            let rewritten = dir.join("sub/rewritten");
+            let mtime = std::fs::metadata(&rewritten).unwrap().modified().unwrap();
+            std::fs::write(&rewritten, b"DATA").unwrap();
+            File::options()
+                .write(true)
+                .open(&rewritten)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+
+            let (seen, changes) = scan(&opts, Some(seen), checksums);
+            assert_eq!(seen.len(), 5);
+            let mut expected = vec![
+                "deleted gone",
+                "written grown",
+                "written new",
+                "written sub/gone",
+            ];
+            if checksums {
+                expected.push("written sub/rewritten");
+            }
+            assert_eq!(names(changes), expected, "checksums: {checksums}");
+            // Nothing more until something else changes
+            assert!(scan(&opts, Some(seen), checksums).1.is_empty());
+
+            std::fs::remove_file(dir.join("new")).unwrap();
+            std::fs::write(dir.join("grown"), b"data").unwrap();
+            std::fs::rename(dir.join("sub/gone"), dir.join("gone")).unwrap();
+            std::fs::write(&rewritten, b"data").unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lost_roots_are_watched_again_once_back() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = std::env::temp_dir().join(format!("fast-sync-remount-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let opts = options(&["--watch-dir", &root.display().to_string()]);
+        let mut watch = InotifyWatch::new(&opts).unwrap();
+        let next = async |watch: &mut InotifyWatch| {
+            tokio::time::timeout(Duration::from_secs(5), watch.changes(&opts))
+                .await
+                .expect("no change within 5s")
+                .unwrap()
+        };
+
+        std::fs::remove_dir_all(&root).unwrap();
+        while !next(&mut watch)
+            .await
+            .iter()
+            .any(|change| matches!(change, Change::Lost(dir) if *dir == root))
+        {}
+        assert!(watch.found(&opts).is_empty());
+
+        // What was written while it was gone comes first, then the scrub
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/a"), b"a").unwrap();
+        match watch.found(&opts).as_slice() {
+            [Change::Written(a), Change::Back(back)] => {
+                assert_eq!(*a, root.join("sub/a"));
+                assert_eq!(*back, root);
+            }
+            _ => panic!("expected sub/a and the root back"),
+        }
+        // Watched again, below the root too
+        std::fs::write(root.join("sub/b"), b"b").unwrap();
+        let changes = next(&mut watch).await;
+        // Created, then closed after writing
+        assert!(!changes.is_empty());
+        assert!(
+            changes
+                .iter()
+                .all(|change| matches!(change, Change::Written(b) if *b == root.join("sub/b")))
+        );
+
+        // An unmounted root is only back once another filesystem is mounted
+        // on it, not while the empty mount point is there
+        let dev = std::fs::metadata(&root).unwrap().dev();
+        watch
+            .lost
+            .insert(root.clone(), (Some(dev), SystemTime::now()));
+        assert!(watch.found(&opts).is_empty());
+        watch
+            .lost
+            .insert(root.clone(), (Some(dev + 1), SystemTime::now()));
+        assert!(matches!(watch.found(&opts).last(), Some(Change::Back(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+//! WebSocket (RFC 6455) framing, for running the protocol through HTTP
+//! proxies and load balancers that pass WebSocket upgrades but nothing else.
+//!
+//! The watcher with `--websocket` asks for the upgrade in place of the TCP
+//! connection's first bytes, and the receiver's `--http` port grants it.
+//! From then on [`pump`] carries the protocol's bytes in binary frames in
+//! both directions and hands them on to a plain stream, so both ends run
+//! the protocol exactly as over TCP. Frames from the connecting side are
+//! masked, as the RFC requires of clients.
+
+use anyhow::{Context, Result};
+use std::{io, net::Ipv4Addr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use crate::http::{self, Request};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Asked for the upgrade; masks its frames
+    Client,
+    Server,
+}
+
+/// Appended to the client's key to prove the server speaks WebSocket
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Largest frame sent; what the plain side has is read up to this much at
+/// a time
+const FRAME_LEN: usize = 64 * 1024;
+
+/// How long the side that closes waits for the other's close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Ask for the upgrade to a WebSocket at `path` of `host` (the `Host` header,
+/// HOST:PORT) on `conn`. Returns what the server sent after its answer,
+/// which belongs to the first frames.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    host: &str,
+    path: &str,
+) -> Result<Vec<u8>> {
+    let key = http::base64(&random::<16>()?);
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    );
+    conn.write_all(request.as_bytes()).await?;
+    let (response, early) = http::read_response(conn)
+        .await
+        .context("Read the WebSocket upgrade answer")?;
+    anyhow::ensure!(
+        response.status == 101,
+        "WebSocket upgrade refused: {} {}",
+        response.status,
+        response.reason
+    );
+    anyhow::ensure!(
+        response.header("Sec-WebSocket-Accept") == Some(accept_key(&key).as_str()),
+        "WebSocket upgrade answered with the wrong Sec-WebSocket-Accept"
+    );
+    Ok(early)
+}
+
+/// Does `request` ask for a WebSocket?
+pub fn is_upgrade(request: &Request) -> bool {
+    request.has_token("Upgrade", "websocket") && request.has_token("Connection", "upgrade")
+}
+
+/// Grant the upgrade `request` asked for on `conn`
+pub async fn accept<S: AsyncWrite + Unpin>(conn: &mut S, request: &Request) -> Result<()> {
+    anyhow::ensure!(
+        request.method == "GET",
+        "WebSocket upgrade with {}",
+        request.method
+    );
+    anyhow::ensure!(
+        request.header("Sec-WebSocket-Version") == Some("13"),
+        "Unsupported WebSocket version"
+    );
+    let key = request
+        .header("Sec-WebSocket-Key")
+        .context("WebSocket upgrade without Sec-WebSocket-Key")?;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    conn.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Carry `local`'s bytes over the WebSocket `ws` and the frames that arrive
+/// back to `local`, until both sides closed; `early` is what arrived with
+/// the upgrade. When `local` ends, the WebSocket is closed, and when the
+/// peer closes, `local` is shut down for writing.
+pub async fn pump<W, L>(ws: W, early: Vec<u8>, local: L, role: Role) -> Result<()>
+where
+    W: AsyncRead + AsyncWrite,
+    L: AsyncRead + AsyncWrite,
+{
+    let (ws_reader, mut ws_writer) = tokio::io::split(ws);
+    let mut ws_reader = io::Cursor::new(early).chain(ws_reader);
+    let (mut local_reader, mut local_writer) = tokio::io::split(local);
+    // Pongs and the answer to a close go out with the data
+    let (control_tx, mut control) = mpsc::unbounded_channel::<(u8, Vec<u8>)>();
+    let mut masks = Masks::new(role)?;
+    let outgoing = async {
+        let mut buf = vec![0; FRAME_LEN];
+        loop {
+            tokio::select! {
+                n = local_reader.read(&mut buf) => match n? {
+                    0 => {
+                        write_frame(&mut ws_writer, OP_CLOSE, &1000u16.to_be_bytes(), masks.next()).await?;
+                        break;
+                    }
+                    n => write_frame(&mut ws_writer, OP_BINARY, &buf[..n], masks.next()).await?,
+                },
+                Some((op, payload)) = control.recv() => {
+                    write_frame(&mut ws_writer, op, &payload, masks.next()).await?;
+                    if op == OP_CLOSE {
+                        break;
+                    }
+                }
+            }
+        }
+        ws_writer.shutdown().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    let incoming = async {
+        let mut buf = vec![0; FRAME_LEN];
+        while let Some((op, len, mask)) = read_header(&mut ws_reader, role == Role::Server).await? {
+            match op {
+                OP_BINARY | OP_CONTINUATION => {
+                    let mut done = 0;
+                    while done < len {
+                        let n = ((len - done) as usize).min(buf.len());
+                        ws_reader.read_exact(&mut buf[..n]).await?;
+                        unmask(&mut buf[..n], mask, done);
+                        local_writer.write_all(&buf[..n]).await?;
+                        done += n as u64;
+                    }
+                }
+                OP_PING | OP_PONG | OP_CLOSE => {
+                    anyhow::ensure!(len <= 125, "WebSocket control frame of {len} bytes");
+                    let mut payload = vec![0; len as usize];
+                    ws_reader.read_exact(&mut payload).await?;
+                    unmask(&mut payload, mask, 0);
+                    match op {
+                        OP_PING => drop(control_tx.send((OP_PONG, payload))),
+                        OP_CLOSE => {
+                            payload.truncate(2);
+                            let _ = control_tx.send((OP_CLOSE, payload));
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                op => anyhow::bail!("Unexpected WebSocket opcode {op:#x}"),
+            }
+        }
+        local_writer.shutdown().await?;
+        Ok(())
+    };
+    tokio::pin!(outgoing, incoming);
+    tokio::select! {
+        sent = &mut outgoing => {
+            sent?;
+            tokio::time::timeout(CLOSE_TIMEOUT, incoming).await.unwrap_or(Ok(()))
+        }
+        received = &mut incoming => {
+            received?;
+            outgoing.await
+        }
+    }
+}
+
+/// Two ends of a TCP connection over loopback, for code that wants a
+/// [`TcpStream`] to run over a WebSocket with [`pump`]
+pub async fn loopback_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let near = TcpStream::connect(listener.local_addr()?).await?;
+    near.set_nodelay(true)?;
+    // Anyone else who connects in between is turned away
+    loop {
+        let (far, peer) = listener.accept().await?;
+        if peer == near.local_addr()? {
+            far.set_nodelay(true)?;
+            return Ok((near, far));
+        }
+    }
+}
+
+/// Opcode, payload length and mask of the next frame; `None` if the
+/// connection ended between frames
+async fn read_header<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    masked: bool,
+) -> Result<Option<(u8, u64, Option<[u8; 4]>)>> {
+    let mut head = [0u8; 2];
+    if reader.read(&mut head[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut head[1..]).await?;
+    anyhow::ensure!(
+        head[0] & 0x70 == 0,
+        "WebSocket frame with extension bits set"
+    );
+    anyhow::ensure!(
+        head[1] & 0x80 != 0 || !masked,
+        "Unmasked WebSocket frame from the client"
+    );
+    let len = match head[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    let mask = match head[1] & 0x80 {
+        0 => None,
+        _ => {
+            let mut mask = [0u8; 4];
+            reader.read_exact(&mut mask).await?;
+            Some(mask)
+        }
+    };
+    Ok(Some((head[0] & 0x0f, len, mask)))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    op: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | op);
+    let masked = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => frame.push(masked | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(masked | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(masked | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let start = frame.len();
+    frame.extend_from_slice(payload);
+    if let Some(mask) = mask {
+        frame.splice(start..start, mask);
+        unmask(&mut frame[start + 4..], Some(mask), 0);
+    }
+    writer.write_all(&frame).await
+}
+
+/// Undo (or apply) `mask` on `data`, which starts `offset` bytes into the
+/// frame's payload
+fn unmask(data: &mut [u8], mask: Option<[u8; 4]>, offset: u64) {
+    let Some(mask) = mask else { return };
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[(offset as usize + i) % 4];
+    }
+}
+
+/// Unpredictable masks for a client's frames, without reading the system's
+/// random source for every one
+struct Masks {
+    key: Option<[u8; 32]>,
+    count: u64,
+}
+
+impl Masks {
+    fn new(role: Role) -> io::Result<Self> {
+        let key = match role {
+            Role::Client => Some(random()?),
+            Role::Server => None,
+        };
+        Ok(Self { key, count: 0 })
+    }
+
+    fn next(&mut self) -> Option<[u8; 4]> {
+        let key = self.key.as_ref()?;
+        self.count += 1;
+        Some(
+            blake3::keyed_hash(key, &self.count.to_le_bytes()).as_bytes()[..4]
+                .try_into()
+                .unwrap(),
+        )
+    }
+}
+
+fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    std::io::Read::read_exact(&mut std::fs::File::open("/dev/urandom")?, &mut buf)?;
+    Ok(buf)
+}
+
+/// `Sec-WebSocket-Accept` for `key`: base64 of the SHA-1 of it and [`GUID`]
+fn accept_key(key: &str) -> String {
+    http::base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// SHA-1, which the upgrade handshake needs and nothing else here does
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..20 => ((b & c) | (!b & d), 0x5a827999),
+                20..40 => (b ^ c ^ d, 0x6ed9eba1),
+                40..60 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 20];
+    for (chunk, v) in out.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn sha1_matches_fips_180_vectors() {
+        let vectors = [
+            ("", "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+            ("abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            ),
+        ];
+        for (input, digest) in vectors {
+            assert_eq!(hex(&sha1(input.as_bytes())), digest, "SHA-1 of {input:?}");
+        }
+        assert_eq!(
+            hex(&sha1(&vec![b'a'; 1_000_000])),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f"
+        );
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
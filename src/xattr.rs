@@ -0,0 +1,168 @@
+//! Extended attributes carried in headers when both sides agree to
+//! [`Features::XATTRS`](crate::handshake::Features::XATTRS).
+//!
+//! Only `user.*` attributes and POSIX ACLs (`system.posix_acl_access` and
+//! `system.posix_acl_default`) are transferred: the other namespaces are
+//! host-specific or need privileges. [`RECEIVED`] is never transferred.
+
+use std::{
+    ffi::CString,
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+};
+
+/// Most attributes one header may carry
+pub const MAX_XATTRS: usize = 1024;
+
+/// Longest attribute name Linux allows
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Largest attribute value Linux allows
+pub const MAX_VALUE_LEN: usize = 64 * 1024;
+
+/// One extended attribute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Set by receivers running with `--mark-received` on the files they put in
+/// place, holding the size and mtime the file had then, so a watcher on the
+/// same directory can tell those writes from local ones
+pub const RECEIVED: &str = "user.fast-sync.received";
+
+/// Is `name` in one of the namespaces we transfer?
+fn transfers(name: &str) -> bool {
+    name != RECEIVED
+        && (name.starts_with("user.")
+            || matches!(name, "system.posix_acl_access" | "system.posix_acl_default"))
+}
+
+fn received_value(file: &File) -> io::Result<String> {
+    let meta = file.metadata()?;
+    Ok(format!(
+        "{} {}.{:09}",
+        meta.size(),
+        meta.mtime(),
+        meta.mtime_nsec()
+    ))
+}
+
+/// Tag `file` as received as it is now
+pub fn mark_received(file: &File) -> io::Result<()> {
+    let value = received_value(file)?;
+    let name = CString::new(RECEIVED)?;
+    let res = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Is `file` unchanged since a receiver tagged it?
+pub fn is_received(file: &File) -> bool {
+    let Ok(name) = CString::new(RECEIVED) else {
+        return false;
+    };
+    let fd = file.as_raw_fd();
+    match fill(|buf| unsafe {
+        libc::fgetxattr(fd, name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    }) {
+        Ok(value) => received_value(file).is_ok_and(|now| now.as_bytes() == value),
+        Err(_) => false,
+    }
+}
+
+/// Ask a size-probing xattr call for its result, growing the buffer until it fits
+fn fill(mut call: impl FnMut(&mut [u8]) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let len = call(&mut []);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        let n = call(&mut buf);
+        if n >= 0 {
+            buf.truncate(n as usize);
+            return Ok(buf);
+        }
+        // Grew between the two calls
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+fn names(file: &File) -> io::Result<Vec<String>> {
+    let fd = file.as_raw_fd();
+    let list = fill(|buf| unsafe { libc::flistxattr(fd, buf.as_mut_ptr().cast(), buf.len()) })?;
+    Ok(list
+        .split(|&b| b == 0)
+        .filter_map(|name| std::str::from_utf8(name).ok())
+        .filter(|name| transfers(name))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The transferable attributes of `file`; empty where the filesystem has none
+pub fn read(file: &File) -> io::Result<Vec<Xattr>> {
+    let fd = file.as_raw_fd();
+    let names = match names(file) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut attrs = Vec::new();
+    for name in names.into_iter().take(MAX_XATTRS) {
+        let c_name = CString::new(name.as_str())?;
+        match fill(|buf| unsafe {
+            libc::fgetxattr(fd, c_name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+        }) {
+            Ok(value) => attrs.push(Xattr { name, value }),
+            // Removed since it was listed
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(attrs)
+}
+
+/// Make the transferable attributes of `file` exactly `attrs`
+pub fn write(file: &File, attrs: &[Xattr]) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    for name in names(file)? {
+        if !attrs.iter().any(|a| a.name == name) {
+            let c_name = CString::new(name)?;
+            if unsafe { libc::fremovexattr(fd, c_name.as_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    for attr in attrs.iter().filter(|a| transfers(&a.name)) {
+        let c_name = CString::new(attr.name.as_str())?;
+        let res = unsafe {
+            libc::fsetxattr(
+                fd,
+                c_name.as_ptr(),
+                attr.value.as_ptr().cast(),
+                attr.value.len(),
+                0,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}